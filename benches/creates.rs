@@ -0,0 +1,71 @@
+//! How long an overlay takes to handle a burst of 20k creates spread over many directories,
+//! like that of extracting a big archive into an input, on as many workers as given.
+//!
+//! Run with `cargo bench --features test-util --bench creates`.
+//!
+//! On one CPU and ext4, all pool sizes put 22k to 33k hard links there a second, from run
+//! to run, so the pool doesn't speed hard links up yet.
+
+use overlay::{OverlayBuilder, SyntheticEvent};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DIRECTORIES: usize = 200;
+const FILES_PER_DIRECTORY: usize = 100;
+/// The sizes of the pool, 1 being without one.
+const WORKERS: [usize; 4] = [1, 2, 4, 8];
+
+fn main() {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    println!("{} CPUs", cpus);
+    let root = env::temp_dir().join(format!("overlay-bench-{}-creates", process::id()));
+    let paths: Vec<PathBuf> = (0..DIRECTORIES)
+        .flat_map(|dir| {
+            (0..FILES_PER_DIRECTORY)
+                .map(move |file| PathBuf::from(format!("dir{:03}/file{:03}.dat", dir, file)))
+        })
+        .collect();
+
+    for workers in WORKERS {
+        let _ = fs::remove_dir_all(&root);
+        let input = root.join("input");
+        fs::create_dir_all(&input).unwrap();
+        let mut overlay = OverlayBuilder::new(root.join("output"))
+            .single_instance(false)
+            .cross_input_window(Duration::ZERO)
+            .workers(workers)
+            .build()
+            .unwrap();
+        let id = overlay.add_input(&input, 0).unwrap();
+        overlay.sync_once().unwrap();
+
+        // The files are all there by the time the overlay hears of them.
+        for path in &paths {
+            let file = input.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, path.to_str().unwrap()).unwrap();
+        }
+        for path in &paths {
+            overlay
+                .inject_event(id, SyntheticEvent::Create(path.clone()))
+                .unwrap();
+        }
+
+        let start = Instant::now();
+        overlay.drain().unwrap();
+        let took = start.elapsed();
+        assert_eq!(overlay.stats().linked, paths.len() as u64);
+        println!(
+            "{} workers: {} creates in {:.0?}, {:.0} per second",
+            workers,
+            paths.len(),
+            took,
+            paths.len() as f64 / took.as_secs_f64()
+        );
+    }
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,581 @@
+use crate::audit::AuditAction;
+use crate::input::Rank;
+use crate::{error_kind, ConfigError, Event, EventType, Overlay, SyncReport};
+use failure::Error;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What the overlay made of the files already in an output it never ran on, when it was
+/// told to adopt them. See `ExistingOutputPolicy::Adopt`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdoptionReport {
+    /// Files that are the file of an input at their path, hard linked from it.
+    pub linked: usize,
+    /// Files with the same contents as it, kept as copies of it. See
+    /// `OverlayBuilder::adopt_copies`.
+    pub copies: usize,
+    /// Files that are neither, which are linked again or dealt with as the foreign file
+    /// policy says.
+    pub rebuilt: usize,
+}
+
+impl fmt::Display for AdoptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Adopted output: {} linked, {} copies, {} rebuilt",
+            self.linked, self.copies, self.rebuilt
+        )
+    }
+}
+
+/// What the overlay does with the files in the output it didn't put there.
+#[derive(Debug, Default)]
+pub(crate) struct Adoption {
+    pub(crate) foreign: ForeignFiles,
+    pub(crate) existing: ExistingOutputPolicy,
+    /// Whether copies of the files of inputs are adopted too.
+    pub(crate) copies: bool,
+    /// The adopted copies that haven't been synced yet, and the inputs they are of.
+    pub(crate) adopted: FxHashMap<PathBuf, usize>,
+    /// What was adopted, until the sync it was for reports it.
+    pub(crate) report: Option<AdoptionReport>,
+}
+
+impl Overlay {
+    /// Takes the files at `existing` in the output that are, or are copies of, the file of
+    /// the input that would provide them for files the overlay put there, so the sync
+    /// keeps rather than replaces them.
+    pub(crate) fn adopt_output(&mut self, existing: &[PathBuf]) -> AdoptionReport {
+        let mut report = AdoptionReport::default();
+        for path in existing {
+            let output_file = self.output.join(path);
+            let found = match self.candidate(path) {
+                Some(found) => found,
+                None => {
+                    report.rebuilt += 1;
+                    continue;
+                }
+            };
+            let (index, source) = found;
+
+            let number = |path: &Path| {
+                self.fs
+                    .identity(path)
+                    .ok()
+                    .and_then(|identity| identity.number())
+            };
+            if number(&output_file).is_some() && number(&output_file) == number(&source) {
+                self.record(path);
+                report.linked += 1;
+            } else if self.adoption.copies
+                && self
+                    .fs
+                    .same_contents(&source, &output_file)
+                    .unwrap_or(false)
+            {
+                self.record(path);
+                self.adoption.adopted.insert(path.clone(), index);
+                report.copies += 1;
+            } else {
+                report.rebuilt += 1;
+            }
+        }
+        info!("{}", report);
+        report
+    }
+
+    /// The input of highest rank that has a file at `path` of the output, and where it is.
+    fn candidate(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        let mut inputs: Vec<(Rank, usize)> = self
+            .inputs
+            .iter()
+            .filter(|input| input.enabled && input.accepts_file(path))
+            .map(|input| (input.rank, input.index))
+            .collect();
+        inputs.sort_by(|a, b| b.cmp(a));
+        inputs.into_iter().find_map(|(_, index)| {
+            let source = self.inputs[index].source(path);
+            Some((index, source))
+                .filter(|(_, source)| self.fs.exists(source) && !self.fs.is_dir(source))
+        })
+    }
+
+    /// Whether the file at `path` in the output is a copy of the one of input `index`
+    /// that was adopted and wasn't changed since, which is then kept as a copy.
+    pub(crate) fn adopted_copy(&mut self, path: &Path, index: usize) -> bool {
+        if self.adoption.adopted.remove(path) != Some(index) {
+            return false;
+        }
+        self.owned(path)
+    }
+
+    /// Whether the output has a file at `path` that the overlay may not remove or replace.
+    pub(crate) fn foreign_file(&self, path: &Path) -> bool {
+        let output_file = self.output.join(path);
+        self.fs.exists(&output_file) && !self.fs.is_dir(&output_file) && !self.removable(path)
+    }
+
+    /// The input the foreign file at `path` is moved into, the writable one of highest
+    /// priority whose files go where it is.
+    fn adopter(&self, path: &Path) -> Option<usize> {
+        self.inputs
+            .iter()
+            .filter(|input| input.writable && path.starts_with(input.keys.mount()))
+            .max_by_key(|input| input.rank)
+            .map(|input| input.index)
+    }
+
+    /// Applies the foreign file policy to the file at `path` in the output, which no input
+    /// provides, and tracks it if it was adopted.
+    pub(crate) fn handle_untracked_foreign(&mut self, path: &Path) {
+        if self.stale_link(path) {
+            info!("Removing stale link {}", path.display());
+            let _ = self.unlink_dir(&self.output.join(path));
+            self.note(|report| report.stale_removed += 1);
+            return;
+        }
+        if self.ledger.contains(path) && self.removable(path) {
+            // The overlay linked it in an earlier run, from a file that is gone since.
+            info!("Removing stale file {}", path.display());
+            if self.remove_link(path).is_ok() {
+                self.ledger.remove(path);
+                if let Some(parent) = path.parent() {
+                    self.remove_empty_dirs(parent);
+                }
+            }
+            self.note(|report| report.stale_removed += 1);
+            return;
+        }
+
+        self.line.begin(format_args!("Foreign {}:", path.display()));
+        let gone = self.handle_foreign(path);
+        self.line.end();
+
+        if let (true, ForeignFiles::Adopt, Some(index)) =
+            (gone, self.adoption.foreign, self.adopter(path))
+        {
+            // Its watcher reports it too, but the output shouldn't wait for that.
+            if !self.dry_run {
+                self.apply_event(EventType::new(index, Event::Create(path.to_path_buf())));
+            }
+        }
+    }
+
+    /// Applies the foreign file policy to the file at `path` in the output. Returns `true`
+    /// if it is no longer there.
+    pub(crate) fn handle_foreign(&mut self, path: &Path) -> bool {
+        let output_file = self.output.join(path);
+        if self.ledger.contains(path) {
+            // The overlay put it there, but it was replaced or changed since.
+            say!(self.line, Warn, " MODIFIED EXTERNALLY,");
+            self.ledger.remove(path);
+            self.links.remove(path);
+            let index = self.input_map.get(path).and_then(BinaryHeap::peek);
+            if let Some(index) = index.map(|input| input.index) {
+                self.audit(AuditAction::Modified, Some(path), index, None);
+            }
+        }
+
+        match self.adoption.foreign {
+            ForeignFiles::Keep => {
+                say!(self.line, Info, " FOREIGN FILE KEPT!");
+                self.note_foreign(path, |report| &mut report.foreign_kept);
+                false
+            }
+            ForeignFiles::Delete => match self.discard(path) {
+                Ok(trashed) => {
+                    if trashed {
+                        say!(self.line, Warn, " FOREIGN FILE MOVED INTO THE TRASH,");
+                    } else {
+                        say!(self.line, Warn, " FOREIGN FILE DELETED,");
+                    }
+                    self.ledger.remove(path);
+                    self.note_foreign(path, |report| &mut report.foreign_deleted);
+                    if let Some(parent) = path.parent() {
+                        self.remove_empty_dirs(parent);
+                    }
+                    true
+                }
+                Err(e) => {
+                    say!(self.line, Error, " FOREIGN FILE NOT DELETED: {}!", e);
+                    self.failed(Some(path), None, error_kind(&e), e.to_string());
+                    let error = format!("couldn't delete {}: {}", path.display(), e);
+                    self.note(|report| report.errors.push(error));
+                    self.note_foreign(path, |report| &mut report.foreign_kept);
+                    false
+                }
+            },
+            ForeignFiles::Adopt => {
+                let index = match self.adopter(path) {
+                    Some(index) => index,
+                    None => {
+                        say!(self.line, Warn, " FOREIGN FILE KEPT, NO WRITABLE INPUT!");
+                        self.note_foreign(path, |report| &mut report.foreign_kept);
+                        return false;
+                    }
+                };
+
+                let adopted = self.inputs[index].source(path);
+                if self.fs.exists(&adopted) {
+                    say!(
+                        self.line,
+                        Info,
+                        " FOREIGN FILE KEPT, INPUT {} HAS ONE!",
+                        self.input_name(index)
+                    );
+                    self.note_foreign(path, |report| &mut report.foreign_kept);
+                    return false;
+                }
+                if let Some(parent) = adopted.parent() {
+                    let _ = self.create_dir_all(parent);
+                }
+                if let Err(e) = self.rename(&output_file, &adopted) {
+                    say!(self.line, Error, " FOREIGN FILE NOT ADOPTED: {}!", e);
+                    self.failed(Some(path), Some(index), error_kind(&e), e.to_string());
+                    let error = format!("couldn't adopt {}: {}", path.display(), e);
+                    self.note(|report| report.errors.push(error));
+                    self.note_foreign(path, |report| &mut report.foreign_kept);
+                    return false;
+                }
+
+                say!(
+                    self.line,
+                    Info,
+                    " FOREIGN FILE ADOPTED BY {},",
+                    self.input_name(index)
+                );
+                self.note_foreign(path, |report| &mut report.foreign_adopted);
+                if let Some(parent) = path.parent() {
+                    self.remove_empty_dirs(parent);
+                }
+                true
+            }
+        }
+    }
+
+    /// Applies the policy for existing outputs to the files in the output, if the overlay
+    /// never ran on it.
+    pub(crate) fn check_existing_output(&mut self) -> Result<(), Error> {
+        if !self.ledger.is_new() {
+            return Ok(());
+        }
+        let existing: Vec<PathBuf> = WalkDir::new(&self.output)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(&self.output)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        if existing.is_empty() {
+            return Ok(());
+        }
+
+        match self.adoption.existing {
+            ExistingOutputPolicy::Refuse if self.dry_run => warn!(
+                "Would refuse to run on {}, which already has {} files",
+                self.output.display(),
+                existing.len()
+            ),
+            ExistingOutputPolicy::Refuse => {
+                return Err(ConfigError::OutputNotEmpty {
+                    output: self.output.clone(),
+                    files: existing.len(),
+                }
+                .into());
+            }
+            ExistingOutputPolicy::Adopt => {
+                self.adoption.report = Some(self.adopt_output(&existing))
+            }
+            ExistingOutputPolicy::Force => {
+                warn!(
+                    "Taking the {} files already in {} for the overlay's own",
+                    existing.len(),
+                    self.output.display()
+                );
+                for path in &existing {
+                    self.ledger.insert(path, None);
+                }
+            }
+        }
+        self.ledger.keep();
+        Ok(())
+    }
+
+    /// Notes that the foreign file at `path` ended up in `list` of the sync report, unless
+    /// it already has.
+    fn note_foreign<F>(&mut self, path: &Path, list: F)
+    where
+        F: FnOnce(&mut SyncReport) -> &mut Vec<PathBuf>,
+    {
+        self.note(|report| {
+            let list = list(report);
+            if !list.iter().any(|noted| noted == path) {
+                list.push(path.to_path_buf());
+            }
+        });
+    }
+
+    pub fn set_foreign_files(&mut self, policy: ForeignFiles) {
+        self.adoption.foreign = policy;
+    }
+
+    /// What to do with the files already in the output when the overlay first runs on it,
+    /// refusing to by default. Dry runs only tell that they would refuse.
+    pub fn set_existing_output(&mut self, policy: ExistingOutputPolicy) {
+        self.adoption.existing = policy;
+    }
+
+    /// Whether files in an output being adopted that only have the same contents as the
+    /// file of their input are kept as copies of it, rather than left to the foreign file
+    /// policy. Comparing them reads both files.
+    pub fn set_adopt_copies(&mut self, adopt: bool) {
+        self.adoption.copies = adopt;
+    }
+}
+
+/// What to do with files in the output that no input provides, such as savegames or the
+/// leftovers of a manual merge.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForeignFiles {
+    /// Leave them alone, even when an input starts providing the same path.
+    #[default]
+    Keep,
+    Delete,
+    /// Move them into the writable input of highest priority, unless it has a file there
+    /// already. See `InputOptions::writable`.
+    Adopt,
+}
+
+/// What to do with the files in an output the overlay never ran on before, as it has no
+/// ledger yet, such as a game directory it was pointed at instead of an empty one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExistingOutputPolicy {
+    /// Don't start, see `ConfigError::OutputNotEmpty`.
+    #[default]
+    Refuse,
+    /// Take those that are hard links or, with `OverlayBuilder::adopt_copies`, copies of
+    /// the file of the input that would provide them for files the overlay put there, and
+    /// deal with the others as the foreign file policy says.
+    Adopt,
+    /// Take them for files the overlay put there itself, which it replaces with those of
+    /// the inputs and removes if no input has them.
+    Force,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::scratch;
+    use crate::{ledger, InputOptions, LinkKind, OverlayBuilder, RealFs};
+    use std::fs;
+
+    /// The input and output of an overlay that never ran on the output, which has a hard
+    /// link to the file `a` of the input, another file at `b`, where the input has one too,
+    /// and `foreign`, which the input doesn't have.
+    fn populated_output(name: &str) -> (PathBuf, PathBuf) {
+        let root = scratch(name);
+        let (input, output) = (root.join("input"), root.join("output"));
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::write(input.join("a"), "a").unwrap();
+        fs::write(input.join("b"), "b").unwrap();
+        fs::hard_link(input.join("a"), output.join("a")).unwrap();
+        fs::write(output.join("b"), "old").unwrap();
+        fs::write(output.join("foreign"), "foreign").unwrap();
+        (input, output)
+    }
+
+    fn sync_populated(
+        input: &Path,
+        output: &Path,
+        policy: ExistingOutputPolicy,
+    ) -> Result<SyncReport, Error> {
+        OverlayBuilder::new(output)
+            .input(input, 0)
+            .existing_output(policy)
+            .single_instance(false)
+            .build()?
+            .sync_once()
+    }
+
+    #[test]
+    fn a_populated_output_is_refused() {
+        let (input, output) = populated_output("existing-refuse");
+        let error = sync_populated(&input, &output, ExistingOutputPolicy::Refuse).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::OutputNotEmpty {
+                output: output.clone(),
+                files: 3,
+            })
+        );
+        assert_eq!(fs::read_to_string(output.join("b")).unwrap(), "old");
+        assert!(output.join("foreign").exists());
+        assert!(!ledger::ledger_path(&output).exists());
+    }
+
+    #[test]
+    fn a_populated_output_is_adopted_as_far_as_it_is_linked() {
+        let (input, output) = populated_output("existing-adopt");
+        let report = sync_populated(&input, &output, ExistingOutputPolicy::Adopt).unwrap();
+        assert_eq!(report.confirmed, 1);
+        assert_eq!(
+            report.foreign_kept,
+            [PathBuf::from("b"), PathBuf::from("foreign")]
+        );
+        assert_eq!(
+            report.adoption,
+            Some(AdoptionReport {
+                linked: 1,
+                copies: 0,
+                rebuilt: 2,
+            })
+        );
+        assert!(RealFs
+            .same_file(&input.join("a"), &output.join("a"))
+            .unwrap());
+        // The others are foreign, and kept.
+        assert_eq!(fs::read_to_string(output.join("b")).unwrap(), "old");
+        assert!(output.join("foreign").exists());
+    }
+
+    #[test]
+    fn copies_of_input_files_are_adopted_as_copies_if_asked_to() {
+        for adopt_copies in [false, true] {
+            let name = format!("existing-adopt-copies-{}", adopt_copies);
+            let (input, output) = populated_output(&name);
+            // As a manual merge leaves them.
+            fs::write(input.join("c"), "c").unwrap();
+            fs::write(output.join("c"), "c").unwrap();
+            let mut overlay = OverlayBuilder::new(&output)
+                .input(&input, 0)
+                .existing_output(ExistingOutputPolicy::Adopt)
+                .adopt_copies(adopt_copies)
+                .single_instance(false)
+                .build()
+                .unwrap();
+            let report = overlay.sync_once().unwrap();
+            let copied = usize::from(adopt_copies);
+            assert_eq!(
+                report.adoption,
+                Some(AdoptionReport {
+                    linked: 1,
+                    copies: copied,
+                    rebuilt: 3 - copied,
+                })
+            );
+            assert_eq!(report.confirmed, 1 + copied);
+            let c = output.join("c");
+            assert_eq!(fs::read_to_string(&c).unwrap(), "c");
+            assert!(!RealFs.same_file(&input.join("c"), &c).unwrap());
+            if adopt_copies {
+                assert_eq!(overlay.links[Path::new("c")].kind, LinkKind::Copy);
+                assert!(overlay.ledger.contains(Path::new("c")));
+            } else {
+                assert!(report.foreign_kept.contains(&PathBuf::from("c")));
+            }
+        }
+    }
+
+    #[test]
+    fn a_populated_output_is_forced_to_be_the_overlays() {
+        let (input, output) = populated_output("existing-force");
+        let report = sync_populated(&input, &output, ExistingOutputPolicy::Force).unwrap();
+        assert_eq!(report.adoption, None);
+        assert_eq!(
+            (report.confirmed, report.relinked, report.stale_removed),
+            (1, 1, 1)
+        );
+        assert!(report.foreign_kept.is_empty());
+        assert!(RealFs
+            .same_file(&input.join("a"), &output.join("a"))
+            .unwrap());
+        assert!(RealFs
+            .same_file(&input.join("b"), &output.join("b"))
+            .unwrap());
+        assert!(!output.join("foreign").exists());
+    }
+
+    /// Syncs an output that the overlay ran on before, with `policy`, once `saves/a.sav`
+    /// and `shared.txt` were put there by something else, and `shared.txt` into the
+    /// writable input too. Returns the input, the output and what the sync did.
+    fn sync_foreign(name: &str, policy: ForeignFiles) -> (PathBuf, PathBuf, SyncReport) {
+        let root = scratch(name);
+        let (input, output) = (root.join("mods"), root.join("output"));
+        fs::create_dir_all(&input).unwrap();
+        // So that it has a ledger.
+        fs::write(input.join("base.txt"), "base").unwrap();
+        let options = InputOptions::new().writable(true);
+        let build = || {
+            let mut overlay = OverlayBuilder::new(&output)
+                .foreign_files(policy)
+                .single_instance(false)
+                .build()
+                .unwrap();
+            overlay.add_input_with_options(&input, 0, &options).unwrap();
+            overlay
+        };
+        build().sync_once().unwrap();
+
+        fs::create_dir_all(output.join("saves")).unwrap();
+        fs::write(output.join("saves/a.sav"), "save").unwrap();
+        fs::write(output.join("shared.txt"), "the user's").unwrap();
+        fs::write(input.join("shared.txt"), "the input's").unwrap();
+        let report = build().sync_once().unwrap();
+        (input, output, report)
+    }
+
+    #[test]
+    fn foreign_files_are_kept_deleted_or_adopted() {
+        let (input, output, report) = sync_foreign("foreign-keep", ForeignFiles::Keep);
+        assert_eq!(
+            report.foreign_kept,
+            [PathBuf::from("shared.txt"), PathBuf::from("saves/a.sav")]
+        );
+        // Not even for the file of an input at the same path.
+        assert_eq!(
+            fs::read_to_string(output.join("shared.txt")).unwrap(),
+            "the user's"
+        );
+        assert!(output.join("saves/a.sav").exists());
+        assert!(!input.join("saves").exists());
+
+        let (_, output, report) = sync_foreign("foreign-delete", ForeignFiles::Delete);
+        assert_eq!(
+            report.foreign_deleted,
+            [PathBuf::from("shared.txt"), PathBuf::from("saves/a.sav")]
+        );
+        assert!(!output.join("saves").exists());
+        assert_eq!(
+            fs::read_to_string(output.join("shared.txt")).unwrap(),
+            "the input's"
+        );
+
+        let (input, output, report) = sync_foreign("foreign-adopt", ForeignFiles::Adopt);
+        assert_eq!(report.foreign_adopted, [PathBuf::from("saves/a.sav")]);
+        // The input has a file there of its own.
+        assert_eq!(report.foreign_kept, [PathBuf::from("shared.txt")]);
+        assert_eq!(
+            fs::read_to_string(input.join("saves/a.sav")).unwrap(),
+            "save"
+        );
+        assert!(RealFs
+            .same_file(&input.join("saves/a.sav"), &output.join("saves/a.sav"))
+            .unwrap());
+    }
+}
@@ -0,0 +1,579 @@
+use crate::filter::Filter;
+use crate::fs_ops::FileOps;
+use crate::identity::FileIdentity;
+use crate::key::Folded;
+use crate::{error_kind, Event, EventType, Overlay};
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(feature = "watch")]
+use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::ZipArchive;
+
+/// The archives of the inputs that are one, by their paths.
+pub(crate) type Archives = Arc<RwLock<HashMap<PathBuf, Arc<Archive>>>>;
+
+/// A zip archive that is an input: the files and directories in it, as paths relative to
+/// the archive, which the overlay can only ever extract.
+#[derive(Debug)]
+pub(crate) struct Archive {
+    /// The file it was read from, if there was one; a replaced archive is another file.
+    identity: Option<FileIdentity>,
+    /// When the archive was last modified. Extracted files are given this time, so that
+    /// they can be told apart from those of another version of the archive.
+    modified: SystemTime,
+    files: BTreeMap<PathBuf, Entry>,
+    dirs: BTreeSet<PathBuf>,
+    zip: Option<Mutex<ZipArchive<File>>>,
+}
+
+/// Only the same archive, as read once, is equal to itself.
+impl PartialEq for Archive {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Archive {}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Its number in the archive.
+    number: usize,
+    size: u64,
+}
+
+impl Archive {
+    /// Reads which files the archive at `path` holds.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let modified = file.metadata()?.modified()?;
+        let identity = FileIdentity::of(path).ok();
+        let mut zip = ZipArchive::new(file).map_err(io::Error::other)?;
+
+        let mut files = BTreeMap::new();
+        let mut dirs = BTreeSet::new();
+        for number in 0..zip.len() {
+            let entry = zip.by_index_raw(number).map_err(io::Error::other)?;
+            // Names that aren't UTF-8 are read as the code page zip files used before it.
+            let name = match entry.enclosed_name() {
+                Some(name) if name != Path::new("") => name,
+                _ => {
+                    warn!(
+                        "Skipping {:?} in {}, which would be outside of it",
+                        entry.name(),
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            if entry.is_dir() {
+                dirs.insert(name);
+            } else {
+                let size = entry.size();
+                files.insert(name, Entry { number, size });
+            }
+        }
+        // Archives needn't have entries for the directories their files are in.
+        for file in files.keys() {
+            for dir in file.ancestors().skip(1) {
+                if dir == Path::new("") || !dirs.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        files.retain(|file, _| !dirs.contains(file));
+
+        Ok(Archive {
+            identity,
+            modified,
+            files,
+            dirs,
+            zip: Some(Mutex::new(zip)),
+        })
+    }
+
+    /// An archive that isn't there, and so holds nothing.
+    pub(crate) fn missing() -> Self {
+        Archive {
+            identity: None,
+            modified: UNIX_EPOCH,
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+            zip: None,
+        }
+    }
+
+    /// When the archive was last modified, if it is there at all.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        self.zip.as_ref().map(|_| self.modified)
+    }
+
+    /// Whether both were read from the same file, which a replaced archive isn't.
+    pub(crate) fn same_file(&self, other: &Archive) -> bool {
+        match (&self.identity, &other.identity) {
+            (Some(a), Some(b)) => match (a.number(), b.number()) {
+                (Some(a), Some(b)) => a == b,
+                _ => a.matches(b),
+            },
+            _ => false,
+        }
+    }
+
+    /// Every file beneath `relative` that `filter` accepts, like `Input::walk`.
+    pub(crate) fn walk(&self, relative: &Path, filter: &Filter) -> Vec<PathBuf> {
+        let depth = filter.max_depth();
+        self.files
+            .keys()
+            .filter(|path| path.starts_with(relative) && *path != relative)
+            .filter(|path| depth.is_none_or(|depth| path.components().count() <= depth))
+            .filter(|path| {
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|dir| *dir != relative)
+                    .all(|dir| filter.accepts_dir(dir))
+            })
+            .filter(|path| filter.accepts_file(path))
+            .cloned()
+            .collect()
+    }
+
+    /// The files and directories directly in `relative` that `filter` accepts, like
+    /// `Input::children`.
+    pub(crate) fn children(&self, relative: &Path, filter: &Filter) -> Vec<PathBuf> {
+        let files = self
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(relative) && filter.accepts_file(path));
+        let dirs = self
+            .dirs
+            .iter()
+            .filter(|path| path.parent() == Some(relative) && filter.accepts_dir(path));
+        files.chain(dirs).cloned().collect()
+    }
+
+    fn is_dir(&self, relative: &Path) -> bool {
+        relative == Path::new("") || self.dirs.contains(relative)
+    }
+
+    fn entry(&self, relative: &Path) -> io::Result<Entry> {
+        self.files.get(relative).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("there is no file {} in the archive", relative.display()),
+            )
+        })
+    }
+
+    /// Decompresses the file at `relative` into `to`.
+    fn extract(&self, relative: &Path, to: &mut dyn Write) -> io::Result<u64> {
+        let entry = self.entry(relative)?;
+        let zip = self
+            .zip
+            .as_ref()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut zip = zip.lock().unwrap();
+        let mut file = zip.by_index(entry.number).map_err(io::Error::other)?;
+        io::copy(&mut file, to)
+    }
+}
+
+/// Lets the overlay see the files in an archive input as if they were beneath the path
+/// of the archive, `<archive>/dir/file` for `dir/file` in it, and extract them. All other
+/// paths go to the file operations it wraps.
+#[derive(Debug)]
+pub(crate) struct ArchiveFs {
+    inner: Box<dyn FileOps>,
+    archives: Archives,
+}
+
+impl ArchiveFs {
+    pub(crate) fn new(inner: Box<dyn FileOps>, archives: Archives) -> Self {
+        ArchiveFs { inner, archives }
+    }
+
+    /// The archive `path` is in, and where in it.
+    fn find(&self, path: &Path) -> Option<(Arc<Archive>, PathBuf)> {
+        let archives = self.archives.read().unwrap();
+        path.ancestors().find_map(|root| {
+            let archive = archives.get(root)?;
+            Some((
+                archive.clone(),
+                path.strip_prefix(root).unwrap().to_path_buf(),
+            ))
+        })
+    }
+}
+
+fn read_only(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is in an archive, which is read-only", path.display()),
+    )
+}
+
+fn nanos(time: SystemTime) -> Option<u128> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since| since.as_nanos())
+}
+
+impl FileOps for ArchiveFs {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.find(from) {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "files in archives can only be extracted, not linked",
+            )),
+            None => self.inner.hard_link(from, to),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.remove_file(path),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        match self.find(path) {
+            Some((archive, relative)) => {
+                archive.is_dir(&relative) || archive.files.contains_key(&relative)
+            }
+            None => self.inner.exists(path),
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        match self.find(path) {
+            Some((archive, relative)) => archive.is_dir(&relative),
+            None => self.inner.is_dir(path),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.create_dir_all(path),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.remove_dir(path),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.remove_dir_all(path),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.find(from).or_else(|| self.find(to)) {
+            Some(_) => Err(read_only(from)),
+            None => self.inner.rename(from, to),
+        }
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        match self.find(a).or_else(|| self.find(b)) {
+            // Nothing outside of an archive is the same file as one inside of it.
+            Some(_) => Ok(false),
+            None => self.inner.same_file(a, b),
+        }
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        match self.find(target) {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "directories in archives can't be linked to",
+            )),
+            None => self.inner.link_dir(target, link),
+        }
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.inner.unlink_dir(link)
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.find(from) {
+            // Extracted files keep the permissions they were created with.
+            Some(_) => Ok(()),
+            None => self.inner.copy_permissions(from, to),
+        }
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.create_empty(path),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (archive, relative) = match self.find(from) {
+            Some(found) => found,
+            None => return self.inner.copy(from, to),
+        };
+
+        let mut file = File::create(to)?;
+        let result = archive
+            .extract(&relative, &mut file)
+            .and_then(|_| file.set_modified(archive.modified));
+        if result.is_err() {
+            drop(file);
+            let _ = fs::remove_file(to);
+        }
+        result
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        match self.find(from) {
+            Some(_) => self.copy(from, to),
+            None => self.inner.copy_with_progress(from, to, progress),
+        }
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        let (archive, relative) = match self.find(a) {
+            Some(found) => found,
+            None => return self.inner.same_copy(a, b),
+        };
+
+        let entry = archive.entry(&relative)?;
+        let copy = fs::metadata(b)?;
+        Ok(copy.is_file() && copy.len() == entry.size && copy.modified()? == archive.modified)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        match self.find(a) {
+            Some(_) => Ok(self.read(a)? == self.inner.read(b)?),
+            None => self.inner.same_contents(a, b),
+        }
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        let (archive, relative) = match self.find(path) {
+            Some(found) => found,
+            None => return self.inner.identity(path),
+        };
+
+        let entry = archive.entry(&relative)?;
+        let volume = archive.identity.map_or(0, |identity| identity.volume);
+        Ok(FileIdentity {
+            volume,
+            index: entry.number as u64,
+            len: entry.size,
+            modified: nanos(archive.modified),
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let (archive, relative) = match self.find(path) {
+            Some(found) => found,
+            None => return self.inner.read(path),
+        };
+
+        let mut contents = vec![];
+        archive.extract(&relative, &mut contents)?;
+        Ok(contents)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        match self.find(path) {
+            Some(_) => Err(read_only(path)),
+            None => self.inner.write(path, contents),
+        }
+    }
+}
+
+impl Overlay {
+    /// Reads archive input `index` again after its archive changed, taking what it no
+    /// longer holds out of the output, extracting what is new and what may have changed.
+    ///
+    /// An archive that was replaced is another file, which its watcher is restarted on if
+    /// `rewatch`; one that went away holds nothing until it is back. Without the `watch`
+    /// feature nothing is watched to restart.
+    #[cfg_attr(not(feature = "watch"), allow(unused_variables))]
+    pub(crate) fn reload_archive(&mut self, index: usize, rewatch: bool) {
+        let path = self.inputs[index].path.clone();
+        let archive = match Archive::open(&path) {
+            Ok(archive) => archive,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Couldn't read the archive {}: {}", path.display(), e);
+                    self.failed(None, Some(index), error_kind(&e), e.to_string());
+                }
+                Archive::missing()
+            }
+        };
+        let archive = Arc::new(archive);
+        let replaced = !self.inputs[index]
+            .archive
+            .as_ref()
+            .is_some_and(|old| old.same_file(&archive));
+        if let Some(archives) = &self.archives {
+            archives.write().unwrap().insert(path, archive.clone());
+        }
+        self.inputs[index].archive = Some(archive);
+        #[cfg(feature = "watch")]
+        if rewatch && replaced {
+            self.watch.restarting.push((Instant::now(), index));
+        }
+        if !self.inputs[index].enabled {
+            return;
+        }
+
+        self.line
+            .begin(format_args!("Input {} archive", self.input_name(index)));
+        say!(self.line, Info, " RELOADED!");
+        self.line.end();
+
+        let provided = self.provided_under(index, Path::new(""));
+        let temp_files = &self.temp_files;
+        let held: HashSet<PathBuf> = self.inputs[index]
+            .walk(Path::new(""))
+            .into_iter()
+            .filter(|path| temp_files.accepts_file(path))
+            .collect();
+        let gone: Vec<PathBuf> = provided
+            .iter()
+            .filter(|path| !held.contains(*path))
+            .cloned()
+            .collect();
+        let provided: HashSet<PathBuf> = provided.into_iter().collect();
+        let mut new: Vec<PathBuf> = held
+            .iter()
+            .filter(|path| !provided.contains(*path))
+            .cloned()
+            .collect();
+        new.sort();
+        let mut kept: Vec<PathBuf> = held
+            .into_iter()
+            .filter(|path| provided.contains(path))
+            .collect();
+        kept.sort();
+
+        for path in gone {
+            self.apply_event(EventType::new(index, Event::Remove(path)));
+        }
+        for path in new {
+            self.apply_event(EventType::new(index, Event::Create(path)));
+        }
+        for path in kept {
+            self.measure(index, &path);
+            let winner = self.materialized(&path).map(|input| input.index);
+            if winner == Some(index) || self.merging.merged.contains(&path) {
+                // Extracted again only if the file in the output is of another version.
+                self.line
+                    .begin(format_args!("Extracting {}:", path.display()));
+                if !self.remerge(&path) {
+                    self.link(&path, &Folded::new(&path), index);
+                }
+                self.line.end();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+    use crate::{OverlayBuilder, RealFs};
+
+    /// Writes a zip archive at `path` holding `entries`, by name.
+    fn write_zip(path: &Path, entries: &[(&str, &str)]) {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, contents) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn the_files_of_an_archive_are_extracted_where_they_win() {
+        let root = scratch("archive");
+        let (zip, loose, output) = (
+            root.join("mod.zip"),
+            root.join("loose"),
+            root.join("output"),
+        );
+        fs::create_dir_all(&loose).unwrap();
+        fs::write(loose.join("readme.txt"), "loose").unwrap();
+        write_zip(
+            &zip,
+            &[
+                ("readme.txt", "zipped"),
+                ("textures/rock.dds", "rock"),
+                ("../outside.txt", "outside"),
+            ],
+        );
+        let build = || {
+            let mut overlay = OverlayBuilder::new(&output)
+                .input(&loose, 1)
+                .single_instance(false)
+                .build()
+                .unwrap();
+            overlay.add_archive_input(&zip, 0).unwrap();
+            overlay
+        };
+        let mut overlay = build();
+        assert_eq!(overlay.sync_once().unwrap().linked, 2);
+        let read = |path: &str| fs::read_to_string(output.join(path)).unwrap();
+        assert_eq!(read("textures/rock.dds"), "rock");
+        assert!(RealFs
+            .same_file(&loose.join("readme.txt"), &output.join("readme.txt"))
+            .unwrap());
+        assert!(!root.join("outside.txt").exists());
+
+        fs::remove_file(loose.join("readme.txt")).unwrap();
+        overlay.apply_event(EventType::new(
+            0,
+            Event::Remove(PathBuf::from("readme.txt")),
+        ));
+        overlay.finish_links();
+        assert_eq!(read("readme.txt"), "zipped");
+
+        // Nothing is extracted again from an archive that is the same.
+        drop(overlay);
+        let mut overlay = build();
+        let report = overlay.sync_once().unwrap();
+        assert_eq!((report.confirmed, report.linked), (2, 0));
+
+        write_zip(
+            &zip,
+            &[("textures/rock.dds", "new rock"), ("new.txt", "new")],
+        );
+        overlay.reload_archive(1, false);
+        overlay.finish_links();
+        assert_eq!(read("textures/rock.dds"), "new rock");
+        assert_eq!(read("new.txt"), "new");
+        assert!(!output.join("readme.txt").exists());
+        assert!(overlay.failures.is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+use crate::Overlay;
+use failure::Error;
+use log::warn;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The version of the records of event streams, raised whenever one changes in a way
+/// that could break what reads them. See `OverlayBuilder::event_stream`.
+pub const EVENT_STREAM_SCHEMA: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    Link,
+    Replace,
+    Unlink,
+    /// A file the overlay put into the output was changed by something else since.
+    Modified,
+    Ignore,
+    Conflict,
+    Error,
+    /// A file was moved from the output into the trash, or back.
+    Trash,
+    Restore,
+}
+
+impl AuditAction {
+    /// What the action is called in event streams.
+    fn event_type(self) -> &'static str {
+        match self {
+            AuditAction::Link => "linked",
+            AuditAction::Replace => "replaced",
+            AuditAction::Unlink => "unlinked",
+            AuditAction::Modified => "modified",
+            AuditAction::Ignore => "shadowed",
+            AuditAction::Conflict => "conflict",
+            AuditAction::Error => "error",
+            AuditAction::Trash => "trashed",
+            AuditAction::Restore => "restored",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    action: AuditAction,
+    #[serde(flatten)]
+    action_record: ActionRecord<'a>,
+}
+
+#[derive(Serialize)]
+struct ActionRecord<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Left out for files no input provides.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    /// Where in the trash the file is, for the files moved there or back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trashed: Option<String>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// A record of an event stream: what was written, with the fields of `body`.
+#[derive(Serialize)]
+struct StreamRecord<'a, T: Serialize> {
+    schema: u32,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    timestamp: String,
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+/// Where an event stream is written, shared by the builders it is given to.
+#[derive(Clone)]
+pub(crate) struct StreamWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl StreamWriter {
+    pub(crate) fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        StreamWriter(Arc::new(Mutex::new(Box::new(writer))))
+    }
+
+    /// Writes a record of type `kind` with the fields of `body`, and flushes it.
+    pub(crate) fn emit<T: Serialize>(&self, kind: &str, body: &T) -> Result<(), Error> {
+        let record = StreamRecord {
+            schema: EVENT_STREAM_SCHEMA,
+            kind,
+            timestamp: timestamp(),
+            body,
+        };
+        let mut writer = self.0.lock().unwrap();
+        // Whatever reads it sees each record as soon as it happens.
+        write_line(&mut *writer, &record)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for StreamWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StreamWriter")
+    }
+}
+
+#[derive(Debug)]
+enum Writer {
+    /// An audit log, which only has the actions.
+    File(BufWriter<File>),
+    /// An event stream, which has everything, each record flushed as soon as it is
+    /// written.
+    Stream(StreamWriter),
+}
+
+/// Appends one JSON object per line for every action the overlay takes.
+///
+/// A log that can't be written to is reported once and then disabled, it never stops
+/// the overlay itself.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    /// What it is called in the warning if it is disabled.
+    name: String,
+    writer: Option<Writer>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog {
+            name: format!("Audit log {}", path.display()),
+            writer: Some(Writer::File(BufWriter::new(file))),
+        })
+    }
+
+    /// An event stream written to `writer`.
+    pub(crate) fn stream(writer: StreamWriter) -> Self {
+        AuditLog {
+            name: "The event stream".to_string(),
+            writer: Some(Writer::Stream(writer)),
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        action: AuditAction,
+        path: Option<&Path>,
+        input: usize,
+        label: Option<&str>,
+        error: Option<&str>,
+    ) {
+        let action_record = ActionRecord {
+            path: path.map(|path| path.to_string_lossy().into_owned()),
+            input: Some(input),
+            label,
+            trashed: None,
+            ok: error.is_none(),
+            error,
+        };
+        self.write(action, action_record);
+    }
+
+    /// Records that the file at `path` was moved into the trash, to `trashed`, or back
+    /// from it.
+    pub(crate) fn record_trash(
+        &mut self,
+        action: AuditAction,
+        path: &Path,
+        trashed: &Path,
+        input: Option<(usize, Option<&str>)>,
+        error: Option<&str>,
+    ) {
+        let action_record = ActionRecord {
+            path: Some(path.to_string_lossy().into_owned()),
+            input: input.map(|(index, _)| index),
+            label: input.and_then(|(_, label)| label),
+            trashed: Some(trashed.to_string_lossy().into_owned()),
+            ok: error.is_none(),
+            error,
+        };
+        self.write(action, action_record);
+    }
+
+    fn write(&mut self, action: AuditAction, action_record: ActionRecord) {
+        let result = match self.writer.as_mut() {
+            Some(Writer::File(writer)) => {
+                let record = Record {
+                    timestamp: timestamp(),
+                    action,
+                    action_record,
+                };
+                write_line(writer, &record)
+            }
+            Some(Writer::Stream(_)) => return self.emit(action.event_type(), &action_record),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.disable(e);
+        }
+    }
+
+    /// Writes a record of type `kind` with the fields of `body` to an event stream. Audit
+    /// logs only have the actions, and leave it out.
+    pub(crate) fn emit<T: Serialize>(&mut self, kind: &str, body: &T) {
+        let writer = match self.writer.as_mut() {
+            Some(Writer::Stream(writer)) => writer,
+            _ => return,
+        };
+        if let Err(e) = writer.emit(kind, body) {
+            self.disable(e);
+        }
+    }
+
+    /// What the event stream is written to, unless this is an audit log or was disabled.
+    pub(crate) fn stream_writer(&self) -> Option<StreamWriter> {
+        match &self.writer {
+            Some(Writer::Stream(writer)) => Some(writer.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Some(Writer::File(writer)) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                self.disable(e.into());
+            }
+        }
+    }
+
+    fn disable(&mut self, e: Error) {
+        warn!("{} is no longer being written: {}", self.name, e);
+        self.writer = None;
+    }
+}
+
+fn timestamp() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+fn write_line<W: Write + ?Sized, T: Serialize>(writer: &mut W, record: &T) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+impl Overlay {
+    /// Writes an action to the audit log and the event stream.
+    pub(crate) fn audit(
+        &mut self,
+        action: AuditAction,
+        path: Option<&Path>,
+        index: usize,
+        error: Option<&str>,
+    ) {
+        let label = self.inputs[index].label.as_deref();
+        for log in self.audit.iter_mut().chain(self.stream.iter_mut()) {
+            log.record(action, path, index, label, error);
+        }
+    }
+
+    /// Writes a record of type `kind` to the event stream, if there is one.
+    pub(crate) fn emit<T: Serialize>(&mut self, kind: &str, body: &T) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.emit(kind, body);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+    use crate::{FileOp, InputId};
+    use std::fs;
+    use std::io;
+
+    /// Only where there is a file that can't be written to.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_log_that_cant_be_written_is_given_up_on() {
+        let mut log = AuditLog::open(Path::new("/dev/full")).unwrap();
+        log.record(AuditAction::Link, Some(Path::new("a")), 0, None, None);
+        assert!(log.writer.is_some());
+        log.flush();
+        assert!(log.writer.is_none());
+        // Which is all that happens to what comes after.
+        log.record(AuditAction::Unlink, Some(Path::new("a")), 0, None, None);
+        log.flush();
+    }
+
+    #[test]
+    fn every_action_is_a_line_of_the_audit_log() {
+        let log = scratch("audit-log").join("audit.jsonl");
+        let mut harness = Harness::with("audit", &[0, 1], |builder| builder.audit_log(&log));
+        harness.create(0, "a");
+        harness.create(1, "a");
+        harness.create(1, "b");
+        harness.create(0, "b");
+        harness.remove(1, "b");
+        harness.remove(0, "b");
+        let failing = harness.output.join("c");
+        for op in [FileOp::HardLink, FileOp::Copy] {
+            harness
+                .fs
+                .fail(op, &failing, io::ErrorKind::PermissionDenied);
+        }
+        harness.create(1, "c");
+        // Written out as it is dropped, if not before.
+        drop(harness);
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let actions: Vec<(&str, &str, u64, bool)> = records
+            .iter()
+            .map(|record| {
+                assert!(record["timestamp"].is_string(), "{}", record);
+                (
+                    record["action"].as_str().unwrap(),
+                    record["path"].as_str().unwrap(),
+                    record["input"].as_u64().unwrap(),
+                    record["ok"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("link", "a", 0, true),
+                ("replace", "a", 1, true),
+                ("link", "b", 1, true),
+                ("ignore", "b", 0, true),
+                ("unlink", "b", 1, true),
+                ("link", "b", 0, true),
+                ("unlink", "b", 0, true),
+                ("link", "c", 1, false),
+            ]
+        );
+        assert_eq!(records[7]["error"], "HardLink failed");
+    }
+
+    #[test]
+    fn a_link_that_fails_is_reported_with_both_ends() {
+        let stream = scratch("link-failed-events").join("events.jsonl");
+        let file = fs::File::create(&stream).unwrap();
+        let mut harness = Harness::with("link-failed", &[0], |builder| builder.event_stream(file));
+        let (source, destination) = (harness.inputs[0].join("x"), harness.output.join("x"));
+        for op in [FileOp::HardLink, FileOp::Copy] {
+            harness
+                .fs
+                .fail(op, &destination, io::ErrorKind::PermissionDenied);
+        }
+        harness.create(0, "x");
+
+        let failure = harness.overlay.failures.last().unwrap();
+        assert_eq!(failure.path.as_deref(), Some(Path::new("x")));
+        assert_eq!(failure.input, Some(InputId::of(0)));
+        assert_eq!(failure.source.as_ref(), Some(&source));
+        assert_eq!(failure.destination.as_ref(), Some(&destination));
+
+        let events = fs::read_to_string(&stream).unwrap();
+        let emitted: serde_json::Value = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|event| event["type"] == "link_failed")
+            .unwrap();
+        assert_eq!(emitted["source"], source.to_str().unwrap());
+        assert_eq!(emitted["destination"], destination.to_str().unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// How long to wait before restarting a watcher the first time.
+const INITIAL: Duration = Duration::from_secs(1);
+
+/// The longest wait before restarting a watcher, unless the builder says otherwise.
+pub(crate) const DEFAULT_MAX: Duration = Duration::from_secs(300);
+
+/// How long a restarted watcher has to keep working before its failures are forgotten.
+const STABLE: Duration = Duration::from_secs(60);
+
+/// When to restart a watcher that failed: twice as late for every failure in a row, up to
+/// a maximum, with some jitter so inputs on the same mount don't all retry at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Backoff {
+    /// How often it failed in a row, without working for long enough in between.
+    pub(crate) failures: u32,
+    restarted: Option<Instant>,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Backoff {
+            failures: 0,
+            restarted: None,
+        }
+    }
+
+    /// Counts a failure at `now`, returning how long to wait before restarting.
+    pub(crate) fn fail(&mut self, now: Instant, max: Duration) -> Duration {
+        self.fail_with(now, max, random())
+    }
+
+    /// Like `fail`, with `jitter`, from 0 to 1, saying how much of the random half to wait.
+    fn fail_with(&mut self, now: Instant, max: Duration, jitter: f64) -> Duration {
+        if let Some(restarted) = self.restarted {
+            if now.duration_since(restarted) >= STABLE {
+                self.failures = 0;
+            }
+        }
+        self.failures += 1;
+
+        let delay = INITIAL
+            .checked_mul(1 << (self.failures - 1).min(16))
+            .unwrap_or(max)
+            .min(max);
+        // Half of it is certain, the other half random.
+        let half = delay / 2;
+        half + half.mul_f64(jitter)
+    }
+
+    pub(crate) fn restarted(&mut self, now: Instant) {
+        self.restarted = Some(now);
+    }
+}
+
+/// A number from 0 to 1 that is different every time, which is all the jitter needs.
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just under 1, the most jitter `random` gives.
+    const MOST: f64 = 1.0 - f64::EPSILON;
+
+    #[test]
+    fn the_wait_doubles_within_its_jitter_up_to_the_maximum() {
+        let now = Instant::now();
+        let max = Duration::from_secs(10);
+        let mut least = Backoff::new();
+        let mut most = Backoff::new();
+        let waits: Vec<(Duration, Duration)> = (0..6)
+            .map(|_| {
+                (
+                    least.fail_with(now, max, 0.0),
+                    most.fail_with(now, max, MOST),
+                )
+            })
+            .collect();
+        let secs = |secs: f64| Duration::from_secs_f64(secs);
+        assert_eq!(waits[0].0, secs(0.5));
+        assert_eq!(waits[1].0, secs(1.0));
+        assert_eq!(waits[2].0, secs(2.0));
+        assert_eq!(waits[3].0, secs(4.0));
+        // Then no more than the maximum.
+        assert_eq!(waits[4].0, secs(5.0));
+        assert_eq!(waits[5].0, secs(5.0));
+        for (failures, (shortest, longest)) in waits.into_iter().enumerate() {
+            let delay = (shortest * 2).min(max);
+            assert!(
+                longest <= delay && longest > delay.mul_f64(0.99),
+                "{}",
+                failures
+            );
+        }
+        assert_eq!(least.failures, 6);
+
+        for _ in 0..100 {
+            let wait = Backoff::new().fail(now, max);
+            assert!(wait >= secs(0.5) && wait <= secs(1.0), "{:?}", wait);
+        }
+    }
+
+    #[test]
+    fn failures_are_forgotten_once_a_restart_has_worked_for_long_enough() {
+        let start = Instant::now();
+        let max = DEFAULT_MAX;
+        let mut backoff = Backoff::new();
+        backoff.fail_with(start, max, 0.0);
+        backoff.fail_with(start, max, 0.0);
+        backoff.restarted(start);
+
+        // Failing again too soon waits longer still.
+        let soon = start + STABLE - Duration::from_millis(1);
+        assert_eq!(backoff.fail_with(soon, max, 0.0), INITIAL * 2);
+        assert_eq!(backoff.failures, 3);
+
+        backoff.restarted(soon);
+        let later = soon + STABLE;
+        assert_eq!(backoff.fail_with(later, max, 0.0), INITIAL / 2);
+        assert_eq!(backoff.failures, 1);
+    }
+}
@@ -0,0 +1,782 @@
+use crate::audit::{AuditLog, StreamWriter};
+#[cfg(feature = "watch")]
+use crate::backoff;
+#[cfg(feature = "watch")]
+use crate::gather;
+use crate::hooks::{self, Hooks};
+use crate::merge::Merger;
+use crate::progress::DEFAULT_COPY_PROGRESS;
+#[cfg(feature = "watch")]
+use crate::recording::{self, Recorder};
+use crate::sync::DEFAULT_FULL_RESYNC_EVERY;
+#[cfg(feature = "watch")]
+use crate::throttle::Throttle;
+#[cfg(feature = "watch")]
+use crate::EventSource;
+use crate::{
+    CaseConflictPolicy, ExistingOutputPolicy, FileMerger, FileOps, ForeignFiles, InputOptions,
+    MergeFormat, MergeRule, OutputCase, Overlay, RetryPolicy, Strategy, TrashRetention,
+};
+use failure::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Collects the configuration for an `Overlay` before it is created.
+#[derive(Debug, Clone)]
+pub struct OverlayBuilder {
+    output: PathBuf,
+    inputs: Vec<(PathBuf, u32, InputOptions)>,
+    tick_interval: Option<Duration>,
+    summary_interval: Option<Duration>,
+    audit_log: Option<PathBuf>,
+    #[cfg(feature = "watch")]
+    control_socket: Option<PathBuf>,
+    #[cfg(feature = "http-status")]
+    http_status: Option<String>,
+    event_stream: Option<StreamWriter>,
+    #[cfg(feature = "watch")]
+    record_events: Option<PathBuf>,
+    #[cfg(feature = "watch")]
+    record_events_max_size: u64,
+    dry_run: bool,
+    strategy: Strategy,
+    case_conflicts: CaseConflictPolicy,
+    output_case: OutputCase,
+    temp_patterns: Option<Vec<String>>,
+    ignore_file: Option<PathBuf>,
+    merges: Vec<MergeRule>,
+    mergers: Vec<Arc<dyn FileMerger>>,
+    single_instance: bool,
+    check_overlaps: bool,
+    merge_duplicate_inputs: bool,
+    foreign_files: ForeignFiles,
+    existing_output: ExistingOutputPolicy,
+    adopt_copies: bool,
+    trash: Option<PathBuf>,
+    trash_retention: TrashRetention,
+    copy_fallback: bool,
+    copy_progress: Option<u64>,
+    retry_policy: RetryPolicy,
+    ignore_free_space: bool,
+    fail_fast: bool,
+    max_consecutive_failures: Option<u32>,
+    workers: usize,
+    #[cfg(feature = "watch")]
+    restart_backoff_max: Duration,
+    #[cfg(feature = "watch")]
+    cross_input_window: Duration,
+    auto_resync: Option<Duration>,
+    full_resync_every: u32,
+    load_order: Option<PathBuf>,
+    on_link: Option<Vec<String>>,
+    on_unlink: Option<Vec<String>>,
+    hook_timeout: Duration,
+    hook_queue: usize,
+    #[cfg(feature = "watch")]
+    throttle: Option<Duration>,
+    file_ops: Option<Arc<dyn FileOps>>,
+    #[cfg(feature = "watch")]
+    event_source: Option<Arc<dyn EventSource>>,
+}
+
+impl OverlayBuilder {
+    pub fn new<P: AsRef<Path>>(output: P) -> Self {
+        OverlayBuilder {
+            output: output.as_ref().to_path_buf(),
+            inputs: vec![],
+            tick_interval: None,
+            summary_interval: None,
+            audit_log: None,
+            #[cfg(feature = "watch")]
+            control_socket: None,
+            #[cfg(feature = "http-status")]
+            http_status: None,
+            event_stream: None,
+            #[cfg(feature = "watch")]
+            record_events: None,
+            #[cfg(feature = "watch")]
+            record_events_max_size: recording::DEFAULT_MAX_SIZE,
+            dry_run: false,
+            strategy: Strategy::default(),
+            case_conflicts: CaseConflictPolicy::default(),
+            output_case: OutputCase::default(),
+            temp_patterns: None,
+            ignore_file: None,
+            merges: vec![],
+            mergers: vec![],
+            single_instance: true,
+            check_overlaps: true,
+            merge_duplicate_inputs: false,
+            foreign_files: ForeignFiles::default(),
+            existing_output: ExistingOutputPolicy::default(),
+            adopt_copies: false,
+            trash: None,
+            trash_retention: TrashRetention::default(),
+            copy_fallback: false,
+            copy_progress: Some(DEFAULT_COPY_PROGRESS),
+            retry_policy: RetryPolicy::default(),
+            ignore_free_space: false,
+            fail_fast: false,
+            max_consecutive_failures: None,
+            workers: 1,
+            #[cfg(feature = "watch")]
+            restart_backoff_max: backoff::DEFAULT_MAX,
+            #[cfg(feature = "watch")]
+            cross_input_window: gather::DEFAULT_WINDOW,
+            auto_resync: None,
+            full_resync_every: DEFAULT_FULL_RESYNC_EVERY,
+            load_order: None,
+            on_link: None,
+            on_unlink: None,
+            hook_timeout: hooks::DEFAULT_TIMEOUT,
+            hook_queue: hooks::DEFAULT_QUEUE,
+            #[cfg(feature = "watch")]
+            throttle: None,
+            file_ops: None,
+            #[cfg(feature = "watch")]
+            event_source: None,
+        }
+    }
+
+    pub fn input<P: AsRef<Path>>(self, path: P, priority: u32) -> Self {
+        self.input_with_options(path, priority, InputOptions::default())
+    }
+
+    /// Adds an input of group `group`, see `Overlay::add_input_in_group`.
+    pub fn input_in_group<P: AsRef<Path>>(self, path: P, group: u32, priority: u32) -> Self {
+        self.input_with_options(path, priority, InputOptions::default().group(group))
+    }
+
+    pub fn input_with_options<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        priority: u32,
+        options: InputOptions,
+    ) -> Self {
+        self.inputs
+            .push((path.as_ref().to_path_buf(), priority, options));
+        self
+    }
+
+    pub fn tick_interval(mut self, interval: Duration) -> Self {
+        self.tick_interval = Some(interval);
+        self
+    }
+
+    /// Logs a summary of what happened every `interval` while watching, see
+    /// `Overlay::set_summary_interval`.
+    pub fn summary_interval(mut self, interval: Duration) -> Self {
+        self.summary_interval = Some(interval);
+        self
+    }
+
+    /// Appends a JSON line to `path` for every link, replace, unlink, ignore, conflict and
+    /// error.
+    pub fn audit_log<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.audit_log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes one JSON object per line to `writer` for everything the overlay does, each
+    /// flushed as soon as it is written, for programs that drive it.
+    ///
+    /// Every record has a `schema`, `EVENT_STREAM_SCHEMA`, a `type` and a `timestamp`.
+    /// The types are:
+    ///
+    /// - `linked`, `replaced`, `unlinked`, `modified`, `shadowed`, `conflict` and `error`,
+    ///   with the `path`, the `input` and its `label`, whether it went `ok`, and the
+    ///   `error` if not, as in the audit log
+    /// - `link_failed`, with the fields of a `Failure`, when a file or directory couldn't be
+    ///   linked into the output
+    /// - `sync_progress`, with the `input` just synced at startup, its `label`, and how
+    ///   many of the `total` enabled inputs are `done`
+    /// - `copy_progress`, with the `path` of a large file being copied into the output, the
+    ///   `input` and its `label`, and how many of the `total` bytes are `copied`
+    /// - `trashed` and `restored`, with the `path` of a file moved into the trash or back
+    ///   from it, where it is `trashed`, and the `input` and `label` of the file there now,
+    ///   if any, as in the audit log
+    /// - `sync`, with the fields of a `SyncReport`, after every sync and resync
+    /// - `output_lost`, with the `output`, when it went away while watching and is
+    ///   recreated
+    /// - `summary`, with the fields of a `Summary`, periodically and when stopping
+    pub fn event_stream<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        self.event_stream = Some(StreamWriter::new(writer));
+        self
+    }
+
+    /// Appends every event the overlay receives to `path`, as a `RecordedEvent` per line,
+    /// for `Overlay::replay` to hand to another overlay later.
+    #[cfg(feature = "watch")]
+    pub fn record_events<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.record_events = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// How many bytes the recording of `record_events` may have, 64 MiB by default. Once
+    /// it would have more, it is moved aside to `<path>.1`, replacing the one there, and
+    /// started again.
+    #[cfg(feature = "watch")]
+    pub fn record_events_max_size(mut self, bytes: u64) -> Self {
+        self.record_events_max_size = bytes;
+        self
+    }
+
+    /// Takes requests at `path`, a Unix domain socket, or a named pipe like
+    /// `\\.\pipe\overlay` on Windows, while running. See `ControlRequest`.
+    ///
+    /// Without one, nothing is listening and connections are refused.
+    #[cfg(feature = "watch")]
+    pub fn control_socket<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Answers health checks over HTTP at `address`, like `127.0.0.1:8080`, while running.
+    /// See `Overlay::serve_http_status`. Without one, no port is ever bound.
+    #[cfg(feature = "http-status")]
+    pub fn http_status<S: Into<String>>(mut self, address: S) -> Self {
+        self.http_status = Some(address.into());
+        self
+    }
+
+    /// Decides everything as usual but leaves the output directory untouched.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// How the files of the inputs are put into the output, `Strategy::HardLinks` by
+    /// default. Directories are only linked as a whole for inputs without a filter.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Uses `Strategy::Hybrid` if `graft`, linking a directory that appears in only one
+    /// input into the output as a single junction on Windows or symlink elsewhere.
+    pub fn graft_directories(self, graft: bool) -> Self {
+        self.strategy(if graft {
+            Strategy::Hybrid
+        } else {
+            Strategy::HardLinks
+        })
+    }
+
+    /// What to do about files whose paths only differ by case, `Warn` by default.
+    pub fn case_conflicts(mut self, policy: CaseConflictPolicy) -> Self {
+        self.case_conflicts = policy;
+        self
+    }
+
+    /// How the paths in the output are spelled, as in the inputs by default.
+    pub fn output_case(mut self, case: OutputCase) -> Self {
+        self.output_case = case;
+        self
+    }
+
+    /// Never links files matching any of `patterns`, instead of `DEFAULT_TEMP_PATTERNS`.
+    ///
+    /// A rename from a matching name to one that doesn't is treated as the file appearing.
+    pub fn temp_patterns<S: Into<String>>(mut self, patterns: Vec<S>) -> Self {
+        self.temp_patterns = Some(patterns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never links what the gitignore-style rules in the file at `path` ignore, in any
+    /// input, see `Overlay::set_ignore_file`.
+    pub fn ignore_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.ignore_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Merges the files at paths matching `pattern` as `format` when more than one input
+    /// has one, see `Overlay::add_merge`. Added after any given before.
+    pub fn merge(mut self, pattern: &str, format: MergeFormat) -> Self {
+        self.merges.push(MergeRule {
+            pattern: pattern.to_string(),
+            format,
+        });
+        self
+    }
+
+    /// Merges the files `merger` can merge when more than one input has one, see
+    /// `Overlay::add_merger`. Mergers are consulted before the patterns given to `merge`,
+    /// in the order they were given.
+    pub fn merger<M: FileMerger + 'static>(mut self, merger: M) -> Self {
+        self.mergers.push(Arc::new(merger));
+        self
+    }
+
+    /// Whether to lock the output against other overlays running on it, `true` by default.
+    /// The lock file is `<output>.lock`, beside the output.
+    pub fn single_instance(mut self, single: bool) -> Self {
+        self.single_instance = single;
+        self
+    }
+
+    /// Whether inputs overlapping each other, the output or the files the overlay keeps
+    /// beside it are refused, `true` by default. See `Overlay::set_check_overlaps`.
+    pub fn check_overlaps(mut self, check: bool) -> Self {
+        self.check_overlaps = check;
+        self
+    }
+
+    /// Whether an input given twice is kept once, at the higher of its priorities, rather
+    /// than failing the build. See `Overlay::set_merge_duplicate_inputs`.
+    pub fn merge_duplicate_inputs(mut self, merge: bool) -> Self {
+        self.merge_duplicate_inputs = merge;
+        self
+    }
+
+    /// What to do with files in the output that no input provides, when syncing, repairing
+    /// or linking over them. They are kept by default.
+    pub fn foreign_files(mut self, policy: ForeignFiles) -> Self {
+        self.foreign_files = policy;
+        self
+    }
+
+    /// What to do with the files already in an output the overlay never ran on, refusing
+    /// to start by default. See `Overlay::set_existing_output`.
+    pub fn existing_output(mut self, policy: ExistingOutputPolicy) -> Self {
+        self.existing_output = policy;
+        self
+    }
+
+    /// Keeps the files of an output being adopted that have the same contents as those of
+    /// their inputs as copies, see `Overlay::set_adopt_copies`.
+    pub fn adopt_copies(mut self, adopt: bool) -> Self {
+        self.adopt_copies = adopt;
+        self
+    }
+
+    /// Moves the files the overlay would delete from or replace in the output, when neither
+    /// it put them there nor an input has them, into `dir` instead. See
+    /// `Overlay::set_trash`.
+    pub fn trash<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.trash = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// How long, and how much of, what is in the `trash` is kept, forever by default.
+    pub fn trash_retention(mut self, retention: TrashRetention) -> Self {
+        self.trash_retention = retention;
+        self
+    }
+
+    /// Copies the files of inputs that turn out not to be hard linkable into the output,
+    /// e.g. because it is on another volume, instead of failing to start.
+    pub fn copy_fallback(mut self, fallback: bool) -> Self {
+        self.copy_fallback = fallback;
+        self
+    }
+
+    /// Tells how far copying files of at least `threshold` bytes into the output is, see
+    /// `Overlay::set_copy_progress`.
+    pub fn copy_progress(mut self, threshold: Option<u64>) -> Self {
+        self.copy_progress = threshold;
+        self
+    }
+
+    /// Tries deletes, links and renames that fail because something has the file open for
+    /// a moment again by `policy`, see `Overlay::set_retry_policy`.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Syncs even if the files to copy don't fit into the output's volume, with a warning,
+    /// instead of failing to start.
+    pub fn ignore_free_space(mut self, ignore: bool) -> Self {
+        self.ignore_free_space = ignore;
+        self
+    }
+
+    /// Stops the overlay when the watcher of an input fails and can't be restarted, instead
+    /// of going on without that input's changes.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Stops the overlay once `max` changes in a row couldn't be handled, see
+    /// `Overlay::set_max_consecutive_failures`.
+    pub fn max_consecutive_failures(mut self, max: u32) -> Self {
+        self.max_consecutive_failures = Some(max);
+        self
+    }
+
+    /// Puts the files into the output on `count` threads, see `Overlay::set_workers`.
+    pub fn workers(mut self, count: usize) -> Self {
+        self.workers = count;
+        self
+    }
+
+    #[cfg(feature = "watch")]
+    /// The longest a failed watcher waits to be restarted, five minutes by default. The
+    /// wait starts at a second and doubles with every failure in a row.
+    pub fn restart_backoff_max(mut self, max: Duration) -> Self {
+        self.restart_backoff_max = max;
+        self
+    }
+
+    #[cfg(feature = "watch")]
+    /// Holds new files for `window` for the same ones to appear in other inputs, see
+    /// `Overlay::set_cross_input_window`.
+    pub fn cross_input_window(mut self, window: Duration) -> Self {
+        self.cross_input_window = window;
+        self
+    }
+
+    /// Resyncs every `interval` while watching, to catch the changes the watchers missed.
+    /// Most of these resyncs are incremental, see `full_resync_every`.
+    pub fn auto_resync(mut self, interval: Duration) -> Self {
+        self.auto_resync = Some(interval);
+        self
+    }
+
+    /// Makes every `n`th scheduled resync walk every input, every tenth by default. With
+    /// 0, none do.
+    pub fn full_resync_every(mut self, n: u32) -> Self {
+        self.full_resync_every = n;
+        self
+    }
+
+    /// Takes the priorities of the inputs from the load order at `path`, which is watched
+    /// for changes, see `Overlay::set_load_order`.
+    pub fn load_order<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.load_order = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Runs `command` after a file is linked into the output.
+    ///
+    /// The first element is the program, the rest its arguments. `{path}`, `{output}` and
+    /// `{input}` are replaced with the relative path, the absolute output path and the
+    /// input index.
+    pub fn on_link<S: Into<String>>(mut self, command: Vec<S>) -> Self {
+        self.on_link = Some(command.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Runs `command` after a file is removed from the output, see `on_link`.
+    pub fn on_unlink<S: Into<String>>(mut self, command: Vec<S>) -> Self {
+        self.on_unlink = Some(command.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// How long a hook may run before it is killed.
+    pub fn hook_timeout(mut self, timeout: Duration) -> Self {
+        self.hook_timeout = timeout;
+        self
+    }
+
+    /// How many hooks may be waiting to run before new ones are dropped.
+    pub fn hook_queue(mut self, size: usize) -> Self {
+        self.hook_queue = size;
+        self
+    }
+
+    #[cfg(feature = "watch")]
+    /// Coalesces repeated events for the same file in the same input that arrive within
+    /// `window` of the last one acted on, processing only its final state once it settles.
+    pub fn throttle(mut self, window: Duration) -> Self {
+        self.throttle = Some(window);
+        self
+    }
+
+    /// Performs every filesystem operation on the output through `ops` instead of `RealFs`.
+    pub fn file_ops<F: FileOps + 'static>(mut self, ops: F) -> Self {
+        self.file_ops = Some(Arc::new(ops));
+        self
+    }
+
+    /// Takes the changes to the inputs from `source` instead of watching them with
+    /// `NotifySource`.
+    #[cfg(feature = "watch")]
+    pub fn event_source<S: EventSource + 'static>(mut self, source: S) -> Self {
+        self.event_source = Some(Arc::new(source));
+        self
+    }
+
+    pub fn build(self) -> Result<Overlay, Error> {
+        let mut overlay = Overlay::new(&self.output);
+        if let Some(ops) = self.file_ops {
+            overlay.set_file_ops(Box::new(ops));
+        }
+        #[cfg(feature = "watch")]
+        if let Some(source) = self.event_source {
+            overlay.watch.source = Box::new(source);
+        }
+
+        overlay.set_merge_duplicate_inputs(self.merge_duplicate_inputs);
+        overlay.set_check_overlaps(self.check_overlaps);
+        overlay.set_output_case(self.output_case);
+        // Before the inputs, which mustn't overlap it.
+        if let Some(dir) = self.trash {
+            overlay.set_trash(dir, self.trash_retention);
+        }
+        for (path, priority, options) in &self.inputs {
+            overlay.add_input_with_options(path, *priority, options)?;
+        }
+
+        overlay.set_tick_interval(self.tick_interval);
+        overlay.set_summary_interval(self.summary_interval);
+
+        if let Some(path) = &self.audit_log {
+            overlay.audit = Some(AuditLog::open(path)?);
+        }
+        if let Some(writer) = &self.event_stream {
+            overlay.stream = Some(AuditLog::stream(writer.clone()));
+        }
+        #[cfg(feature = "watch")]
+        if let Some(path) = &self.record_events {
+            overlay.watch.recorder = Some(Recorder::open(path, self.record_events_max_size)?);
+        }
+
+        overlay.dry_run = self.dry_run;
+        overlay.set_strategy(self.strategy);
+        overlay.set_case_conflicts(self.case_conflicts);
+        overlay.set_single_instance(self.single_instance);
+        overlay.set_foreign_files(self.foreign_files);
+        overlay.set_existing_output(self.existing_output);
+        overlay.set_adopt_copies(self.adopt_copies);
+        overlay.set_copy_fallback(self.copy_fallback);
+        overlay.set_copy_progress(self.copy_progress);
+        overlay.set_retry_policy(self.retry_policy);
+        overlay.set_ignore_free_space(self.ignore_free_space);
+        overlay.set_fail_fast(self.fail_fast);
+        overlay.set_max_consecutive_failures(self.max_consecutive_failures);
+        overlay.set_workers(self.workers);
+        #[cfg(feature = "watch")]
+        overlay.set_restart_backoff_max(self.restart_backoff_max);
+        #[cfg(feature = "watch")]
+        overlay.set_cross_input_window(self.cross_input_window);
+        overlay.set_auto_resync(self.auto_resync);
+        overlay.set_full_resync_every(self.full_resync_every);
+        if let Some(patterns) = &self.temp_patterns {
+            overlay.set_temp_patterns(patterns)?;
+        }
+        if let Some(path) = &self.ignore_file {
+            overlay.set_ignore_file(path)?;
+        }
+        for merger in self.mergers {
+            overlay.merging.mergers.push(Merger::Custom(merger));
+        }
+        for rule in &self.merges {
+            overlay.add_merge(&rule.pattern, rule.format)?;
+        }
+        if let Some(path) = &self.load_order {
+            overlay.set_load_order(path);
+        }
+        #[cfg(feature = "watch")]
+        {
+            overlay.watch.throttle = self.throttle.map(Throttle::new);
+        }
+        if self.on_link.is_some() || self.on_unlink.is_some() {
+            overlay.hooks = Some(Hooks::new(
+                self.on_link,
+                self.on_unlink,
+                self.hook_timeout,
+                self.hook_queue,
+            ));
+        }
+        // Last, so that nothing is answered by an overlay that fails to build.
+        #[cfg(feature = "watch")]
+        if let Some(path) = &self.control_socket {
+            overlay.listen_control(path)?;
+        }
+        #[cfg(feature = "http-status")]
+        if let Some(address) = &self.http_status {
+            overlay.serve_http_status(address)?;
+        }
+
+        Ok(overlay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+    use crate::{canonical, ConfigError};
+    use std::fs;
+
+    /// The `ConfigError` building and syncing an overlay of `output` set up by `configure`
+    /// fails with.
+    fn config_error<F>(output: &Path, configure: F) -> ConfigError
+    where
+        F: FnOnce(OverlayBuilder) -> OverlayBuilder,
+    {
+        let builder = OverlayBuilder::new(output).single_instance(false);
+        let error = match configure(builder).build() {
+            Ok(mut overlay) => overlay.sync_once().map(|_| ()).unwrap_err(),
+            Err(e) => e,
+        };
+        match error.downcast_ref::<ConfigError>() {
+            Some(e) => e.clone(),
+            None => panic!("not a config error: {}", error),
+        }
+    }
+
+    #[test]
+    fn each_bad_config_is_its_own_config_error() {
+        let root = scratch("config-errors");
+        let (input, output, full) = (root.join("input"), root.join("output"), root.join("full"));
+        let file = root.join("file");
+        fs::create_dir_all(input.join("nested")).unwrap();
+        fs::create_dir_all(output.join("inside")).unwrap();
+        fs::create_dir_all(&full).unwrap();
+        fs::write(full.join("x"), "").unwrap();
+        fs::write(&file, "").unwrap();
+
+        type Configure = Box<dyn FnOnce(OverlayBuilder) -> OverlayBuilder>;
+        #[cfg_attr(not(unix), allow(unused_mut))]
+        let mut cases: Vec<(&str, &Path, Configure, ConfigError)> = vec![
+            (
+                "missing input",
+                &output,
+                Box::new({
+                    let missing = root.join("missing");
+                    move |builder| builder.input(missing, 0)
+                }),
+                ConfigError::Missing {
+                    path: root.join("missing"),
+                },
+            ),
+            (
+                "input that is a file",
+                &output,
+                Box::new({
+                    let file = file.clone();
+                    move |builder| builder.input(file, 0)
+                }),
+                ConfigError::NotADirectory { path: file.clone() },
+            ),
+            (
+                "same input twice",
+                &output,
+                Box::new({
+                    let input = input.clone();
+                    move |builder| builder.input(&input, 0).input(&input, 1)
+                }),
+                ConfigError::DuplicateInput {
+                    path: canonical(&input),
+                    input: "0".to_string(),
+                },
+            ),
+            (
+                "same label twice",
+                &output,
+                Box::new({
+                    let (input, full) = (input.clone(), full.clone());
+                    move |builder| {
+                        builder
+                            .input_with_options(input, 0, InputOptions::new().label("a"))
+                            .input_with_options(full, 1, InputOptions::new().label("a"))
+                    }
+                }),
+                ConfigError::DuplicateLabel {
+                    path: full.clone(),
+                    label: "a".to_string(),
+                },
+            ),
+            (
+                "input inside another",
+                &output,
+                Box::new({
+                    let input = input.clone();
+                    move |builder| builder.input(&input, 0).input(input.join("nested"), 1)
+                }),
+                ConfigError::OverlapsInput {
+                    path: input.join("nested"),
+                    input: "0".to_string(),
+                },
+            ),
+            (
+                "input inside the output",
+                &output,
+                Box::new({
+                    let inside = output.join("inside");
+                    move |builder| builder.input(inside, 0)
+                }),
+                ConfigError::OverlapsOutput {
+                    path: output.join("inside"),
+                    what: "the output",
+                    other: output.clone(),
+                },
+            ),
+            (
+                "invalid pattern",
+                &output,
+                Box::new({
+                    let input = input.clone();
+                    move |builder| {
+                        builder.input_with_options(input, 0, InputOptions::new().include("["))
+                    }
+                }),
+                ConfigError::InvalidPattern {
+                    pattern: "[".to_string(),
+                    reason: glob::Pattern::new("[").unwrap_err().to_string(),
+                },
+            ),
+            (
+                "mount outside the output",
+                &output,
+                Box::new({
+                    let input = input.clone();
+                    move |builder| {
+                        builder.input_with_options(input, 0, InputOptions::new().mount("../up"))
+                    }
+                }),
+                ConfigError::InvalidMapping {
+                    path: input.clone(),
+                    dir: PathBuf::from("../up"),
+                },
+            ),
+            (
+                "output with foreign files",
+                &full,
+                Box::new({
+                    let input = input.clone();
+                    move |builder| builder.input(input, 0)
+                }),
+                ConfigError::OutputNotEmpty {
+                    output: full.clone(),
+                    files: 1,
+                },
+            ),
+            (
+                "trash inside the output",
+                &output,
+                Box::new({
+                    let trash = output.join("trash");
+                    move |builder| builder.trash(trash)
+                }),
+                ConfigError::TrashInOutput {
+                    trash: output.join("trash"),
+                    output: output.clone(),
+                },
+            ),
+        ];
+        // Root reads any directory, but nothing is beneath a file.
+        #[cfg(unix)]
+        cases.push((
+            "input beneath a file",
+            &output,
+            Box::new({
+                let beneath = file.join("input");
+                move |builder| builder.input(beneath, 0)
+            }),
+            ConfigError::Unreadable {
+                path: file.join("input"),
+                reason: fs::metadata(file.join("input")).unwrap_err().to_string(),
+            },
+        ));
+
+        for (config, output, configure, expected) in cases {
+            assert_eq!(config_error(output, configure), expected, "{}", config);
+        }
+    }
+}
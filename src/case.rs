@@ -0,0 +1,258 @@
+use crate::audit::AuditAction;
+use crate::input::{Input, Rank};
+use crate::key::Folded;
+use crate::{InputId, Overlay};
+use log::warn;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, BinaryHeap};
+use std::path::{Path, PathBuf};
+
+/// Two tracked paths that only differ by case, which a case-insensitive output can only
+/// hold one of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaseConflict {
+    pub path: PathBuf,
+    pub input: InputId,
+    pub label: Option<String>,
+    /// The path of highest priority it collides with.
+    pub other: PathBuf,
+    pub other_input: InputId,
+    pub other_label: Option<String>,
+}
+
+/// What to do when a file would be linked at a path that only differs by case from one
+/// already in the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseConflictPolicy {
+    /// Link it anyway and warn.
+    #[default]
+    Warn,
+    /// Only keep the one of higher priority, the one already there on a tie, and warn.
+    Priority,
+    /// Keep the one already there and count the other as an error.
+    Error,
+}
+
+/// How the names of files and directories in the output are spelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputCase {
+    /// As they are in the inputs.
+    #[default]
+    Preserve,
+    /// In lowercase, for programs that only look for them so. Files that only differ by
+    /// case then are at the same path, which the one of highest priority wins.
+    Lower,
+}
+
+/// How the overlay deals with paths that only differ by case.
+#[derive(Debug, Default)]
+pub(crate) struct Casing {
+    pub(crate) conflicts: CaseConflictPolicy,
+    pub(crate) output: OutputCase,
+    /// Every tracked path by its case-folded form.
+    pub(crate) folded: FxHashMap<Folded, BTreeSet<PathBuf>>,
+}
+
+impl Overlay {
+    /// Returns every tracked path that only differs by case from one of higher priority,
+    /// sorted by path.
+    pub fn case_conflicts(&self) -> Vec<CaseConflict> {
+        let mut conflicts = vec![];
+
+        for paths in self.case.folded.values() {
+            let mut winners: Vec<(&PathBuf, &Input)> = paths
+                .iter()
+                .filter_map(|path| {
+                    let winner = self.input_map.get(path).and_then(BinaryHeap::peek);
+                    winner.map(|winner| (path, &self.inputs[winner.index]))
+                })
+                .collect();
+            if winners.len() < 2 {
+                continue;
+            }
+
+            winners.sort_by(|a, b| b.1.rank.cmp(&a.1.rank).then(a.0.cmp(b.0)));
+            let (other, other_input) = winners[0];
+            for (path, input) in &winners[1..] {
+                conflicts.push(CaseConflict {
+                    path: (*path).clone(),
+                    input: input.id(),
+                    label: input.label.clone(),
+                    other: other.clone(),
+                    other_input: other_input.id(),
+                    other_label: other_input.label.clone(),
+                });
+            }
+        }
+
+        conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+        conflicts
+    }
+
+    /// Warns when the file input `index` has at `path` is there along with those of other
+    /// inputs only as their names are folded to lowercase, unless it already was. The
+    /// usual priorities decide between them.
+    pub(crate) fn warn_folded(&self, index: usize, path: &Path) {
+        let providers = match self.input_map.get(path) {
+            Some(providers) if self.case.output == OutputCase::Lower => providers,
+            _ => return,
+        };
+        if providers.iter().any(|other| other.index == index) {
+            return;
+        }
+        let unfolded = |input: &Input| {
+            input
+                .keys
+                .relative(path)
+                .map_or_else(PathBuf::new, |relative| input.keys.unfolded(&relative))
+        };
+        let name = unfolded(&self.inputs[index]);
+        for other in providers {
+            let theirs = unfolded(&self.inputs[other.index]);
+            if theirs != name {
+                warn!(
+                    "{} of input {} and {} of input {} both are {} in the output",
+                    name.display(),
+                    self.input_name(index),
+                    theirs.display(),
+                    self.input_name(other.index),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    /// Returns the other tracked paths that only differ from `path`, folding to `key`, by
+    /// case.
+    pub(crate) fn case_siblings(&self, path: &Path, key: &Folded) -> Vec<PathBuf> {
+        match self.case.folded.get(key) {
+            Some(paths) => paths
+                .iter()
+                .filter(|other| *other != path)
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Deals with the files in the output at paths that only differ from `path` by case,
+    /// as the case conflict policy says. Returns `false` if `path` has to stay out.
+    pub(crate) fn resolve_case_conflicts(
+        &mut self,
+        path: &Path,
+        key: &Folded,
+        index: usize,
+        rank: Rank,
+    ) -> bool {
+        let others: Vec<(PathBuf, Rank)> = self
+            .case_siblings(path, key)
+            .into_iter()
+            .filter_map(|other| {
+                let winner = self.materialized(&other).map(|input| input.rank);
+                winner.map(|rank| (other, rank))
+            })
+            .collect();
+
+        let mut allowed = true;
+        for (other, other_rank) in others {
+            say!(self.line, Warn, " CASE CONFLICT WITH {},", other.display());
+            let action = match self.case.conflicts {
+                CaseConflictPolicy::Warn => AuditAction::Conflict,
+                CaseConflictPolicy::Priority if rank > other_rank => {
+                    self.displace(&other);
+                    AuditAction::Conflict
+                }
+                CaseConflictPolicy::Priority => {
+                    allowed = false;
+                    AuditAction::Conflict
+                }
+                CaseConflictPolicy::Error => {
+                    allowed = false;
+                    let message = format!("differs only by case from {}", other.display());
+                    self.failed(
+                        Some(path),
+                        Some(index),
+                        "case_conflict".to_string(),
+                        message,
+                    );
+                    AuditAction::Error
+                }
+            };
+
+            let error = format!("differs only by case from {}", other.display());
+            let error = Some(error.as_str()).filter(|_| action == AuditAction::Error);
+            self.audit(action, Some(path), index, error);
+        }
+
+        allowed
+    }
+
+    pub fn set_case_conflicts(&mut self, policy: CaseConflictPolicy) {
+        self.case.conflicts = policy;
+    }
+
+    /// Spells the paths in the output as `case` says, for the inputs already added as well.
+    /// It has to be set before the overlay starts, the paths it already has aren't moved.
+    pub fn set_output_case(&mut self, case: OutputCase) {
+        self.case.output = case;
+        for input in &self.inputs {
+            input.keys.set_lower(case == OutputCase::Lower);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+
+    #[test]
+    fn paths_that_only_differ_by_case_are_dealt_with_by_the_policy() {
+        let (lower, upper) = ("Textures/Rock.dds", "textures/rock.dds");
+        let conflicted = |policy: CaseConflictPolicy| {
+            let name = format!("case-{:?}", policy);
+            let mut harness =
+                Harness::with(&name, &[0, 1], |builder| builder.case_conflicts(policy));
+            harness.create(0, lower);
+            harness.create(1, upper);
+            harness
+        };
+
+        let harness = conflicted(CaseConflictPolicy::Warn);
+        assert_eq!(harness.winner(lower), Some(0));
+        assert_eq!(harness.winner(upper), Some(1));
+        assert_eq!(
+            harness.overlay.case_conflicts(),
+            [CaseConflict {
+                path: PathBuf::from(lower),
+                input: InputId::of(0),
+                label: None,
+                other: PathBuf::from(upper),
+                other_input: InputId::of(1),
+                other_label: None,
+            }]
+        );
+        assert!(harness.overlay.failures.is_empty());
+
+        // The file of higher priority takes the place of the other.
+        let mut harness = conflicted(CaseConflictPolicy::Priority);
+        assert!(!harness.in_output(lower));
+        assert_eq!(harness.winner(upper), Some(1));
+        harness.create(0, "textures/ROCK.dds");
+        assert!(!harness.in_output("textures/ROCK.dds"));
+
+        let harness = conflicted(CaseConflictPolicy::Error);
+        assert_eq!(harness.winner(lower), Some(0));
+        assert!(!harness.in_output(upper));
+        let failure = harness.overlay.failures.last().unwrap();
+        assert_eq!(failure.kind, "case_conflict");
+        assert_eq!(failure.path.as_deref(), Some(Path::new(upper)));
+        assert_eq!(
+            failure.message,
+            format!("differs only by case from {}", Path::new(lower).display())
+        );
+    }
+}
@@ -0,0 +1,690 @@
+use crate::builder::OverlayBuilder;
+use crate::filter::{default_enabled, InputOptions};
+#[cfg(feature = "watch")]
+use crate::NotifySource;
+use crate::{
+    CaseConflictPolicy, ExistingOutputPolicy, ForeignFiles, MergeRule, OutputCase, RetryPolicy,
+    RewriteRule, Strategy, TrashRetention,
+};
+use failure::{err_msg, format_err, Error};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The TOML configuration file understood by the `overlay` binary.
+///
+/// ```toml
+/// output = "D:\\Games\\Merged"
+/// audit_log = "overlay.log"
+/// record_events = "events.jsonl"
+/// record_events_max_mb = 64
+/// control_socket = "\\\\.\\pipe\\overlay"
+/// strategy = "hybrid"
+/// case_conflicts = "priority"
+/// output_case = "preserve"
+/// foreign_files = "keep"
+/// existing_output = "refuse"
+/// adopt_copies = false
+/// trash = "D:\\Games\\Trash"
+/// trash_max_age_hours = 168
+/// trash_max_mb = 4096
+/// copy_fallback = false
+/// copy_progress_mb = 1024
+/// retry_attempts = 3
+/// retry_delay_ms = 100
+/// ignore_free_space = false
+/// workers = 4
+/// load_order = "loadorder.txt"
+/// ignore_file = "overlay.ignore"
+///
+/// [[inputs]]
+/// path = "D:\\Games\\Base"
+/// label = "BaseGame"
+/// enabled = true
+/// group = 0
+/// priority = 0
+/// exclude = ["*.psd", "source/"]
+/// writable = false
+/// poll_interval_ms = 60000
+/// settle_ms = 2000
+/// min_age_ms = 30000
+///
+/// [[inputs]]
+/// path = "D:\\Mods\\HD"
+/// priority = 1
+/// source = "textures"
+/// mount = "Data/Textures"
+/// rewrite = [
+///     { from = "Normals/", to = "normals/" },
+///     { from = "readme.txt", to = "docs/HD-readme.txt" },
+/// ]
+///
+/// [[merge]]
+/// pattern = "*.ini"
+/// format = "ini"
+///
+/// [hooks]
+/// on_link = ["notify-server", "{path}"]
+/// timeout_ms = 5000
+/// ```
+///
+/// Environment variables override the file, and flags given to the binary override both:
+///
+/// | Variable | Setting |
+/// |---|---|
+/// | `OVERLAY_OUTPUT` | `output` |
+/// | `OVERLAY_INPUTS` | `inputs`, as `path=priority` entries separated like `PATH` |
+/// | `OVERLAY_DEBOUNCE_MS` | `debounce_ms` |
+/// | `OVERLAY_POLL_INTERVAL_MS` | `poll_interval_ms` |
+/// | `OVERLAY_THROTTLE_MS` | `throttle_ms` |
+/// | `OVERLAY_CROSS_INPUT_WINDOW_MS` | `cross_input_window_ms` |
+/// | `OVERLAY_AUTO_RESYNC_MS` | `auto_resync_ms` |
+/// | `OVERLAY_FULL_RESYNC_EVERY` | `full_resync_every` |
+/// | `OVERLAY_SUMMARY_INTERVAL_MS` | `summary_interval_ms` |
+/// | `OVERLAY_TICK_INTERVAL_MS` | `tick_interval_ms` |
+/// | `OVERLAY_DRY_RUN` | `dry_run` |
+/// | `OVERLAY_STRATEGY` | `strategy` |
+/// | `OVERLAY_GRAFT_DIRECTORIES` | `graft_directories` |
+/// | `OVERLAY_SINGLE_INSTANCE` | `single_instance` |
+/// | `OVERLAY_CHECK_OVERLAPS` | `check_overlaps` |
+/// | `OVERLAY_MERGE_DUPLICATE_INPUTS` | `merge_duplicate_inputs` |
+/// | `OVERLAY_CASE_CONFLICTS` | `case_conflicts` |
+/// | `OVERLAY_OUTPUT_CASE` | `output_case` |
+/// | `OVERLAY_FOREIGN_FILES` | `foreign_files` |
+/// | `OVERLAY_EXISTING_OUTPUT` | `existing_output` |
+/// | `OVERLAY_ADOPT_COPIES` | `adopt_copies` |
+/// | `OVERLAY_TRASH` | `trash` |
+/// | `OVERLAY_TRASH_MAX_AGE_HOURS` | `trash_max_age_hours` |
+/// | `OVERLAY_TRASH_MAX_MB` | `trash_max_mb` |
+/// | `OVERLAY_COPY_FALLBACK` | `copy_fallback` |
+/// | `OVERLAY_COPY_PROGRESS_MB` | `copy_progress_mb` |
+/// | `OVERLAY_RETRY_ATTEMPTS` | `retry_attempts` |
+/// | `OVERLAY_RETRY_DELAY_MS` | `retry_delay_ms` |
+/// | `OVERLAY_IGNORE_FREE_SPACE` | `ignore_free_space` |
+/// | `OVERLAY_FAIL_FAST` | `fail_fast` |
+/// | `OVERLAY_MAX_CONSECUTIVE_FAILURES` | `max_consecutive_failures` |
+/// | `OVERLAY_WORKERS` | `workers` |
+/// | `OVERLAY_AUDIT_LOG` | `audit_log` |
+/// | `OVERLAY_RECORD_EVENTS` | `record_events` |
+/// | `OVERLAY_RECORD_EVENTS_MAX_MB` | `record_events_max_mb` |
+/// | `OVERLAY_CONTROL_SOCKET` | `control_socket` |
+/// | `OVERLAY_HTTP_STATUS` | `http_status` |
+/// | `OVERLAY_LOAD_ORDER` | `load_order` |
+/// | `OVERLAY_IGNORE_FILE` | `ignore_file` |
+///
+/// Inputs given in the environment replace those in the file, rather than being added.
+///
+/// The rules of `ignore_file` apply to every input, matched against the paths in the
+/// output. What they ignore is never linked, even if an input includes it or negates the
+/// rule in its own `.overlayignore`.
+///
+/// Each `[[merge]]` has the files at paths matching `pattern` merged as `format`, `json` or
+/// `ini`, when more than one input has one, rather than the winner's being linked.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub output: PathBuf,
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    pub audit_log: Option<PathBuf>,
+    /// Where every event received is recorded, see `OverlayBuilder::record_events`.
+    pub record_events: Option<PathBuf>,
+    /// How many MB the recording may have before it is rotated, never with 0, see
+    /// `OverlayBuilder::record_events_max_size`.
+    pub record_events_max_mb: Option<u64>,
+    /// Where requests are taken while running, see `OverlayBuilder::control_socket`.
+    pub control_socket: Option<PathBuf>,
+    /// Where health checks are answered over HTTP, see `OverlayBuilder::http_status`. Only
+    /// with the `http-status` feature.
+    pub http_status: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// The same as `strategy = "hybrid"`, if `true`.
+    #[serde(default)]
+    pub graft_directories: bool,
+    #[serde(default)]
+    pub case_conflicts: CaseConflictPolicy,
+    #[serde(default)]
+    pub output_case: OutputCase,
+    pub temp_patterns: Option<Vec<String>>,
+    #[serde(default = "default_single_instance")]
+    pub single_instance: bool,
+    #[serde(default = "default_check_overlaps")]
+    pub check_overlaps: bool,
+    #[serde(default)]
+    pub merge_duplicate_inputs: bool,
+    #[serde(default)]
+    pub foreign_files: ForeignFiles,
+    /// See `Overlay::set_existing_output`.
+    #[serde(default)]
+    pub existing_output: ExistingOutputPolicy,
+    /// See `Overlay::set_adopt_copies`.
+    #[serde(default)]
+    pub adopt_copies: bool,
+    /// Where the files the overlay displaces from the output are moved, see
+    /// `Overlay::set_trash`.
+    pub trash: Option<PathBuf>,
+    /// How long, and how many MB of, the files in the trash are kept, forever without.
+    pub trash_max_age_hours: Option<u64>,
+    pub trash_max_mb: Option<u64>,
+    #[serde(default)]
+    pub copy_fallback: bool,
+    /// How many MB a file has to have for how far copying it is told, never with 0, see
+    /// `Overlay::set_copy_progress`.
+    pub copy_progress_mb: Option<u64>,
+    /// How often deletes, links and renames that fail for a moment are tried, see
+    /// `Overlay::set_retry_policy`.
+    pub retry_attempts: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub ignore_free_space: bool,
+    #[serde(default)]
+    pub fail_fast: bool,
+    pub max_consecutive_failures: Option<u32>,
+    /// How many threads put the files into the output, see `Overlay::set_workers`.
+    pub workers: Option<usize>,
+    pub restart_backoff_max_ms: Option<u64>,
+    /// How often to resync while watching, see `OverlayBuilder::auto_resync`.
+    pub auto_resync_ms: Option<u64>,
+    pub full_resync_every: Option<u32>,
+    /// How often to log a summary while watching, see `Overlay::set_summary_interval`.
+    pub summary_interval_ms: Option<u64>,
+    /// How often the periodic work is done while watching, see
+    /// `Overlay::set_tick_interval`.
+    pub tick_interval_ms: Option<u64>,
+    pub load_order: Option<PathBuf>,
+    pub ignore_file: Option<PathBuf>,
+    #[serde(default)]
+    pub merge: Vec<MergeRule>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    pub throttle_ms: Option<u64>,
+    /// How long new files wait for other inputs to have them too, never with 0, see
+    /// `Overlay::set_cross_input_window`.
+    pub cross_input_window_ms: Option<u64>,
+    pub debounce_ms: Option<u64>,
+    /// Polls every input this often instead of watching it, see `NotifySource::poll`.
+    pub poll_interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputConfig {
+    pub path: PathBuf,
+    pub label: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub group: u32,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub max_depth: Option<usize>,
+    #[serde(default)]
+    pub writable: bool,
+    pub poll_interval_ms: Option<u64>,
+    pub settle_ms: Option<u64>,
+    pub min_age_ms: Option<u64>,
+    pub source: Option<PathBuf>,
+    pub mount: Option<PathBuf>,
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+}
+
+impl InputConfig {
+    pub fn options(&self) -> InputOptions {
+        InputOptions {
+            label: self.label.clone(),
+            enabled: self.enabled,
+            group: self.group,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            max_depth: self.max_depth,
+            writable: self.writable,
+            poll_interval_ms: self.poll_interval_ms,
+            settle_ms: self.settle_ms,
+            min_age_ms: self.min_age_ms,
+            source: self.source.clone(),
+            mount: self.mount.clone(),
+            rewrite: self.rewrite.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    pub on_link: Option<Vec<String>>,
+    pub on_unlink: Option<Vec<String>>,
+    pub timeout_ms: Option<u64>,
+    pub queue: Option<usize>,
+}
+
+fn default_single_instance() -> bool {
+    true
+}
+
+fn default_check_overlaps() -> bool {
+    true
+}
+
+impl Config {
+    /// Reads the configuration file at `path`, with the environment variables applied.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut config = Self::parse(&fs::read_to_string(path)?)?;
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Takes the whole configuration from the environment variables, for when there is no
+    /// file.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut config = Self::parse("")?;
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a configuration file, without looking at the environment.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Overrides the settings that are given in environment variables.
+    pub fn apply_env(&mut self) -> Result<(), Error> {
+        if let Some(output) = env::var_os("OVERLAY_OUTPUT") {
+            self.output = PathBuf::from(output);
+        }
+        if let Some(inputs) = env::var_os("OVERLAY_INPUTS") {
+            self.inputs = env::split_paths(&inputs)
+                .filter(|entry| !entry.as_os_str().is_empty())
+                .map(|entry| parse_input(&entry))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format_err!("OVERLAY_INPUTS: {}", e))?;
+        }
+
+        if let Some(delay) = parsed_var("OVERLAY_DEBOUNCE_MS")? {
+            self.debounce_ms = Some(delay);
+        }
+        if let Some(interval) = parsed_var("OVERLAY_POLL_INTERVAL_MS")? {
+            self.poll_interval_ms = Some(interval);
+        }
+        if let Some(window) = parsed_var("OVERLAY_THROTTLE_MS")? {
+            self.throttle_ms = Some(window);
+        }
+        if let Some(window) = parsed_var("OVERLAY_CROSS_INPUT_WINDOW_MS")? {
+            self.cross_input_window_ms = Some(window);
+        }
+        if let Some(interval) = parsed_var("OVERLAY_AUTO_RESYNC_MS")? {
+            self.auto_resync_ms = Some(interval);
+        }
+        if let Some(n) = parsed_var("OVERLAY_FULL_RESYNC_EVERY")? {
+            self.full_resync_every = Some(n);
+        }
+        if let Some(interval) = parsed_var("OVERLAY_SUMMARY_INTERVAL_MS")? {
+            self.summary_interval_ms = Some(interval);
+        }
+        if let Some(interval) = parsed_var("OVERLAY_TICK_INTERVAL_MS")? {
+            self.tick_interval_ms = Some(interval);
+        }
+        if let Some(dry_run) = flag_var("OVERLAY_DRY_RUN")? {
+            self.dry_run = dry_run;
+        }
+        if let Some(strategy) = named_var("OVERLAY_STRATEGY")? {
+            self.strategy = strategy;
+        }
+        if let Some(graft) = flag_var("OVERLAY_GRAFT_DIRECTORIES")? {
+            self.graft_directories = graft;
+        }
+        if let Some(single) = flag_var("OVERLAY_SINGLE_INSTANCE")? {
+            self.single_instance = single;
+        }
+        if let Some(check) = flag_var("OVERLAY_CHECK_OVERLAPS")? {
+            self.check_overlaps = check;
+        }
+        if let Some(merge) = flag_var("OVERLAY_MERGE_DUPLICATE_INPUTS")? {
+            self.merge_duplicate_inputs = merge;
+        }
+        if let Some(policy) = named_var("OVERLAY_CASE_CONFLICTS")? {
+            self.case_conflicts = policy;
+        }
+        if let Some(case) = named_var("OVERLAY_OUTPUT_CASE")? {
+            self.output_case = case;
+        }
+        if let Some(policy) = named_var("OVERLAY_FOREIGN_FILES")? {
+            self.foreign_files = policy;
+        }
+        if let Some(policy) = named_var("OVERLAY_EXISTING_OUTPUT")? {
+            self.existing_output = policy;
+        }
+        if let Some(adopt) = flag_var("OVERLAY_ADOPT_COPIES")? {
+            self.adopt_copies = adopt;
+        }
+        if let Some(path) = env::var_os("OVERLAY_TRASH") {
+            self.trash = Some(PathBuf::from(path));
+        }
+        if let Some(hours) = parsed_var("OVERLAY_TRASH_MAX_AGE_HOURS")? {
+            self.trash_max_age_hours = Some(hours);
+        }
+        if let Some(size) = parsed_var("OVERLAY_TRASH_MAX_MB")? {
+            self.trash_max_mb = Some(size);
+        }
+        if let Some(fallback) = flag_var("OVERLAY_COPY_FALLBACK")? {
+            self.copy_fallback = fallback;
+        }
+        if let Some(threshold) = parsed_var("OVERLAY_COPY_PROGRESS_MB")? {
+            self.copy_progress_mb = Some(threshold);
+        }
+        if let Some(attempts) = parsed_var("OVERLAY_RETRY_ATTEMPTS")? {
+            self.retry_attempts = Some(attempts);
+        }
+        if let Some(delay) = parsed_var("OVERLAY_RETRY_DELAY_MS")? {
+            self.retry_delay_ms = Some(delay);
+        }
+        if let Some(ignore) = flag_var("OVERLAY_IGNORE_FREE_SPACE")? {
+            self.ignore_free_space = ignore;
+        }
+        if let Some(fail_fast) = flag_var("OVERLAY_FAIL_FAST")? {
+            self.fail_fast = fail_fast;
+        }
+        if let Some(max) = parsed_var("OVERLAY_MAX_CONSECUTIVE_FAILURES")? {
+            self.max_consecutive_failures = Some(max);
+        }
+        if let Some(count) = parsed_var("OVERLAY_WORKERS")? {
+            self.workers = Some(count);
+        }
+
+        if let Some(path) = env::var_os("OVERLAY_AUDIT_LOG") {
+            self.audit_log = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env::var_os("OVERLAY_RECORD_EVENTS") {
+            self.record_events = Some(PathBuf::from(path));
+        }
+        if let Some(size) = parsed_var("OVERLAY_RECORD_EVENTS_MAX_MB")? {
+            self.record_events_max_mb = Some(size);
+        }
+        if let Some(path) = env::var_os("OVERLAY_CONTROL_SOCKET") {
+            self.control_socket = Some(PathBuf::from(path));
+        }
+        if let Ok(address) = env::var("OVERLAY_HTTP_STATUS") {
+            self.http_status = Some(address);
+        }
+        if let Some(path) = env::var_os("OVERLAY_LOAD_ORDER") {
+            self.load_order = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env::var_os("OVERLAY_IGNORE_FILE") {
+            self.ignore_file = Some(PathBuf::from(path));
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.output.as_os_str().is_empty() {
+            return Err(err_msg(
+                "there is no output, it is set with `output` or OVERLAY_OUTPUT",
+            ));
+        }
+        #[cfg(not(feature = "watch"))]
+        if self.control_socket.is_some() {
+            return Err(err_msg(
+                "control_socket is set, but overlay was built without the watch feature",
+            ));
+        }
+        #[cfg(not(feature = "watch"))]
+        if self.record_events.is_some() {
+            return Err(err_msg(
+                "record_events is set, but overlay was built without the watch feature",
+            ));
+        }
+        // Rather than leave the health checks failing for no apparent reason.
+        #[cfg(not(feature = "http-status"))]
+        if self.http_status.is_some() {
+            return Err(err_msg(
+                "http_status is set, but overlay was built without the http-status feature",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn builder(&self) -> OverlayBuilder {
+        let mut builder = OverlayBuilder::new(&self.output)
+            .dry_run(self.dry_run)
+            .strategy(if self.graft_directories {
+                Strategy::Hybrid
+            } else {
+                self.strategy
+            })
+            .case_conflicts(self.case_conflicts)
+            .output_case(self.output_case)
+            .single_instance(self.single_instance)
+            .check_overlaps(self.check_overlaps)
+            .merge_duplicate_inputs(self.merge_duplicate_inputs)
+            .foreign_files(self.foreign_files)
+            .existing_output(self.existing_output)
+            .adopt_copies(self.adopt_copies)
+            .copy_fallback(self.copy_fallback)
+            .ignore_free_space(self.ignore_free_space)
+            .fail_fast(self.fail_fast);
+
+        for input in &self.inputs {
+            builder = builder.input_with_options(&input.path, input.priority, input.options());
+        }
+
+        if let Some(max) = self.max_consecutive_failures {
+            builder = builder.max_consecutive_failures(max);
+        }
+        if let Some(count) = self.workers {
+            builder = builder.workers(count);
+        }
+        if let Some(threshold) = self.copy_progress_mb {
+            builder = builder.copy_progress(Some(threshold << 20).filter(|&bytes| bytes > 0));
+        }
+        if self.retry_attempts.is_some() || self.retry_delay_ms.is_some() {
+            let default = RetryPolicy::default();
+            builder = builder.retry_policy(RetryPolicy {
+                attempts: self.retry_attempts.unwrap_or(default.attempts),
+                delay: self
+                    .retry_delay_ms
+                    .map_or(default.delay, Duration::from_millis),
+            });
+        }
+
+        if let Some(patterns) = &self.temp_patterns {
+            builder = builder.temp_patterns(patterns.clone());
+        }
+
+        if let Some(path) = &self.load_order {
+            builder = builder.load_order(path);
+        }
+
+        if let Some(path) = &self.ignore_file {
+            builder = builder.ignore_file(path);
+        }
+
+        for rule in &self.merge {
+            builder = builder.merge(&rule.pattern, rule.format);
+        }
+
+        #[cfg(feature = "watch")]
+        if self.debounce_ms.is_some() || self.poll_interval_ms.is_some() {
+            let default = NotifySource::default();
+            builder = builder.event_source(NotifySource {
+                delay: self
+                    .debounce_ms
+                    .map_or(default.delay, Duration::from_millis),
+                poll: self.poll_interval_ms.map(Duration::from_millis),
+            });
+        }
+
+        #[cfg(feature = "watch")]
+        if let Some(max) = self.restart_backoff_max_ms {
+            builder = builder.restart_backoff_max(Duration::from_millis(max));
+        }
+
+        if let Some(interval) = self.auto_resync_ms {
+            builder = builder.auto_resync(Duration::from_millis(interval));
+        }
+        if let Some(n) = self.full_resync_every {
+            builder = builder.full_resync_every(n);
+        }
+        if let Some(interval) = self.summary_interval_ms {
+            builder = builder.summary_interval(Duration::from_millis(interval));
+        }
+        if let Some(interval) = self.tick_interval_ms {
+            builder = builder.tick_interval(Duration::from_millis(interval));
+        }
+
+        #[cfg(feature = "watch")]
+        if let Some(window) = self.throttle_ms {
+            builder = builder.throttle(Duration::from_millis(window));
+        }
+        #[cfg(feature = "watch")]
+        if let Some(window) = self.cross_input_window_ms {
+            builder = builder.cross_input_window(Duration::from_millis(window));
+        }
+
+        if let Some(path) = &self.audit_log {
+            builder = builder.audit_log(path);
+        }
+        if let Some(path) = &self.trash {
+            builder = builder.trash(path).trash_retention(TrashRetention {
+                max_age: self
+                    .trash_max_age_hours
+                    .map(|hours| Duration::from_secs(hours * 3600)),
+                max_size: self.trash_max_mb.map(|size| size << 20),
+            });
+        }
+        #[cfg(feature = "watch")]
+        if let Some(path) = &self.record_events {
+            builder = builder.record_events(path);
+        }
+        #[cfg(feature = "watch")]
+        if let Some(size) = self.record_events_max_mb.filter(|&size| size > 0) {
+            builder = builder.record_events_max_size(size << 20);
+        }
+        #[cfg(feature = "watch")]
+        if let Some(path) = &self.control_socket {
+            builder = builder.control_socket(path);
+        }
+        #[cfg(feature = "http-status")]
+        if let Some(address) = &self.http_status {
+            builder = builder.http_status(address.as_str());
+        }
+
+        if let Some(command) = &self.hooks.on_link {
+            builder = builder.on_link(command.clone());
+        }
+        if let Some(command) = &self.hooks.on_unlink {
+            builder = builder.on_unlink(command.clone());
+        }
+        if let Some(timeout) = self.hooks.timeout_ms {
+            builder = builder.hook_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(queue) = self.hooks.queue {
+            builder = builder.hook_queue(queue);
+        }
+
+        builder
+    }
+}
+
+/// Parses a `path=priority` entry of `OVERLAY_INPUTS`. Without a priority the input gets 0.
+fn parse_input(entry: &Path) -> Result<InputConfig, Error> {
+    let text = entry
+        .to_str()
+        .ok_or_else(|| format_err!("{} is not valid unicode", entry.display()))?;
+    let (path, priority) = match text.rfind('=') {
+        Some(at) => {
+            let priority = &text[at + 1..];
+            let priority = priority
+                .parse()
+                .map_err(|_| format_err!("{:?} is not a priority in {:?}", priority, text))?;
+            (&text[..at], priority)
+        }
+        None => (text, 0),
+    };
+
+    let mut input = InputConfig::from(Path::new(path));
+    input.priority = priority;
+    Ok(input)
+}
+
+impl From<&Path> for InputConfig {
+    fn from(path: &Path) -> Self {
+        InputConfig {
+            path: path.to_path_buf(),
+            label: None,
+            enabled: true,
+            group: 0,
+            priority: 0,
+            include: vec![],
+            exclude: vec![],
+            max_depth: None,
+            writable: false,
+            poll_interval_ms: None,
+            settle_ms: None,
+            min_age_ms: None,
+            source: None,
+            mount: None,
+            rewrite: vec![],
+        }
+    }
+}
+
+/// Reads the environment variable `name`, which has to be valid unicode if it is set.
+fn var(name: &str) -> Result<Option<String>, Error> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(format_err!("{} is not valid unicode", name)),
+    }
+}
+
+fn parsed_var<T: FromStr>(name: &str) -> Result<Option<T>, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    match var(name)? {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| format_err!("{}={:?}: {}", name, value, e)),
+        None => Ok(None),
+    }
+}
+
+/// Reads a yes or no setting, given as `1`, `true`, `yes` or `on`, or their opposites.
+fn flag_var(name: &str) -> Result<Option<bool>, Error> {
+    match var(name)? {
+        Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Some(true)),
+            "0" | "false" | "no" | "off" | "" => Ok(Some(false)),
+            _ => Err(format_err!("{}={:?}: expected true or false", name, value)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads one of the names the configuration file accepts for a setting.
+fn named_var<T: DeserializeOwned>(name: &str) -> Result<Option<T>, Error> {
+    match var(name)? {
+        Some(value) => toml::Value::String(value.trim().to_string())
+            .try_into()
+            .map(Some)
+            .map_err(|e| format_err!("{}={:?}: {}", name, value, e.to_string().trim())),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,544 @@
+use crate::{Controller, Phase, PlannedChange, Stats};
+use failure::{err_msg, format_err, Error};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// A request to the control socket of a running overlay, see
+/// `OverlayBuilder::control_socket`.
+///
+/// Requests are sent as one JSON object per line, e.g.
+/// `{"command": "set_priority", "input": "BaseGame", "priority": 5}`, and each is answered
+/// with a `ControlResponse` on a line of its own. Inputs are named by their label, or by
+/// their `InputId` or path if none has that label.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// The phase of the overlay and, once it is ready, its stats.
+    Status,
+    /// A full resync, answered with its `SyncReport`.
+    Resync,
+    SetPriority {
+        input: String,
+        priority: u32,
+    },
+    /// Moves the input right above `reference`, see `Overlay::move_input_above`.
+    MoveAbove {
+        input: String,
+        reference: String,
+    },
+    MoveBelow {
+        input: String,
+        reference: String,
+    },
+    /// Disables the input, see `Overlay::set_enabled`.
+    Pause {
+        input: String,
+    },
+    Resume {
+        input: String,
+    },
+    /// The case conflicts, see `Overlay::case_conflicts`.
+    Conflicts,
+    /// The files of the input that others win over, see `Overlay::shadowed`.
+    Shadowed {
+        input: String,
+    },
+    /// Which paths would change winners if `changes`, requests that reorder, pause or
+    /// resume inputs, were made, see `Overlay::preview`. Nothing is changed.
+    Preview {
+        changes: Vec<ControlRequest>,
+    },
+    /// Puts the `count` files moved into the trash last back, see `Overlay::undo_last`.
+    Undo {
+        count: usize,
+    },
+}
+
+/// The answer to a `ControlRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    /// What the request returned, nothing for those that only do something.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub result: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ControlResponse {
+    fn failed(error: String) -> Self {
+        ControlResponse {
+            ok: false,
+            result: Value::Null,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    phase: Phase,
+    /// Only once the overlay is ready, as it doesn't answer before.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
+}
+
+impl ControlRequest {
+    /// Sends the request to the control socket at `socket` and waits for the answer.
+    pub fn send<P: AsRef<Path>>(&self, socket: P) -> Result<ControlResponse, Error> {
+        let socket = socket.as_ref();
+        let stream = endpoint::connect(socket)
+            .map_err(|e| format_err!("Could not connect to {}: {}", socket.display(), e))?;
+
+        write_line(&mut stream.try_clone()?, self)?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        if line.is_empty() {
+            return Err(err_msg(
+                "the overlay closed the connection without answering",
+            ));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    fn answer(self, controller: &Controller) -> Result<Value, Error> {
+        let value = match self {
+            ControlRequest::Status => {
+                let phase = controller.phase();
+                let stats = match phase {
+                    Phase::Ready => Some(controller.stats()?),
+                    _ => None,
+                };
+                serde_json::to_value(Status { phase, stats })?
+            }
+            ControlRequest::Resync => serde_json::to_value(controller.resync()?)?,
+            ControlRequest::SetPriority { input, priority } => {
+                controller.set_priority(controller.find_input(&input)?, priority)?;
+                Value::Null
+            }
+            ControlRequest::MoveAbove { input, reference } => {
+                let reference = controller.find_input(&reference)?;
+                controller.move_input_above(controller.find_input(&input)?, reference)?;
+                Value::Null
+            }
+            ControlRequest::MoveBelow { input, reference } => {
+                let reference = controller.find_input(&reference)?;
+                controller.move_input_below(controller.find_input(&input)?, reference)?;
+                Value::Null
+            }
+            ControlRequest::Pause { input } => {
+                controller.set_enabled(controller.find_input(&input)?, false)?;
+                Value::Null
+            }
+            ControlRequest::Resume { input } => {
+                controller.set_enabled(controller.find_input(&input)?, true)?;
+                Value::Null
+            }
+            ControlRequest::Conflicts => serde_json::to_value(controller.case_conflicts()?)?,
+            ControlRequest::Shadowed { input } => {
+                serde_json::to_value(controller.shadowed(controller.find_input(&input)?)?)?
+            }
+            ControlRequest::Preview { changes } => {
+                let changes = changes
+                    .into_iter()
+                    .map(|change| change.planned(controller))
+                    .collect::<Result<_, _>>()?;
+                serde_json::to_value(controller.preview(changes)?)?
+            }
+            ControlRequest::Undo { count } => serde_json::to_value(controller.undo(count)?)?,
+        };
+        Ok(value)
+    }
+
+    /// The change this request would make to the inputs, erring for those that make
+    /// none.
+    fn planned(self, controller: &Controller) -> Result<PlannedChange, Error> {
+        let change = match self {
+            ControlRequest::SetPriority { input, priority } => PlannedChange::SetPriority {
+                input: controller.find_input(&input)?,
+                priority,
+            },
+            ControlRequest::MoveAbove { input, reference } => PlannedChange::MoveAbove {
+                input: controller.find_input(&input)?,
+                reference: controller.find_input(&reference)?,
+            },
+            ControlRequest::MoveBelow { input, reference } => PlannedChange::MoveBelow {
+                input: controller.find_input(&input)?,
+                reference: controller.find_input(&reference)?,
+            },
+            ControlRequest::Pause { input } => PlannedChange::SetEnabled {
+                input: controller.find_input(&input)?,
+                enabled: false,
+            },
+            ControlRequest::Resume { input } => PlannedChange::SetEnabled {
+                input: controller.find_input(&input)?,
+                enabled: true,
+            },
+            request => return Err(format_err!("{:?} changes nothing to preview", request)),
+        };
+        Ok(change)
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, record: &T) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+/// Answers every request on `stream` until the client hangs up.
+fn serve(controller: Controller, stream: endpoint::Stream) -> Result<(), Error> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                debug!("Control request: {:?}", request);
+                match request.answer(&controller) {
+                    Ok(result) => ControlResponse {
+                        ok: true,
+                        result,
+                        error: None,
+                    },
+                    Err(e) => ControlResponse::failed(e.to_string()),
+                }
+            }
+            Err(e) => ControlResponse::failed(format!("not a request: {}", e)),
+        };
+        write_line(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// Where a running overlay takes requests, a Unix domain socket, or a named pipe like
+/// `\\.\pipe\overlay` on Windows. The socket is removed again when this is dropped.
+#[derive(Debug)]
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Starts answering the requests sent to `path` through `controller`, each connection
+    /// on a thread of its own.
+    pub(crate) fn listen(path: &Path, controller: Controller) -> Result<Self, Error> {
+        let mut listener = endpoint::bind(path)
+            .map_err(|e| format_err!("Could not listen on {}: {}", path.display(), e))?;
+
+        thread::spawn(move || loop {
+            match listener.accept() {
+                Ok(stream) => {
+                    let controller = controller.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(controller, stream) {
+                            debug!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("The control socket no longer takes requests: {}", e);
+                    break;
+                }
+            }
+        });
+
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        endpoint::remove(&self.path);
+    }
+}
+
+#[cfg(unix)]
+mod endpoint {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+
+    pub(super) type Stream = UnixStream;
+
+    pub(super) struct Listener(UnixListener);
+
+    impl Listener {
+        pub(super) fn accept(&mut self) -> io::Result<Stream> {
+            self.0.accept().map(|(stream, _)| stream)
+        }
+    }
+
+    fn is_socket(path: &Path) -> io::Result<bool> {
+        Ok(fs::symlink_metadata(path)?.file_type().is_socket())
+    }
+
+    pub(super) fn bind(path: &Path) -> io::Result<Listener> {
+        // A socket left behind by an overlay that didn't stop cleanly is in the way, one
+        // that is still answered, or anything else at the path, isn't ours to remove.
+        match is_socket(path) {
+            Ok(true) if UnixStream::connect(path).is_ok() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another overlay is listening on it",
+                ));
+            }
+            Ok(true) => fs::remove_file(path)?,
+            Ok(false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "there is something else there",
+                ));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        UnixListener::bind(path).map(Listener)
+    }
+
+    pub(super) fn connect(path: &Path) -> io::Result<Stream> {
+        UnixStream::connect(path)
+    }
+
+    pub(super) fn remove(path: &Path) {
+        if is_socket(path).unwrap_or(false) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod endpoint {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::path::Path;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::{ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+    };
+    use windows_sys::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+        PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    /// The size of the buffers of the pipe, which requests and answers rarely fill.
+    const BUFFER: u32 = 64 * 1024;
+
+    pub(super) type Stream = File;
+
+    /// A named pipe, and the instance of it the next client connects to.
+    pub(super) struct Listener {
+        name: Vec<u16>,
+        next: File,
+    }
+
+    impl Listener {
+        pub(super) fn accept(&mut self) -> io::Result<Stream> {
+            loop {
+                // There is always an instance waiting, so clients never find the pipe gone.
+                let next = create(&self.name, false)?;
+                let pipe = mem::replace(&mut self.next, next);
+                let connected = unsafe { ConnectNamedPipe(pipe.as_raw_handle(), ptr::null_mut()) };
+                if connected != 0
+                    || io::Error::last_os_error().raw_os_error()
+                        == Some(ERROR_PIPE_CONNECTED as i32)
+                {
+                    return Ok(pipe);
+                }
+                // The client went away before it could be answered.
+            }
+        }
+    }
+
+    fn create(name: &[u16], first: bool) -> io::Result<File> {
+        let flags = if first {
+            PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            PIPE_ACCESS_DUPLEX
+        };
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                flags,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER,
+                BUFFER,
+                0,
+                ptr::null(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    pub(super) fn bind(path: &Path) -> io::Result<Listener> {
+        let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        // Fails if another overlay has the pipe already.
+        let next = create(&name, true)?;
+        Ok(Listener { name, next })
+    }
+
+    pub(super) fn connect(path: &Path) -> io::Result<Stream> {
+        OpenOptions::new().read(true).write(true).open(path)
+    }
+
+    /// Named pipes go away with their last handle.
+    pub(super) fn remove(_path: &Path) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use crate::{InputOptions, ReplaySource};
+    use std::fs;
+    use std::io;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::Arc;
+
+    fn pause(socket: &Path, input: &str) -> ControlResponse {
+        let request = ControlRequest::Pause {
+            input: input.to_string(),
+        };
+        request.send(socket).unwrap()
+    }
+
+    #[test]
+    fn inputs_are_named_by_label_or_path() {
+        let source = Arc::new(ReplaySource::new());
+        let harness = Harness::with("control-find-input", &[0], |builder| {
+            builder.event_source(source.clone())
+        });
+        let mods = harness.inputs[0].with_file_name("mods");
+        fs::create_dir_all(&mods).unwrap();
+        harness.fs.create_dir(&mods);
+        let mut overlay = harness.overlay;
+        overlay
+            .add_input_with_options(&mods, 1, &InputOptions::new().label("mods"))
+            .unwrap();
+        let socket = harness.output.with_file_name("control.sock");
+        overlay.listen_control(&socket).unwrap();
+        let controller = overlay.controller();
+        let running = thread::spawn(move || overlay.process_loop());
+        assert!(controller.wait_ready());
+        let enabled = || -> Vec<bool> {
+            let inputs = controller.snapshot().unwrap().inputs;
+            inputs.iter().map(|input| input.enabled).collect()
+        };
+
+        assert_eq!(pause(&socket, "mods").error, None);
+        assert_eq!(enabled(), [true, false]);
+        let path = harness.inputs[0].to_str().unwrap();
+        assert_eq!(pause(&socket, path).error, None);
+        assert_eq!(enabled(), [false, false]);
+
+        let response = pause(&socket, "base");
+        assert!(!response.ok);
+        assert_eq!(
+            response.error.as_deref(),
+            Some("unknown input 'base', the inputs are 0, 'mods'")
+        );
+
+        controller.shutdown().unwrap();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn requests_on_one_connection_are_answered_in_turn() {
+        let harness = Harness::with("control-protocol", &[0, 1], |builder| {
+            builder.event_source(ReplaySource::new())
+        });
+        let mut overlay = harness.overlay;
+        let socket = harness.output.with_file_name("control.sock");
+        overlay.listen_control(&socket).unwrap();
+        let controller = overlay.controller();
+        let running = thread::spawn(move || overlay.process_loop());
+        assert!(controller.wait_ready());
+
+        let stream = UnixStream::connect(&socket).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer
+            .write_all(
+                b"{\"command\": \"status\"}\n\n\
+                  {\"command\": \"set_priority\", \"input\": \"0\", \"priority\": 5}\n\
+                  {\"command\": \"conflicts\"}\n\
+                  {\"command\": \"status\", \"input\": \"0\"}\n\
+                  {\"command\": \"reboot\"}\n",
+            )
+            .unwrap();
+        let answers: Vec<ControlResponse> = BufReader::new(stream)
+            .lines()
+            .take(5)
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        assert_eq!(answers[0].result["phase"], "ready");
+        assert_eq!(
+            answers[0].result["stats"]["inputs"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            answers[1],
+            ControlResponse {
+                ok: true,
+                result: Value::Null,
+                error: None
+            }
+        );
+        assert_eq!(controller.snapshot().unwrap().inputs[0].priority, 5);
+        assert_eq!(answers[2].result, Value::Array(vec![]));
+        // Fields it doesn't know are left out.
+        assert!(answers[3].ok);
+        assert!(!answers[4].ok);
+        assert!(answers[4]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("not a request: unknown variant `reboot`"));
+
+        controller.shutdown().unwrap();
+        running.join().unwrap().unwrap();
+        // Nothing listens once the overlay is gone.
+        assert!(!socket.exists());
+        assert!(ControlRequest::Status.send(&socket).is_err());
+    }
+
+    #[test]
+    fn only_a_socket_nothing_answers_is_taken_over() {
+        let dir = crate::tests::scratch("control-bind");
+        let socket = dir.join("control.sock");
+        // Left behind by an overlay that didn't stop cleanly.
+        drop(UnixListener::bind(&socket).unwrap());
+        let listener = endpoint::bind(&socket).unwrap();
+        let error = endpoint::bind(&socket).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        let file = dir.join("notes.txt");
+        fs::write(&file, "mine").unwrap();
+        let error = endpoint::bind(&file).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        endpoint::remove(&file);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "mine");
+    }
+}
@@ -0,0 +1,178 @@
+use failure::Error;
+use overlay::Controller;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The pid of the running overlay, written to a file for as long as this is alive.
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub(crate) fn create(path: &Path) -> Result<Self, Error> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Stops the overlay behind `controller` when the service manager or the user asks, and
+/// calls `reopen` when asked to reopen the log file.
+#[cfg(unix)]
+pub(crate) fn handle_signals<F>(controller: Controller, reopen: F) -> Result<(), Error>
+where
+    F: Fn() + Send + 'static,
+{
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::thread;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                reopen();
+            } else {
+                notify("STOPPING=1");
+                let _ = controller.shutdown();
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stops the overlay behind `controller` on Ctrl+C or when its console is closed, which is
+/// how service wrappers stop it. There is nothing asking to reopen the log file.
+#[cfg(windows)]
+pub(crate) fn handle_signals<F>(controller: Controller, _reopen: F) -> Result<(), Error>
+where
+    F: Fn() + Send + 'static,
+{
+    ctrlc::set_handler(move || {
+        let _ = controller.shutdown();
+    })?;
+    Ok(())
+}
+
+/// Tells systemd about the state of the service, if it is running under it with
+/// `Type=notify`.
+#[cfg(unix)]
+pub(crate) fn notify(state: &str) {
+    use std::env;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    let sender = match UnixDatagram::unbound() {
+        Ok(sender) => sender,
+        Err(_) => return,
+    };
+
+    let socket = PathBuf::from(socket);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::SocketAddr;
+
+        // Abstract sockets are given with a leading `@`.
+        let name = socket.as_os_str().as_bytes();
+        if let Some(name) = name.strip_prefix(b"@") {
+            if let Ok(address) = SocketAddr::from_abstract_name(name) {
+                let _ = sender.send_to_addr(state.as_bytes(), &address);
+            }
+            return;
+        }
+    }
+    let _ = sender.send_to(state.as_bytes(), &socket);
+}
+
+#[cfg(windows)]
+pub(crate) fn notify(_state: &str) {}
+
+/// The end of a detached overlay that tells the process that started it how it went.
+pub(crate) struct Detached {
+    #[cfg(unix)]
+    ready: Option<fs::File>,
+}
+
+impl Detached {
+    /// Lets the process that started this one exit with `code`, 0 once it is up. Only
+    /// the first call does anything.
+    pub(crate) fn exit(&mut self, code: i32) {
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+
+            if let Some(mut pipe) = self.ready.take() {
+                let _ = pipe.write_all(&[code as u8]);
+            }
+        }
+        #[cfg(windows)]
+        let _ = code;
+    }
+}
+
+/// Moves the overlay into the background, detached from the terminal.
+///
+/// The process that was started waits until the detached one calls `Detached::exit`, and
+/// exits with the code it is given, or as if the detached one failed fatally if it stopped
+/// without saying. This has to happen before any threads are started.
+#[cfg(unix)]
+pub(crate) fn detach() -> Result<Detached, Error> {
+    use std::io::{self, Read};
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (mut waiting, ready) =
+        unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            drop(waiting);
+            unsafe {
+                libc::setsid();
+                let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+                if null >= 0 {
+                    libc::dup2(null, 0);
+                    libc::dup2(null, 1);
+                    libc::dup2(null, 2);
+                    if null > 2 {
+                        libc::close(null);
+                    }
+                }
+            }
+            Ok(Detached { ready: Some(ready) })
+        }
+        _ => {
+            drop(ready);
+            let mut byte = [0];
+            let code = match waiting.read(&mut byte) {
+                Ok(1) => i32::from(byte[0]),
+                _ => 3,
+            };
+            process::exit(code);
+        }
+    }
+}
+
+/// There is nothing to detach from on Windows, where a service wrapper starts the overlay
+/// without a console to write to.
+#[cfg(windows)]
+pub(crate) fn detach() -> Result<Detached, Error> {
+    Ok(Detached {})
+}
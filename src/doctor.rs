@@ -0,0 +1,771 @@
+use crate::identity::FileIdentity;
+use crate::lock::{self, LockState};
+use crate::probe::{self, LinkProbe};
+use crate::{
+    canonical, ledger, space, CaseConflictPolicy, Config, InputConfig, OutputCase, RealFs,
+};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// How a check of `Config::doctor` came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// The overlay starts, but likely not as hoped.
+    Warn,
+    /// The overlay doesn't start, or fails soon after.
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+/// What one check of `Config::doctor` found, and what to do about it unless it passed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    /// What was checked, like `inputs` or `hard links`.
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: String) -> Self {
+        Check {
+            name,
+            status: CheckStatus::Pass,
+            message,
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: String, hint: &str) -> Self {
+        Check {
+            name,
+            status: CheckStatus::Warn,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(name: &'static str, message: String, hint: &str) -> Self {
+        Check {
+            name,
+            status: CheckStatus::Fail,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    /// A warning if `warn`, a failure otherwise.
+    fn fail_unless(warn: bool, name: &'static str, message: String, hint: &str) -> Self {
+        if warn {
+            Check::warn(name, message, hint)
+        } else {
+            Check::fail(name, message, hint)
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.name, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n     {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Checks whether the overlay of this configuration can start and run as it should,
+    /// without changing anything: the inputs and the output, whether hard links work from
+    /// each input into the output, the limits on watching the inputs, inputs overlapping,
+    /// how the output treats case, the free space for the inputs that are copied, and what
+    /// a run that crashed left behind.
+    ///
+    /// Each check may be run by itself too.
+    pub fn doctor(&self) -> Vec<Check> {
+        let mut checks = self.check_inputs();
+        checks.push(self.check_output());
+        checks.extend(self.check_links());
+        checks.push(self.check_watches());
+        checks.extend(self.check_overlaps());
+        checks.push(self.check_case());
+        checks.push(self.check_free_space());
+        checks.extend(self.check_leftovers());
+        checks
+    }
+
+    /// Whether every enabled input is a directory that can be read.
+    pub fn check_inputs(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        for input in self.enabled_inputs() {
+            let path = directory(input);
+            let unreadable = |reason: String| {
+                Check::fail(
+                    "inputs",
+                    format!("{} can't be read: {}", path.display(), reason),
+                    "check that the input is mounted and that this user may read it",
+                )
+            };
+            let check = match fs::metadata(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Check::fail(
+                    "inputs",
+                    format!("{} doesn't exist", path.display()),
+                    "fix its path, or disable it with `enabled = false`",
+                ),
+                Err(e) => unreadable(e.to_string()),
+                Ok(metadata) if !metadata.is_dir() => Check::fail(
+                    "inputs",
+                    format!("{} isn't a directory", path.display()),
+                    "inputs are directories, give the one holding it",
+                ),
+                Ok(_) => match fs::read_dir(&path) {
+                    Ok(_) => Check::pass("inputs", format!("{} can be read", path.display())),
+                    Err(e) => unreadable(e.to_string()),
+                },
+            };
+            checks.push(check);
+        }
+        if checks.is_empty() {
+            checks.push(Check::warn(
+                "inputs",
+                "there is no enabled input".to_string(),
+                "add one with `[[inputs]]`",
+            ));
+        }
+        checks
+    }
+
+    /// Whether the overlay may create files in the output, or create it if it doesn't exist.
+    pub fn check_output(&self) -> Check {
+        let hint = "give this user write access to it, or choose another output";
+        let output = &self.output;
+        if output.exists() {
+            if !output.is_dir() {
+                return Check::fail(
+                    "output",
+                    format!("{} isn't a directory", output.display()),
+                    "the output is a directory, move what is there or choose another",
+                );
+            }
+            return match probe::writable(output) {
+                Ok(true) => {
+                    Check::pass("output", format!("{} can be written to", output.display()))
+                }
+                Ok(false) => Check::fail(
+                    "output",
+                    format!("{} can't be written to", output.display()),
+                    hint,
+                ),
+                Err(e) => Check::fail(
+                    "output",
+                    format!(
+                        "couldn't tell whether {} can be written to: {}",
+                        output.display(),
+                        e
+                    ),
+                    hint,
+                ),
+            };
+        }
+
+        let parent = match existing(output) {
+            Some(parent) => parent,
+            None => {
+                return Check::fail(
+                    "output",
+                    format!("nothing of {} exists", output.display()),
+                    "check the path of the output",
+                )
+            }
+        };
+        match probe::writable(parent) {
+            Ok(true) => Check::pass(
+                "output",
+                format!("{} doesn't exist yet, and can be created", output.display()),
+            ),
+            _ => Check::fail(
+                "output",
+                format!(
+                    "{} doesn't exist, and can't be created in {}",
+                    output.display(),
+                    parent.display()
+                ),
+                hint,
+            ),
+        }
+    }
+
+    /// Whether the files of each enabled input can be hard linked into the output, and
+    /// what happens to them if not. Linking is only tried if the output exists, leaving
+    /// nothing behind, and otherwise told from the volumes they are on.
+    pub fn check_links(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        for input in self.enabled_inputs() {
+            let path = directory(input);
+            if !path.is_dir() {
+                continue;
+            }
+            let result = self.link_probe(&path);
+            let message = format!(
+                "{} into {}: {}",
+                path.display(),
+                self.output.display(),
+                result
+            );
+            let check = match &result {
+                result if result.can_link() => Check::pass("hard links", message),
+                LinkProbe::ReadOnly(_) => {
+                    Check::pass("hard links", format!("{}, it is copied", message))
+                }
+                // Nothing to be done about the output's filesystem but choosing another.
+                LinkProbe::Unsupported(_) if self.copy_fallback => {
+                    Check::pass("hard links", format!("{}, it is copied", message))
+                }
+                LinkProbe::CrossDevice(_) if self.copy_fallback => Check::warn(
+                    "hard links",
+                    format!("{}, it is copied", message),
+                    "put the output on the volume of the input to link its files rather than \
+                     copy them",
+                ),
+                LinkProbe::CrossDevice(_) => Check::fail(
+                    "hard links",
+                    message,
+                    "put the output on the volume of the input, or set `copy_fallback = true` \
+                     to copy its files",
+                ),
+                _ if self.copy_fallback => Check::warn(
+                    "hard links",
+                    format!("{}, it is copied", message),
+                    "copying takes more time and space than linking",
+                ),
+                _ => Check::fail(
+                    "hard links",
+                    message,
+                    "set `copy_fallback = true` to copy its files instead",
+                ),
+            };
+            checks.push(check);
+        }
+        checks
+    }
+
+    /// Whether the inputs can all be watched, as far as the limits of Linux on watches go,
+    /// going by how many directories there are in those that aren't polled.
+    pub fn check_watches(&self) -> Check {
+        let watched: Vec<PathBuf> = self
+            .enabled_inputs()
+            .filter(|input| input.poll_interval_ms.is_none() && self.poll_interval_ms.is_none())
+            .map(directory)
+            .filter(|path| path.is_dir())
+            .collect();
+        let needed: usize = watched
+            .iter()
+            .map(|path| {
+                WalkDir::new(path)
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.file_type().is_dir())
+                    .count()
+            })
+            .sum();
+        watch_limits(watched.len(), needed)
+    }
+
+    /// Whether enabled inputs overlap each other or what the overlay writes to.
+    pub fn check_overlaps(&self) -> Vec<Check> {
+        let hint = "overlapping inputs have their files linked more than once, and an input \
+                    in the output links the output into itself; choose directories apart";
+        let fail = |message| {
+            // Nothing stops the overlay from starting then.
+            Check::fail_unless(!self.check_overlaps, "overlaps", message, hint)
+        };
+
+        let roots: Vec<(PathBuf, PathBuf)> = self
+            .enabled_inputs()
+            .map(|input| {
+                let path = directory(input);
+                (canonical(&path), path)
+            })
+            .collect();
+        let mut checks = vec![];
+        for (index, (root, path)) in roots.iter().enumerate() {
+            let overlaps = |other: &Path| root.starts_with(other) || other.starts_with(root);
+            for (other, other_path) in &roots[index + 1..] {
+                if overlaps(other) && !(root == other && self.merge_duplicate_inputs) {
+                    checks.push(fail(format!(
+                        "{} overlaps {}",
+                        path.display(),
+                        other_path.display()
+                    )));
+                }
+            }
+            let written = [
+                ("the output", self.output.clone()),
+                ("the ledger", ledger::ledger_path(&self.output)),
+                ("the lock file", lock::lock_path(&self.output)),
+            ];
+            for (what, other) in written {
+                if overlaps(&canonical(&other)) {
+                    checks.push(fail(format!(
+                        "{} overlaps {}, {}",
+                        path.display(),
+                        what,
+                        other.display()
+                    )));
+                }
+            }
+        }
+        if checks.is_empty() {
+            checks.push(Check::pass(
+                "overlaps",
+                "no input overlaps another or the output".to_string(),
+            ));
+        }
+        checks
+    }
+
+    /// Whether the output's volume tells names apart that only differ by case, and whether
+    /// that is taken care of if not.
+    pub fn check_case(&self) -> Check {
+        match case_insensitive(&self.output) {
+            None => Check::warn(
+                "case",
+                format!(
+                    "couldn't tell whether {} is case-sensitive",
+                    self.output.display()
+                ),
+                "run it again once the output has files in it",
+            ),
+            Some(false) => Check::pass(
+                "case",
+                format!("{} is case-sensitive", self.output.display()),
+            ),
+            Some(true)
+                if self.case_conflicts == CaseConflictPolicy::Warn
+                    && self.output_case == OutputCase::Preserve =>
+            {
+                Check::warn(
+                    "case",
+                    format!(
+                        "{} is case-insensitive, so files of inputs that only differ by case \
+                         take each other's place",
+                        self.output.display()
+                    ),
+                    "set `case_conflicts = \"priority\"` to have the higher priority win, or \
+                     `output_case = \"lower\"`",
+                )
+            }
+            Some(true) => Check::pass(
+                "case",
+                format!(
+                    "{} is case-insensitive, which the configuration takes care of",
+                    self.output.display()
+                ),
+            ),
+        }
+    }
+
+    /// Whether the files of the enabled inputs that are copied rather than linked fit into
+    /// the output, counting every file of them as if it were copied.
+    pub fn check_free_space(&self) -> Check {
+        let copied: Vec<PathBuf> = self
+            .enabled_inputs()
+            .map(directory)
+            .filter(|path| path.is_dir())
+            .filter(|path| match self.link_probe(path) {
+                LinkProbe::ReadOnly(_) => true,
+                result => !result.can_link() && self.copy_fallback,
+            })
+            .collect();
+        if copied.is_empty() {
+            return Check::pass("free space", "no input is copied".to_string());
+        }
+
+        let needed: u64 = copied
+            .iter()
+            .flat_map(|path| WalkDir::new(path).into_iter().filter_map(Result::ok))
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        let available = match space::available(&self.output) {
+            Ok(available) => available,
+            Err(e) => {
+                return Check::warn(
+                    "free space",
+                    format!(
+                        "couldn't tell the free space of {}: {}",
+                        self.output.display(),
+                        e
+                    ),
+                    "check that the output's volume is mounted",
+                )
+            }
+        };
+        let message = format!(
+            "copying {} input(s) into {} takes up to {}, and {} are free",
+            copied.len(),
+            self.output.display(),
+            space::format(needed),
+            space::format(available)
+        );
+        if needed <= available {
+            return Check::pass("free space", message);
+        }
+        Check::fail_unless(
+            self.ignore_free_space,
+            "free space",
+            message,
+            "free up space, or put the output on the volume of the inputs so they're linked",
+        )
+    }
+
+    /// Whether a run that crashed left behind its lock, a ledger it didn't finish writing,
+    /// or files it was putting into the output.
+    pub fn check_leftovers(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        match lock::inspect(&self.output) {
+            Ok(LockState::Free) => {}
+            Ok(LockState::Held(holder)) => checks.push(Check::fail_unless(
+                !self.single_instance,
+                "leftovers",
+                format!("{} is being overlaid by {}", self.output.display(), holder),
+                "stop it first, or give this configuration another output",
+            )),
+            Ok(LockState::Left(holder)) => checks.push(Check::warn(
+                "leftovers",
+                format!(
+                    "{} was left behind by {}, which didn't stop cleanly",
+                    lock::lock_path(&self.output).display(),
+                    holder
+                ),
+                "it is taken over on the next start, but the output may be out of date until \
+                 then",
+            )),
+            Err(e) => checks.push(Check::warn(
+                "leftovers",
+                format!(
+                    "couldn't read {}: {}",
+                    lock::lock_path(&self.output).display(),
+                    e
+                ),
+                "check that this user may read it",
+            )),
+        }
+
+        let half_written = crate::beside(&ledger::ledger_path(&self.output), ".new");
+        if half_written.exists() {
+            checks.push(Check::warn(
+                "leftovers",
+                format!("{} was left half written", half_written.display()),
+                "it can be deleted, the ledger beside it is the one read",
+            ));
+        }
+
+        let unfinished: Vec<PathBuf> = WalkDir::new(&self.output)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| unfinished(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.into_path())
+            .collect();
+        if let Some(first) = unfinished.first() {
+            checks.push(Check::warn(
+                "leftovers",
+                format!(
+                    "{} file(s) the overlay didn't finish putting there are in the output, \
+                     like {}",
+                    unfinished.len(),
+                    first.display()
+                ),
+                "they can be deleted",
+            ));
+        }
+
+        if checks.is_empty() {
+            checks.push(Check::pass(
+                "leftovers",
+                "nothing was left behind by an earlier run".to_string(),
+            ));
+        }
+        checks
+    }
+
+    fn enabled_inputs(&self) -> impl Iterator<Item = &InputConfig> {
+        self.inputs.iter().filter(|input| input.enabled)
+    }
+
+    /// What trying to link from the input directory `path` into the output finds, trying
+    /// only if the output exists.
+    fn link_probe(&self, path: &Path) -> LinkProbe {
+        if probe::read_only(path).unwrap_or(false) {
+            return LinkProbe::ReadOnly(format!("{} is mounted read-only", path.display()));
+        }
+        if let Some(filesystem) = probe::without_hard_links(&self.output) {
+            return LinkProbe::Unsupported(format!(
+                "{} is on {}, which has none",
+                self.output.display(),
+                filesystem
+            ));
+        }
+        if self.output.is_dir() {
+            return probe::probe(&RealFs, path, &self.output);
+        }
+
+        let volume = |path: &Path| FileIdentity::of(path).ok().map(|identity| identity.volume);
+        match existing(&self.output).and_then(volume) {
+            Some(output) if volume(path) != Some(output) => {
+                LinkProbe::CrossDevice(format!("{} doesn't exist yet", self.output.display()))
+            }
+            _ => LinkProbe::Untested(format!("{} doesn't exist yet", self.output.display())),
+        }
+    }
+}
+
+/// The directory of `input` whose files are overlaid.
+fn directory(input: &InputConfig) -> PathBuf {
+    match &input.source {
+        Some(source) => input.path.join(source),
+        None => input.path.clone(),
+    }
+}
+
+/// The closest of `path` and its parents that exists.
+fn existing(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|ancestor| ancestor.exists())
+}
+
+/// Whether `name` is of a file the overlay or its probe left unfinished in the output.
+fn unfinished(name: &str) -> bool {
+    name.ends_with(".overlay-new")
+        || name.ends_with(".overlay-merge")
+        || name.starts_with(".overlay-probe-")
+}
+
+/// Whether names on the volume of `path`, or the closest of its parents that exists, are
+/// case-insensitive, found out by looking up a name there spelled otherwise. `None` if
+/// there is no name to try.
+fn case_insensitive(path: &Path) -> Option<bool> {
+    let existing = existing(path)?;
+    let children = fs::read_dir(existing)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path());
+    for candidate in children.chain(Some(existing.to_path_buf())) {
+        let name = match candidate.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let swapped: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_lowercase() {
+                    c.to_ascii_uppercase()
+                } else {
+                    c.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        if swapped == name {
+            continue;
+        }
+        let identity = match FileIdentity::of(&candidate) {
+            Ok(identity) => identity,
+            Err(_) => continue,
+        };
+        return Some(match FileIdentity::of(&candidate.with_file_name(swapped)) {
+            Ok(other) => other.number().is_some() && other.number() == identity.number(),
+            Err(_) => false,
+        });
+    }
+    None
+}
+
+/// How `needed` watches for `inputs` inputs compare with the limits of inotify.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn watch_limits(inputs: usize, needed: usize) -> Check {
+    let limit = |name: &str| {
+        fs::read_to_string(Path::new("/proc/sys/fs/inotify").join(name))
+            .ok()
+            .and_then(|limit| limit.trim().parse::<usize>().ok())
+    };
+    let (watches, instances) = match (limit("max_user_watches"), limit("max_user_instances")) {
+        (Some(watches), Some(instances)) => (watches, instances),
+        _ => {
+            return Check::warn(
+                "watches",
+                "couldn't read the limits of inotify".to_string(),
+                "check /proc/sys/fs/inotify, or poll the inputs with `poll_interval_ms`",
+            )
+        }
+    };
+
+    let message = format!(
+        "watching {} input(s) takes about {} of the {} watches allowed",
+        inputs, needed, watches
+    );
+    let hint = format!(
+        "raise the limit, e.g. with `sysctl fs.inotify.max_user_watches={}`, or poll the \
+         largest inputs with `poll_interval_ms`",
+        (needed * 2).max(watches)
+    );
+    if needed > watches {
+        Check::fail("watches", message, &hint)
+    } else if needed > watches / 2 {
+        // Other programs of the same user watch too.
+        Check::warn("watches", message, &hint)
+    } else if inputs > instances {
+        Check::warn(
+            "watches",
+            format!(
+                "watching {} inputs takes more than the {} inotify instances allowed",
+                inputs, instances
+            ),
+            "raise it with `sysctl fs.inotify.max_user_instances`, or poll some inputs with \
+             `poll_interval_ms`",
+        )
+    } else {
+        Check::pass("watches", message)
+    }
+}
+
+/// How many watches the inputs take, which nothing limits on other platforms.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn watch_limits(inputs: usize, needed: usize) -> Check {
+    Check::pass(
+        "watches",
+        format!(
+            "watching {} input(s) takes {} directories, which is not limited here",
+            inputs, needed
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    /// The configuration of `inputs`, in their order of priority, overlaid into `output`.
+    fn configured(output: &Path, inputs: &[&Path]) -> Config {
+        let mut text = format!("output = '{}'\n", output.display());
+        for (priority, input) in inputs.iter().enumerate() {
+            text += &format!(
+                "[[inputs]]\npath = '{}'\npriority = {}\n",
+                input.display(),
+                priority
+            );
+        }
+        Config::parse(&text).unwrap()
+    }
+
+    fn statuses(checks: &[Check]) -> Vec<(&'static str, CheckStatus)> {
+        checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect()
+    }
+
+    #[test]
+    fn a_sound_configuration_passes_every_check() {
+        let root = scratch("doctor-sound");
+        let (base, output) = (root.join("base"), root.join("output"));
+        fs::create_dir_all(base.join("Data")).unwrap();
+        fs::write(base.join("Data/x"), "x").unwrap();
+        fs::create_dir_all(&output).unwrap();
+
+        let checks = configured(&output, &[&base]).doctor();
+        assert_eq!(
+            statuses(&checks),
+            [
+                ("inputs", CheckStatus::Pass),
+                ("output", CheckStatus::Pass),
+                ("hard links", CheckStatus::Pass),
+                ("watches", CheckStatus::Pass),
+                ("overlaps", CheckStatus::Pass),
+                ("case", CheckStatus::Pass),
+                ("free space", CheckStatus::Pass),
+                ("leftovers", CheckStatus::Pass),
+            ],
+            "{:#?}",
+            checks
+        );
+        assert!(checks.iter().all(|check| check.hint.is_none()));
+        // Nothing was left behind by checking.
+        assert_eq!(fs::read_dir(&output).unwrap().count(), 0);
+        assert_eq!(
+            checks[2].to_string(),
+            format!(
+                "PASS hard links: {} into {}: hard links work",
+                base.display(),
+                output.display()
+            )
+        );
+    }
+
+    #[test]
+    fn what_keeps_an_overlay_from_running_is_told_with_what_to_do_about_it() {
+        let root = scratch("doctor-unsound");
+        let (base, output) = (root.join("base"), root.join("output"));
+        let (nested, missing, file) =
+            (base.join("nested"), root.join("missing"), root.join("file"));
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(&file, "").unwrap();
+        fs::create_dir_all(&output).unwrap();
+        // As a run that crashed halfway leaves them.
+        fs::write(lock::lock_path(&output), "pid 1 on elsewhere").unwrap();
+        fs::write(crate::beside(&ledger::ledger_path(&output), ".new"), "").unwrap();
+        fs::write(output.join("x.overlay-new"), "").unwrap();
+
+        let config = configured(&output, &[&base, &nested, &missing, &file]);
+        let failed: Vec<String> = config
+            .check_inputs()
+            .into_iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .map(|check| check.message)
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                format!("{} doesn't exist", missing.display()),
+                format!("{} isn't a directory", file.display()),
+            ]
+        );
+
+        let overlaps = config.check_overlaps();
+        assert_eq!(statuses(&overlaps), [("overlaps", CheckStatus::Fail)]);
+        assert_eq!(
+            overlaps[0].message,
+            format!("{} overlaps {}", base.display(), nested.display())
+        );
+        assert!(overlaps[0].hint.is_some());
+
+        let leftovers = config.check_leftovers();
+        assert_eq!(
+            statuses(&leftovers),
+            [("leftovers", CheckStatus::Warn); 3],
+            "{:#?}",
+            leftovers
+        );
+        assert!(leftovers[0].message.contains("pid 1 on elsewhere"));
+        assert!(leftovers[2].message.starts_with("1 file(s)"));
+        assert!(leftovers.iter().all(|check| check.hint.is_some()));
+    }
+}
@@ -0,0 +1,590 @@
+use crate::input::Rank;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::path::{Path, PathBuf};
+
+/// An input with a file at a path, as the heap of the path's providers holds it. It is
+/// looked up in the inputs for anything but its rank.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ranked {
+    pub(crate) index: usize,
+    pub(crate) rank: Rank,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank.cmp(&other.rank)
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank == other.rank
+    }
+}
+
+impl Eq for Ranked {}
+
+/// A step of what a change in an input means for the output, planned from the providers
+/// of the paths alone. The overlay carries the steps out in order, so a change is planned
+/// the same whether it syncs, resyncs, watches or does a dry run, and `Overlay::preview`
+/// follows the plans without carrying them out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Makes directory `path` of the output, and those above it.
+    CreateDir { path: PathBuf },
+    /// Puts the file of input `from` at `to` in the output, moving aside whatever of lower
+    /// priority is in the way there.
+    Link { from: usize, to: PathBuf },
+    /// Makes the file of input `from` at `to` in the output again, unless the output has
+    /// it already.
+    Relink { from: usize, to: PathBuf },
+    /// Takes the file of input `index` at `path` out of the output.
+    Remove { path: PathBuf, index: usize },
+    /// Removes directory `path` of the output, and those above it, if they are empty.
+    RemoveEmptyDirs { path: PathBuf },
+    /// Changes what the overlay tracks at `path`.
+    UpdateMap { path: PathBuf, update: MapUpdate },
+    /// Counts the file of input `index` at `path` differently in the stats.
+    Count {
+        index: usize,
+        path: PathBuf,
+        count: Count,
+    },
+}
+
+/// A change to what the overlay tracks at a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MapUpdate {
+    /// The input provides the path, at its rank.
+    Track(Ranked),
+    /// The input doesn't provide the path anymore.
+    Untrack(usize),
+    /// The winner of the path isn't kept out of the output anymore.
+    Unblock,
+    /// Nothing is at the path anymore, so what it kept out of the output can go there.
+    Release,
+}
+
+/// How the stats count a file from now on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Count {
+    /// It isn't in the output anymore.
+    Hidden,
+    /// It is behind another input's file.
+    Shadowed,
+    /// It isn't behind another input's file anymore.
+    Unshadowed,
+}
+
+/// What a change means, and the actions that carry it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Plan<T> {
+    pub(crate) outcome: T,
+    pub(crate) actions: Vec<Action>,
+}
+
+/// What a file that appeared in an input means for the path it goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Appeared {
+    /// It is the winner's, which was recreated, so the output has to follow it.
+    Recreated,
+    /// The input provides the path already, behind the winner.
+    Known,
+    /// It goes behind the winner's file.
+    Shadowed,
+    /// It wins the path, over `previous` if that was in the output until now. A winner
+    /// that was blocked simply becomes an ordinary shadowed provider.
+    Wins { previous: Option<usize> },
+}
+
+/// What the file of `input` that appeared at a path means, with `heap` the providers of
+/// the path so far and `blocked` whether their winner is kept out of the output.
+fn appeared(heap: Option<&BinaryHeap<Ranked>>, blocked: bool, input: Ranked) -> Appeared {
+    let (heap, winner) = match heap.and_then(|heap| heap.peek().map(|winner| (heap, winner))) {
+        Some(found) => found,
+        None => return Appeared::Wins { previous: None },
+    };
+
+    if winner.index == input.index {
+        Appeared::Recreated
+    } else if heap.iter().any(|other| other.index == input.index) {
+        Appeared::Known
+    } else if input.rank <= winner.rank {
+        Appeared::Shadowed
+    } else if blocked {
+        Appeared::Wins { previous: None }
+    } else {
+        Appeared::Wins {
+            previous: Some(winner.index),
+        }
+    }
+}
+
+/// What the file of an input that went away meant for the path it went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Vanished {
+    /// It was the winner's, with the next provider taking over if `next`.
+    Winner { next: bool },
+    /// It was behind the winner's file.
+    Shadowed,
+    /// The input didn't provide the path.
+    Untracked,
+}
+
+/// What the file of input `index` that went away from a path meant, with `heap` the
+/// providers of the path.
+fn vanished(heap: Option<&BinaryHeap<Ranked>>, index: usize) -> Vanished {
+    let heap = match heap {
+        Some(heap) => heap,
+        None => return Vanished::Untracked,
+    };
+
+    match heap.peek() {
+        Some(winner) if winner.index == index => Vanished::Winner {
+            next: heap.len() > 1,
+        },
+        _ if heap.iter().any(|other| other.index == index) => Vanished::Shadowed,
+        _ => Vanished::Untracked,
+    }
+}
+
+/// Plans what the file of `input` that appeared at `path` means, with `heap` the providers
+/// of the path so far and `blocked` whether their winner is kept out of the output.
+pub(crate) fn plan_appeared(
+    path: &Path,
+    heap: Option<&BinaryHeap<Ranked>>,
+    blocked: bool,
+    input: Ranked,
+) -> Plan<Appeared> {
+    let outcome = appeared(heap, blocked, input);
+    let update = |update| Action::UpdateMap {
+        path: path.to_path_buf(),
+        update,
+    };
+    let count = |index, count| Action::Count {
+        index,
+        path: path.to_path_buf(),
+        count,
+    };
+
+    let mut actions = vec![];
+    match outcome {
+        // The winning file was recreated, so the output has to follow it.
+        Appeared::Recreated if !blocked => actions.push(Action::Relink {
+            from: input.index,
+            to: path.to_path_buf(),
+        }),
+        Appeared::Recreated | Appeared::Known => {}
+        Appeared::Shadowed => {
+            actions.push(update(MapUpdate::Track(input)));
+            actions.push(count(input.index, Count::Shadowed));
+        }
+        Appeared::Wins { previous } => {
+            if blocked {
+                actions.push(update(MapUpdate::Unblock));
+            }
+            actions.push(update(MapUpdate::Track(input)));
+            if let Some(previous) = previous {
+                actions.push(count(previous, Count::Hidden));
+                actions.push(count(previous, Count::Shadowed));
+            }
+            actions.push(Action::Link {
+                from: input.index,
+                to: path.to_path_buf(),
+            });
+        }
+    }
+    Plan { outcome, actions }
+}
+
+/// Plans what the file of input `index` that went away from `path` means, with `heap` the
+/// providers of the path and `blocked` whether their winner is kept out of the output.
+pub(crate) fn plan_vanished(
+    path: &Path,
+    heap: Option<&BinaryHeap<Ranked>>,
+    blocked: bool,
+    index: usize,
+) -> Plan<Vanished> {
+    let outcome = vanished(heap, index);
+    let update = |update| Action::UpdateMap {
+        path: path.to_path_buf(),
+        update,
+    };
+    let count = |index, count| Action::Count {
+        index,
+        path: path.to_path_buf(),
+        count,
+    };
+
+    let mut actions = vec![];
+    match outcome {
+        Vanished::Winner { next } => {
+            actions.push(update(MapUpdate::Untrack(index)));
+            if blocked {
+                // Nothing of this input's was ever in the output.
+                actions.push(count(index, Count::Unshadowed));
+                if !next {
+                    actions.push(update(MapUpdate::Unblock));
+                }
+                return Plan { outcome, actions };
+            }
+
+            actions.push(Action::Remove {
+                path: path.to_path_buf(),
+                index,
+            });
+            actions.push(count(index, Count::Hidden));
+            let mut rest = heap.cloned().unwrap_or_default();
+            update_heap(&mut rest, MapUpdate::Untrack(index));
+            match rest.peek() {
+                Some(next) => {
+                    actions.push(count(next.index, Count::Unshadowed));
+                    actions.push(Action::Link {
+                        from: next.index,
+                        to: path.to_path_buf(),
+                    });
+                }
+                None => {
+                    if let Some(parent) = path.parent() {
+                        actions.push(Action::RemoveEmptyDirs {
+                            path: parent.to_path_buf(),
+                        });
+                    }
+                    actions.push(update(MapUpdate::Release));
+                }
+            }
+        }
+        Vanished::Shadowed => {
+            actions.push(update(MapUpdate::Untrack(index)));
+            actions.push(count(index, Count::Unshadowed));
+        }
+        Vanished::Untracked => {}
+    }
+    Plan { outcome, actions }
+}
+
+/// Plans moving the paths in `provided`, at or beneath `from`, to `to`: the paths each
+/// goes to, and the directories they need in the output.
+pub(crate) fn plan_renamed(
+    from: &Path,
+    to: &Path,
+    provided: Vec<PathBuf>,
+) -> Plan<Vec<(PathBuf, PathBuf)>> {
+    let renames: Vec<(PathBuf, PathBuf)> = provided
+        .into_iter()
+        .map(|key| {
+            let rest = key.strip_prefix(from).unwrap();
+            let renamed = if rest.as_os_str().is_empty() {
+                to.to_path_buf()
+            } else {
+                to.join(rest)
+            };
+            (key, renamed)
+        })
+        .collect();
+    let directories: BTreeSet<&Path> = renames
+        .iter()
+        .filter_map(|(_, renamed)| renamed.parent())
+        .collect();
+    let actions = directories
+        .into_iter()
+        .map(|path| Action::CreateDir {
+            path: path.to_path_buf(),
+        })
+        .collect();
+    Plan {
+        outcome: renames,
+        actions,
+    }
+}
+
+/// Makes the change of `update` to `heap`, the providers of its path. Only tracking and
+/// untracking change them.
+pub(crate) fn update_heap(heap: &mut BinaryHeap<Ranked>, update: MapUpdate) {
+    match update {
+        MapUpdate::Track(input) => heap.push(input),
+        MapUpdate::Untrack(index) => heap.retain(|other| other.index != index),
+        MapUpdate::Unblock | MapUpdate::Release => {}
+    }
+}
+
+/// The providers of `heap` again, with the ranks the inputs have by `rank`.
+pub(crate) fn reranked<F: Fn(usize) -> Rank>(
+    heap: &BinaryHeap<Ranked>,
+    rank: F,
+) -> BinaryHeap<Ranked> {
+    heap.iter()
+        .map(|input| Ranked {
+            index: input.index,
+            rank: rank(input.index),
+        })
+        .collect()
+}
+
+/// The providers of `heap`, the winner first and then by rank.
+pub(crate) fn by_precedence(heap: &BinaryHeap<Ranked>) -> Vec<Ranked> {
+    let winner = heap.peek().map(|input| input.index);
+    let mut inputs: Vec<Ranked> = heap.iter().copied().collect();
+    inputs.sort_by(|a, b| {
+        (Some(b.index) == winner)
+            .cmp(&(Some(a.index) == winner))
+            .then(b.rank.cmp(&a.rank))
+    });
+    inputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(index: usize, priority: u32) -> Ranked {
+        Ranked {
+            index,
+            rank: Rank { group: 0, priority },
+        }
+    }
+
+    fn heap(inputs: &[Ranked]) -> BinaryHeap<Ranked> {
+        inputs.iter().copied().collect()
+    }
+
+    fn path() -> PathBuf {
+        PathBuf::from("a/x")
+    }
+
+    fn update(update: MapUpdate) -> Action {
+        Action::UpdateMap {
+            path: path(),
+            update,
+        }
+    }
+
+    fn count(index: usize, count: Count) -> Action {
+        Action::Count {
+            index,
+            path: path(),
+            count,
+        }
+    }
+
+    #[test]
+    fn a_file_on_a_new_path_is_linked() {
+        let plan = plan_appeared(&path(), None, false, ranked(0, 1));
+        assert_eq!(plan.outcome, Appeared::Wins { previous: None });
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Track(ranked(0, 1))),
+                Action::Link {
+                    from: 0,
+                    to: path()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_file_of_higher_priority_moves_the_winner_behind_it() {
+        let providers = heap(&[ranked(0, 1)]);
+        let plan = plan_appeared(&path(), Some(&providers), false, ranked(1, 2));
+        assert_eq!(plan.outcome, Appeared::Wins { previous: Some(0) });
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Track(ranked(1, 2))),
+                count(0, Count::Hidden),
+                count(0, Count::Shadowed),
+                Action::Link {
+                    from: 1,
+                    to: path()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_file_of_lower_priority_goes_behind_the_winner() {
+        let providers = heap(&[ranked(0, 2)]);
+        let plan = plan_appeared(&path(), Some(&providers), false, ranked(1, 1));
+        assert_eq!(plan.outcome, Appeared::Shadowed);
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Track(ranked(1, 1))),
+                count(1, Count::Shadowed),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_file_that_wins_a_blocked_path_unblocks_it() {
+        let providers = heap(&[ranked(0, 1)]);
+        let plan = plan_appeared(&path(), Some(&providers), true, ranked(1, 2));
+        // What was blocked was never in the output.
+        assert_eq!(plan.outcome, Appeared::Wins { previous: None });
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Unblock),
+                update(MapUpdate::Track(ranked(1, 2))),
+                Action::Link {
+                    from: 1,
+                    to: path()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_recreated_winner_is_linked_again_unless_blocked() {
+        let providers = heap(&[ranked(0, 2), ranked(1, 1)]);
+        let plan = plan_appeared(&path(), Some(&providers), false, ranked(0, 2));
+        assert_eq!(plan.outcome, Appeared::Recreated);
+        assert_eq!(
+            plan.actions,
+            vec![Action::Relink {
+                from: 0,
+                to: path()
+            }]
+        );
+
+        let plan = plan_appeared(&path(), Some(&providers), true, ranked(0, 2));
+        assert_eq!(plan.outcome, Appeared::Recreated);
+        assert!(plan.actions.is_empty());
+
+        let plan = plan_appeared(&path(), Some(&providers), false, ranked(1, 1));
+        assert_eq!(plan.outcome, Appeared::Known);
+        assert!(plan.actions.is_empty());
+    }
+
+    #[test]
+    fn a_winner_that_went_away_makes_way_for_the_next() {
+        let providers = heap(&[ranked(0, 1), ranked(1, 2)]);
+        let plan = plan_vanished(&path(), Some(&providers), false, 1);
+        assert_eq!(plan.outcome, Vanished::Winner { next: true });
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Untrack(1)),
+                Action::Remove {
+                    path: path(),
+                    index: 1
+                },
+                count(1, Count::Hidden),
+                count(0, Count::Unshadowed),
+                Action::Link {
+                    from: 0,
+                    to: path()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn the_last_provider_that_went_away_empties_the_path() {
+        let providers = heap(&[ranked(0, 1)]);
+        let plan = plan_vanished(&path(), Some(&providers), false, 0);
+        assert_eq!(plan.outcome, Vanished::Winner { next: false });
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Untrack(0)),
+                Action::Remove {
+                    path: path(),
+                    index: 0
+                },
+                count(0, Count::Hidden),
+                Action::RemoveEmptyDirs {
+                    path: PathBuf::from("a")
+                },
+                update(MapUpdate::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_blocked_winner_that_went_away_leaves_the_output_alone() {
+        let providers = heap(&[ranked(0, 1), ranked(1, 2)]);
+        let plan = plan_vanished(&path(), Some(&providers), true, 1);
+        assert_eq!(
+            plan.actions,
+            vec![update(MapUpdate::Untrack(1)), count(1, Count::Unshadowed)]
+        );
+
+        let providers = heap(&[ranked(1, 2)]);
+        let plan = plan_vanished(&path(), Some(&providers), true, 1);
+        assert_eq!(
+            plan.actions,
+            vec![
+                update(MapUpdate::Untrack(1)),
+                count(1, Count::Unshadowed),
+                update(MapUpdate::Unblock),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_shadowed_file_that_went_away_is_only_untracked() {
+        let providers = heap(&[ranked(0, 1), ranked(1, 2)]);
+        let plan = plan_vanished(&path(), Some(&providers), false, 0);
+        assert_eq!(plan.outcome, Vanished::Shadowed);
+        assert_eq!(
+            plan.actions,
+            vec![update(MapUpdate::Untrack(0)), count(0, Count::Unshadowed)]
+        );
+
+        let plan = plan_vanished(&path(), Some(&providers), false, 2);
+        assert_eq!(plan.outcome, Vanished::Untracked);
+        assert!(plan.actions.is_empty());
+        let plan = plan_vanished(&path(), None, false, 0);
+        assert_eq!(plan.outcome, Vanished::Untracked);
+    }
+
+    #[test]
+    fn a_renamed_directory_moves_what_is_beneath_it() {
+        let provided = vec![
+            PathBuf::from("a/b/x"),
+            PathBuf::from("a/b/c/y"),
+            PathBuf::from("a/b/c/z"),
+        ];
+        let plan = plan_renamed(Path::new("a/b"), Path::new("d"), provided);
+        assert_eq!(
+            plan.outcome,
+            vec![
+                (PathBuf::from("a/b/x"), PathBuf::from("d/x")),
+                (PathBuf::from("a/b/c/y"), PathBuf::from("d/c/y")),
+                (PathBuf::from("a/b/c/z"), PathBuf::from("d/c/z")),
+            ]
+        );
+        assert_eq!(
+            plan.actions,
+            vec![
+                Action::CreateDir {
+                    path: PathBuf::from("d")
+                },
+                Action::CreateDir {
+                    path: PathBuf::from("d/c")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_tracking_changes_the_providers() {
+        let mut providers = heap(&[ranked(0, 1)]);
+        update_heap(&mut providers, MapUpdate::Track(ranked(1, 2)));
+        assert_eq!(providers.peek().map(|input| input.index), Some(1));
+        update_heap(&mut providers, MapUpdate::Unblock);
+        update_heap(&mut providers, MapUpdate::Release);
+        assert_eq!(providers.len(), 2);
+        update_heap(&mut providers, MapUpdate::Untrack(1));
+        assert_eq!(providers.peek().map(|input| input.index), Some(0));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::Failure;
+use failure::Fail;
+use std::fmt;
+use std::path::PathBuf;
+
+/// What is wrong with an input or a pattern given to an `Overlay` or its builder, found
+/// when it is added rather than once the overlay runs. The errors of adding them can be
+/// downcast to it, to tell which entry of a configuration is at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There is nothing at the path of the input.
+    Missing {
+        path: PathBuf,
+    },
+    /// The input is a file, but not an archive.
+    NotADirectory {
+        path: PathBuf,
+    },
+    /// The directory of the input can't be read.
+    Unreadable {
+        path: PathBuf,
+        reason: String,
+    },
+    /// The input already is input `input`, under this or another spelling of its path.
+    DuplicateInput {
+        path: PathBuf,
+        input: String,
+    },
+    DuplicateLabel {
+        path: PathBuf,
+        label: String,
+    },
+    /// The input is inside of input `input`, or the other way around.
+    OverlapsInput {
+        path: PathBuf,
+        input: String,
+    },
+    /// The input is inside of `what`, something the overlay writes to at `other`, or the
+    /// other way around.
+    OverlapsOutput {
+        path: PathBuf,
+        what: &'static str,
+        other: PathBuf,
+    },
+    InvalidPattern {
+        pattern: String,
+        reason: String,
+    },
+    /// The directory the input is taken from or mounted at isn't a plain relative path.
+    InvalidMapping {
+        path: PathBuf,
+        dir: PathBuf,
+    },
+    /// The overlay never ran on the output, which already has `files` files, and
+    /// `ExistingOutputPolicy::Refuse` says not to.
+    OutputNotEmpty {
+        output: PathBuf,
+        files: usize,
+    },
+    /// The trash is inside of the output, or the other way around.
+    TrashInOutput {
+        trash: PathBuf,
+        output: PathBuf,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Missing { path } => write!(f, "input {} doesn't exist", path.display()),
+            ConfigError::NotADirectory { path } => {
+                write!(f, "input {} isn't a directory", path.display())
+            }
+            ConfigError::Unreadable { path, reason } => {
+                write!(f, "input {} can't be read: {}", path.display(), reason)
+            }
+            ConfigError::DuplicateInput { path, input } => {
+                write!(f, "{} already is input {}", path.display(), input)
+            }
+            ConfigError::DuplicateLabel { path, label } => write!(
+                f,
+                "input {} can't be labelled {:?}, there already is an input labelled so",
+                path.display(),
+                label
+            ),
+            ConfigError::OverlapsInput { path, input } => write!(
+                f,
+                "input {} overlaps input {}, one is inside of the other",
+                path.display(),
+                input
+            ),
+            ConfigError::OverlapsOutput { path, what, other } => write!(
+                f,
+                "input {} overlaps {}, {}, which the overlay writes to",
+                path.display(),
+                what,
+                other.display()
+            ),
+            ConfigError::InvalidPattern { pattern, reason } => {
+                write!(f, "{:?} isn't a valid pattern: {}", pattern, reason)
+            }
+            ConfigError::InvalidMapping { path, dir } => write!(
+                f,
+                "input {} can't be mapped from or to {}, which has to be a relative path without ..",
+                path.display(),
+                dir.display()
+            ),
+            ConfigError::OutputNotEmpty { output, files } => write!(
+                f,
+                "the overlay never ran on {}, which already has {} files it could replace or \
+                 remove; with existing_output = \"adopt\" (--adopt) they are dealt with as the \
+                 foreign file policy says, with \"force\" (--force) like those the overlay put \
+                 there, otherwise another output has to be used",
+                output.display(),
+                files
+            ),
+            ConfigError::TrashInOutput { trash, output } => write!(
+                f,
+                "the trash {} overlaps the output {}, it has to be elsewhere",
+                trash.display(),
+                output.display()
+            ),
+        }
+    }
+}
+
+impl Fail for ConfigError {}
+
+/// What went wrong while handling an event: how many things failed, and the `Failure`s
+/// of those that were kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverlayError {
+    pub errors: u64,
+    /// Empty once the overlay stopped keeping failures, see `Overlay::failures`.
+    pub failures: Vec<Failure>,
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.failures.first() {
+            Some(failure) if self.errors > 1 => {
+                write!(f, "{} and {} more errors", failure.message, self.errors - 1)
+            }
+            Some(failure) => f.write_str(&failure.message),
+            None => write!(f, "{} errors", self.errors),
+        }
+    }
+}
+
+impl Fail for OverlayError {}
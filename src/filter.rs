@@ -0,0 +1,550 @@
+use crate::{error_kind, ConfigError, Event, EventType, Overlay, RewriteRule};
+use failure::{format_err, Error};
+use glob::{MatchOptions, Pattern};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Names of the files editors and downloads leave next to the real ones for a while,
+/// which are never linked unless `OverlayBuilder::temp_patterns` says otherwise.
+pub const DEFAULT_TEMP_PATTERNS: &[&str] = &["*~", ".*.swp", "*.part", "*.crdownload", "~$*"];
+
+/// The file in the root of an input listing, in gitignore syntax, what of the input is
+/// never linked. It is never linked itself either.
+pub const IGNORE_FILE: &str = ".overlayignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Per-input settings: what the input is called, and which files it contributes to the
+/// overlay.
+///
+/// Patterns are globs. One without a `/` is matched against a single file or directory
+/// name, one with a `/` against the whole path relative to the input.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputOptions {
+    /// A name shown alongside the index in logs, queries and reports, unique among inputs.
+    pub label: Option<String>,
+    /// Whether the input contributes anything at all, `true` by default.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The priority of the group the input is in, which outranks its own priority.
+    #[serde(default)]
+    pub group: u32,
+    /// Only files matching one of these are linked, unless it is empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Files matching one of these, or inside a directory matching one, are never linked.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// How many path components deep files may be, `Some(1)` meaning only the input root.
+    pub max_depth: Option<usize>,
+    /// Whether the overlay may write into the input, like adopting foreign files does.
+    /// Inputs are read-only by default.
+    #[serde(default)]
+    pub writable: bool,
+    /// How often the input is polled for changes, in milliseconds, if it is to be polled
+    /// at its own interval. See `NotifySource::poll`.
+    pub poll_interval_ms: Option<u64>,
+    /// How long, in milliseconds, a changed file has to keep its size and time of
+    /// modification before the change is acted on, so that files still being copied into
+    /// the input aren't linked half-written. Changes are acted on right away by default,
+    /// as this holds back every one of them.
+    pub settle_ms: Option<u64>,
+    /// How long ago, in milliseconds, a file has to have been modified last before it is
+    /// linked. Younger ones are linked once they are old enough.
+    pub min_age_ms: Option<u64>,
+    /// The directory inside the input its files are taken from, rather than all of it.
+    /// Patterns are relative to it.
+    pub source: Option<PathBuf>,
+    /// The directory of the output its files go into, rather than the top of it.
+    pub mount: Option<PathBuf>,
+    /// How its files are renamed in the output, by the first of these that applies.
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        InputOptions {
+            label: None,
+            enabled: true,
+            group: 0,
+            include: vec![],
+            exclude: vec![],
+            max_depth: None,
+            writable: false,
+            poll_interval_ms: None,
+            settle_ms: None,
+            min_age_ms: None,
+            source: None,
+            mount: None,
+            rewrite: vec![],
+        }
+    }
+}
+
+pub(crate) fn default_enabled() -> bool {
+    true
+}
+
+impl InputOptions {
+    pub fn new() -> Self {
+        InputOptions::default()
+    }
+
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval_ms = Some(interval.as_millis() as u64);
+        self
+    }
+
+    pub fn settle(mut self, interval: Duration) -> Self {
+        self.settle_ms = Some(interval.as_millis() as u64);
+        self
+    }
+
+    pub fn min_age(mut self, age: Duration) -> Self {
+        self.min_age_ms = Some(age.as_millis() as u64);
+        self
+    }
+
+    pub fn source<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.source = Some(dir.into());
+        self
+    }
+
+    pub fn mount<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.mount = Some(dir.into());
+        self
+    }
+
+    pub fn rewrite<F: Into<String>, T: Into<String>>(mut self, from: F, to: T) -> Self {
+        self.rewrite.push(RewriteRule::new(from, to));
+        self
+    }
+}
+
+/// A pattern matched against a single name if it has no `/`, and against the whole path
+/// otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Glob {
+    pattern: Pattern,
+    whole_path: bool,
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Result<Self, Error> {
+        let pattern = pattern.trim_end_matches('/');
+        let invalid = |e: glob::PatternError| ConfigError::InvalidPattern {
+            pattern: pattern.to_string(),
+            reason: e.to_string(),
+        };
+        Ok(Glob {
+            pattern: Pattern::new(pattern).map_err(invalid)?,
+            whole_path: pattern.contains('/'),
+        })
+    }
+
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        if self.whole_path {
+            self.pattern.matches_path_with(path, MATCH_OPTIONS)
+        } else {
+            path.file_name()
+                .map(|name| {
+                    self.pattern
+                        .matches_with(&name.to_string_lossy(), MATCH_OPTIONS)
+                })
+                .unwrap_or(false)
+        }
+    }
+}
+
+/// Rules in gitignore syntax, from an input's `IGNORE_FILE` or the overlay's own.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IgnoreFile {
+    rules: Option<Arc<Rules>>,
+}
+
+#[derive(Debug)]
+struct Rules {
+    /// What the rules were read from, to tell whether the file really changed.
+    text: String,
+    matcher: Gitignore,
+}
+
+impl IgnoreFile {
+    /// Reads the `IGNORE_FILE` in `root`, which has no rules if there is none.
+    pub(crate) fn load(root: &Path) -> Result<Self, Error> {
+        match Self::read(&root.join(IGNORE_FILE)) {
+            Err(e) if is_not_found(&e) => Ok(IgnoreFile::default()),
+            result => result,
+        }
+    }
+
+    /// Reads the ignore file at `path`.
+    pub(crate) fn read(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)?;
+
+        // Paths are matched as they are, relative to the input, which "." keeps them.
+        let mut builder = GitignoreBuilder::new(".");
+        for (number, line) in text.lines().enumerate() {
+            builder
+                .add_line(None, line)
+                .map_err(|e| format_err!("line {} of {}: {}", number + 1, path.display(), e))?;
+        }
+        let matcher = builder.build()?;
+
+        Ok(IgnoreFile {
+            rules: Some(Arc::new(Rules { text, matcher })),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules
+            .as_ref()
+            .is_none_or(|rules| rules.matcher.is_empty())
+    }
+
+    /// Whether the file or directory at `path`, relative to the input, is ignored.
+    pub(crate) fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        !path.as_os_str().is_empty()
+            && self.rules.as_ref().is_some_and(|rules| {
+                rules
+                    .matcher
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore()
+            })
+    }
+}
+
+impl PartialEq for IgnoreFile {
+    fn eq(&self, other: &Self) -> bool {
+        fn text(ignore: &IgnoreFile) -> Option<&str> {
+            ignore.rules.as_ref().map(|rules| rules.text.as_str())
+        }
+        text(self) == text(other)
+    }
+}
+
+impl Eq for IgnoreFile {}
+
+fn is_not_found(e: &Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+/// `InputOptions` with its patterns compiled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    max_depth: Option<usize>,
+    /// The rules of the input's `IGNORE_FILE`, if the filter is an input's.
+    ignore: Option<IgnoreFile>,
+    /// The overlay's own rules, which no rule of an input can take back.
+    global: IgnoreFile,
+}
+
+impl Filter {
+    /// A filter rejecting the files matching any of `patterns`, and everything in
+    /// directories that do.
+    pub(crate) fn excluding<S: AsRef<str>>(patterns: &[S]) -> Result<Self, Error> {
+        Ok(Filter {
+            exclude: patterns
+                .iter()
+                .map(|pattern| Glob::new(pattern.as_ref()))
+                .collect::<Result<_, _>>()?,
+            ..Filter::default()
+        })
+    }
+
+    pub(crate) fn new(options: &InputOptions) -> Result<Self, Error> {
+        Ok(Filter {
+            include: options
+                .include
+                .iter()
+                .map(|pattern| Glob::new(pattern))
+                .collect::<Result<_, _>>()?,
+            exclude: options
+                .exclude
+                .iter()
+                .map(|pattern| Glob::new(pattern))
+                .collect::<Result<_, _>>()?,
+            max_depth: options.max_depth,
+            ignore: None,
+            global: IgnoreFile::default(),
+        })
+    }
+
+    /// Whether every file is accepted, but for the `IGNORE_FILE` itself and what the
+    /// overlay's own rules ignore.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.max_depth.is_none()
+            && self.ignore.as_ref().is_none_or(IgnoreFile::is_empty)
+    }
+
+    pub(crate) fn ignore(&self) -> Option<&IgnoreFile> {
+        self.ignore.as_ref()
+    }
+
+    /// Makes the filter an input's, which also rejects what `ignore` does.
+    pub(crate) fn set_ignore(&mut self, ignore: IgnoreFile) {
+        self.ignore = Some(ignore);
+    }
+
+    pub(crate) fn set_global(&mut self, global: IgnoreFile) {
+        self.global = global;
+    }
+
+    pub(crate) fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Whether the file at `path`, relative to the input, belongs in the overlay.
+    pub(crate) fn accepts_file(&self, path: &Path) -> bool {
+        if let Some(depth) = self.max_depth {
+            if path.components().count() > depth {
+                return false;
+            }
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|glob| glob.matches(path)) {
+            return false;
+        }
+        if self.ignores(path, false) {
+            return false;
+        }
+
+        !path.ancestors().any(|path| self.excludes(path))
+    }
+
+    /// Whether files inside the directory at `path` could belong in the overlay.
+    pub(crate) fn accepts_dir(&self, path: &Path) -> bool {
+        if let Some(depth) = self.max_depth {
+            if path.components().count() >= depth {
+                return false;
+            }
+        }
+        if self.ignores(path, true) {
+            return false;
+        }
+
+        !path.ancestors().any(|path| self.excludes(path))
+    }
+
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        match &self.ignore {
+            Some(_) if path == Path::new(IGNORE_FILE) => true,
+            Some(ignore) if ignore.ignores(path, is_dir) => true,
+            _ => self.global.ignores(path, is_dir),
+        }
+    }
+
+    fn excludes(&self, path: &Path) -> bool {
+        !path.as_os_str().is_empty() && self.exclude.iter().any(|glob| glob.matches(path))
+    }
+}
+
+impl Overlay {
+    /// Replaces `DEFAULT_TEMP_PATTERNS` as the names of files no input ever provides.
+    pub fn set_temp_patterns<S: AsRef<str>>(&mut self, patterns: &[S]) -> Result<(), Error> {
+        self.temp_files = Filter::excluding(patterns)?;
+        Ok(())
+    }
+
+    /// Reads the ignore file at `path`, whose gitignore-style rules apply to every input.
+    ///
+    /// What they ignore is never linked, whatever an input's own `IGNORE_FILE`, includes
+    /// or negated rules say.
+    pub fn set_ignore_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.global_ignore = IgnoreFile::read(path)
+            .map_err(|e| format_err!("couldn't read {}: {}", path.display(), e))?;
+        for input in &mut self.inputs {
+            input.filter.set_global(self.global_ignore.clone());
+        }
+        Ok(())
+    }
+
+    /// Reads the `IGNORE_FILE` of input `index` again, keeping the rules it had if that
+    /// fails. Returns whether they changed.
+    pub(crate) fn load_ignore(&mut self, index: usize) -> bool {
+        // Archives have no rules of their own.
+        if self.inputs[index].is_archive() {
+            return false;
+        }
+        let ignore = match IgnoreFile::load(&self.inputs[index].path) {
+            Ok(ignore) => ignore,
+            Err(e) => {
+                warn!(
+                    "Keeping the rules of input {} as they were: {}",
+                    self.input_name(index),
+                    e
+                );
+                let kind = match e.downcast_ref::<io::Error>() {
+                    Some(e) => error_kind(e),
+                    None => "ignore".to_string(),
+                };
+                self.failed(
+                    Some(Path::new(IGNORE_FILE)),
+                    Some(index),
+                    kind,
+                    e.to_string(),
+                );
+                return false;
+            }
+        };
+
+        let filter = &mut self.inputs[index].filter;
+        if filter.ignore() == Some(&ignore) {
+            return false;
+        }
+        filter.set_ignore(ignore);
+        true
+    }
+
+    /// Reads the `IGNORE_FILE` of input `index` again after it changed, taking what it
+    /// ignores now out of the output and linking what it no longer does.
+    pub(crate) fn reload_ignore(&mut self, index: usize) {
+        if !self.load_ignore(index) || !self.inputs[index].enabled {
+            return;
+        }
+
+        self.line.begin(format_args!(
+            "Input {} {}",
+            self.input_name(index),
+            IGNORE_FILE
+        ));
+        if !self.inputs[index].filter.is_empty() {
+            // A graft would show the ignored files along with the rest.
+            let mut grafts: Vec<PathBuf> = self
+                .grafts
+                .dirs
+                .iter()
+                .filter(|(_, owner)| **owner == index)
+                .map(|(graft, _)| graft.clone())
+                .collect();
+            grafts.sort();
+            for graft in grafts {
+                self.ungraft(&graft);
+            }
+        }
+        say!(self.line, Info, " RELOADED!");
+        self.line.end();
+
+        let provided = self.provided_under(index, Path::new(""));
+        let input = &self.inputs[index];
+        let ignored: Vec<PathBuf> = provided
+            .iter()
+            .filter(|path| !input.accepts_file(path))
+            .cloned()
+            .collect();
+        let temp_files = &self.temp_files;
+        let provided: HashSet<PathBuf> = provided.into_iter().collect();
+        let unignored: Vec<PathBuf> = input
+            .walk(Path::new(""))
+            .into_iter()
+            .filter(|path| temp_files.accepts_file(path) && !provided.contains(path))
+            .collect();
+
+        for path in ignored {
+            self.apply_event(EventType::new(index, Event::Remove(path)));
+        }
+        for path in unignored {
+            self.apply_event(EventType::new(index, Event::Create(path)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+
+    #[test]
+    fn the_global_ignore_file_wins_over_what_an_input_includes() {
+        let rules = scratch("global-ignore-rules").join("overlay.ignore");
+        fs::write(&rules, "*.psd\nsource/\n").unwrap();
+        let mut harness =
+            Harness::with("global-ignore", &[], |builder| builder.ignore_file(&rules));
+        let input = harness.output.with_file_name("input0");
+        fs::create_dir_all(&input).unwrap();
+        harness.fs.create_dir(&input);
+        // Only what the global rules ignore, and what they don't.
+        let options = InputOptions::new().include("*.psd").include("*.dds");
+        harness
+            .overlay
+            .add_input_with_options(&input, 0, &options)
+            .unwrap();
+        harness.inputs.push(input.clone());
+
+        // Found by the sync, and then by the events.
+        for path in ["synced.psd", "source/synced.dds", "synced.dds"] {
+            let file = input.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, path).unwrap();
+            harness.fs.create_file(&file);
+        }
+        harness.overlay.sync_once().unwrap();
+        for path in ["created.psd", "source/created.dds", "created.dds"] {
+            harness.create(0, path);
+        }
+
+        for path in ["synced", "created"] {
+            assert!(!harness.in_output(&format!("{}.psd", path)), "{}", path);
+            assert!(
+                !harness.in_output(&format!("source/{}.dds", path)),
+                "{}",
+                path
+            );
+            assert!(harness.in_output(&format!("{}.dds", path)), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,1462 @@
+use crate::identity::FileIdentity;
+use crate::sparse;
+use log::debug;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// The filesystem operations the overlay performs while linking.
+///
+/// `RealFs` is used unless the builder is given something else, `MemoryFs` lets the
+/// decisions be exercised without touching the disk.
+pub trait FileOps: Debug + Send + Sync {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Whether both paths are the same file, e.g. hard links of each other.
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool>;
+    /// Makes `link` point at the directory `target`, a junction on Windows and a symlink
+    /// elsewhere.
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()>;
+    /// Removes a link made by `link_dir`, leaving what it points at alone.
+    fn unlink_dir(&self, link: &Path) -> io::Result<()>;
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf>;
+    /// Gives `to` the permissions `from` has.
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Creates an empty file at `path`, failing if there already is one.
+    fn create_empty(&self, path: &Path) -> io::Result<()>;
+    /// Copies the file `from` to `to`, as a stand-in for a hard link where there can't be
+    /// one.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Copies like `copy`, telling `progress` how many of how many bytes are copied as it
+    /// goes, where it can. The copy is given up, and what there is of it removed, once
+    /// `progress` returns `false`.
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        let _ = progress;
+        self.copy(from, to)
+    }
+    /// Whether `b` is a copy of `a` made by `copy`, that neither was changed since.
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool>;
+    /// Whether the files `a` and `b` hold the same bytes, however they got there.
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        Ok(self.read(a)? == self.read(b)?)
+    }
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Puts a new file with `contents` at `path`, in place of any file there. A file that
+    /// is there is replaced rather than written to, as it may be linked to an input.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+}
+
+impl<T: FileOps + ?Sized> FileOps for Arc<T> {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        (**self).is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        (**self).create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        (**self).same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        (**self).link_dir(target, link)
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        (**self).unlink_dir(link)
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        (**self).read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).copy_permissions(from, to)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        (**self).create_empty(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).copy(from, to)
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        (**self).copy_with_progress(from, to, progress)
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        (**self).same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        (**self).same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        (**self).identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        (**self).read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        (**self).write(path, contents)
+    }
+}
+
+/// `FileOps` on the real filesystem through `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl FileOps for RealFs {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    #[cfg(not(windows))]
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    #[cfg(windows)]
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path).or_else(|e| {
+            // Copies of read-only files are read-only too, which Windows doesn't delete. A
+            // hard link shares the attribute with the input's file, so it is left as it is.
+            let mut permissions = fs::metadata(path)?.permissions();
+            let links = winapi_util::file::information(&fs::File::open(path)?)?.number_of_links();
+            if e.kind() != io::ErrorKind::PermissionDenied || !permissions.readonly() || links > 1 {
+                return Err(e);
+            }
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            fs::set_permissions(path, permissions)?;
+            fs::remove_file(path)
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        // Never for files the filesystem doesn't number, which would all be the same.
+        let (a, b) = (FileIdentity::of(a)?, FileIdentity::of(b)?);
+        Ok(a.number().is_some() && a.number() == b.number())
+    }
+
+    #[cfg(windows)]
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        junction::create(target, link)
+    }
+
+    #[cfg(unix)]
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    #[cfg(not(any(windows, unix)))]
+    fn link_dir(&self, _target: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::Error::other("directory links are not supported"))
+    }
+
+    #[cfg(windows)]
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        fs::remove_dir(link)
+    }
+
+    #[cfg(not(windows))]
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        fs::remove_file(link)
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        fs::read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::set_permissions(to, fs::metadata(from)?.permissions())
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map(drop)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.copy_with_progress(from, to, &mut |_, _| true)
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        let source = fs::File::open(from)?;
+        let copy = fs::File::create(to)?;
+        copy_file(&source, copy, to, progress).inspect_err(|_| {
+            let _ = fs::remove_file(to);
+        })
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+        Ok(a.is_file() && a.len() == b.len() && a.modified()? == b.modified()?)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        use std::io::Read;
+
+        let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+        if a.metadata()?.len() != b.metadata()?.len() {
+            return Ok(false);
+        }
+        // A chunk at a time, as the files may be larger than what fits in memory.
+        let (mut ours, mut theirs) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+        loop {
+            let read = a.read(&mut ours)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            b.read_exact(&mut theirs[..read])?;
+            if ours[..read] != theirs[..read] {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        FileIdentity::of(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        // Written aside and renamed over the file, so a link there is only replaced.
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".overlay-new");
+        let temporary = PathBuf::from(temporary);
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path).inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+    }
+}
+
+/// How much of a file is copied at a time, between telling how far the copy is.
+const COPY_CHUNK: u64 = 8 << 20;
+
+/// Copies `source` into `copy`, a new file at `to`, without its holes if it is sparse.
+fn copy_file(
+    source: &fs::File,
+    copy: fs::File,
+    to: &Path,
+    progress: &mut dyn FnMut(u64, u64) -> bool,
+) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let metadata = source.metadata()?;
+    let ranges = sparse::data(source, &copy)?.unwrap_or_else(|| vec![(0, metadata.len())]);
+    let total = ranges.iter().map(|(_, len)| len).sum();
+    let mut copied = 0;
+    for (offset, len) in ranges {
+        let (mut source, mut copy) = (source, &copy);
+        source.seek(SeekFrom::Start(offset))?;
+        copy.seek(SeekFrom::Start(offset))?;
+        let mut left = len;
+        while left > 0 {
+            let chunk = io::copy(&mut source.take(left.min(COPY_CHUNK)), &mut copy)?;
+            if chunk == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the file got shorter while it was copied",
+                ));
+            }
+            left -= chunk;
+            copied += chunk;
+            if !progress(copied, total) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "the copy was cancelled",
+                ));
+            }
+        }
+    }
+
+    // The time it was modified tells the copy apart from one that was changed since.
+    copy.set_modified(metadata.modified()?)?;
+    drop(copy);
+    // Only once it is written, as it may be read-only, like the files of read-only media
+    // are.
+    fs::set_permissions(to, metadata.permissions())
+}
+
+/// Where the overlay may write: its output, and none of its inputs but the writable ones.
+#[derive(Debug, Default)]
+pub(crate) struct Protected {
+    output: PathBuf,
+    inputs: Vec<PathBuf>,
+}
+
+impl Protected {
+    pub(crate) fn new(output: &Path) -> Self {
+        Protected {
+            output: absolute(output),
+            inputs: vec![],
+        }
+    }
+
+    /// Makes the input at `path` one that is never written into.
+    pub(crate) fn protect(&mut self, path: &Path) {
+        self.inputs.push(absolute(path));
+    }
+
+    /// The protected input that `path` is in, if any. An output inside of an input is
+    /// still written into.
+    fn input_of(&self, path: &Path) -> Option<&Path> {
+        let path = absolute(path);
+        self.inputs
+            .iter()
+            .find(|input| {
+                path.starts_with(input)
+                    && !(path.starts_with(&self.output) && self.output.starts_with(input))
+            })
+            .map(PathBuf::as_path)
+    }
+}
+
+/// `path` made absolute, without touching the disk.
+pub(crate) fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Refuses to write into an input that isn't writable, whatever asks it to. The overlay
+/// only reads its inputs, and a write into one would change what it is there to present.
+///
+/// Debug builds panic instead, so that what tried is found where it did.
+#[derive(Debug)]
+pub(crate) struct ReadOnlyInputs {
+    inner: Box<dyn FileOps>,
+    protected: Arc<RwLock<Protected>>,
+}
+
+impl ReadOnlyInputs {
+    pub(crate) fn new(inner: Box<dyn FileOps>, protected: Arc<RwLock<Protected>>) -> Self {
+        ReadOnlyInputs { inner, protected }
+    }
+
+    fn check(&self, path: &Path) -> io::Result<()> {
+        let protected = self.protected.read().unwrap();
+        let input = match protected.input_of(path) {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+
+        let message = format!(
+            "refusing to write to {}, which is in the input {} that isn't writable",
+            path.display(),
+            input.display()
+        );
+        if cfg!(debug_assertions) {
+            panic!("{}", message);
+        }
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, message))
+    }
+}
+
+impl FileOps for ReadOnlyInputs {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(to)?;
+        self.inner.hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(from)?;
+        self.check(to)?;
+        self.inner.rename(from, to)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.check(link)?;
+        self.inner.link_dir(target, link)
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.check(link)?;
+        self.inner.unlink_dir(link)
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(to)?;
+        self.inner.copy_permissions(from, to)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.create_empty(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(to)?;
+        self.inner.copy(from, to)
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        self.check(to)?;
+        self.inner.copy_with_progress(from, to, progress)
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        self.inner.identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.check(path)?;
+        self.inner.write(path, contents)
+    }
+}
+
+/// How often the operations that change the output are tried again when they fail for
+/// a moment, e.g. on Windows while a virus scanner or the game has the file open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times an operation is tried in all, once if 0 or 1.
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Tries `op` until it succeeds, fails with an error that isn't transient, or runs out
+    /// of attempts, returning the last error.
+    fn run<T, F: FnMut() -> io::Result<T>>(
+        &self,
+        what: &str,
+        path: &Path,
+        mut op: F,
+    ) -> io::Result<T> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(ref e) if attempt < self.attempts && is_transient(e) => {
+                    debug!("{} {} failed, trying again: {}", what, path.display(), e);
+                    attempt += 1;
+                    thread::sleep(self.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether `e` is of a hard link to a file that has as many as its filesystem allows,
+/// 1023 on NTFS.
+pub(crate) fn is_too_many_links(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::TooManyLinks {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        const ERROR_TOO_MANY_LINKS: i32 = 1142;
+        if e.raw_os_error() == Some(ERROR_TOO_MANY_LINKS) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether `e` is likely gone if the operation is tried again a moment later: the file is
+/// busy, or on Windows open without sharing, which is reported as access denied as well.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    if let io::ErrorKind::ResourceBusy | io::ErrorKind::ExecutableFileBusy = e.kind() {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        const ERROR_ACCESS_DENIED: i32 = 5;
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        const ERROR_LOCK_VIOLATION: i32 = 33;
+        if let Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION) =
+            e.raw_os_error()
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Tries the deletes, links and renames it wraps again, by a `RetryPolicy`, when they
+/// fail with an error that `is_transient`.
+#[derive(Debug)]
+pub(crate) struct Retrying {
+    inner: Box<dyn FileOps>,
+    policy: Arc<RwLock<RetryPolicy>>,
+}
+
+impl Retrying {
+    pub(crate) fn new(inner: Box<dyn FileOps>, policy: Arc<RwLock<RetryPolicy>>) -> Self {
+        Retrying { inner, policy }
+    }
+
+    fn retry<T, F: FnMut() -> io::Result<T>>(
+        &self,
+        what: &str,
+        path: &Path,
+        op: F,
+    ) -> io::Result<T> {
+        let policy = *self.policy.read().unwrap();
+        policy.run(what, path, op)
+    }
+}
+
+impl FileOps for Retrying {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.retry("Linking", to, || self.inner.hard_link(from, to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.retry("Removing", path, || self.inner.remove_file(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.retry("Removing", path, || self.inner.remove_dir(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.retry("Removing", path, || self.inner.remove_dir_all(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.retry("Renaming", from, || self.inner.rename(from, to))
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.retry("Linking", link, || self.inner.link_dir(target, link))
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.retry("Removing", link, || self.inner.unlink_dir(link))
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.copy_permissions(from, to)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_empty(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.copy(from, to)
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        self.inner.copy_with_progress(from, to, progress)
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        self.inner.identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.retry("Writing", path, || self.inner.write(path, contents))
+    }
+}
+
+/// An operation of `MemoryFs` that can be made to fail, and one a `DecisionTrace` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOp {
+    HardLink,
+    RemoveFile,
+    CreateDirAll,
+    RemoveDir,
+    Rename,
+    LinkDir,
+    CopyPermissions,
+    CreateEmpty,
+    Copy,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Every file and the file it is, files with the same number being hard links.
+    files: FxHashMap<PathBuf, u64>,
+    directories: FxHashSet<PathBuf>,
+    /// Every directory link and the directory it points at.
+    links: FxHashMap<PathBuf, PathBuf>,
+    /// Every file made by `copy` and the file it is a copy of.
+    copies: HashMap<u64, u64>,
+    /// What the files written by `write` or `write_file` hold, the others are empty.
+    contents: HashMap<u64, Vec<u8>>,
+    /// The sizes given with `set_len`, in place of those of the contents.
+    lens: HashMap<u64, u64>,
+    next_file: u64,
+    failures: HashMap<(FileOp, PathBuf), io::ErrorKind>,
+}
+
+impl MemoryState {
+    fn check(&self, op: FileOp, path: &Path) -> io::Result<()> {
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+        match self.failures.get(&(op, path.to_path_buf())) {
+            Some(kind) => Err(io::Error::new(*kind, format!("{:?} failed", op))),
+            None => Ok(()),
+        }
+    }
+
+    /// Follows the directory links leading to `path`, and `path` itself if it is one.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        let mut path = path.to_path_buf();
+
+        // Links to links are followed too, within reason.
+        for _ in 0..8 {
+            let link = path
+                .ancestors()
+                .find(|ancestor| self.links.contains_key(*ancestor))
+                .map(Path::to_path_buf);
+            match link {
+                Some(link) => {
+                    let rest = path.strip_prefix(&link).unwrap();
+                    path = if rest.as_os_str().is_empty() {
+                        self.links[&link].clone()
+                    } else {
+                        self.links[&link].join(rest)
+                    };
+                }
+                None => break,
+            }
+        }
+
+        path
+    }
+
+    /// Follows the directory links leading to `path`, but not `path` itself.
+    fn resolve_parent(&self, path: &Path) -> PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self.resolve(parent).join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn occupied(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+            || self.directories.contains(path)
+            || self.links.contains_key(path)
+    }
+
+    fn parent_exists(&self, path: &Path) -> bool {
+        match path.parent() {
+            Some(parent) if parent.parent().is_some() => self.directories.contains(parent),
+            _ => true,
+        }
+    }
+
+    fn new_file(&mut self) -> u64 {
+        self.next_file += 1;
+        self.next_file
+    }
+
+    fn add_directories(&mut self, path: &Path) {
+        for ancestor in path.ancestors() {
+            // Then the directories above are there already.
+            if ancestor.parent().is_none() || self.directories.contains(ancestor) {
+                break;
+            }
+            self.directories.insert(ancestor.to_path_buf());
+        }
+    }
+}
+
+/// An in-memory `FileOps` for tests, able to simulate failures of single operations.
+/// Permissions aren't kept, copying them only checks that both files exist. Files are
+/// empty unless written with `write_file`.
+///
+/// Share it with an overlay through an `Arc` to inspect it afterwards.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    state: Mutex<MemoryState>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs::default()
+    }
+
+    /// Creates a new, distinct file at `path`, along with any missing parents.
+    pub fn create_file<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        let mut state = self.state.lock().unwrap();
+        if let Some(parent) = path.parent() {
+            state.add_directories(parent);
+        }
+        let file = state.new_file();
+        state.files.insert(path.to_path_buf(), file);
+    }
+
+    /// Creates a new, distinct file at `path` holding `contents`, along with any missing
+    /// parents.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, contents: &[u8]) {
+        let path = path.as_ref();
+        self.create_file(path);
+        let mut state = self.state.lock().unwrap();
+        let file = state.files[path];
+        state.contents.insert(file, contents.to_vec());
+    }
+
+    /// Has the file at `path` be `len` bytes long as far as its identity tells, without
+    /// holding them, e.g. to be too big for a volume.
+    pub fn set_len<P: AsRef<Path>>(&self, path: P, len: u64) {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files[path.as_ref()];
+        state.lens.insert(file, len);
+    }
+
+    pub fn create_dir<P: AsRef<Path>>(&self, path: P) {
+        self.state.lock().unwrap().add_directories(path.as_ref());
+    }
+
+    /// Makes every `op` on `path` fail with `kind` until `clear_failures`.
+    pub fn fail<P: AsRef<Path>>(&self, op: FileOp, path: P, kind: io::ErrorKind) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .insert((op, path.as_ref().to_path_buf()), kind);
+    }
+
+    pub fn clear_failures(&self) {
+        self.state.lock().unwrap().failures.clear();
+    }
+
+    /// Every file, sorted. Files only reachable through a directory link aren't repeated.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let state = self.state.lock().unwrap();
+        let mut files: Vec<PathBuf> = state.files.keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+    pub fn is_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(&state.resolve(path.as_ref()))
+    }
+
+    /// Every directory link and the directory it points at, sorted.
+    pub fn links(&self) -> Vec<(PathBuf, PathBuf)> {
+        let state = self.state.lock().unwrap();
+        let mut links: Vec<(PathBuf, PathBuf)> = state
+            .links
+            .iter()
+            .map(|(link, target)| (link.clone(), target.clone()))
+            .collect();
+        links.sort();
+        links
+    }
+}
+
+impl FileOps for MemoryFs {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::HardLink, to)?;
+
+        let source = state.resolve(from);
+        let to = state.resolve_parent(to);
+        let file = *state.files.get(&source).ok_or_else(|| not_found(from))?;
+        if state.occupied(&to) {
+            return Err(already_exists(&to));
+        }
+        if !state.parent_exists(&to) {
+            return Err(not_found(&to));
+        }
+
+        state.files.insert(to, file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::RemoveFile, path)?;
+
+        let path = state.resolve_parent(path);
+        state
+            .files
+            .remove(&path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(&path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        let path = state.resolve(path);
+        state.files.contains_key(&path) || state.directories.contains(&path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.directories.contains(&state.resolve(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::CreateDirAll, path)?;
+
+        let path = state.resolve(path);
+        if let Some(file) = path.ancestors().find(|a| state.files.contains_key(*a)) {
+            return Err(already_exists(file));
+        }
+
+        state.add_directories(&path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::RemoveDir, path)?;
+
+        let path = state.resolve_parent(path);
+        if !state.directories.contains(&path) {
+            return Err(not_found(&path));
+        }
+
+        let within = |entry: &PathBuf| entry.parent() == Some(path.as_path());
+        let occupied = state.files.keys().any(within)
+            || state.directories.iter().any(within)
+            || state.links.keys().any(within);
+        if occupied {
+            return Err(io::Error::other(format!("{} is not empty", path.display())));
+        }
+
+        state.directories.remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::RemoveDir, path)?;
+
+        let path = state.resolve_parent(path);
+        if state.links.remove(&path).is_some() {
+            // Like on a real filesystem, a link is removed without following it.
+            return Ok(());
+        }
+        if !state.directories.contains(&path) {
+            return Err(not_found(&path));
+        }
+
+        state.files.retain(|file, _| !file.starts_with(&path));
+        state
+            .directories
+            .retain(|directory| !directory.starts_with(&path));
+        state.links.retain(|link, _| !link.starts_with(&path));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::Rename, from)?;
+
+        let from = state.resolve_parent(from);
+        let to = state.resolve_parent(to);
+        if !state.parent_exists(&to) {
+            return Err(not_found(&to));
+        }
+
+        if let Some(file) = state.files.remove(&from) {
+            state.files.insert(to, file);
+            return Ok(());
+        }
+        if let Some(target) = state.links.remove(&from) {
+            state.links.insert(to, target);
+            return Ok(());
+        }
+
+        if !state.directories.contains(&from) {
+            return Err(not_found(&from));
+        }
+
+        let moved = |path: &PathBuf| match path.strip_prefix(&from) {
+            Ok(rest) if rest.as_os_str().is_empty() => to.clone(),
+            Ok(rest) => to.join(rest),
+            Err(_) => path.clone(),
+        };
+        state.files = state
+            .files
+            .drain()
+            .map(|(path, file)| (moved(&path), file))
+            .collect();
+        state.directories = state.directories.iter().map(moved).collect();
+        state.links = state
+            .links
+            .drain()
+            .map(|(link, target)| (moved(&link), target))
+            .collect();
+        Ok(())
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        let state = self.state.lock().unwrap();
+        let a = state
+            .files
+            .get(&state.resolve(a))
+            .ok_or_else(|| not_found(a))?;
+        let b = state
+            .files
+            .get(&state.resolve(b))
+            .ok_or_else(|| not_found(b))?;
+        Ok(a == b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::LinkDir, link)?;
+
+        let link = state.resolve_parent(link);
+        if state.occupied(&link) {
+            return Err(already_exists(&link));
+        }
+        if !state.parent_exists(&link) {
+            return Err(not_found(&link));
+        }
+
+        state.links.insert(link, target.to_path_buf());
+        Ok(())
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::RemoveDir, link)?;
+
+        let link = state.resolve_parent(link);
+        state
+            .links
+            .remove(&link)
+            .map(|_| ())
+            .ok_or_else(|| not_found(&link))
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state
+            .links
+            .get(&state.resolve_parent(link))
+            .cloned()
+            .ok_or_else(|| not_found(link))
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        state.check(FileOp::CopyPermissions, to)?;
+
+        for path in &[from, to] {
+            if !state.files.contains_key(&state.resolve(path)) {
+                return Err(not_found(path));
+            }
+        }
+        Ok(())
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::CreateEmpty, path)?;
+
+        let path = state.resolve_parent(path);
+        if state.occupied(&path) {
+            return Err(already_exists(&path));
+        }
+        if !state.parent_exists(&path) {
+            return Err(not_found(&path));
+        }
+
+        let file = state.new_file();
+        state.files.insert(path, file);
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::Copy, to)?;
+
+        let source = state.resolve(from);
+        let to = state.resolve_parent(to);
+        let original = *state.files.get(&source).ok_or_else(|| not_found(from))?;
+        if state.links.contains_key(&to) || state.directories.contains(&to) {
+            return Err(already_exists(&to));
+        }
+        if !state.parent_exists(&to) {
+            return Err(not_found(&to));
+        }
+
+        let file = state.new_file();
+        state.files.insert(to, file);
+        state.copies.insert(file, original);
+        if let Some(contents) = state.contents.get(&original).cloned() {
+            state.contents.insert(file, contents);
+        }
+        Ok(())
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        let state = self.state.lock().unwrap();
+        let a = *state
+            .files
+            .get(&state.resolve(a))
+            .ok_or_else(|| not_found(a))?;
+        let b = *state
+            .files
+            .get(&state.resolve(b))
+            .ok_or_else(|| not_found(b))?;
+        Ok(state.copies.get(&b) == Some(&a))
+    }
+
+    /// Files are told apart by their number alone, a file only changes by being replaced.
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        let state = self.state.lock().unwrap();
+        let file = *state
+            .files
+            .get(&state.resolve(path))
+            .ok_or_else(|| not_found(path))?;
+        Ok(FileIdentity {
+            volume: 0,
+            index: file,
+            len: state.lens.get(&file).copied().unwrap_or_else(|| {
+                state
+                    .contents
+                    .get(&file)
+                    .map_or(0, |contents| contents.len() as u64)
+            }),
+            modified: None,
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let file = *state
+            .files
+            .get(&state.resolve(path))
+            .ok_or_else(|| not_found(path))?;
+        Ok(state.contents.get(&file).cloned().unwrap_or_default())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FileOp::Write, path)?;
+
+        let path = state.resolve_parent(path);
+        if state.links.contains_key(&path) || state.directories.contains(&path) {
+            return Err(already_exists(&path));
+        }
+        if !state.parent_exists(&path) {
+            return Err(not_found(&path));
+        }
+
+        let file = state.new_file();
+        state.files.insert(path, file);
+        state.contents.insert(file, contents.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A `MemoryFs` whose links, removes, renames and copies fail with `kind` the first
+    /// `failures` times they are tried, counting how often they are.
+    #[derive(Debug)]
+    struct Flaky {
+        inner: MemoryFs,
+        kind: io::ErrorKind,
+        failures: AtomicU32,
+        calls: AtomicU32,
+    }
+
+    impl Flaky {
+        fn new(kind: io::ErrorKind, failures: u32) -> Arc<Self> {
+            let inner = MemoryFs::new();
+            inner.create_file("/a");
+            Arc::new(Flaky {
+                inner,
+                kind,
+                failures: AtomicU32::new(failures),
+                calls: AtomicU32::new(0),
+            })
+        }
+
+        fn attempt<T, F: FnOnce() -> io::Result<T>>(&self, op: F) -> io::Result<T> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let failing = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Err(io::Error::from(self.kind));
+            }
+            op()
+        }
+
+        fn calls(&self) -> u32 {
+            self.calls.load(Ordering::Relaxed)
+        }
+    }
+
+    impl FileOps for Flaky {
+        fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.attempt(|| self.inner.hard_link(from, to))
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.attempt(|| self.inner.remove_file(path))
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.inner.is_dir(path)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.inner.create_dir_all(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove_dir(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.attempt(|| self.inner.rename(from, to))
+        }
+
+        fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+            self.inner.same_file(a, b)
+        }
+
+        fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+            self.inner.link_dir(target, link)
+        }
+
+        fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+            self.inner.unlink_dir(link)
+        }
+
+        fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+            self.inner.read_link(link)
+        }
+
+        fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.copy_permissions(from, to)
+        }
+
+        fn create_empty(&self, path: &Path) -> io::Result<()> {
+            self.inner.create_empty(path)
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.attempt(|| self.inner.copy(from, to))
+        }
+
+        fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+            self.inner.same_copy(a, b)
+        }
+
+        fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+            self.inner.identity(path)
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            self.inner.read(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.inner.write(path, contents)
+        }
+    }
+
+    fn retrying(fs: &Arc<Flaky>, attempts: u32) -> Retrying {
+        let policy = RetryPolicy {
+            attempts,
+            delay: Duration::ZERO,
+        };
+        Retrying::new(Box::new(fs.clone()), Arc::new(RwLock::new(policy)))
+    }
+
+    #[test]
+    fn busy_files_are_transient_and_the_rest_are_not() {
+        let cases = [
+            (io::ErrorKind::ResourceBusy, true),
+            (io::ErrorKind::ExecutableFileBusy, true),
+            (io::ErrorKind::PermissionDenied, false),
+            (io::ErrorKind::NotFound, false),
+            (io::ErrorKind::AlreadyExists, false),
+            (io::ErrorKind::StorageFull, false),
+            (io::ErrorKind::Other, false),
+        ];
+        for (kind, transient) in cases {
+            assert_eq!(
+                is_transient(&io::Error::from(kind)),
+                transient,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn sharing_and_lock_violations_are_transient() {
+        for (code, transient) in [(5, true), (32, true), (33, true), (2, false)] {
+            let e = io::Error::from_raw_os_error(code);
+            assert_eq!(is_transient(&e), transient, "{}", code);
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_tried_again_until_they_are_gone() {
+        let fs = Flaky::new(io::ErrorKind::ResourceBusy, 2);
+        let ops = retrying(&fs, 3);
+        ops.hard_link(Path::new("/a"), Path::new("/b")).unwrap();
+        assert_eq!(fs.calls(), 3);
+        assert!(fs
+            .inner
+            .same_file(Path::new("/a"), Path::new("/b"))
+            .unwrap());
+    }
+
+    #[test]
+    fn transient_errors_are_given_up_on_after_the_attempts() {
+        let fs = Flaky::new(io::ErrorKind::ResourceBusy, 5);
+        let ops = retrying(&fs, 3);
+        let e = ops.remove_file(Path::new("/a")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(fs.calls(), 3);
+        assert!(fs.exists(Path::new("/a")));
+    }
+
+    #[test]
+    fn permanent_errors_are_tried_once() {
+        let fs = Flaky::new(io::ErrorKind::PermissionDenied, 1);
+        let ops = retrying(&fs, 3);
+        let e = ops.rename(Path::new("/a"), Path::new("/b")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.calls(), 1);
+    }
+
+    #[test]
+    fn no_attempts_are_one_attempt() {
+        for attempts in [0, 1] {
+            let fs = Flaky::new(io::ErrorKind::ResourceBusy, 1);
+            let ops = retrying(&fs, attempts);
+            assert!(ops.remove_file(Path::new("/a")).is_err());
+            assert_eq!(fs.calls(), 1, "{}", attempts);
+        }
+    }
+
+    #[test]
+    fn copies_are_not_tried_again() {
+        let fs = Flaky::new(io::ErrorKind::ResourceBusy, 1);
+        let ops = retrying(&fs, 3);
+        assert!(ops.copy(Path::new("/a"), Path::new("/b")).is_err());
+        assert_eq!(fs.calls(), 1);
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long a file that appeared in an input is held by default, on top of the debounce
+/// of the watchers, for the same file to appear in other inputs too.
+pub(crate) const DEFAULT_WINDOW: Duration = Duration::from_millis(50);
+
+/// Holds back the files that appear in inputs for a moment, keyed by their path in the
+/// output, so that when the same one appears in several inputs at once only the winner's
+/// is put there. See `Overlay::set_cross_input_window`.
+#[derive(Debug, Default)]
+pub(crate) struct Gathering {
+    held: HashMap<PathBuf, (Vec<usize>, Instant)>,
+}
+
+impl Gathering {
+    /// Holds the file that appeared at `path` in input `index` until `due`, or until the
+    /// path is due already if another input's file at it is held.
+    pub(crate) fn hold(&mut self, index: usize, path: &Path, due: Instant) {
+        let (inputs, _) = self
+            .held
+            .entry(path.to_path_buf())
+            .or_insert_with(|| (vec![], due));
+        if !inputs.contains(&index) {
+            inputs.push(index);
+        }
+    }
+
+    /// Forgets the file of input `index` at `path`, which is gone or went elsewhere.
+    pub(crate) fn forget(&mut self, index: usize, path: &Path) {
+        if let Some((inputs, _)) = self.held.get_mut(path) {
+            inputs.retain(|&held| held != index);
+            if inputs.is_empty() {
+                self.held.remove(path);
+            }
+        }
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.held.values().map(|(_, due)| *due).min()
+    }
+
+    /// Removes and returns the paths that are due by `now`, or every path if `all`, with
+    /// the inputs their files appeared in.
+    pub(crate) fn take_due(&mut self, now: Instant, all: bool) -> Vec<(PathBuf, Vec<usize>)> {
+        let due: Vec<PathBuf> = self
+            .held
+            .iter()
+            .filter(|(_, (_, due))| all || *due <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|path| {
+                let (inputs, _) = self.held.remove(&path)?;
+                Some((path, inputs))
+            })
+            .collect()
+    }
+}
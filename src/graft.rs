@@ -0,0 +1,502 @@
+use crate::audit::AuditAction;
+use crate::key::Folded;
+use crate::{fold, Overlay, ProcessedAction, Strategy};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The directories of the inputs linked into the output whole, with the `Hybrid` strategy.
+#[derive(Debug, Default)]
+pub(crate) struct Grafts {
+    /// Directories linked into the output whole, and the input they are from.
+    pub(crate) dirs: HashMap<PathBuf, usize>,
+    /// Directories that might be grafted again, as they lost a provider of something in
+    /// them.
+    pub(crate) collapsible: BTreeSet<PathBuf>,
+}
+
+impl Overlay {
+    /// Returns the graft `path` is in, or is, and the input it is from.
+    pub(crate) fn graft_of(&self, path: &Path) -> Option<(PathBuf, usize)> {
+        path.ancestors()
+            .find_map(|ancestor| self.grafts.dirs.get_key_value(ancestor))
+            .map(|(graft, index)| (graft.clone(), *index))
+    }
+
+    /// Whether the output has a link to the directory its graft at `graft` is from.
+    pub(crate) fn graft_intact(&self, graft: &Path) -> bool {
+        let target = self.inputs[self.grafts.dirs[graft]].source(graft);
+        self.dry_run || self.fs.read_link(&self.output.join(graft)).ok() == Some(target)
+    }
+
+    /// Whether the output has a link to directory `relative` of input `index` at `relative`.
+    fn linked_to(&self, index: usize, relative: &Path) -> bool {
+        let target = self.inputs[index].source(relative);
+        self.fs.read_link(&self.output.join(relative)).ok() == Some(target)
+    }
+
+    /// Whether `path` in the output is a directory link into one of the inputs, which only
+    /// an earlier run could have left there.
+    pub(crate) fn stale_link(&self, path: &Path) -> bool {
+        !self.grafts.dirs.contains_key(path)
+            && match self.fs.read_link(&self.output.join(path)) {
+                Ok(target) => self
+                    .inputs
+                    .iter()
+                    .any(|input| target.starts_with(&input.path)),
+                Err(_) => false,
+            }
+    }
+
+    /// Replaces the stale links at and above `path` with directories, so nothing is done to
+    /// the inputs through them.
+    pub(crate) fn remove_stale_links(&mut self, path: &Path) {
+        let mut stale: Vec<PathBuf> = path
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty() && self.stale_link(ancestor))
+            .map(Path::to_path_buf)
+            .collect();
+        // The outermost first, the others are gone with it.
+        stale.reverse();
+
+        for link in stale {
+            if self.stale_link(&link) {
+                say!(self.line, Info, " STALE LINK {} REMOVED,", link.display());
+                self.note(|report| report.stale_removed += 1);
+                let output_link = self.output.join(&link);
+                let _ = self.unlink_dir(&output_link);
+                if link != path {
+                    let _ = self.create_dir_all(&output_link);
+                }
+            }
+        }
+    }
+
+    /// Whether directory `relative` of input `index` can be linked into the output whole,
+    /// i.e. nothing else is or could be anywhere in it.
+    pub(crate) fn graftable(&self, index: usize, relative: &Path) -> bool {
+        // Paths that only differ by case would end up in the graft on some volumes.
+        let folded = fold(relative);
+        let overlaps = |path: &Path| {
+            let path = fold(path);
+            path.starts_with(&folded) || folded.starts_with(&path)
+        };
+
+        self.strategy == Strategy::Hybrid
+            && !relative.as_os_str().is_empty()
+            && self.inputs[index].can_graft(relative)
+            && !self.inputs[index].is_archive()
+            && !self.globally_ignored_within(index, relative)
+            && (!self.fs.exists(&self.output.join(relative)) || self.linked_to(index, relative))
+            && self
+                .tracked_around(relative)
+                .into_iter()
+                .all(|key| self.input_map[key].is_empty())
+            && !self.grafts.dirs.keys().any(|graft| overlaps(graft))
+            && self
+                .inputs
+                .iter()
+                .all(|other| other.index == index || !self.fs.exists(&other.source(relative)))
+    }
+
+    /// Whether the overlay's own rules ignore anything in directory `relative` of input
+    /// `index`, which a graft of it would show.
+    fn globally_ignored_within(&self, index: usize, relative: &Path) -> bool {
+        if self.global_ignore.is_empty() {
+            return false;
+        }
+
+        let root = &self.inputs[index].path;
+        WalkDir::new(self.inputs[index].source(relative))
+            .into_iter()
+            .filter_map(Result::ok)
+            .any(|entry| {
+                let path = entry.path().strip_prefix(root).unwrap();
+                self.global_ignore.ignores(path, entry.file_type().is_dir())
+            })
+    }
+
+    /// Links directory `relative` of input `index` into the output, tracking every file in
+    /// it as visible. Returns `false` if the link couldn't be made.
+    pub(crate) fn graft(&mut self, index: usize, relative: &Path) -> bool {
+        let target = self.inputs[index].source(relative);
+        let link = self.output.join(relative);
+        if let Some(parent) = link.parent() {
+            let _ = self.create_dir_all(parent);
+        }
+        // A link left by an earlier run is taken over as it is.
+        if self.linked_to(index, relative) {
+            self.note(|report| report.confirmed += 1);
+        } else {
+            match self.link_dir(&target, &link) {
+                Ok(()) => {
+                    self.processed(ProcessedAction::Linked);
+                    self.note(|report| report.linked += 1);
+                }
+                Err(e) => {
+                    say!(self.line, Warn, " NOT GRAFTED: {},", e);
+                    self.link_failed(relative, index, &target, &link, &e);
+                    return false;
+                }
+            }
+        }
+
+        let found = self.inputs[index].walk(relative);
+        say!(self.line, Info, " GRAFTED {} PATHS!", found.len());
+        for file in found {
+            self.measure(index, &file);
+            let input = self.inputs[index].ranked();
+            self.track(&file, &Folded::new(&file)).push(input);
+            self.stats.input_visible(index, self.size(index, &file));
+        }
+        self.grafts.dirs.insert(relative.to_path_buf(), index);
+
+        self.stats.linked();
+        self.audit(AuditAction::Link, Some(relative), index, None);
+        true
+    }
+
+    /// Grafts the directories that lost a provider of something in them again, where only
+    /// one input is left there. The outermost such directory is grafted.
+    pub(crate) fn collapse_grafts(&mut self) {
+        let candidates = std::mem::take(&mut self.grafts.collapsible);
+        if self.strategy != Strategy::Hybrid {
+            return;
+        }
+
+        let mut grafted: Vec<PathBuf> = vec![];
+        // Sorted, so a directory comes before those in it.
+        for dir in candidates {
+            if grafted.iter().any(|graft| dir.starts_with(graft)) {
+                continue;
+            }
+            let providers: BTreeSet<usize> = self
+                .tree
+                .beneath(&dir)
+                .into_iter()
+                .chain(Some(dir.as_path()))
+                .filter(|key| key.starts_with(&dir))
+                .filter_map(|key| self.input_map.get(key))
+                .flat_map(|heap| heap.iter().map(|input| input.index))
+                .collect();
+            let index = match providers.iter().next() {
+                Some(index) if providers.len() == 1 => *index,
+                _ => continue,
+            };
+            if self.collapsible(index, &dir) && self.collapse(index, &dir) {
+                grafted.push(dir);
+            }
+        }
+    }
+
+    /// Whether the files of input `index` in directory `relative` of the output, linked one
+    /// by one, can be replaced with a graft of the directory.
+    fn collapsible(&self, index: usize, relative: &Path) -> bool {
+        let folded = fold(relative);
+        let overlaps = |path: &Path| {
+            let path = fold(path);
+            path.starts_with(&folded) || folded.starts_with(&path)
+        };
+        let output_dir = self.output.join(relative);
+        let own_graft = |path: &Path| {
+            self.grafts
+                .dirs
+                .iter()
+                .any(|(graft, owner)| *owner == index && path.starts_with(graft))
+        };
+
+        self.inputs[index].enabled
+            && self.inputs[index].can_graft(relative)
+            && !self.inputs[index].is_archive()
+            && self.graft_of(relative).is_none()
+            && self.fs.is_dir(&self.inputs[index].source(relative))
+            && self.fs.is_dir(&output_dir)
+            && self.fs.read_link(&output_dir).is_err()
+            && !self.globally_ignored_within(index, relative)
+            && self.tracked_around(relative).into_iter().all(|key| {
+                key.starts_with(relative)
+                    && !self.blocked.contains(key)
+                    && self.input_map[key].iter().all(|input| input.index == index)
+            })
+            && self
+                .grafts
+                .dirs
+                .iter()
+                .all(|(graft, owner)| *owner == index || !overlaps(graft))
+            && self
+                .inputs
+                .iter()
+                .all(|other| other.index == index || !self.fs.exists(&other.source(relative)))
+            // Nothing that isn't the input's may go along with the directory.
+            && WalkDir::new(&output_dir)
+                .min_depth(1)
+                .into_iter()
+                .filter_entry(|entry| {
+                    let path = entry.path().strip_prefix(&self.output).unwrap();
+                    !own_graft(path)
+                })
+                .all(|entry| match entry {
+                    Ok(entry) if entry.file_type().is_dir() => true,
+                    Ok(entry) => {
+                        let path = entry.path().strip_prefix(&self.output).unwrap();
+                        self.materialized(path).map(|input| input.index) == Some(index)
+                            && self.provides(index, &self.inputs[index].source(path), entry.path())
+                    }
+                    Err(_) => false,
+                })
+    }
+
+    /// Replaces the links to each file of input `index` in directory `relative` of the
+    /// output with a graft of the directory. Returns `false`, with the files linked again,
+    /// if that didn't work.
+    fn collapse(&mut self, index: usize, relative: &Path) -> bool {
+        self.line
+            .begin(format_args!("Collapsing {}:", relative.display()));
+        let mut inner: Vec<PathBuf> = self
+            .grafts
+            .dirs
+            .keys()
+            .filter(|graft| graft.starts_with(relative))
+            .cloned()
+            .collect();
+        inner.sort();
+        for graft in &inner {
+            self.grafts.dirs.remove(graft);
+            let _ = self.unlink_dir(&self.output.join(graft));
+        }
+
+        let files = self.provided_under(index, relative);
+        for key in &files {
+            let _ = self.remove_link(key);
+            self.ledger.remove(key);
+        }
+
+        let link = self.output.join(relative);
+        let removed = self.dry_run || {
+            let dirs: Vec<PathBuf> = WalkDir::new(&link)
+                .contents_first(true)
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|entry| entry.into_path())
+                .collect();
+            dirs.iter().all(|dir| self.fs.remove_dir(dir).is_ok())
+        };
+        let target = self.inputs[index].source(relative);
+        let linked = removed
+            && match self.link_dir(&target, &link) {
+                Ok(()) => true,
+                Err(e) => {
+                    self.link_failed(relative, index, &target, &link, &e);
+                    false
+                }
+            };
+        if !linked {
+            say!(self.line, Error, " NOT GRAFTED!");
+            let _ = self.create_dir_all(&link);
+            for key in &files {
+                self.place(key, &Folded::new(key));
+            }
+            self.line.end();
+            return false;
+        }
+
+        say!(self.line, Info, " GRAFTED {} PATHS!", files.len());
+        self.grafts.dirs.insert(relative.to_path_buf(), index);
+        self.stats.linked();
+        self.audit(AuditAction::Link, Some(relative), index, None);
+        self.line.end();
+        true
+    }
+
+    /// Puts the link of the graft at `graft` back in place of whatever link is there.
+    pub(crate) fn regraft(&mut self, graft: &Path) -> bool {
+        let index = self.grafts.dirs[graft];
+        let target = self.inputs[index].source(graft);
+        let link = self.output.join(graft);
+
+        let _ = self.unlink_dir(&link);
+        if let Some(parent) = link.parent() {
+            let _ = self.create_dir_all(parent);
+        }
+        match self.link_dir(&target, &link) {
+            Ok(()) => {
+                say!(self.line, Info, " GRAFTED!");
+                true
+            }
+            Err(e) => {
+                say!(self.line, Error, " NOT GRAFTED: {}!", e);
+                self.link_failed(graft, index, &target, &link, &e);
+                false
+            }
+        }
+    }
+
+    /// Replaces the graft at `graft` with links to each of its files, so that other inputs
+    /// can provide paths in it too.
+    pub(crate) fn ungraft(&mut self, graft: &Path) {
+        let index = match self.grafts.dirs.remove(graft) {
+            Some(index) => index,
+            None => return,
+        };
+
+        say!(self.line, Info, " UNGRAFTING {},", graft.display());
+        let link = self.output.join(graft);
+        let _ = self.unlink_dir(&link);
+        let _ = self.create_dir_all(&link);
+
+        for key in self.provided_under(index, graft) {
+            self.stats.input_hidden(index, self.size(index, &key));
+            if self.fs.exists(&self.inputs[index].source(&key)) {
+                self.place(&key, &Folded::new(&key));
+            } else {
+                // Already gone from the input, e.g. moved out of the directory.
+                let heap = self.input_map.get_mut(&key).unwrap();
+                heap.retain(|input| input.index != index);
+            }
+        }
+    }
+
+    /// Breaks up the grafts of other inputs that input `index` is about to provide
+    /// something in, or in place of.
+    pub(crate) fn ungraft_around(&mut self, index: usize, path: &Path) {
+        let mut grafts: Vec<PathBuf> = self
+            .grafts
+            .dirs
+            .iter()
+            .filter(|(graft, owner)| {
+                let (graft, path) = (fold(graft), fold(path));
+                **owner != index && (graft.starts_with(&path) || path.starts_with(&graft))
+            })
+            .map(|(graft, _)| graft.clone())
+            .collect();
+        grafts.sort();
+
+        for graft in grafts {
+            self.ungraft(&graft);
+        }
+    }
+
+    /// Takes the grafts of input `index` at or beneath `path` out of the output, after
+    /// their directories went away in the input. Returns where they were.
+    pub(crate) fn drop_grafts(&mut self, index: usize, path: &Path) -> Vec<PathBuf> {
+        let mut grafts: Vec<PathBuf> = self
+            .grafts
+            .dirs
+            .iter()
+            .filter(|(graft, owner)| **owner == index && graft.starts_with(path))
+            .map(|(graft, _)| graft.clone())
+            .collect();
+        grafts.sort();
+
+        for graft in &grafts {
+            say!(self.line, Info, " UNGRAFTED {},", graft.display());
+            self.grafts.dirs.remove(graft);
+            let _ = self.unlink_dir(&self.output.join(graft));
+
+            for key in self.provided_under(index, graft) {
+                let heap = self.input_map.get_mut(&key).unwrap();
+                heap.retain(|input| input.index != index);
+                self.stats.input_hidden(index, self.size(index, &key));
+            }
+
+            self.stats.unlinked();
+            self.audit(AuditAction::Unlink, Some(graft), index, None);
+            if let Some(parent) = graft.parent() {
+                self.remove_empty_dirs(parent);
+            }
+        }
+
+        grafts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::{scratch, Harness};
+    use crate::{canonical, Event, EventType, OverlayBuilder, RealFs};
+    use std::fs;
+
+    #[test]
+    fn a_directory_of_one_input_is_grafted_until_another_has_something_in_it() {
+        let mut harness = Harness::with("graft", &[0, 1], |builder| {
+            builder.strategy(Strategy::Hybrid)
+        });
+        // Walked on disk, and linked from memory.
+        for path in ["music/a.ogg", "music/album/b.ogg"] {
+            let file = harness.inputs[0].join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, path).unwrap();
+            harness.fs.create_file(&file);
+        }
+        harness.event(0, Event::Create(PathBuf::from("music")));
+        let link = harness.output.join("music");
+        let target = harness.inputs[0].join("music");
+        assert_eq!(harness.fs.read_link(&link).unwrap(), target);
+        let paths: Vec<PathBuf> = harness.overlay.list().map(|entry| entry.path).collect();
+        assert_eq!(
+            paths,
+            ["music/a.ogg", "music/album/b.ogg"].map(PathBuf::from)
+        );
+
+        harness.create(1, "music/album/c.ogg");
+        assert!(harness.fs.read_link(&link).is_err());
+        assert_eq!(harness.winner("music/a.ogg"), Some(0));
+        assert_eq!(harness.winner("music/album/b.ogg"), Some(0));
+        assert_eq!(harness.winner("music/album/c.ogg"), Some(1));
+        assert!(harness.overlay.failures.is_empty());
+    }
+
+    #[test]
+    fn a_grafted_directory_is_broken_up_and_grafted_again_as_providers_come_and_go() {
+        let root = scratch("graft-collapse");
+        let (base, mods, output) = (root.join("base"), root.join("mods"), root.join("output"));
+        for path in ["music/a.ogg", "music/album/b.ogg", "textures/rock.dds"] {
+            let input = if path.starts_with("music") {
+                &base
+            } else {
+                &mods
+            };
+            let file = input.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, path).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .input(&base, 0)
+            .input(&mods, 1)
+            .strategy(Strategy::Hybrid)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        let grafted = |what: &str| fs::read_link(output.join(what)).ok();
+        assert_eq!(grafted("music"), Some(canonical(&base).join("music")));
+        assert_eq!(grafted("textures"), Some(canonical(&mods).join("textures")));
+        assert!(overlay.diff().is_empty());
+
+        fs::create_dir_all(mods.join("music/album")).unwrap();
+        fs::write(mods.join("music/album/c.ogg"), "c").unwrap();
+        overlay.apply_event(EventType::new(1, Event::Create(PathBuf::from("music"))));
+        overlay.finish_links();
+        assert_eq!(grafted("music"), None);
+        assert!(RealFs
+            .same_file(&base.join("music/a.ogg"), &output.join("music/a.ogg"))
+            .unwrap());
+        assert!(RealFs
+            .same_file(
+                &mods.join("music/album/c.ogg"),
+                &output.join("music/album/c.ogg")
+            )
+            .unwrap());
+        assert!(overlay.diff().is_empty());
+
+        fs::remove_dir_all(mods.join("music")).unwrap();
+        overlay.apply_event(EventType::new(1, Event::Remove(PathBuf::from("music"))));
+        overlay.finish_links();
+        overlay.collapse_grafts();
+        assert_eq!(grafted("music"), Some(canonical(&base).join("music")));
+        assert!(overlay.diff().is_empty());
+        assert!(overlay.failures.is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::{Command, Controller, InputId, InputStats};
+use crossbeam_channel::bounded;
+use failure::Error;
+
+/// What an `InputHandle` asks of its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputCommand {
+    SetPriority(u32),
+    SetEnabled(bool),
+    Remove,
+    Stats,
+}
+
+/// One input of a running overlay, to change and look at without passing its id along,
+/// see `Controller::input`. It can be sent to and cloned for other threads.
+///
+/// Like other commands its requests are answered once `process_loop` runs and is done
+/// syncing, so a handle taken before the overlay starts works once it did. Each of them
+/// errs rather than doing anything once the input was removed, however that happened.
+#[derive(Debug, Clone)]
+pub struct InputHandle {
+    controller: Controller,
+    id: InputId,
+}
+
+impl InputHandle {
+    pub(crate) fn new(controller: Controller, id: InputId) -> Self {
+        InputHandle { controller, id }
+    }
+
+    pub fn id(&self) -> InputId {
+        self.id
+    }
+
+    /// See `Overlay::set_priority`.
+    pub fn set_priority(&self, priority: u32) -> Result<(), Error> {
+        self.send(InputCommand::SetPriority(priority)).map(drop)
+    }
+
+    /// Disables the input, see `Overlay::set_enabled`.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.send(InputCommand::SetEnabled(false)).map(drop)
+    }
+
+    pub fn resume(&self) -> Result<(), Error> {
+        self.send(InputCommand::SetEnabled(true)).map(drop)
+    }
+
+    /// See `Overlay::remove_input`. The handle, and every clone of it, errs from then on.
+    pub fn remove(&self) -> Result<(), Error> {
+        self.send(InputCommand::Remove).map(drop)
+    }
+
+    pub fn stats(&self) -> Result<InputStats, Error> {
+        self.send(InputCommand::Stats)
+    }
+
+    pub fn label(&self) -> Result<Option<String>, Error> {
+        Ok(self.stats()?.label)
+    }
+
+    /// Has the overlay do `command`, answered with the stats of the input after.
+    fn send(&self, command: InputCommand) -> Result<InputStats, Error> {
+        let (tx, rx) = bounded(1);
+        self.controller.send(Command::Input(self.id, command, tx))?;
+        rx.recv()?
+    }
+}
@@ -0,0 +1,229 @@
+use crate::Overlay;
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use log::warn;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const DEFAULT_QUEUE: usize = 64;
+
+/// Runs the configured `on_link`/`on_unlink` commands on a background thread.
+///
+/// Each command is a program followed by its arguments, in which `{path}` (relative path),
+/// `{output}` (absolute output path) and `{input}` (input index) are substituted. Nothing
+/// is passed through a shell.
+#[derive(Debug)]
+pub(crate) struct Hooks {
+    on_link: Option<Vec<String>>,
+    on_unlink: Option<Vec<String>>,
+    queue: Sender<Vec<String>>,
+}
+
+impl Hooks {
+    pub(crate) fn new(
+        on_link: Option<Vec<String>>,
+        on_unlink: Option<Vec<String>>,
+        timeout: Duration,
+        queue: usize,
+    ) -> Self {
+        let (tx, rx) = bounded::<Vec<String>>(queue);
+
+        thread::spawn(move || {
+            for command in rx {
+                run(&command, timeout);
+            }
+        });
+
+        Hooks {
+            on_link,
+            on_unlink,
+            queue: tx,
+        }
+    }
+
+    pub(crate) fn linked(&self, path: &Path, output: &Path, input: usize) {
+        if let Some(template) = &self.on_link {
+            self.queue(template, path, output, input);
+        }
+    }
+
+    pub(crate) fn unlinked(&self, path: &Path, output: &Path, input: usize) {
+        if let Some(template) = &self.on_unlink {
+            self.queue(template, path, output, input);
+        }
+    }
+
+    fn queue(&self, template: &[String], path: &Path, output: &Path, input: usize) {
+        let path = path.to_string_lossy();
+        let output = output.to_string_lossy();
+        let input = input.to_string();
+
+        let command = template
+            .iter()
+            .map(|arg| {
+                arg.replace("{path}", &path)
+                    .replace("{output}", &output)
+                    .replace("{input}", &input)
+            })
+            .collect();
+
+        match self.queue.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => {
+                warn!("Hook queue is full, dropping {:?}", command);
+            }
+            Err(TrySendError::Disconnected(command)) => {
+                warn!("Hook runner has stopped, dropping {:?}", command);
+            }
+        }
+    }
+}
+
+fn run(command: &[String], timeout: Duration) {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let mut child = match Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Hook {:?} failed to start: {}", command, e);
+            return;
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    warn!("Hook {:?} exited with {}", command, status);
+                }
+                return;
+            }
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                warn!("Hook {:?} timed out after {:?}", command, timeout);
+                return;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                warn!("Hook {:?} could not be waited on: {}", command, e);
+                return;
+            }
+        }
+    }
+}
+
+impl Overlay {
+    pub(crate) fn run_hooks(&self, linked: bool, path: &Path, output: &Path, index: usize) {
+        if self.dry_run {
+            return;
+        }
+
+        if let Some(hooks) = &self.hooks {
+            if linked {
+                hooks.linked(path, output, index);
+            } else {
+                hooks.unlinked(path, output, index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use crossbeam_channel::Receiver;
+
+    /// Hooks whose commands wait in a queue of `size` for the test to take them, rather than
+    /// being run. An empty command is none.
+    pub(crate) fn held(
+        on_link: &[&str],
+        on_unlink: &[&str],
+        size: usize,
+    ) -> (Hooks, Receiver<Vec<String>>) {
+        let command = |args: &[&str]| match args {
+            [] => None,
+            args => Some(args.iter().map(|arg| arg.to_string()).collect()),
+        };
+        let (tx, rx) = bounded(size);
+        let hooks = Hooks {
+            on_link: command(on_link),
+            on_unlink: command(on_unlink),
+            queue: tx,
+        };
+        (hooks, rx)
+    }
+
+    #[test]
+    fn the_placeholders_of_a_command_are_filled_in() {
+        let (hooks, queued) = held(
+            &["notify", "--file={path}", "{output}", "{input}{input}"],
+            &["forget", "{path}"],
+            4,
+        );
+        let path = Path::new("Data/Sky.dds");
+        let output = Path::new("/game/Data/Sky.dds");
+        hooks.linked(path, output, 3);
+        hooks.unlinked(path, output, 1);
+        assert_eq!(
+            queued.try_recv().unwrap(),
+            ["notify", "--file=Data/Sky.dds", "/game/Data/Sky.dds", "33"]
+        );
+        assert_eq!(queued.try_recv().unwrap(), ["forget", "Data/Sky.dds"]);
+        assert!(queued.is_empty());
+    }
+
+    #[test]
+    fn hooks_past_a_full_queue_are_dropped() {
+        let (hooks, queued) = held(&["notify", "{path}"], &[], 2);
+        for path in ["a", "b", "c", "d"] {
+            hooks.linked(Path::new(path), Path::new("/out"), 0);
+        }
+        let commands: Vec<Vec<String>> = queued.try_iter().collect();
+        assert_eq!(commands, [["notify", "a"], ["notify", "b"]]);
+
+        // Nor does a hook without a command queue anything.
+        hooks.unlinked(Path::new("e"), Path::new("/out"), 0);
+        assert!(queued.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_hook_running_too_long_is_killed() {
+        let start = Instant::now();
+        run(
+            &["sleep".to_string(), "30".to_string()],
+            Duration::from_millis(50),
+        );
+        let took = start.elapsed();
+        assert!(took >= Duration::from_millis(50), "{:?}", took);
+        assert!(took < Duration::from_secs(10), "{:?}", took);
+    }
+
+    #[test]
+    fn a_dry_run_runs_no_hooks() {
+        let mut harness = Harness::with("dry-run-hooks", &[0], |builder| builder.dry_run(true));
+        let (hooks, queued) = held(&["linked", "{path}"], &["unlinked", "{path}"], 8);
+        harness.overlay.hooks = Some(hooks);
+        harness.create(0, "a");
+        harness.remove(0, "a");
+        assert!(queued.is_empty());
+
+        harness.overlay.dry_run = false;
+        harness.create(0, "b");
+        harness.remove(0, "b");
+        let commands: Vec<Vec<String>> = queued.try_iter().collect();
+        assert_eq!(commands, [["linked", "b"], ["unlinked", "b"]]);
+    }
+}
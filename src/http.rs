@@ -0,0 +1,252 @@
+use crate::{Controller, Phase, WatcherHealth};
+use failure::{format_err, Error};
+use log::{debug, info, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the head of a request may be, far more than any health check sends.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// A tiny HTTP server answering health checks of a running overlay, see
+/// `OverlayBuilder::http_status`. It stops when this is dropped.
+#[derive(Debug)]
+pub(crate) struct HttpStatus {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl HttpStatus {
+    /// Starts answering requests at `address` through `controller`, each connection on a
+    /// thread of its own.
+    pub(crate) fn serve(address: &str, controller: Controller) -> Result<Self, Error> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format_err!("Could not serve the status on {}: {}", address, e))?;
+        let address = listener.local_addr()?;
+        info!("Serving the status on http://{}", address);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopping = stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let controller = controller.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = respond(&controller, stream) {
+                                debug!("Status request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Could not accept a status request: {}", e),
+                }
+            }
+        });
+
+        Ok(HttpStatus { address, stopped })
+    }
+
+    pub(crate) fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for HttpStatus {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the listener up, so it sees it was stopped and lets go of the port.
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+/// Reads the request on `stream` and answers it, closing the connection after.
+fn respond(controller: &Controller, stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are of no interest, only read so the client doesn't see a reset.
+    let mut head = request.len();
+    loop {
+        let mut header = String::new();
+        let read = reader.read_line(&mut header)?;
+        head += read;
+        if read == 0 || header.trim().is_empty() || head > MAX_HEAD {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, body) = match (method, path) {
+        ("GET", "/healthz") => healthz(controller),
+        ("GET", "/status") => status(controller),
+        (_, "/healthz") | (_, "/status") => (405, "method not allowed\n".to_string()),
+        _ => (404, "not found\n".to_string()),
+    };
+    let content_type = if status == 200 && path == "/status" {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+
+    write!(
+        &stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        body
+    )?;
+    (&stream).flush()?;
+    Ok(())
+}
+
+/// Healthy once the initial sync completed, for as long as every watcher is.
+fn healthz(controller: &Controller) -> (u16, String) {
+    let phase = controller.phase();
+    if phase != Phase::Ready {
+        return (503, format!("{:?}\n", phase).to_lowercase());
+    }
+    let stats = match controller.stats() {
+        Ok(stats) => stats,
+        Err(e) => return (503, format!("{}\n", e)),
+    };
+
+    let unhealthy: Vec<String> = stats
+        .inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| input.watcher != WatcherHealth::Healthy)
+        .map(|(index, input)| match &input.label {
+            Some(label) => format!("{} ({})", index, label),
+            None => index.to_string(),
+        })
+        .collect();
+    if unhealthy.is_empty() {
+        (200, "ok\n".to_string())
+    } else {
+        (
+            503,
+            format!("unhealthy watchers: {}\n", unhealthy.join(", ")),
+        )
+    }
+}
+
+/// The stats as JSON, once the overlay is ready to tell them.
+fn status(controller: &Controller) -> (u16, String) {
+    let phase = controller.phase();
+    if phase != Phase::Ready {
+        return (503, format!("{:?}\n", phase).to_lowercase());
+    }
+    match controller
+        .stats()
+        .map(|stats| serde_json::to_string(&stats))
+    {
+        Ok(Ok(json)) => (200, json),
+        Ok(Err(e)) => (500, format!("{}\n", e)),
+        Err(e) => (503, format!("{}\n", e)),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::Harness;
+    use crate::{InputId, ReplaySource};
+    use failure::err_msg;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// The status and the body of the answer to `method path`.
+    fn request(address: SocketAddr, method: &str, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: overlay\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        let (head, body) = answer.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn health_is_that_of_the_sync_and_the_watchers() {
+        let source = Arc::new(ReplaySource::new());
+        let harness = Harness::with("http-status", &[0], |builder| {
+            builder.event_source(source.clone())
+        });
+        let mut overlay = harness.overlay;
+        let address = overlay.serve_http_status("127.0.0.1:0").unwrap();
+        assert_ne!(address.port(), 0);
+        assert_eq!(
+            request(address, "GET", "/healthz"),
+            (503, "starting\n".into())
+        );
+        assert_eq!(request(address, "GET", "/status").0, 503);
+
+        let controller = overlay.controller();
+        let running = thread::spawn(move || overlay.process_loop());
+        assert!(controller.wait_ready());
+        assert_eq!(
+            request(address, "GET", "/healthz?probe=1"),
+            (200, "ok\n".into())
+        );
+        let (status, body) = request(address, "GET", "/status");
+        assert_eq!(status, 200);
+        let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(stats["inputs"].as_array().unwrap().len(), 1);
+        assert_eq!(request(address, "POST", "/healthz").0, 405);
+        assert_eq!(request(address, "GET", "/").0, 404);
+
+        source.fail(InputId::of(0), err_msg("gone"));
+        let until = Instant::now() + Duration::from_secs(10);
+        let unhealthy = loop {
+            let answer = request(address, "GET", "/healthz");
+            if answer.0 != 200 {
+                break answer;
+            }
+            assert!(Instant::now() < until, "the watcher took too long to fail");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(unhealthy, (503, "unhealthy watchers: 0\n".into()));
+
+        controller.shutdown().unwrap();
+        running.join().unwrap().unwrap();
+        // The port is let go of with the overlay.
+        let until = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(address).is_ok() {
+            assert!(Instant::now() < until, "the port is still taken");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// What tells a file apart from any other: the volume it is on and its number there, the
+/// device and inode on Unix and the volume serial number and file index on Windows.
+///
+/// The size and the time it was last modified are kept too, so that a copy can be told
+/// apart from one that was changed since. They are all there is to go by where the
+/// filesystem doesn't number its files, like some network filesystems, whose files have
+/// number 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileIdentity {
+    pub volume: u64,
+    pub index: u64,
+    pub len: u64,
+    /// Nanoseconds since the Unix epoch, if the filesystem keeps the time.
+    pub modified: Option<u128>,
+}
+
+impl FileIdentity {
+    /// The identity of the file at `path`, without following a link there.
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let (volume, index) = match number(path, &metadata) {
+            Ok(number) => number,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+            // Only the size and time tell it apart then.
+            Err(_) => (0, 0),
+        };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos());
+
+        Ok(FileIdentity {
+            volume,
+            index,
+            len: metadata.len(),
+            modified,
+        })
+    }
+
+    /// The volume and number of the file, unless the filesystem didn't tell them.
+    pub fn number(&self) -> Option<(u64, u64)> {
+        Some((self.volume, self.index)).filter(|&(_, index)| index != 0)
+    }
+
+    /// Whether this is the file `recorded` was, and unchanged since. Without the numbers
+    /// of both, a file of the same size and time, like a copy that kept it, is taken for
+    /// it, and one without a time never is.
+    pub fn matches(&self, recorded: &FileIdentity) -> bool {
+        match (self.number(), recorded.number()) {
+            (Some(_), Some(_)) => self == recorded,
+            _ => {
+                self.len == recorded.len
+                    && self.modified.is_some()
+                    && self.modified == recorded.modified
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn number(_path: &Path, metadata: &fs::Metadata) -> io::Result<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(windows)]
+fn number(path: &Path, _metadata: &fs::Metadata) -> io::Result<(u64, u64)> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // FILE_FLAG_BACKUP_SEMANTICS, for directories, and FILE_FLAG_OPEN_REPARSE_POINT, so
+    // that a junction is not followed. Nothing of the file has to be readable.
+    let file = fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(0x0200_0000 | 0x0020_0000)
+        .open(path)?;
+    let information = winapi_util::file::information(&file)?;
+    Ok((information.volume_serial_number(), information.file_index()))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn number(_path: &Path, _metadata: &fs::Metadata) -> io::Result<(u64, u64)> {
+    Err(io::Error::other("file identities are not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    #[test]
+    fn links_to_a_file_are_it() {
+        let dir = scratch("identity-links");
+        let (file, link) = (dir.join("file"), dir.join("link"));
+        fs::write(&file, "data").unwrap();
+        fs::hard_link(&file, &link).unwrap();
+        let identity = FileIdentity::of(&file).unwrap();
+        assert_eq!(FileIdentity::of(&link).unwrap(), identity);
+        assert!(FileIdentity::of(&link).unwrap().matches(&identity));
+        assert_eq!(identity.len, 4);
+        assert!(identity.number().is_some());
+    }
+
+    #[test]
+    fn copies_and_other_files_are_not() {
+        let dir = scratch("identity-copies");
+        let (file, copy) = (dir.join("file"), dir.join("copy"));
+        fs::write(&file, "data").unwrap();
+        fs::copy(&file, &copy).unwrap();
+        let identity = FileIdentity::of(&file).unwrap();
+        let other = FileIdentity::of(&copy).unwrap();
+        assert_ne!(other.number(), identity.number());
+        assert!(!other.matches(&identity));
+    }
+
+    #[test]
+    fn a_changed_file_is_not_what_it_was() {
+        let dir = scratch("identity-changed");
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        let recorded = FileIdentity::of(&file).unwrap();
+        fs::write(&file, "more data").unwrap();
+        let identity = FileIdentity::of(&file).unwrap();
+        assert_eq!(identity.number(), recorded.number());
+        assert!(!identity.matches(&recorded));
+    }
+
+    #[test]
+    fn a_missing_file_has_none() {
+        let dir = scratch("identity-missing");
+        let error = FileIdentity::of(&dir.join("missing")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_is_not_followed() {
+        let dir = scratch("identity-symlink");
+        let (file, link) = (dir.join("file"), dir.join("link"));
+        fs::write(&file, "data").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        let identity = FileIdentity::of(&link).unwrap();
+        assert_ne!(identity.number(), FileIdentity::of(&file).unwrap().number());
+    }
+
+    /// An identity of `len` bytes modified at `modified`, numbered `index` or not at
+    /// all.
+    fn identity(index: u64, len: u64, modified: Option<u128>) -> FileIdentity {
+        FileIdentity {
+            volume: if index == 0 { 0 } else { 1 },
+            index,
+            len,
+            modified,
+        }
+    }
+
+    #[test]
+    fn without_numbers_the_size_and_time_tell_files_apart() {
+        let recorded = identity(0, 4, Some(10));
+        assert_eq!(recorded.number(), None);
+        assert!(identity(0, 4, Some(10)).matches(&recorded));
+        assert!(!identity(0, 5, Some(10)).matches(&recorded));
+        assert!(!identity(0, 4, Some(11)).matches(&recorded));
+    }
+
+    #[test]
+    fn without_numbers_or_a_time_files_never_match() {
+        let recorded = identity(0, 4, None);
+        assert!(!identity(0, 4, None).matches(&recorded));
+        assert!(!identity(0, 4, Some(10)).matches(&recorded));
+        assert!(!recorded.matches(&identity(0, 4, Some(10))));
+    }
+
+    #[test]
+    fn only_one_number_falls_back_to_the_size_and_time() {
+        // Like a file recorded before its filesystem was mounted another way.
+        let recorded = identity(0, 4, Some(10));
+        assert!(identity(7, 4, Some(10)).matches(&recorded));
+        assert!(recorded.matches(&identity(7, 4, Some(10))));
+        assert!(!identity(7, 4, Some(11)).matches(&recorded));
+    }
+
+    #[test]
+    fn with_numbers_the_size_and_time_alone_dont_do() {
+        let recorded = identity(7, 4, Some(10));
+        assert!(identity(7, 4, Some(10)).matches(&recorded));
+        assert!(!identity(8, 4, Some(10)).matches(&recorded));
+        // The numbers are enough without a time.
+        assert!(identity(7, 4, None).matches(&identity(7, 4, None)));
+    }
+}
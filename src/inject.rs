@@ -0,0 +1,131 @@
+use crate::source::EventSink;
+use crate::{EventType, InputId, Overlay};
+use failure::Error;
+use notify::DebouncedEvent;
+use std::path::PathBuf;
+
+/// A change to an input made up for `Overlay::inject_event`, with paths relative to the
+/// input as its watcher would see them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntheticEvent {
+    Create(PathBuf),
+    Remove(PathBuf),
+    Rename(PathBuf, PathBuf),
+    /// The file was written to.
+    Modify(PathBuf),
+}
+
+impl Overlay {
+    /// Hands `event` to the overlay as if the watcher of input `id` reported it, to be
+    /// handled by the next `drain`. Only with the `test-util` feature, for testing what is
+    /// built on an overlay without watching anything; it isn't meant for production use.
+    ///
+    /// The files are looked at as the event is handled, so they have to be there, or gone,
+    /// in the input, or in the `FileOps` the overlay was built with.
+    pub fn inject_event(&mut self, id: InputId, event: SyntheticEvent) -> Result<(), Error> {
+        let index = self.existing_input(id)?;
+        let input = &self.inputs[index];
+        let sink = EventSink::new(
+            index,
+            &input.path,
+            input.keys.clone(),
+            input.poll_interval,
+            self.injected.0.clone(),
+        );
+
+        let path = |relative: PathBuf| input.path.join(relative);
+        let event = match event {
+            SyntheticEvent::Create(relative) => DebouncedEvent::Create(path(relative)),
+            SyntheticEvent::Remove(relative) => DebouncedEvent::Remove(path(relative)),
+            SyntheticEvent::Rename(from, to) => DebouncedEvent::Rename(path(from), path(to)),
+            SyntheticEvent::Modify(relative) => DebouncedEvent::Write(path(relative)),
+        };
+        sink.send(event);
+        Ok(())
+    }
+
+    /// Handles every injected event like `process_loop` handles those of the watchers,
+    /// then what is throttled, and whatever waits to be tried again or to settle and is due
+    /// by now. Only with the `test-util` feature.
+    pub fn drain(&mut self) -> Result<(), Error> {
+        loop {
+            let batch: Vec<EventType> = self.injected.1.try_iter().collect();
+            if batch.is_empty() {
+                break;
+            }
+            self.process_batch(batch)?;
+        }
+        self.process_gathered(true);
+        self.process_throttled(true);
+        self.process_retries();
+        self.process_settling();
+        self.collapse_grafts();
+        self.save_ledger();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use crate::FileOps;
+    use std::time::Duration;
+
+    #[test]
+    fn injected_events_are_handled_once_drained() {
+        let mut harness = Harness::with("injected", &[0, 1], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        let (base, mods) = (InputId::of(0), InputId::of(1));
+        let path = PathBuf::from;
+        for (index, id) in [(0, base), (1, mods)] {
+            harness.fs.create_file(harness.inputs[index].join("a"));
+            harness
+                .overlay
+                .inject_event(id, SyntheticEvent::Create(path("a")))
+                .unwrap();
+        }
+        assert!(!harness.in_output("a"));
+        harness.overlay.drain().unwrap();
+        assert_eq!(harness.winner("a"), Some(1));
+
+        harness
+            .overlay
+            .inject_event(mods, SyntheticEvent::Modify(path("a")))
+            .unwrap();
+        harness.overlay.drain().unwrap();
+        assert_eq!(harness.winner("a"), Some(1));
+        let (from, to) = (harness.inputs[1].join("a"), harness.inputs[1].join("b"));
+        harness.fs.rename(&from, &to).unwrap();
+        harness
+            .overlay
+            .inject_event(mods, SyntheticEvent::Rename(path("a"), path("b")))
+            .unwrap();
+        harness.overlay.drain().unwrap();
+        assert_eq!(harness.winner("a"), Some(0));
+        assert_eq!(harness.winner("b"), Some(1));
+
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("a"))
+            .unwrap();
+        harness
+            .overlay
+            .inject_event(base, SyntheticEvent::Remove(path("a")))
+            .unwrap();
+        harness.overlay.drain().unwrap();
+        assert!(
+            harness.overlay.failures.is_empty(),
+            "{:?}",
+            harness.overlay.failures
+        );
+        assert!(!harness.in_output("a"));
+
+        let error = harness
+            .overlay
+            .inject_event(InputId::of(2), SyntheticEvent::Create(path("c")))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "there is no input 2");
+    }
+}
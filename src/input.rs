@@ -0,0 +1,1066 @@
+#[cfg(feature = "archives")]
+use crate::archive::{Archive, ArchiveFs, Archives};
+#[cfg(feature = "watch")]
+use crate::backoff::Backoff;
+use crate::case::OutputCase;
+use crate::engine::Ranked;
+use crate::filter::{Filter, IgnoreFile, InputOptions};
+#[cfg(feature = "archives")]
+use crate::fs_ops::RealFs;
+use crate::link::LinkStrategy;
+use crate::probe::LinkProbe;
+use crate::rewrite::{mapping, KeyMap};
+use crate::{canonical, ledger, lock, ConfigError, Event, EventType, InputId, Overlay};
+use crossbeam_channel::unbounded;
+use failure::{format_err, Error};
+use ignore::{WalkBuilder, WalkState};
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+#[derive(Debug)]
+pub(crate) struct Input {
+    pub(crate) index: usize,
+    pub(crate) label: Option<String>,
+    pub(crate) enabled: bool,
+    /// Whether it was taken out of the overlay, and is only kept so its id stays taken.
+    pub(crate) removed: bool,
+    /// Where its files are taken from, the directory given or the one of it it is mapped
+    /// from.
+    pub(crate) path: PathBuf,
+    /// Where its files go in the output.
+    pub(crate) keys: Arc<KeyMap>,
+    pub(crate) rank: Rank,
+    pub(crate) filter: Filter,
+    /// Whether its files can be hard linked into the output, once that was tried.
+    pub(crate) probe: Option<LinkProbe>,
+    /// Whether its files are copied into the output, because they can't be linked.
+    pub(crate) copies: bool,
+    /// How its files are put into the output, if not by hard linking or copying them.
+    pub(crate) strategy: Option<Arc<dyn LinkStrategy>>,
+    /// Whether the overlay may write into it.
+    pub(crate) writable: bool,
+    /// How often it is polled, rather than as often as the source polls every input.
+    pub(crate) poll_interval: Option<Duration>,
+    /// How long its changed files have to stay the same before they are looked at.
+    pub(crate) settle: Option<Duration>,
+    /// How old its files have to be before they are linked.
+    pub(crate) min_age: Option<Duration>,
+    #[cfg(feature = "watch")]
+    /// When its watcher is restarted after failing.
+    pub(crate) backoff: Backoff,
+    /// What it holds, if it is an archive rather than a directory.
+    #[cfg(feature = "archives")]
+    pub(crate) archive: Option<Arc<Archive>>,
+}
+
+/// How far apart `move_input_above` and `move_input_below` put priorities, so something
+/// fits in between later.
+const PRIORITY_GAP: u32 = 16;
+
+/// A priority above `below` and under `over`, if there is one, halfway in between or a
+/// gap away from the one of them there is.
+fn free_priority(below: Option<u32>, over: Option<u32>) -> Option<u32> {
+    let (low, high) = (below.map_or(-1, i64::from), over.map_or(1 << 32, i64::from));
+    if high - low < 2 {
+        return None;
+    }
+    let priority = match (below, over) {
+        (Some(_), Some(_)) => low + (high - low) / 2,
+        (Some(below), None) => (i64::from(below) + i64::from(PRIORITY_GAP)).min(high - 1),
+        (None, Some(over)) => (i64::from(over) - i64::from(PRIORITY_GAP)).max(0),
+        (None, None) => 0,
+    };
+    Some(priority as u32)
+}
+
+/// The new ranks of the inputs, which have `ranks`, for input `index` to go right above or
+/// below input `reference`, see `Overlay::move_input_above`.
+pub(crate) fn moved(
+    ranks: &[Rank],
+    index: usize,
+    reference: usize,
+    above: bool,
+) -> Vec<(usize, Rank)> {
+    if index == reference {
+        return vec![];
+    }
+    let group = ranks[reference].group;
+    let mut order: Vec<(u32, usize)> = ranks
+        .iter()
+        .enumerate()
+        .filter(|(other, rank)| rank.group == group && *other != index)
+        .map(|(other, rank)| (rank.priority, other))
+        .collect();
+    order.sort_unstable();
+    let at = order
+        .iter()
+        .position(|(_, other)| *other == reference)
+        .unwrap()
+        + usize::from(above);
+
+    let below = at.checked_sub(1).map(|below| order[below].0);
+    let over = order.get(at).map(|(priority, _)| *priority);
+    order.insert(at, (0, index));
+
+    match free_priority(below, over) {
+        Some(priority) => vec![(index, Rank { group, priority })],
+        None => order
+            .iter()
+            .enumerate()
+            .map(|(position, (_, index))| {
+                let priority = (position as u32 + 1) * PRIORITY_GAP;
+                (*index, Rank { group, priority })
+            })
+            .collect(),
+    }
+}
+
+/// Where an input stands among the others: first by the priority of its group, then by
+/// its own priority within the group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Rank {
+    pub(crate) group: u32,
+    pub(crate) priority: u32,
+}
+
+impl Input {
+    pub(crate) fn id(&self) -> InputId {
+        InputId::of(self.index)
+    }
+
+    /// What the heaps of the paths it provides hold of it.
+    pub(crate) fn ranked(&self) -> Ranked {
+        Ranked {
+            index: self.index,
+            rank: self.rank,
+        }
+    }
+
+    /// Where the file at `key` in the output is in this input. The directories it is
+    /// mounted in are all of it, anything else is nowhere, an empty path.
+    pub(crate) fn source(&self, key: &Path) -> PathBuf {
+        match self.relative(key) {
+            Some(relative) => self.path.join(relative),
+            None => PathBuf::new(),
+        }
+    }
+
+    /// Where the file at `relative` in this input goes in the output.
+    pub(crate) fn key(&self, relative: &Path) -> PathBuf {
+        self.keys.key(relative)
+    }
+
+    /// What of this input the output's `key` stands for, its root for the directories it
+    /// is mounted in, `None` if it is outside of them.
+    pub(crate) fn relative(&self, key: &Path) -> Option<PathBuf> {
+        match self.keys.relative(key) {
+            Some(relative) => Some(relative),
+            None if self.keys.mount().starts_with(key) => Some(PathBuf::new()),
+            None => None,
+        }
+    }
+
+    /// Whether the filter accepts the file the output has at `key` from this input.
+    pub(crate) fn accepts_file(&self, key: &Path) -> bool {
+        self.keys
+            .relative(key)
+            .is_some_and(|relative| self.filter.accepts_file(&relative))
+    }
+
+    /// Whether its directories can be linked into the output whole, with every file of
+    /// them where it is in the input.
+    pub(crate) fn can_graft(&self, key: &Path) -> bool {
+        key.starts_with(self.keys.mount()) && !self.keys.rewrites() && self.filter.is_empty()
+    }
+
+    /// Returns every file beneath `relative` that this input's filter accepts, as paths
+    /// in the output.
+    pub(crate) fn walk(&self, relative: &Path) -> Vec<PathBuf> {
+        #[cfg(feature = "archives")]
+        if let Some(archive) = &self.archive {
+            return archive.walk(relative, &self.filter);
+        }
+        let relative = match self.relative(relative) {
+            Some(relative) => relative,
+            None => return vec![],
+        };
+
+        // Directories are read in parallel, which matters most where each read waits on
+        // the network. The order they come in doesn't matter, each path is decided alone.
+        let mut walker = WalkBuilder::new(self.path.join(&relative));
+        walker.standard_filters(false);
+        if let Some(depth) = self.filter.max_depth() {
+            let below = depth.saturating_sub(relative.components().count());
+            walker.max_depth(Some(below));
+        }
+        let (root, filter) = (self.path.clone(), self.filter.clone());
+        // Excluded directories aren't descended into at all.
+        walker.filter_entry(move |entry| {
+            !entry.file_type().is_some_and(|kind| kind.is_dir())
+                || filter.accepts_dir(entry.path().strip_prefix(&root).unwrap())
+        });
+
+        let (tx, rx) = unbounded();
+        walker.build_parallel().run(|| {
+            let tx = tx.clone();
+            Box::new(move |entry| {
+                if let Ok(entry) = entry {
+                    if entry.file_type().is_some_and(|kind| kind.is_file()) {
+                        let _ = tx.send(entry.into_path());
+                    }
+                }
+                WalkState::Continue
+            })
+        });
+        drop(tx);
+
+        let mut files: Vec<PathBuf> = rx
+            .into_iter()
+            .map(|path| path.strip_prefix(&self.path).unwrap().to_path_buf())
+            .filter(|path| self.filter.accepts_file(path))
+            .map(|path| self.key(&path))
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Returns the files and directories directly in `relative` that this input's filter
+    /// accepts, as paths in the output. Of the directories it is mounted in, that is the
+    /// one it is mounted at.
+    pub(crate) fn children(&self, relative: &Path) -> Vec<PathBuf> {
+        #[cfg(feature = "archives")]
+        if let Some(archive) = &self.archive {
+            return archive.children(relative, &self.filter);
+        }
+        let mount = self.keys.mount();
+        let relative = match self.keys.relative(relative) {
+            Some(relative) => relative,
+            None if mount.starts_with(relative) && self.path.is_dir() => {
+                return vec![mount.to_path_buf()];
+            }
+            None => return vec![],
+        };
+
+        WalkDir::new(self.path.join(relative))
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let path = entry.path().strip_prefix(&self.path).unwrap().to_path_buf();
+                let accepted = if entry.file_type().is_dir() {
+                    self.filter.accepts_dir(&path)
+                } else {
+                    entry.file_type().is_file() && self.filter.accepts_file(&path)
+                };
+                Some(self.key(&path)).filter(|_| accepted)
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "archives")]
+impl Input {
+    /// Whether it is an archive rather than a directory.
+    pub(crate) fn is_archive(&self) -> bool {
+        self.archive.is_some()
+    }
+
+    /// Whether the archive isn't the one last read anymore, going by when it was modified.
+    pub(crate) fn archive_changed(&self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        self.archive
+            .as_ref()
+            .is_some_and(|archive| archive.modified() != modified)
+    }
+}
+
+#[cfg(not(feature = "archives"))]
+impl Input {
+    pub(crate) fn is_archive(&self) -> bool {
+        false
+    }
+}
+
+impl Overlay {
+    /// Adds the directory at `path` as an input. Errs if it already is one, however it is
+    /// spelled, unless duplicates are merged, see `set_merge_duplicate_inputs`.
+    pub fn add_input<P: AsRef<Path>>(&mut self, path: P, priority: u32) -> Result<InputId, Error> {
+        let rank = Rank { group: 0, priority };
+        if let Some(index) = self.duplicate(path.as_ref(), rank)? {
+            return Ok(InputId::of(index));
+        }
+        self.check_input(path.as_ref(), false)?;
+        let index = self.push_input(path.as_ref(), None, true, false, rank, Filter::default());
+        Ok(InputId::of(index))
+    }
+
+    /// Adds the zip archive at `path` as an input, whose files are extracted into the
+    /// output rather than linked. It is read-only, and replacing or changing it is like
+    /// changing the files of an input.
+    ///
+    /// Extracted files are given the time the archive was last modified, so that after a
+    /// restart only those of an archive that changed are extracted again.
+    #[cfg(feature = "archives")]
+    pub fn add_archive_input<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        priority: u32,
+    ) -> Result<InputId, Error> {
+        let path = path.as_ref();
+        let rank = Rank { group: 0, priority };
+        if let Some(index) = self.duplicate(path, rank)? {
+            return Ok(InputId::of(index));
+        }
+        self.check_input(path, true)?;
+        let archive = Archive::open(path)
+            .map_err(|e| format_err!("couldn't read the archive {}: {}", path.display(), e))?;
+        let archive = Arc::new(archive);
+
+        let archives = match &self.archives {
+            Some(archives) => archives.clone(),
+            None => {
+                let archives = Archives::default();
+                let fs = std::mem::replace(&mut self.fs, Arc::new(RealFs));
+                self.fs = Arc::new(ArchiveFs::new(Box::new(fs), archives.clone()));
+                self.archives = Some(archives.clone());
+                archives
+            }
+        };
+        archives
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), archive.clone());
+
+        let index = self.push_input(path, None, true, false, rank, Filter::default());
+        self.inputs[index].archive = Some(archive);
+        Ok(InputId::of(index))
+    }
+
+    /// Adds an input of group `group`, which comes before every input of a lower group
+    /// whatever their priorities.
+    pub fn add_input_in_group<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        group: u32,
+        priority: u32,
+    ) -> Result<InputId, Error> {
+        let rank = Rank { group, priority };
+        if let Some(index) = self.duplicate(path.as_ref(), rank)? {
+            return Ok(InputId::of(index));
+        }
+        self.check_input(path.as_ref(), false)?;
+        let index = self.push_input(path.as_ref(), None, true, false, rank, Filter::default());
+        Ok(InputId::of(index))
+    }
+
+    /// Adds the directory `source` of the directory at `path` as an input whose files go
+    /// into directory `mount` of the output, e.g. the `textures` of a mod as
+    /// `Data/Textures`. Where its files end up, they compete with those of the other inputs.
+    pub fn add_input_mapped<P: AsRef<Path>, S: AsRef<Path>, M: AsRef<Path>>(
+        &mut self,
+        path: P,
+        priority: u32,
+        source: S,
+        mount: M,
+    ) -> Result<InputId, Error> {
+        let options = InputOptions::new()
+            .source(source.as_ref())
+            .mount(mount.as_ref());
+        self.add_input_with_options(path, priority, &options)
+    }
+
+    /// Adds an input that only contributes the files `options` accepts.
+    pub fn add_input_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        priority: u32,
+        options: &InputOptions,
+    ) -> Result<InputId, Error> {
+        if let Some(label) = &options.label {
+            if self.input_by_label(label).is_some() {
+                return Err(ConfigError::DuplicateLabel {
+                    path: path.as_ref().to_path_buf(),
+                    label: label.clone(),
+                }
+                .into());
+            }
+        }
+        let (path, keys) = mapping(path.as_ref(), options)?;
+
+        let rank = Rank {
+            group: options.group,
+            priority,
+        };
+        if let Some(index) = self.duplicate(&path, rank)? {
+            return Ok(InputId::of(index));
+        }
+        self.check_input(&path, false)?;
+        let filter = Filter::new(options)?;
+        let label = options.label.clone();
+        let (enabled, writable) = (options.enabled, options.writable);
+        let index = self.push_input(&path, label, enabled, writable, rank, filter);
+        keys.set_lower(self.case.output == OutputCase::Lower);
+        self.inputs[index].keys = Arc::new(keys);
+        self.inputs[index].poll_interval = options.poll_interval_ms.map(Duration::from_millis);
+        self.inputs[index].settle = options.settle_ms.map(Duration::from_millis);
+        self.inputs[index].min_age = options.min_age_ms.map(Duration::from_millis);
+        Ok(InputId::of(index))
+    }
+
+    /// The input `path` already is, if it is one. Errs then, unless duplicates are merged,
+    /// in which case the input is given `rank` if that is higher than its own.
+    fn duplicate(&mut self, path: &Path, rank: Rank) -> Result<Option<usize>, Error> {
+        let path = canonical(path);
+        let index = match self
+            .inputs
+            .iter()
+            .position(|input| !input.removed && canonical(&input.path) == path)
+        {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let name = self.input_name(index);
+        if !self.merge_duplicate_inputs {
+            return Err(ConfigError::DuplicateInput { path, input: name }.into());
+        }
+        if rank > self.inputs[index].rank {
+            self.rerank(vec![(index, rank)]);
+        }
+        warn!(
+            "{} already is input {}, which keeps its options but ranks as the higher of the two",
+            path.display(),
+            name
+        );
+        Ok(Some(index))
+    }
+
+    /// Errs if `path` can't be an input: if there is nothing there, if it isn't a directory
+    /// that can be read, unless it is an `archive`, or if it overlaps another input or what
+    /// the overlay writes to, unless overlaps aren't checked.
+    fn check_input(&self, path: &Path, archive: bool) -> Result<(), ConfigError> {
+        check_readable(path, archive)?;
+        if !self.check_overlaps {
+            return Ok(());
+        }
+        let root = canonical(path);
+        let overlaps = |other: &Path| root.starts_with(other) || other.starts_with(&root);
+        if let Some(input) = self
+            .inputs
+            .iter()
+            .find(|input| !input.removed && overlaps(&canonical(&input.path)))
+        {
+            return Err(ConfigError::OverlapsInput {
+                path: path.to_path_buf(),
+                input: self.input_name(input.index),
+            });
+        }
+        let mut written = vec![
+            ("the output", self.output.clone()),
+            ("the ledger", ledger::ledger_path(&self.output)),
+            ("the lock file", lock::lock_path(&self.output)),
+        ];
+        if let Some((dir, _)) = &self.trash_dir {
+            written.push(("the trash", dir.clone()));
+        }
+        for (what, other) in written {
+            if overlaps(&canonical(&other)) {
+                return Err(ConfigError::OverlapsOutput {
+                    path: path.to_path_buf(),
+                    what,
+                    other,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn push_input(
+        &mut self,
+        path: &Path,
+        label: Option<String>,
+        enabled: bool,
+        writable: bool,
+        rank: Rank,
+        mut filter: Filter,
+    ) -> usize {
+        if !writable {
+            self.protected.write().unwrap().protect(path);
+        }
+        // Read once the overlay starts, when the input is walked.
+        filter.set_ignore(IgnoreFile::default());
+        filter.set_global(self.global_ignore.clone());
+        let keys = KeyMap::default();
+        keys.set_lower(self.case.output == OutputCase::Lower);
+        self.inputs.push(Input {
+            index: self.inputs.len(),
+            label: label.clone(),
+            enabled,
+            removed: false,
+            path: path.to_path_buf(),
+            keys: Arc::new(keys),
+            rank,
+            filter,
+            probe: None,
+            copies: false,
+            strategy: None,
+            writable,
+            poll_interval: None,
+            settle: None,
+            min_age: None,
+            #[cfg(feature = "watch")]
+            backoff: Backoff::new(),
+            #[cfg(feature = "archives")]
+            archive: None,
+        });
+        self.stats.add_input(label);
+
+        self.inputs.len() - 1
+    }
+
+    /// Returns the id of the input labelled `label`.
+    pub fn input_by_label(&self, label: &str) -> Option<InputId> {
+        self.inputs
+            .iter()
+            .find(|input| !input.removed && input.label.as_deref() == Some(label))
+            .map(|input| InputId::of(input.index))
+    }
+
+    /// Returns the id of the input labelled `name`, or else numbered it, or else at the
+    /// path `name`.
+    pub fn find_input(&self, name: &str) -> Result<InputId, Error> {
+        let labels: Vec<Option<&str>> = self
+            .inputs
+            .iter()
+            .map(|input| input.label.as_deref().filter(|_| !input.removed))
+            .collect();
+        let paths: Vec<Option<&Path>> = self
+            .inputs
+            .iter()
+            .map(|input| Some(input.path.as_path()).filter(|_| !input.removed))
+            .collect();
+        find_input(&labels, &paths, name)
+    }
+
+    /// Where the data of input `id` is, erring if there is no such input or it was removed.
+    pub(crate) fn existing_input(&self, id: InputId) -> Result<usize, Error> {
+        match self.inputs.get(id.index()) {
+            Some(input) if input.removed => Err(format_err!("input {} was removed", id)),
+            Some(input) => Ok(input.index),
+            None => Err(format_err!("there is no input {}", id)),
+        }
+    }
+
+    /// Takes input `id` out of the overlay: its files leave the output, whatever they hid
+    /// takes their place, and its changes are ignored from then on. Its id isn't given to
+    /// another input, so whatever still holds on to it gets an error rather than the wrong
+    /// input.
+    pub fn remove_input(&mut self, id: InputId) -> Result<(), Error> {
+        let index = self.existing_input(id)?;
+        self.set_enabled(id, false)?;
+        self.inputs[index].removed = true;
+        info!("Removed input {}", self.input_name(index));
+        Ok(())
+    }
+
+    pub fn label(&self, id: InputId) -> Option<&str> {
+        self.inputs.get(id.index())?.label.as_deref()
+    }
+
+    /// Puts the files of input `id` into the output with `strategy` from now on, rather
+    /// than hard linking or copying them. Files already there are only made again once
+    /// they change, and are removed by the strategy that made them.
+    pub fn set_link_strategy(
+        &mut self,
+        id: InputId,
+        strategy: Arc<dyn LinkStrategy>,
+    ) -> Result<(), Error> {
+        let index = self.existing_input(id)?;
+        self.inputs[index].strategy = Some(strategy);
+        Ok(())
+    }
+
+    /// Moves input `id` to `priority` within its group, relinking the paths whose winner
+    /// changes.
+    pub fn set_priority(&mut self, id: InputId, priority: u32) -> Result<(), Error> {
+        let index = self.existing_input(id)?;
+        let rank = Rank {
+            priority,
+            ..self.inputs[index].rank
+        };
+        self.rerank(vec![(index, rank)]);
+        Ok(())
+    }
+
+    /// Moves every input of group `group` to group `to`, keeping their priorities, and
+    /// relinks the paths whose winner changes all in one pass.
+    pub fn set_group_priority(&mut self, group: u32, to: u32) {
+        let changes = self
+            .inputs
+            .iter()
+            .filter(|input| input.rank.group == group)
+            .map(|input| {
+                (
+                    input.index,
+                    Rank {
+                        group: to,
+                        ..input.rank
+                    },
+                )
+            })
+            .collect();
+        self.rerank(changes);
+    }
+
+    /// Moves input `id` into the group of input `reference`, right above it, relinking the
+    /// paths whose winner changes.
+    ///
+    /// It takes a priority between those of `reference` and the input above it. If there
+    /// is no number left in between, the priorities of the group are spread out again, in
+    /// the same order, which only changes the numbers.
+    pub fn move_input_above(&mut self, id: InputId, reference: InputId) -> Result<(), Error> {
+        self.move_input(id, reference, true)
+    }
+
+    /// Moves input `id` into the group of input `reference`, right below it, see
+    /// `move_input_above`.
+    pub fn move_input_below(&mut self, id: InputId, reference: InputId) -> Result<(), Error> {
+        self.move_input(id, reference, false)
+    }
+
+    pub(crate) fn move_input(
+        &mut self,
+        id: InputId,
+        reference: InputId,
+        above: bool,
+    ) -> Result<(), Error> {
+        let (index, reference) = (self.existing_input(id)?, self.existing_input(reference)?);
+        let ranks: Vec<Rank> = self.inputs.iter().map(|input| input.rank).collect();
+        self.rerank(moved(&ranks, index, reference, above));
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, id: InputId) -> Result<bool, Error> {
+        let index = self.existing_input(id)?;
+        Ok(self.inputs[index].enabled)
+    }
+
+    /// Turns input `id` on or off. A disabled input keeps its place among the others
+    /// but contributes nothing: its files leave the output, whatever they hid takes their
+    /// place, and its events are ignored until it is enabled again and walked anew.
+    /// A removed input stays off, and errs like one that never was.
+    pub fn set_enabled(&mut self, id: InputId, enabled: bool) -> Result<(), Error> {
+        let index = self.existing_input(id)?;
+        if self.inputs[index].enabled == enabled {
+            return Ok(());
+        }
+
+        let event = if enabled {
+            info!("Enabling input {}", self.input_name(index));
+            // Changes to it went unnoticed while it was disabled.
+            self.load_ignore(index);
+            Event::Create(PathBuf::new())
+        } else {
+            info!("Disabling input {}", self.input_name(index));
+            Event::Remove(PathBuf::new())
+        };
+        self.inputs[index].enabled = enabled;
+        // The same as if everything in the input appeared or went away at once.
+        self.apply_event(EventType::new(index, event));
+        Ok(())
+    }
+
+    /// The index of input `index` followed by its label, for log lines.
+    pub(crate) fn input_name(&self, index: usize) -> String {
+        match self.inputs[index].label.as_deref() {
+            Some(label) => format!("{} ({})", index, label),
+            None => index.to_string(),
+        }
+    }
+}
+
+/// The id of the input named `name` among inputs labelled `labels` at `paths`, by its
+/// label, else by its id, else by its path however it is spelled, with an error listing
+/// the inputs there are if there is none. Removed inputs have no label or path.
+fn find_input(
+    labels: &[Option<&str>],
+    paths: &[Option<&Path>],
+    name: &str,
+) -> Result<InputId, Error> {
+    labels
+        .iter()
+        .position(|label| *label == Some(name))
+        .or_else(|| name.parse().ok().filter(|&index| index < labels.len()))
+        .or_else(|| {
+            let path = canonical(Path::new(name));
+            paths
+                .iter()
+                .position(|other| other.is_some_and(|other| canonical(other) == path))
+        })
+        .map(InputId::of)
+        .ok_or_else(|| {
+            let known: Vec<String> = labels
+                .iter()
+                .enumerate()
+                .map(|(index, label)| match label {
+                    Some(label) => format!("'{}'", label),
+                    None => index.to_string(),
+                })
+                .collect();
+            format_err!(
+                "unknown input '{}', the inputs are {}",
+                name,
+                known.join(", ")
+            )
+        })
+}
+
+/// Errs if there is nothing at `path`, or if it isn't a directory that can be read,
+/// unless it is an `archive`.
+pub(crate) fn check_readable(path: &Path, archive: bool) -> Result<(), ConfigError> {
+    let unreadable = |e: io::Error| match e.kind() {
+        io::ErrorKind::NotFound => ConfigError::Missing {
+            path: path.to_path_buf(),
+        },
+        _ => ConfigError::Unreadable {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        },
+    };
+    let metadata = fs::metadata(path).map_err(unreadable)?;
+    if !archive {
+        if !metadata.is_dir() {
+            return Err(ConfigError::NotADirectory {
+                path: path.to_path_buf(),
+            });
+        }
+        fs::read_dir(path).map_err(unreadable)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+    use crate::HardLinks;
+    use std::env;
+
+    #[test]
+    fn a_moved_input_takes_a_free_priority_or_spreads_the_group_out() {
+        let rank = |priority| Rank { group: 0, priority };
+        let ranks = [rank(0), rank(16), rank(32), rank(8)];
+        assert_eq!(moved(&ranks, 0, 2, true), [(0, rank(48))]);
+        assert_eq!(moved(&ranks, 2, 1, false), [(2, rank(12))]);
+        assert_eq!(moved(&ranks, 1, 1, true), []);
+        // Nothing fits below 0.
+        assert_eq!(
+            moved(&ranks, 2, 0, false),
+            [(2, rank(16)), (0, rank(32)), (3, rank(48)), (1, rank(64))]
+        );
+        // Only within the group of the reference.
+        let ranks = [
+            rank(0),
+            Rank {
+                group: 1,
+                priority: 0,
+            },
+        ];
+        assert_eq!(moved(&ranks, 1, 0, true), [(1, rank(16))]);
+        assert_eq!(free_priority(Some(u32::MAX - 1), None), Some(u32::MAX));
+        assert_eq!(free_priority(Some(u32::MAX), None), None);
+    }
+
+    #[test]
+    fn unknown_and_removed_inputs_are_errors() {
+        let mut harness = Harness::new("unknown-input", &[0, 1]);
+        let overlay = &mut harness.overlay;
+        let (kept, removed, unknown) = (InputId::of(0), InputId::of(1), InputId::of(5));
+        overlay.remove_input(removed).unwrap();
+
+        for id in [removed, unknown] {
+            assert!(overlay.input_stats(id).is_err());
+            assert!(overlay.is_enabled(id).is_err());
+            assert!(overlay.set_enabled(id, true).is_err());
+            assert!(overlay.set_priority(id, 3).is_err());
+            assert!(overlay.set_link_strategy(id, Arc::new(HardLinks)).is_err());
+            assert!(overlay.move_input_above(id, kept).is_err());
+            assert!(overlay.move_input_below(kept, id).is_err());
+        }
+        assert!(!overlay.inputs[1].enabled);
+        overlay.set_priority(kept, 3).unwrap();
+        assert_eq!(overlay.inputs[0].rank.priority, 3);
+    }
+
+    #[test]
+    fn ids_keep_naming_their_input_when_another_is_removed() {
+        let mut harness = Harness::new("stable-ids", &[0, 1, 2]);
+        for (index, path) in [(0, "x"), (1, "x"), (1, "y"), (2, "z")] {
+            harness.create(index, path);
+        }
+        harness.overlay.remove_input(InputId::of(1)).unwrap();
+        assert_eq!(harness.overlay.resolve("x").unwrap().input, InputId::of(0));
+        assert!(harness.overlay.resolve("y").is_none());
+        assert_eq!(harness.overlay.resolve("z").unwrap().input, InputId::of(2));
+
+        let input = harness.output.with_file_name("input3");
+        fs::create_dir_all(&input).unwrap();
+        harness.fs.create_dir(&input);
+        let added = harness.overlay.add_input(&input, 3).unwrap();
+        assert_eq!(added, InputId::of(3));
+        harness.inputs.push(input);
+        harness.create(3, "z");
+        assert_eq!(harness.overlay.resolve("z").unwrap().input, added);
+        harness.overlay.set_priority(InputId::of(2), 4).unwrap();
+        assert_eq!(harness.winner("z"), Some(2));
+        assert_eq!(serde_json::to_string(&added).unwrap(), "3");
+        assert_eq!(added.to_string(), "3");
+    }
+
+    #[test]
+    fn the_same_input_under_another_spelling_is_a_duplicate() {
+        let root = scratch("duplicate-spellings");
+        let input = root.join("input");
+        fs::create_dir_all(&input).unwrap();
+        let trailing = PathBuf::from(format!("{}{}", input.display(), std::path::MAIN_SEPARATOR));
+        let cwd = env::current_dir().unwrap();
+        let top = cwd.ancestors().last().unwrap();
+        let relative = cwd
+            .components()
+            .skip(1)
+            .map(|_| Path::new(".."))
+            .collect::<PathBuf>()
+            .join(input.strip_prefix(top).unwrap());
+
+        let mut overlay = Overlay::new(root.join("output"));
+        overlay.add_input(&input, 0).unwrap();
+        for spelling in [trailing, relative] {
+            let error = overlay.add_input(&spelling, 1).unwrap_err();
+            let expected = ConfigError::DuplicateInput {
+                path: canonical(&input),
+                input: "0".to_string(),
+            };
+            assert_eq!(
+                error.downcast_ref::<ConfigError>(),
+                Some(&expected),
+                "{}",
+                spelling.display()
+            );
+        }
+        assert_eq!(overlay.inputs.len(), 1);
+    }
+
+    #[test]
+    fn labels_name_the_inputs_in_what_is_reported() {
+        let log = scratch("labels-log").join("audit.jsonl");
+        let mut harness = Harness::with("labels", &[], |builder| builder.audit_log(&log));
+        for (index, label) in ["BaseGame", "HD Textures"].iter().enumerate() {
+            let input = harness.output.with_file_name(format!("input{}", index));
+            fs::create_dir_all(&input).unwrap();
+            harness.fs.create_dir(&input);
+            let options = InputOptions::new().label(*label);
+            harness
+                .overlay
+                .add_input_with_options(&input, index as u32, &options)
+                .unwrap();
+            harness.inputs.push(input);
+        }
+        harness.create(0, "sky.dds");
+        harness.create(1, "sky.dds");
+
+        let overlay = &harness.overlay;
+        assert_eq!(overlay.input_by_label("HD Textures"), Some(InputId::of(1)));
+        assert_eq!(overlay.input_by_label("MyFixes"), None);
+        assert_eq!(overlay.label(InputId::of(0)), Some("BaseGame"));
+        let entry = overlay.list().next().unwrap();
+        assert_eq!(entry.label.as_deref(), Some("HD Textures"));
+        let labels: Vec<Option<String>> = overlay
+            .providers("sky.dds")
+            .into_iter()
+            .map(|provider| provider.label)
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                Some("HD Textures".to_string()),
+                Some("BaseGame".to_string())
+            ]
+        );
+        drop(harness);
+
+        let audited: Vec<String> = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                format!("{} {}", record["action"], record["label"])
+            })
+            .collect();
+        assert_eq!(
+            audited,
+            [r#""link" "BaseGame""#, r#""replace" "HD Textures""#]
+        );
+    }
+
+    #[test]
+    fn groups_rank_above_the_priorities_within_them() {
+        let mut harness = Harness::new("groups", &[]);
+        // The base game, a DLC, and two texture mods.
+        for (index, (group, priority)) in [(0, 5), (1, 0), (2, 0), (2, 1)].iter().enumerate() {
+            let input = harness.output.with_file_name(format!("input{}", index));
+            fs::create_dir_all(&input).unwrap();
+            harness.fs.create_dir(&input);
+            harness
+                .overlay
+                .add_input_in_group(&input, *group, *priority)
+                .unwrap();
+            harness.inputs.push(input);
+        }
+        for index in 0..4 {
+            harness.create(index, "x");
+        }
+        harness.create(0, "y");
+        harness.create(1, "y");
+        harness.create(2, "z");
+        assert_eq!(harness.winner("x"), Some(3));
+        assert_eq!(harness.winner("y"), Some(1));
+        assert_eq!(harness.winner("z"), Some(2));
+
+        // The base game above everything, its inputs keeping their priority in it.
+        let linked = harness.overlay.stats.linked;
+        harness.overlay.set_group_priority(0, 3);
+        assert_eq!(
+            harness.overlay.inputs[0].rank,
+            Rank {
+                group: 3,
+                priority: 5
+            }
+        );
+        assert_eq!(harness.winner("x"), Some(0));
+        assert_eq!(harness.winner("y"), Some(0));
+        assert_eq!(harness.winner("z"), Some(2));
+        assert_eq!(harness.overlay.stats.linked, linked + 2);
+
+        // No input is in the group any more.
+        harness.overlay.set_group_priority(0, 1);
+        assert_eq!(harness.overlay.stats.linked, linked + 2);
+    }
+
+    #[test]
+    fn moving_an_input_relinks_only_the_paths_whose_winner_changes() {
+        let mut harness = Harness::new("move-input", &[0, 1, 2]);
+        for (index, path) in [(0, "x"), (2, "x"), (0, "y"), (1, "y"), (2, "z")] {
+            harness.create(index, path);
+        }
+        let (a, b, c) = (InputId::of(0), InputId::of(1), InputId::of(2));
+        let linked = harness.overlay.stats().linked;
+        harness.overlay.move_input_below(c, a).unwrap();
+        assert_eq!(harness.overlay.stats().linked, linked + 1);
+        assert_eq!(harness.winner("x"), Some(0));
+        assert_eq!(harness.winner("y"), Some(1));
+        assert_eq!(harness.winner("z"), Some(2));
+
+        harness.overlay.move_input_above(a, b).unwrap();
+        assert_eq!(harness.overlay.stats().linked, linked + 2);
+        assert_eq!(harness.winner("y"), Some(0));
+        let priorities: Vec<u32> = harness
+            .overlay
+            .snapshot()
+            .inputs
+            .iter()
+            .map(|input| input.priority)
+            .collect();
+        assert_eq!(priorities, [64, 48, 16]);
+
+        // Numbers still say where an input goes among the moved ones.
+        harness.overlay.set_priority(c, 1000).unwrap();
+        assert_eq!(harness.winner("x"), Some(2));
+    }
+
+    #[test]
+    fn inputs_are_found_by_label_id_or_path() {
+        let mut harness = Harness::new("find-input", &[0]);
+        let mods = harness.inputs[0].with_file_name("mods");
+        fs::create_dir_all(&mods).unwrap();
+        harness.fs.create_dir(&mods);
+        let overlay = &mut harness.overlay;
+        let id = overlay
+            .add_input_with_options(&mods, 1, &InputOptions::new().label("mods"))
+            .unwrap();
+        let first = InputId::of(0);
+
+        assert_eq!(overlay.find_input("mods").unwrap(), id);
+        assert_eq!(overlay.find_input("0").unwrap(), first);
+        let path = harness.inputs[0].to_str().unwrap();
+        assert_eq!(overlay.find_input(path).unwrap(), first);
+        let trailing = format!("{}{}", path, std::path::MAIN_SEPARATOR);
+        assert_eq!(overlay.find_input(&trailing).unwrap(), first);
+        let around = harness.inputs[0].join("..").join("mods");
+        assert_eq!(overlay.find_input(around.to_str().unwrap()).unwrap(), id);
+
+        let error = overlay.find_input("base").unwrap_err().to_string();
+        assert_eq!(error, "unknown input 'base', the inputs are 0, 'mods'");
+        overlay.remove_input(id).unwrap();
+        assert!(overlay.find_input("mods").is_err());
+        assert!(overlay.find_input(mods.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn add_input_rejects_what_the_options_path_does() {
+        let root = scratch("add-input-errors");
+        let (input, output, file) = (root.join("input"), root.join("output"), root.join("file"));
+        fs::create_dir_all(input.join("nested")).unwrap();
+        fs::create_dir_all(output.join("inside")).unwrap();
+        fs::write(&file, "").unwrap();
+
+        let cases = [
+            (
+                root.join("missing"),
+                ConfigError::Missing {
+                    path: root.join("missing"),
+                },
+            ),
+            (file.clone(), ConfigError::NotADirectory { path: file }),
+            (
+                input.join("nested"),
+                ConfigError::OverlapsInput {
+                    path: input.join("nested"),
+                    input: "0".to_string(),
+                },
+            ),
+            (
+                output.join("inside"),
+                ConfigError::OverlapsOutput {
+                    path: output.join("inside"),
+                    what: "the output",
+                    other: output.clone(),
+                },
+            ),
+        ];
+        for (path, expected) in cases {
+            for grouped in [false, true] {
+                let mut overlay = Overlay::new(&output);
+                overlay.add_input(&input, 0).unwrap();
+                let error = if grouped {
+                    overlay.add_input_in_group(&path, 1, 0)
+                } else {
+                    overlay.add_input(&path, 1)
+                }
+                .unwrap_err();
+                assert_eq!(
+                    error.downcast_ref::<ConfigError>(),
+                    Some(&expected),
+                    "{}",
+                    path.display()
+                );
+                assert_eq!(overlay.inputs.len(), 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Names an input of an `Overlay`, and never any other one, for as long as the overlay
+/// lives. Ids are handed out in the order the inputs are added, from 0, and not reused,
+/// so they are what callers should hold on to rather than where an input is listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InputId(u64);
+
+impl InputId {
+    /// The id of the input whose data is at `index`, which it is for good as inputs are
+    /// never moved once added.
+    pub(crate) fn of(index: usize) -> Self {
+        InputId(index as u64)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<InputId> for u64 {
+    fn from(id: InputId) -> u64 {
+        id.0
+    }
+}
+
+impl fmt::Display for InputId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
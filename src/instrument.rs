@@ -0,0 +1,306 @@
+use crate::fs_ops::{FileOp, FileOps};
+use crate::identity::FileIdentity;
+use crate::{Event, EventType};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info_span, warn, Span};
+
+// Everything is under the crate's target, with these names and fields only, so that
+// subscribers can rely on them:
+//
+// - span `sync`, with `kind`: `sync`, `resync` or `resync_incremental`
+// - span `sync_input` inside it, with `input` and `label`, for each input
+// - span `event`, with `input`, `kind` and `path`, for each event handled, also those of
+//   the files of a sync
+// - event `file_op`, with `op`, `path` and, if it failed, `error`, at debug level or at
+//   warn level if it failed
+
+/// The span of syncing every input, as `kind` does.
+pub(crate) fn sync(kind: &'static str) -> Span {
+    info_span!(target: "overlay", "sync", kind)
+}
+
+/// The span of syncing input `index` within that of the sync.
+pub(crate) fn sync_input(index: usize, label: Option<&str>) -> Span {
+    info_span!(target: "overlay", "sync_input", input = index, label)
+}
+
+/// The span of handling `event`, with the path it is about, the new one of a rename.
+pub(crate) fn event(event: &EventType) -> Span {
+    let (kind, path) = match &event.event {
+        Event::Create(path) => ("create", Some(path)),
+        Event::Remove(path) => ("remove", Some(path)),
+        Event::Rename(_, to) => ("rename", Some(to)),
+        Event::PermissionsChanged(path) => ("permissions_changed", Some(path)),
+        Event::Error(_, path) => ("error", path.as_ref()),
+        Event::WatcherFailed(_) => ("watcher_failed", None),
+        Event::Polled(_) => ("polled", None),
+    };
+    let path = path.map(|path| path.display().to_string());
+    info_span!(target: "overlay", "event", input = event.index, kind, path)
+}
+
+fn name(op: FileOp) -> &'static str {
+    match op {
+        FileOp::HardLink => "hard_link",
+        FileOp::RemoveFile => "remove_file",
+        FileOp::CreateDirAll => "create_dir_all",
+        FileOp::RemoveDir => "remove_dir",
+        FileOp::Rename => "rename",
+        FileOp::LinkDir => "link_dir",
+        FileOp::CopyPermissions => "copy_permissions",
+        FileOp::CreateEmpty => "create_empty",
+        FileOp::Copy => "copy",
+        FileOp::Write => "write",
+    }
+}
+
+/// Tells `tracing` about every change the file operations it wraps make, in the span
+/// they are made in.
+#[derive(Debug)]
+pub(crate) struct Instrumented {
+    inner: Box<dyn FileOps>,
+}
+
+impl Instrumented {
+    pub(crate) fn new(inner: Box<dyn FileOps>) -> Self {
+        Instrumented { inner }
+    }
+
+    fn record<T>(&self, op: FileOp, path: &Path, result: io::Result<T>) -> io::Result<T> {
+        let path = path.display();
+        match &result {
+            Ok(_) => debug!(target: "overlay", op = name(op), %path, "file_op"),
+            Err(e) => warn!(target: "overlay", op = name(op), %path, error = %e, "file_op"),
+        }
+        result
+    }
+}
+
+impl FileOps for Instrumented {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::HardLink, to, self.inner.hard_link(from, to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveFile, path, self.inner.remove_file(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::CreateDirAll, path, self.inner.create_dir_all(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, path, self.inner.remove_dir(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, path, self.inner.remove_dir_all(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::Rename, to, self.inner.rename(from, to))
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.record(FileOp::LinkDir, link, self.inner.link_dir(target, link))
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, link, self.inner.unlink_dir(link))
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let result = self.inner.copy_permissions(from, to);
+        self.record(FileOp::CopyPermissions, to, result)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::CreateEmpty, path, self.inner.create_empty(path))
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::Copy, to, self.inner.copy(from, to))
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        let result = self.inner.copy_with_progress(from, to, progress);
+        self.record(FileOp::Copy, to, result)
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        self.inner.identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.record(FileOp::Write, path, self.inner.write(path, contents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs_ops::{FileOp, FileOps};
+    use crate::tests::Harness;
+    use std::fmt::{self, Write};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// The fields of a span or event, as `name=value` in the order they were given.
+    #[derive(Default)]
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            let _ = write!(self.0, " {}={}", field, value);
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let _ = write!(self.0, " {}={:?}", field, value);
+        }
+    }
+
+    /// What was traced, each as the span it was in, its name and its fields.
+    #[derive(Default)]
+    struct Traced {
+        spans: Vec<String>,
+        entered: Vec<usize>,
+        lines: Vec<String>,
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Traced>>);
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut traced = self.0.lock().unwrap();
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let name = format!("{}{}", span.metadata().name(), fields.0);
+            let within = traced
+                .entered
+                .last()
+                .map(|&index| traced.spans[index].clone());
+            let line = format!("{} > {}", within.as_deref().unwrap_or("-"), name);
+            traced.lines.push(line);
+            traced.spans.push(name);
+            Id::from_u64(traced.spans.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut traced = self.0.lock().unwrap();
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let within = traced
+                .entered
+                .last()
+                .map(|&index| traced.spans[index].clone());
+            let line = format!("{} >{}", within.as_deref().unwrap_or("-"), fields.0);
+            traced.lines.push(line);
+        }
+
+        fn enter(&self, span: &Id) {
+            let index = span.into_u64() as usize - 1;
+            self.0.lock().unwrap().entered.push(index);
+        }
+
+        fn exit(&self, _: &Id) {
+            self.0.lock().unwrap().entered.pop();
+        }
+    }
+
+    #[test]
+    fn syncs_events_and_what_they_change_are_traced_in_their_spans() {
+        let mut harness = Harness::new("tracing", &[0, 1]);
+        let recorder = Recorder::default();
+        let failed = harness.output.join("y");
+        tracing::subscriber::with_default(recorder.clone(), || {
+            harness.overlay.sync_once().unwrap();
+            harness.create(1, "x");
+            harness
+                .fs
+                .fail(FileOp::HardLink, &failed, io::ErrorKind::PermissionDenied);
+            harness.create(0, "y");
+        });
+
+        let traced = recorder.0.lock().unwrap();
+        let (output, x, y) = (&harness.output, harness.output.join("x"), failed);
+        let within_x = "event input=1 kind=create path=x";
+        let within_y = "event input=0 kind=create path=y";
+        let expected = [
+            "- > sync kind=sync".to_string(),
+            // The winner's first, each from its root.
+            "sync kind=sync > sync_input input=1".to_string(),
+            "sync_input input=1 > event input=1 kind=create path=".to_string(),
+            "sync kind=sync > sync_input input=0".to_string(),
+            "sync_input input=0 > event input=0 kind=create path=".to_string(),
+            format!("- > {}", within_x),
+            format!(
+                "{} > message=file_op op=create_dir_all path={}",
+                within_x,
+                output.display()
+            ),
+            format!(
+                "{} > message=file_op op=hard_link path={}",
+                within_x,
+                x.display()
+            ),
+            format!("- > {}", within_y),
+            format!(
+                "{} > message=file_op op=create_dir_all path={}",
+                within_y,
+                output.display()
+            ),
+            format!(
+                "{} > message=file_op op=hard_link path={} error=HardLink failed",
+                within_y,
+                y.display()
+            ),
+        ];
+        assert_eq!(traced.lines, expected);
+        assert!(harness.fs.exists(&x));
+    }
+}
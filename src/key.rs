@@ -0,0 +1,71 @@
+use crate::fold;
+use rustc_hash::FxBuildHasher;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// A path in its case-folded form, as the indexes that don't care about case have it, with
+/// its hash made along with it. An event folds its path into one of these once, and looks
+/// it up in each index with it, rather than folding and hashing the path again for each.
+#[derive(Debug, Clone, Eq)]
+pub(crate) struct Folded {
+    path: PathBuf,
+    hash: u64,
+}
+
+impl Folded {
+    pub(crate) fn new(path: &Path) -> Folded {
+        Folded::of(fold(path))
+    }
+
+    /// `path`, which is folded already.
+    fn of(path: PathBuf) -> Folded {
+        let hash = FxBuildHasher.hash_one(&path);
+        Folded { path, hash }
+    }
+
+    /// The directories the path is in, innermost first, without folding them again.
+    pub(crate) fn parents(&self) -> impl Iterator<Item = Folded> + '_ {
+        self.path
+            .ancestors()
+            .skip(1)
+            .map(|parent| Folded::of(parent.to_path_buf()))
+    }
+}
+
+impl PartialEq for Folded {
+    fn eq(&self, other: &Folded) -> bool {
+        self.hash == other.hash && self.path == other.path
+    }
+}
+
+impl Hash for Folded {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_of_a_path_are_the_same_key() {
+        let key = Folded::new(Path::new("Data/Textures/Sky.DDS"));
+        assert_eq!(key, Folded::of(PathBuf::from("data/textures/sky.dds")));
+        assert_eq!(key, Folded::new(Path::new("data/TEXTURES/sky.dds")));
+        assert_ne!(key, Folded::new(Path::new("Data/Textures/Sky.png")));
+        let hash = |key: &Folded| FxBuildHasher.hash_one(key);
+        assert_eq!(
+            hash(&key),
+            hash(&Folded::new(Path::new("DATA/textures/SKY.dds")))
+        );
+    }
+
+    #[test]
+    fn parents_are_those_of_the_folded_path() {
+        let key = Folded::new(Path::new("Data/Textures/Sky.dds"));
+        let parents: Vec<Folded> = key.parents().collect();
+        let folded = ["Data/Textures", "Data", ""].map(|dir| Folded::new(Path::new(dir)));
+        assert_eq!(parents, folded);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::identity::FileIdentity;
+use crate::Overlay;
+use failure::Error;
+use log::error;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The files the overlay put into an output, the only ones it may remove or replace
+/// without going through the foreign file policy, and what they were when it did.
+///
+/// It is kept in `<output>.ledger`, beside the output, so later runs know them too.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    path: Option<PathBuf>,
+    /// Looked up and added to for every file linked, so hashed rather than sorted.
+    files: FxHashMap<PathBuf, Option<FileIdentity>>,
+    changed: bool,
+    /// Whether there was none to load, as before the first run on the output.
+    new: bool,
+}
+
+impl Ledger {
+    /// Reads the ledger of `output`, which is empty if there is none yet.
+    pub(crate) fn load(output: &Path) -> Result<Self, Error> {
+        let path = ledger_path(output);
+        let (files, new) = match fs::read(&path) {
+            Ok(bytes) => (serde_json::from_slice(&bytes)?, false),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (FxHashMap::default(), true),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Ledger {
+            path: Some(path),
+            files,
+            changed: false,
+            new,
+        })
+    }
+
+    pub(crate) fn is_new(&self) -> bool {
+        self.new
+    }
+
+    /// Has the ledger written even if nothing is put into it, so later runs know the
+    /// output is the overlay's.
+    pub(crate) fn keep(&mut self) {
+        self.changed |= self.new;
+    }
+
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// What the file at `path` was when the overlay put it there, if it could tell.
+    pub(crate) fn identity(&self, path: &Path) -> Option<&FileIdentity> {
+        self.files.get(path).and_then(Option::as_ref)
+    }
+
+    pub(crate) fn insert(&mut self, path: &Path, identity: Option<FileIdentity>) {
+        if self.files.get(path) != Some(&identity) {
+            self.files.insert(path.to_path_buf(), identity);
+            self.changed = true;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, path: &Path) {
+        self.changed |= self.files.remove(path).is_some();
+    }
+
+    /// Forgets every file, for when the output is gone with them.
+    #[cfg(feature = "watch")]
+    pub(crate) fn clear(&mut self) {
+        self.changed |= !self.files.is_empty();
+        self.files.clear();
+    }
+
+    /// Writes the ledger if it changed since it was last written. One that wasn't loaded
+    /// from an output is never written.
+    pub(crate) fn save(&mut self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) if self.changed => path,
+            _ => return Ok(()),
+        };
+
+        // Written aside and renamed, so a crash never leaves half of it.
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".new");
+        // Sorted, so the same files are always written the same.
+        let files: BTreeMap<&PathBuf, &Option<FileIdentity>> = self.files.iter().collect();
+        fs::write(&temporary, serde_json::to_vec(&files)?)?;
+        fs::rename(&temporary, path)?;
+
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// `<output>.ledger`, beside the output like the lock file.
+pub(crate) fn ledger_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.components().as_path());
+    path.push(".ledger");
+    PathBuf::from(path)
+}
+
+impl Overlay {
+    /// Puts the file that was just linked to `path` into the ledger, as it is now.
+    pub(crate) fn record(&mut self, path: &Path) {
+        let identity = self.fs.identity(&self.output.join(path)).ok();
+        self.ledger.insert(path, identity);
+    }
+
+    /// Whether the file at `path` in the output is the one the ledger has, and unchanged
+    /// since the overlay put it there.
+    pub(crate) fn owned(&self, path: &Path) -> bool {
+        let recorded = match self.ledger.identity(path) {
+            Some(recorded) => recorded,
+            None => return false,
+        };
+        self.fs
+            .identity(&self.output.join(path))
+            .is_ok_and(|identity| identity.matches(recorded))
+    }
+
+    /// Whether the overlay may remove the file at `path` in the output: the ledger has it
+    /// and it wasn't changed since, or it is the file of one of its providers, which loses
+    /// nothing by it.
+    pub(crate) fn removable(&self, path: &Path) -> bool {
+        // What it was isn't known if it was only linked in a dry run, for example.
+        if self.ledger.contains(path) && (self.ledger.identity(path).is_none() || self.owned(path))
+        {
+            return true;
+        }
+        self.provided(path)
+    }
+
+    /// Whether the file at `path` in the output is the file of one of its providers.
+    pub(crate) fn provided(&self, path: &Path) -> bool {
+        let output_file = self.output.join(path);
+        let providers = self.input_map.get(path).into_iter().flatten();
+        providers.into_iter().any(|input| {
+            let source = self.inputs[input.index].source(path);
+            self.provides(input.index, &source, &output_file)
+        })
+    }
+
+    /// Writes the ledger, unless this is a dry run and it only has what would have been
+    /// linked.
+    pub(crate) fn save_ledger(&mut self) {
+        if self.dry_run {
+            return;
+        }
+        if let Err(e) = self.ledger.save() {
+            error!("Could not save the ledger: {}", e);
+            self.failed(None, None, "ledger".to_string(), e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::{scratch, Harness};
+
+    #[test]
+    fn the_files_are_known_to_the_next_run_once_saved() {
+        let output = scratch("ledger-save").join("output");
+        let mut ledger = Ledger::load(&output).unwrap();
+        assert!(ledger.is_new());
+        ledger.save().unwrap();
+        assert!(!ledger_path(&output).exists());
+        ledger.keep();
+        ledger.save().unwrap();
+        assert!(!Ledger::load(&output).unwrap().is_new());
+
+        let file = output.with_file_name("file");
+        fs::write(&file, "").unwrap();
+        let identity = FileIdentity::of(&file).unwrap();
+        ledger.insert(Path::new("b"), Some(identity));
+        ledger.insert(Path::new("a"), None);
+        ledger.insert(Path::new("gone"), None);
+        ledger.remove(Path::new("gone"));
+        ledger.save().unwrap();
+        let saved = fs::read_to_string(ledger_path(&output)).unwrap();
+        assert!(saved.starts_with("{\"a\":null,\"b\":{"), "{}", saved);
+
+        let ledger = Ledger::load(&output).unwrap();
+        assert_eq!(ledger.identity(Path::new("b")), Some(&identity));
+        assert!(ledger.contains(Path::new("a")));
+        assert_eq!(ledger.identity(Path::new("a")), None);
+        assert!(!ledger.contains(Path::new("gone")));
+    }
+
+    #[test]
+    fn only_what_the_overlay_put_there_unchanged_is_removed_or_replaced() {
+        let mut harness = Harness::new("ledger", &[0, 1]);
+        harness.create(0, "linked");
+        harness.create(0, "edited");
+        // Something else put these there.
+        harness.fs.create_file(harness.output.join("foreign"));
+        harness
+            .fs
+            .write_file(harness.output.join("edited"), b"the user's");
+        harness.create(0, "foreign");
+        assert!(harness.overlay.ledger.contains(Path::new("linked")));
+        assert!(!harness.overlay.ledger.contains(Path::new("foreign")));
+        assert_eq!(harness.winner("foreign"), None);
+
+        harness.create(1, "linked");
+        harness.create(1, "edited");
+        assert_eq!(harness.winner("linked"), Some(1));
+        assert_eq!(harness.winner("edited"), None);
+        harness.remove(0, "linked");
+        harness.remove(1, "linked");
+        harness.remove(0, "foreign");
+        assert!(!harness.in_output("linked"));
+        assert!(!harness.overlay.ledger.contains(Path::new("linked")));
+        assert!(harness.in_output("foreign"));
+        harness.remove(0, "edited");
+        harness.remove(1, "edited");
+        assert_eq!(
+            harness.fs.read(&harness.output.join("edited")).unwrap(),
+            b"the user's"
+        );
+    }
+}
@@ -1,229 +1,2368 @@
+/// Adds a piece to the line being logged, at `level`.
+macro_rules! say {
+    ($line:expr, $level:ident, $($arg:tt)+) => {
+        $line.say(log::Level::$level, format_args!($($arg)+))
+    };
+}
+
+mod adoption;
+#[cfg(feature = "archives")]
+mod archive;
+mod audit;
+#[cfg(feature = "watch")]
+mod backoff;
+mod builder;
+mod case;
+mod config;
+#[cfg(feature = "watch")]
+mod control;
+mod doctor;
+mod engine;
+mod error;
+mod filter;
+mod fs_ops;
+#[cfg(feature = "watch")]
+mod gather;
+mod graft;
+#[cfg(feature = "watch")]
+mod handle;
+mod hooks;
+#[cfg(feature = "http-status")]
+mod http;
+mod identity;
+#[cfg(feature = "test-util")]
+mod inject;
+mod input;
+mod input_id;
+#[cfg(feature = "tracing")]
+mod instrument;
+mod key;
+mod ledger;
+mod link;
+mod load_order;
+mod lock;
+mod log_line;
+mod manifest;
+mod merge;
+#[cfg(feature = "watch")]
+mod poll;
+mod preview;
+mod probe;
+mod progress;
+#[cfg(feature = "watch")]
+mod recording;
+mod retry;
+mod rewrite;
+#[cfg(feature = "watch")]
+mod settle;
+mod snapshot;
+#[cfg(feature = "watch")]
+mod source;
+mod space;
+mod sparse;
+mod state;
+mod stats;
+mod sync;
+#[cfg(feature = "watch")]
+mod throttle;
+mod trace;
+mod trash;
+mod tree;
+#[cfg(feature = "watch")]
+mod watch;
+mod workers;
+
+pub use crate::adoption::{AdoptionReport, ExistingOutputPolicy, ForeignFiles};
+pub use crate::audit::EVENT_STREAM_SCHEMA;
+pub use crate::builder::OverlayBuilder;
+pub use crate::case::{CaseConflict, CaseConflictPolicy, OutputCase};
+pub use crate::config::{Config, HooksConfig, InputConfig};
+#[cfg(feature = "watch")]
+pub use crate::control::{ControlRequest, ControlResponse};
+pub use crate::doctor::{Check, CheckStatus};
+pub use crate::error::{ConfigError, OverlayError};
+pub use crate::filter::{InputOptions, DEFAULT_TEMP_PATTERNS, IGNORE_FILE};
+pub use crate::fs_ops::{FileOp, FileOps, MemoryFs, RealFs, RetryPolicy};
+#[cfg(feature = "watch")]
+pub use crate::handle::{InputCommand, InputHandle};
+pub use crate::identity::FileIdentity;
+#[cfg(feature = "test-util")]
+pub use crate::inject::SyntheticEvent;
+pub use crate::input_id::InputId;
+pub use crate::link::{Copies, HardLinks, LinkKind, LinkStrategy};
+pub use crate::manifest::{
+    Manifest, ManifestEntry, ManifestReport, MANIFEST_ALGORITHM, MANIFEST_VERSION,
+};
+pub use crate::merge::{FileMerger, MergeFormat, MergeRule};
+pub use crate::preview::{PlannedChange, PreviewEntry, PreviewReport};
+pub use crate::probe::LinkProbe;
+#[cfg(feature = "watch")]
+pub use crate::recording::{
+    read_recording, RecordedChange, RecordedEvent, ReplayPace, ReplayReport,
+};
+pub use crate::rewrite::RewriteRule;
+pub use crate::snapshot::{InputSnapshot, OverlaySnapshot, RestoreReport};
+#[cfg(feature = "watch")]
+pub use crate::source::{EventSink, EventSource, NotifySource, ReplaySource};
+pub use crate::state::{InputState, OverlayState, STATE_VERSION};
+pub use crate::stats::{Housekeeping, InputStats, Skipped, Stats, Summary, WatcherHealth};
+pub use crate::sync::{DiffReport, SyncReport};
+pub use crate::trace::{DecisionTrace, EventKind, TracedAction, DECISIONS};
+pub use crate::trash::{TrashRetention, TrashedFile, UndoReport};
+#[cfg(feature = "watch")]
+pub use crate::watch::{Command, Controller, Phase};
+
+use crate::adoption::Adoption;
+#[cfg(feature = "archives")]
+use crate::archive::Archives;
+use crate::audit::{AuditAction, AuditLog};
+use crate::case::Casing;
+use crate::engine::{Action, Appeared, Count, MapUpdate, Ranked, Vanished};
+use crate::filter::{Filter, IgnoreFile};
+use crate::fs_ops::{absolute, is_too_many_links, Protected, ReadOnlyInputs, Retrying};
+use crate::graft::Grafts;
+use crate::hooks::Hooks;
+#[cfg(feature = "http-status")]
+use crate::http::HttpStatus;
+use crate::input::{Input, Rank};
+use crate::key::Folded;
+use crate::ledger::Ledger;
+use crate::link::Link;
+use crate::lock::InstanceLock;
+use crate::log_line::LogLine;
+use crate::merge::Merging;
+use crate::progress::Copying;
+use crate::retry::{RetryAction, RetryQueue};
+use crate::stats::{Skip, Summaries};
+use crate::sync::Syncs;
+use crate::trace::Traces;
+use crate::trash::Trash;
+use crate::tree::PathTree;
+#[cfg(feature = "watch")]
+use crate::watch::Watching;
+use crate::workers::{Pool, Put, MAX_IN_FLIGHT};
+#[cfg(feature = "test-util")]
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use failure::Error;
-use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
-use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::fmt;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
 
+/// The log target of the summaries of the sync at startup and of the whole run at the end,
+/// for loggers that show them even when little else is shown.
+pub const SUMMARY: &str = "overlay::summary";
+
+/// A change to an input. Without the `watch` feature, only the overlay's own.
 #[derive(Debug)]
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
 enum Event {
     Create(PathBuf),
     Remove(PathBuf),
     Rename(PathBuf, PathBuf),
+    PermissionsChanged(PathBuf),
     Error(Error, Option<PathBuf>),
+    /// The source of the input stopped, and nothing more follows until it is restarted.
+    WatcherFailed(Error),
+    /// A poll of the input completed.
+    Polled(SystemTime),
 }
 
-#[derive(Debug)]
+/// The sequence number of the next event, across all inputs.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
 struct EventType {
     index: usize,
     event: Event,
+    observation: Observation,
 }
 
-#[derive(Debug, Clone, Eq)]
-struct Input {
-    index: usize,
-    path: PathBuf,
-    priority: u32,
+/// When an event was observed, and in which order, as the events of different inputs may
+/// arrive in another.
+#[derive(Debug, Clone, Copy)]
+struct Observation {
+    seq: u64,
+    at: SystemTime,
 }
 
-impl Event {
-    fn from(input: &Input, event: DebouncedEvent) -> Result<Option<EventType>, Error> {
-        let event: Option<Event> = match event {
-            DebouncedEvent::Create(path) => {
-                Some(Event::Create(path.strip_prefix(&input.path)?.to_path_buf()))
-            }
-            DebouncedEvent::Remove(path) => {
-                Some(Event::Remove(path.strip_prefix(&input.path)?.to_path_buf()))
-            }
-            DebouncedEvent::Rename(from, to) => Some(Event::Rename(
-                from.strip_prefix(&input.path)?.to_path_buf(),
-                to.strip_prefix(&input.path)?.to_path_buf(),
-            )),
-            DebouncedEvent::Error(e, path) => Some(Event::Error(e.into(), path)),
-            _ => None,
+impl EventType {
+    /// `event` of input `index`, observed just now.
+    fn new(index: usize, event: Event) -> Self {
+        let observation = Observation {
+            seq: SEQUENCE.fetch_add(1, atomic::Ordering::Relaxed),
+            at: SystemTime::now(),
         };
+        EventType::following(index, event, observation)
+    }
 
-        Ok(match event {
-            Some(event) => Some(EventType {
-                index: input.index,
-                event,
-            }),
-            None => None,
-        })
+    /// `event` of input `index`, which follows from the one of `observation`.
+    fn following(index: usize, event: Event, observation: Observation) -> Self {
+        EventType {
+            index,
+            event,
+            observation,
+        }
     }
 }
 
-impl Input {
-    fn build_watcher(&self, transmiter: Sender<EventType>) -> Result<(), Error> {
-        let input = self.clone();
+// Only what happened to which input, as it is logged for every event.
+impl fmt::Debug for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventType")
+            .field("index", &self.index)
+            .field("event", &self.event)
+            .finish()
+    }
+}
 
-        thread::spawn(move || {
-            let (tx, rx): (Sender<DebouncedEvent>, Receiver<DebouncedEvent>) = mpsc::channel();
+/// A file the overlay currently has in the output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OverlayEntry {
+    /// The path relative to the output.
+    pub path: PathBuf,
+    /// The input the file comes from.
+    pub input: InputId,
+    pub label: Option<String>,
+    /// How many inputs provide this path, including the winning one.
+    pub providers: usize,
+}
 
-            let mut watcher: RecommendedWatcher = watcher(tx, Duration::from_secs(1)).unwrap();
-            watcher
-                .watch(&input.path, RecursiveMode::Recursive)
-                .unwrap();
+/// An input that offers a file at some path of the overlay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provider {
+    pub input: InputId,
+    pub label: Option<String>,
+    pub group: u32,
+    /// The priority of the input within its group.
+    pub priority: u32,
+    /// The absolute path of the file in the input.
+    pub source: PathBuf,
+    /// The input no longer has the file on disk, though the overlay still tracks it.
+    pub stale: bool,
+}
 
-            loop {
-                match rx.recv() {
-                    Ok(event) => {
-                        if let Some(event) = Event::from(&input, event).unwrap() {
-                            transmiter.send(event).unwrap();
-                        }
-                    }
-                    Err(e) => {
-                        transmiter
-                            .send(EventType {
-                                index: input.index,
-                                event: Event::Error(e.into(), None),
-                            })
-                            .unwrap();
+/// A file of an input that the file of another input at the same path wins over, see
+/// `Overlay::shadowed`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShadowedEntry {
+    /// The path relative to the output.
+    pub path: PathBuf,
+    /// The absolute path of the shadowed file in its input.
+    pub source: PathBuf,
+    /// The input whose file is in the output instead, as `Overlay::resolve` has it.
+    pub winner: Provider,
+}
 
-                        println!("Unrecoverable error on watcher {}: {:?}", input.index, e);
-                        break;
-                    }
-                }
-            }
-        });
+/// Something the overlay couldn't do, kept for the summary at the end of a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    pub path: Option<PathBuf>,
+    /// The input it was about, if any, e.g. not for foreign files.
+    pub input: Option<InputId>,
+    /// What went wrong in a word or two, e.g. `permission_denied` or `case_conflict`.
+    pub kind: String,
+    pub message: String,
+    /// The file or directory in the input a link that couldn't be made was to, if it was
+    /// about one.
+    pub source: Option<PathBuf>,
+    /// Where in the output that link was to be.
+    pub destination: Option<PathBuf>,
+}
+
+/// How many failures are kept, the rest are only counted.
+const MAX_FAILURES: usize = 1000;
 
-        Ok(())
+#[cfg(feature = "watch")]
+/// How often in a row the watcher of an input is restarted before it is given up on.
+const MAX_RESTARTS: u32 = 10;
+
+/// The kind of an I/O error in snake case, e.g. `permission_denied`. A full volume is
+/// always `storage_full`, which callers can look for to free space and repair.
+fn error_kind(e: &io::Error) -> String {
+    if space::is_full(e) {
+        return "storage_full".to_string();
+    }
+    if is_too_many_links(e) {
+        return "too_many_links".to_string();
     }
-}
 
-impl Ord for Input {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.priority.cmp(&other.priority)
+    let mut kind = String::new();
+    for (i, c) in format!("{:?}", e.kind()).chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            kind.push('_');
+        }
+        kind.extend(c.to_lowercase());
     }
+    kind
 }
 
-impl PartialOrd for Input {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// What handling an event did to the output, the most notable if it did more than one
+/// thing: replacing beats linking, which beats removing, which beats deferring it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessedAction {
+    /// Nothing changed, e.g. as the file is shadowed, filtered, or was linked already.
+    #[default]
+    Ignored,
+    /// It is looked at again later, e.g. as the file is still being written or in use.
+    Deferred,
+    Removed,
+    Linked,
+    Replaced,
+}
+
+/// How the files of the inputs are put into the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// A hard link for every file, or a copy where there can't be one.
+    #[default]
+    HardLinks,
+    /// A single junction on Windows, or symlink elsewhere, for every directory only one
+    /// input has anything in, and hard links for the files in the others.
+    ///
+    /// A linked directory is broken up into links to each file as soon as another input
+    /// has something in it, and linked as a whole again once that is gone.
+    Hybrid,
 }
 
-impl PartialEq for Input {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority
+/// The path of a file next to `path`, named like it with `suffix` appended.
+fn beside(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// `ops`, telling `tracing` about what they change with the `tracing` feature.
+fn instrumented(ops: Box<dyn FileOps>) -> Arc<dyn FileOps> {
+    #[cfg(feature = "tracing")]
+    let ops: Box<dyn FileOps> = Box::new(instrument::Instrumented::new(ops));
+    Arc::from(ops)
+}
+
+/// `path` made absolute, with every link in it resolved as far as it exists.
+fn canonical(path: &Path) -> PathBuf {
+    let path = absolute(path);
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap();
+            if rest == Path::new("") {
+                return resolved;
+            }
+            return resolved.join(rest);
+        }
     }
+    path
 }
 
-#[derive(Debug, Clone, Default)]
+/// The key of `path` in the case-folded index.
+fn fold(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_lowercase())
+}
+
+#[derive(Debug)]
 pub struct Overlay {
     inputs: Vec<Input>,
     output: PathBuf,
-    input_map: HashMap<PathBuf, BinaryHeap<Input>>,
+    /// The inputs with a file at each tracked path. Looked up for every event, so hashed
+    /// with a hash that is fast on long paths rather than one resisting collisions.
+    input_map: FxHashMap<PathBuf, BinaryHeap<Ranked>>,
+    /// The size of every file an input provides, by input and path.
+    sizes: FxHashMap<(usize, PathBuf), u64>,
+    /// Tracked paths whose winner can't be in the output, because something of higher
+    /// priority needs the path to be of the other type. Looked up for every directory
+    /// above a file that is put there.
+    blocked: FxHashSet<PathBuf>,
+    /// What failed on files that were in use, to be tried again later.
+    retries: RetryQueue,
+    grafts: Grafts,
+    strategy: Strategy,
+    /// Every tracked path by the directory it is in.
+    tree: PathTree,
+    case: Casing,
+    /// Passes everything but the temporary files of editors and downloads.
+    temp_files: Filter,
+    /// The rules of the overlay's own ignore file, applied to every input.
+    global_ignore: IgnoreFile,
+    merging: Merging,
+    /// What the archive inputs hold, shared with the file operations that read them, once
+    /// there is one.
+    #[cfg(feature = "archives")]
+    archives: Option<Archives>,
+    single_instance: bool,
+    check_overlaps: bool,
+    /// Whether adding an input twice only raises it to the higher rank, rather than erring.
+    merge_duplicate_inputs: bool,
+    /// Where the file operations refuse to write.
+    protected: Arc<RwLock<Protected>>,
+    retry: Arc<RwLock<RetryPolicy>>,
+    adoption: Adoption,
+    /// Where the files the overlay displaces from the output are moved, and for how long.
+    trash_dir: Option<(PathBuf, TrashRetention)>,
+    trash: Option<Trash>,
+    copying: Copying,
+    fail_fast: bool,
+    /// How many events in a row may fail before the loop stops, if it ever does.
+    max_consecutive_failures: Option<u32>,
+    /// What the event being handled did so far.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    processed: ProcessedAction,
+    ledger: Ledger,
+    /// How each file linked into the output was put there, to be removed the same way.
+    links: FxHashMap<PathBuf, Link>,
+    load_order: Option<PathBuf>,
+    line: LogLine,
+    failures: Vec<Failure>,
+    syncs: Syncs,
+    traces: Traces,
+    /// The events `inject_event` made up, which `drain` handles.
+    #[cfg(feature = "test-util")]
+    injected: (Sender<EventType>, Receiver<EventType>),
+    tick_interval: Option<Duration>,
+    summaries: Summaries,
+    stats: Stats,
+    audit: Option<AuditLog>,
+    /// Where every action, sync and summary is written as it happens, if anywhere.
+    stream: Option<AuditLog>,
+    hooks: Option<Hooks>,
+    #[cfg(feature = "http-status")]
+    http_status: Option<HttpStatus>,
+    dry_run: bool,
+    pool: Pool,
+    #[cfg(feature = "watch")]
+    watch: Watching,
+    fs: Arc<dyn FileOps>,
 }
 
 impl Overlay {
-    pub fn new(path: &str) -> Self {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let protected = Arc::new(RwLock::new(Protected::new(path.as_ref())));
+        let retry = Arc::new(RwLock::new(RetryPolicy::default()));
         Overlay {
             inputs: vec![],
-            output: Path::new(path).to_path_buf(),
-            input_map: HashMap::new(),
+            output: path.as_ref().to_path_buf(),
+            input_map: FxHashMap::default(),
+            sizes: FxHashMap::default(),
+            blocked: FxHashSet::default(),
+            retries: RetryQueue::default(),
+            grafts: Grafts::default(),
+            strategy: Strategy::default(),
+            tree: PathTree::default(),
+            case: Casing::default(),
+            temp_files: Filter::excluding(DEFAULT_TEMP_PATTERNS).unwrap(),
+            global_ignore: IgnoreFile::default(),
+            merging: Merging::default(),
+            #[cfg(feature = "archives")]
+            archives: None,
+            single_instance: true,
+            check_overlaps: true,
+            merge_duplicate_inputs: false,
+            adoption: Adoption::default(),
+            trash_dir: None,
+            trash: None,
+            copying: Copying::default(),
+            fail_fast: false,
+            max_consecutive_failures: None,
+            processed: ProcessedAction::Ignored,
+            ledger: Ledger::default(),
+            links: FxHashMap::default(),
+            load_order: None,
+            line: LogLine::default(),
+            failures: vec![],
+            syncs: Syncs::default(),
+            traces: Traces::default(),
+            #[cfg(feature = "test-util")]
+            injected: unbounded(),
+            tick_interval: None,
+            summaries: Summaries::default(),
+            stats: Stats::default(),
+            audit: None,
+            stream: None,
+            hooks: None,
+            #[cfg(feature = "http-status")]
+            http_status: None,
+            dry_run: false,
+            pool: Pool::default(),
+            #[cfg(feature = "watch")]
+            watch: Watching::default(),
+            fs: instrumented(Box::new(ReadOnlyInputs::new(
+                Box::new(Retrying::new(Box::new(RealFs), retry.clone())),
+                protected.clone(),
+            ))),
+            protected,
+            retry,
         }
     }
 
-    pub fn add_input(&mut self, path: &str, priority: u32) -> usize {
-        self.inputs.push(Input {
-            index: self.inputs.len(),
-            path: Path::new(path).to_path_buf(),
-            priority,
+    pub fn builder<P: AsRef<Path>>(output: P) -> OverlayBuilder {
+        OverlayBuilder::new(output)
+    }
+
+    /// Creates an overlay configured entirely by environment variables, see `Config`.
+    pub fn from_env() -> Result<Self, Error> {
+        Config::from_env()?.builder().build()
+    }
+
+    /// Sets how often `process_loop` runs its periodic work, trying again what failed and
+    /// tidying up the paths it tracks, or `None` to never run it.
+    pub fn set_tick_interval(&mut self, interval: Option<Duration>) {
+        self.tick_interval = interval;
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Uses `Strategy::Hybrid` if `graft`, `Strategy::HardLinks` otherwise.
+    pub fn set_graft_directories(&mut self, graft: bool) {
+        self.strategy = if graft {
+            Strategy::Hybrid
+        } else {
+            Strategy::HardLinks
+        };
+    }
+
+    /// Whether `process_loop` refuses to run while another overlay is running on the same
+    /// output, which it is by default.
+    pub fn set_single_instance(&mut self, single: bool) {
+        self.single_instance = single;
+    }
+
+    /// Whether adding an input that overlaps another, the output, the ledger or the lock
+    /// file errs, which it does by default. Only inputs added after are checked.
+    pub fn set_check_overlaps(&mut self, check: bool) {
+        self.check_overlaps = check;
+    }
+
+    /// Whether an input that is added again, under the same path or another spelling of
+    /// it, is merged with the one already there, which then ranks as the higher of the two.
+    /// Adding it errs by default.
+    pub fn set_merge_duplicate_inputs(&mut self, merge: bool) {
+        self.merge_duplicate_inputs = merge;
+    }
+
+    /// Performs the file operations with `ops`, which never write into an input that isn't
+    /// writable either, and are retried by the `RetryPolicy`.
+    pub(crate) fn set_file_ops(&mut self, ops: Box<dyn FileOps>) {
+        let ops = Box::new(Retrying::new(ops, self.retry.clone()));
+        self.fs = instrumented(Box::new(ReadOnlyInputs::new(ops, self.protected.clone())));
+    }
+
+    /// How the deletes, links and renames that fail for a moment, because something else
+    /// has the file open, are tried again. They are tried 3 times, 100 ms apart, by
+    /// default. What still fails is handled like any other error.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        *self.retry.write().unwrap() = policy;
+    }
+
+    /// Stops `process_loop` with an error when the watcher of an input fails and can't be
+    /// restarted, instead of going on without it.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Stops `process_loop` with an error once `max` events in a row couldn't be handled
+    /// without failing. It goes on regardless by default, as most failures are about single
+    /// files.
+    pub fn set_max_consecutive_failures(&mut self, max: Option<u32>) {
+        self.max_consecutive_failures = max;
+    }
+
+    /// Counts an event of input `index` that wasn't acted on, for `why`, unless it is one
+    /// of syncing.
+    fn skip(&mut self, index: usize, why: Skip) {
+        if self.syncs.current.is_none() {
+            self.stats.skipped(index, why);
+        }
+    }
+
+    /// Notes that the event being handled did `action`, if nothing more notable yet.
+    fn processed(&mut self, action: ProcessedAction) {
+        self.processed = self.processed.max(action);
+    }
+
+    /// Whether the output file at `output` is the one input `index` has at `source`, or
+    /// the copy of it there had to be, e.g. as it had too many hard links.
+    fn provides(&self, index: usize, source: &Path, output: &Path) -> bool {
+        let copied = output
+            .strip_prefix(&self.output)
+            .ok()
+            .and_then(|path| self.links.get(path))
+            .is_some_and(|link| link.kind == LinkKind::Copy);
+        let strategy = if copied {
+            Arc::new(Copies)
+        } else {
+            self.link_strategy(index)
+        };
+        strategy
+            .provides(source, output, &*self.fs)
+            .unwrap_or(false)
+    }
+
+    /// How input `index` puts its files into the output.
+    fn link_strategy(&self, index: usize) -> Arc<dyn LinkStrategy> {
+        let input = &self.inputs[index];
+        match &input.strategy {
+            Some(strategy) => strategy.clone(),
+            None if input.copies => Arc::new(Copies),
+            None => Arc::new(HardLinks),
+        }
+    }
+
+    /// What input `index` is going to put into the output, for a dry run that doesn't.
+    fn planned_kind(&self, index: usize) -> LinkKind {
+        let input = &self.inputs[index];
+        if input.strategy.is_some() {
+            LinkKind::Custom
+        } else if input.copies {
+            LinkKind::Copy
+        } else {
+            LinkKind::HardLink
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// How many files input `id` has in the output and behind other inputs, and their
+    /// sizes.
+    pub fn input_stats(&self, id: InputId) -> Result<InputStats, Error> {
+        let index = self.existing_input(id)?;
+        Ok(self.stats.inputs[index].clone())
+    }
+
+    /// The first of the errors counted in the stats, with what they were about.
+    pub fn failures(&self) -> &[Failure] {
+        &self.failures
+    }
+
+    /// Counts an error, keeping it for the summary at the end of the run.
+    fn failed(&mut self, path: Option<&Path>, input: Option<usize>, kind: String, message: String) {
+        self.record_failure(Failure {
+            path: path.map(Path::to_path_buf),
+            input: input.map(InputId::of),
+            kind,
+            message,
+            source: None,
+            destination: None,
         });
+    }
+
+    /// Records that `source` of input `index` couldn't be linked to `destination`, for
+    /// `path`, with `e`, and writes it to the event stream.
+    fn link_failed(
+        &mut self,
+        path: &Path,
+        index: usize,
+        source: &Path,
+        destination: &Path,
+        e: &io::Error,
+    ) {
+        let failure = Failure {
+            path: Some(path.to_path_buf()),
+            input: Some(InputId::of(index)),
+            kind: error_kind(e),
+            message: e.to_string(),
+            source: Some(source.to_path_buf()),
+            destination: Some(destination.to_path_buf()),
+        };
+        self.emit("link_failed", &failure);
+        self.record_failure(failure);
+        let error = format!("couldn't link {}: {}", path.display(), e);
+        self.note(|report| report.errors.push(error));
+    }
 
-        self.inputs.len() - 1
+    fn record_failure(&mut self, failure: Failure) {
+        self.stats.error();
+        if self.failures.len() < MAX_FAILURES {
+            self.failures.push(failure);
+        }
+    }
+
+    /// Returns the input whose file is in the output at `relative`, if any.
+    pub fn resolve<P: AsRef<Path>>(&self, relative: P) -> Option<Provider> {
+        let relative = relative.as_ref();
+        self.materialized(relative)
+            .map(|input| self.provider(input, relative))
     }
 
-    fn build_watchers(&self) -> Result<Receiver<EventType>, Error> {
-        let (tx, rx): (Sender<EventType>, Receiver<EventType>) = mpsc::channel();
+    /// Returns every path input `id` has a file at whose file another input wins over,
+    /// with the winner, sorted by path. Paths no input wins, like those held back for case
+    /// conflicts, aren't shadowed by anything.
+    pub fn shadowed(&self, id: InputId) -> Vec<ShadowedEntry> {
+        let input = match self.inputs.get(id.index()) {
+            Some(input) if !input.removed => input,
+            _ => return vec![],
+        };
+
+        let mut shadowed: Vec<ShadowedEntry> = self
+            .input_map
+            .iter()
+            .filter(|(_, heap)| heap.iter().any(|ranked| ranked.index == input.index))
+            .filter_map(|(path, _)| {
+                let winner = self.materialized(path)?;
+                if winner.index == input.index {
+                    return None;
+                }
+                Some(ShadowedEntry {
+                    path: path.clone(),
+                    source: input.source(path),
+                    winner: self.provider(winner, path),
+                })
+            })
+            .collect();
+        shadowed.sort_by(|a, b| a.path.cmp(&b.path));
+        shadowed
+    }
+
+    /// Returns every input offering a file at `relative`, the winning one first and the
+    /// rest by descending priority.
+    pub fn providers<P: AsRef<Path>>(&self, relative: P) -> Vec<Provider> {
+        let relative = relative.as_ref();
+        let heap = match self.input_map.get(relative) {
+            Some(heap) => heap,
+            None => return vec![],
+        };
+
+        self.by_precedence(heap)
+            .into_iter()
+            .map(|input| self.provider(input, relative))
+            .collect()
+    }
+
+    /// The inputs of `heap`, the one at the top first and then by rank.
+    fn by_precedence(&self, heap: &BinaryHeap<Ranked>) -> Vec<&Input> {
+        engine::by_precedence(heap)
+            .into_iter()
+            .map(|input| &self.inputs[input.index])
+            .collect()
+    }
+
+    fn provider(&self, input: &Input, relative: &Path) -> Provider {
+        let source = input.source(relative);
+        Provider {
+            input: input.id(),
+            label: input.label.clone(),
+            group: input.rank.group,
+            priority: input.rank.priority,
+            stale: !self.fs.exists(&source) || self.fs.is_dir(&source),
+            source,
+        }
+    }
+
+    /// Iterates over every file the overlay currently provides, sorted by path.
+    pub fn list(&self) -> impl Iterator<Item = OverlayEntry> + '_ {
+        let mut paths: Vec<&PathBuf> = self
+            .input_map
+            .keys()
+            .filter(|path| self.materialized(path).is_some())
+            .collect();
+        paths.sort();
+
+        paths.into_iter().map(move |path| {
+            let input = self.materialized(path).unwrap();
+            OverlayEntry {
+                path: path.clone(),
+                input: input.id(),
+                label: input.label.clone(),
+                providers: self.input_map[path].len(),
+            }
+        })
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.remove_file(path)
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.create_dir_all(path)
+        }
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.link_dir(target, link)
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.rename(from, to)
+        }
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.copy_permissions(from, to)
+        }
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.fs.unlink_dir(link)
+        }
+    }
+
+    /// Removes the output directory at `relative` and then its parents, as long as they
+    /// are empty.
+    fn remove_empty_dirs(&self, relative: &Path) {
+        if self.dry_run {
+            return;
+        }
+
+        let mut relative = Some(relative);
+        while let Some(dir) = relative {
+            // Directories in a graft belong to the input.
+            if dir.as_os_str().is_empty()
+                || self.graft_of(dir).is_some()
+                || self.fs.remove_dir(&self.output.join(dir)).is_err()
+            {
+                break;
+            }
+            relative = dir.parent();
+        }
+    }
+
+    /// Links `path`, which folds to `key`, from input `index` into the output, replacing
+    /// the file already there.
+    fn link(&mut self, path: &Path, key: &Folded, index: usize) -> bool {
+        if self.in_flight(key) {
+            self.finish_links();
+        }
+        let input_file = self.inputs[index].source(path);
+        let output_file = self.output.join(path);
+
+        if let Some(parent) = output_file.parent() {
+            let _ = self.create_dir_all(parent);
+        }
+
+        if let Some(merger) = self.merger(path) {
+            let sources = self.merge_sources(path);
+            if sources.len() > 1 {
+                match self.write_merged(path, index, &merger, &sources) {
+                    Ok(written) => return written,
+                    // The winner's file is better than none.
+                    Err(e) => say!(self.line, Warn, " NOT MERGED: {},", e),
+                }
+            }
+        }
+
+        let mut replaced = self.fs.exists(&output_file);
+        if replaced && self.provides(index, &input_file, &output_file) {
+            say!(self.line, Debug, " UNCHANGED!");
+            self.record(path);
+            self.note(|report| report.confirmed += 1);
+            return true;
+        }
+        if replaced && self.adopted_copy(path, index) {
+            say!(self.line, Debug, " ADOPTED COPY!");
+            let link = Link {
+                strategy: Arc::new(Copies),
+                kind: LinkKind::Copy,
+            };
+            self.links.insert(path.to_path_buf(), link);
+            self.note(|report| report.confirmed += 1);
+            return true;
+        }
+        if replaced && !self.removable(path) {
+            if !self.ledger.contains(path) {
+                say!(self.line, Warn, " NOT PUT THERE BY THE OVERLAY,");
+            }
+            if !self.handle_foreign(path) {
+                return false;
+            }
+            replaced = false;
+        }
+        if replaced && self.displaces(path) {
+            if !self.make_room(path, index) {
+                return false;
+            }
+            replaced = false;
+        }
+        if replaced {
+            say!(self.line, Info, " REPLACED,");
+        }
+        let put = Put {
+            path: path.to_path_buf(),
+            index,
+            from: input_file,
+            to: output_file,
+            strategy: self.link_strategy(index),
+            replaces: replaced,
+            progress: self.copy_progress(index),
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        };
+        if self.defers() {
+            self.pool.in_flight.insert(key.clone());
+            self.processed(if replaced {
+                ProcessedAction::Replaced
+            } else {
+                ProcessedAction::Linked
+            });
+            self.pool.workers.as_mut().unwrap().submit(put, &self.fs);
+            return true;
+        }
+
+        let result = if self.dry_run {
+            Ok(self.planned_kind(index))
+        } else {
+            put.run(&*self.fs)
+        };
+        self.linked(put, result)
+    }
+
+    /// Removes the file input `index` provided for `path` from the output.
+    fn unlink(&mut self, path: &Path, index: usize) {
+        let output_file = self.output.join(path);
+
+        if self.fs.exists(&output_file) && !self.removable(path) {
+            // Something else replaced it since, which is not the overlay's to remove.
+            if !self.handle_foreign(path) {
+                return;
+            }
+        }
+
+        say!(self.line, Info, " DELETED!");
+        self.merging.merged.remove(path);
+        match self.remove_link(path) {
+            Ok(()) => {
+                self.ledger.remove(path);
+                self.processed(ProcessedAction::Removed);
+            }
+            // Whatever took it away did the job.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.processed(ProcessedAction::Removed)
+            }
+            Err(e) => {
+                say!(self.line, Error, " NOT DELETED: {}!", e);
+                self.failed(Some(path), Some(index), error_kind(&e), e.to_string());
+                self.park(path, RetryAction::Unlink, index, &e);
+            }
+        }
+        self.run_hooks(false, path, &output_file, index);
+        self.stats.unlinked();
+        self.audit(AuditAction::Unlink, Some(path), index, None);
+    }
+
+    /// Removes the file at `path` in the output the way it was put there, or like any
+    /// other file if that isn't known.
+    fn remove_link(&mut self, path: &Path) -> io::Result<()> {
+        let output_file = self.output.join(path);
+        let link = match self.links.remove(path) {
+            Some(link) => link,
+            None => return self.discard(path).map(|_| ()),
+        };
+        if self.dry_run {
+            return Ok(());
+        }
+        let result = link.strategy.remove(&output_file, link.kind, &*self.fs);
+        if matches!(&result, Err(e) if e.kind() != io::ErrorKind::NotFound) {
+            self.links.insert(path.to_path_buf(), link);
+        }
+        result
+    }
+
+    /// Returns the providers of `path`, which folds to `key`, tracking it if it isn't yet.
+    fn track(&mut self, path: &Path, key: &Folded) -> &mut BinaryHeap<Ranked> {
+        if !self.input_map.contains_key(path) {
+            self.case
+                .folded
+                .entry(key.clone())
+                .or_default()
+                .insert(path.to_path_buf());
+            self.tree.insert(path, key);
+        }
+        self.input_map.entry(path.to_path_buf()).or_default()
+    }
+
+    /// Stops tracking `path`, which no input provides anymore.
+    fn untrack(&mut self, path: &Path) {
+        self.input_map.remove(path);
+        self.blocked.remove(path);
+        let key = Folded::new(path);
+        if let Some(paths) = self.case.folded.get_mut(&key) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.case.folded.remove(&key);
+            }
+        }
+        let input_map = &self.input_map;
+        self.tree.remove(path, |path| input_map.contains_key(path));
+    }
+
+    /// The tracked paths at, above or beneath `relative`, in any spelling of it.
+    fn tracked_around(&self, relative: &Path) -> Vec<&Path> {
+        let above = relative
+            .ancestors()
+            .filter_map(|ancestor| self.case.folded.get(&Folded::new(ancestor)))
+            .flatten()
+            .map(PathBuf::as_path);
+        above
+            .chain(self.tree.beneath(relative))
+            .filter(|path| self.input_map.contains_key(*path))
+            .collect()
+    }
+
+    /// Returns the winner of `path`, if the overlay has a file there in the output.
+    fn materialized(&self, path: &Path) -> Option<&Input> {
+        if self.blocked.contains(path) {
+            return None;
+        }
+
+        self.input_map
+            .get(path)
+            .and_then(BinaryHeap::peek)
+            .map(|winner| &self.inputs[winner.index])
+    }
+
+    /// Returns the highest priority among the files the overlay has beneath `path`.
+    fn directory_claim(&self, path: &Path) -> Option<Rank> {
+        self.tree
+            .beneath(path)
+            .into_iter()
+            .filter(|key| key.starts_with(path))
+            .filter_map(|key| self.materialized(key))
+            .map(|input| input.rank)
+            .max()
+    }
+
+    /// Puts the winner of `path` into the output, first moving aside whatever of lower
+    /// priority is there as a different type, e.g. a file where a directory is needed.
+    ///
+    /// The winner must not be blocked nor counted as visible or shadowed in the stats yet.
+    fn place(&mut self, path: &Path, key: &Folded) {
+        if self.in_flight(key) {
+            self.finish_links();
+        }
+        let (index, rank) = match self.input_map.get(path).and_then(BinaryHeap::peek) {
+            Some(winner) => (winner.index, winner.rank),
+            None => return,
+        };
+
+        let ancestor = path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            // Not by its directory claim, which looks at everything beneath.
+            .find(|ancestor| self.materialized(ancestor).is_some())
+            .map(Path::to_path_buf);
+        let mut lost = false;
+        if let Some(ancestor) = ancestor {
+            let ancestor_rank = self.materialized(&ancestor).unwrap().rank;
+            if rank > ancestor_rank {
+                say!(self.line, Info, " REPLACING FILE {},", ancestor.display());
+                self.displace(&ancestor);
+            } else {
+                lost = true;
+            }
+        }
+
+        if !lost {
+            self.remove_stale_links(path);
+        }
+
+        let output_file = self.output.join(path);
+        if !lost && self.fs.is_dir(&output_file) {
+            let owned = WalkDir::new(&output_file)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| !entry.file_type().is_dir())
+                .all(|entry| {
+                    let relative = entry.path().strip_prefix(&self.output).unwrap();
+                    self.materialized(relative).is_some() && self.removable(relative)
+                });
+
+            match self.directory_claim(path) {
+                _ if !owned => {
+                    say!(self.line, Warn, " FOREIGN DIRECTORY!");
+                    lost = true;
+                }
+                Some(claim) if claim >= rank => lost = true,
+                _ => {
+                    say!(self.line, Info, " REPLACING DIRECTORY,");
+                    let mut displaced: Vec<PathBuf> = self
+                        .tree
+                        .beneath(path)
+                        .into_iter()
+                        .filter(|key| key.starts_with(path))
+                        .filter(|key| self.materialized(key).is_some())
+                        .map(Path::to_path_buf)
+                        .collect();
+                    displaced.sort();
+                    for key in displaced {
+                        self.displace(&key);
+                    }
+                    if !self.dry_run {
+                        let _ = self.fs.remove_dir_all(&output_file);
+                    }
+                }
+            }
+        }
+
+        if !lost && self.foreign_file(path) {
+            lost = !self.handle_foreign(path);
+        }
+        if !lost {
+            lost = !self.resolve_case_conflicts(path, key, index, rank);
+        }
+
+        if lost {
+            say!(self.line, Debug, " BLOCKED!");
+            self.blocked.insert(path.to_path_buf());
+            self.stats.input_shadowed(index, self.size(index, path));
+        } else if self.link(path, key, index) || self.retries.holds(path, RetryAction::Link, index)
+        {
+            // A file that is only in use for now is still the winner's to put there.
+            self.stats.input_visible(index, self.size(index, path));
+        } else {
+            self.fall_back(path, index);
+        }
+    }
 
-        // NOTE: There should be a watcher on Output.
-        // NOTE: That moves files not created by Overlay to highest priority Input.
+    /// Falls back to the next provider of `path` rather than tracking a file of input
+    /// `index` that isn't there.
+    fn fall_back(&mut self, path: &Path, index: usize) {
+        let heap = self.input_map.get_mut(path).unwrap();
+        heap.retain(|input| input.index != index);
+        if let Some(next) = heap.peek().map(|input| input.index) {
+            self.stats.input_unshadowed(next, self.size(next, path));
+            self.place(path, &Folded::new(path));
+        } else {
+            self.prune(path);
+        }
+    }
 
+    /// Forgets `path` if no input provides it anymore, so paths that come and go don't
+    /// pile up.
+    fn prune(&mut self, path: &Path) {
+        if self.input_map.get(path).is_some_and(BinaryHeap::is_empty) {
+            self.untrack(path);
+        }
         for index in 0..self.inputs.len() {
-            self.inputs[index].build_watcher(tx.clone())?;
+            if !self.provided_by(index, path) {
+                self.sizes.remove(&(index, path.to_path_buf()));
+            }
         }
+        self.stats.set_tracked_paths(self.input_map.len());
+    }
 
-        Ok(rx)
+    fn provided_by(&self, index: usize, path: &Path) -> bool {
+        self.input_map
+            .get(path)
+            .is_some_and(|heap| heap.iter().any(|input| input.index == index))
     }
 
-    fn process_event(&mut self, event: EventType) {
-        print!("{:?}", &event);
+    /// The size of the file input `index` has at `path`, as of when it was last seen.
+    fn size(&self, index: usize, path: &Path) -> u64 {
+        let key = (index, path.to_path_buf());
+        self.sizes.get(&key).copied().unwrap_or(0)
+    }
+
+    /// Looks at the size of the file input `index` has at `path` again, after it appeared
+    /// or changed, and updates the byte counts of the input if it already provided it.
+    fn measure(&mut self, index: usize, path: &Path) {
+        let source = self.inputs[index].source(path);
+        let bytes = self.fs.identity(&source).map_or(0, |identity| identity.len);
+        let before = self.sizes.insert((index, path.to_path_buf()), bytes);
+
+        match before {
+            Some(before) if before != bytes && self.provided_by(index, path) => {
+                let visible = !self.blocked.contains(path)
+                    && self.materialized(path).map(|input| input.index) == Some(index);
+                self.stats.input_resized(index, visible, before, bytes);
+            }
+            _ => {}
+        }
+    }
+
+    /// Forgets every path at or beneath `prefix` that no input provides anymore, after a
+    /// directory went away.
+    fn prune_under(&mut self, prefix: &Path) {
+        let mut gone: Vec<PathBuf> = self
+            .tree
+            .beneath(prefix)
+            .into_iter()
+            .chain(Some(prefix))
+            .filter(|key| key.starts_with(prefix))
+            .filter(|key| self.input_map.get(*key).is_some_and(BinaryHeap::is_empty))
+            .map(Path::to_path_buf)
+            .collect();
+        // The deepest first, so the directories above them are left empty.
+        gone.sort_by(|a, b| b.cmp(a));
+        for key in gone {
+            self.untrack(&key);
+        }
+        let input_map = &self.input_map;
+        self.sizes.retain(|(index, key), _| {
+            !key.starts_with(prefix)
+                || input_map
+                    .get(key)
+                    .is_some_and(|heap| heap.iter().any(|input| input.index == *index))
+        });
+        self.stats.set_tracked_paths(self.input_map.len());
+    }
+
+    /// Takes the file the overlay has at `path` out of the output, keeping its providers
+    /// so it can come back once whatever displaced it is gone.
+    fn displace(&mut self, path: &Path) {
+        let index = self.materialized(path).unwrap().index;
+        self.unlink(path, index);
+        self.blocked.insert(path.to_path_buf());
+        self.stats.input_hidden(index, self.size(index, path));
+        self.stats.input_shadowed(index, self.size(index, path));
+    }
+
+    /// Brings back entries that were blocked by the file which was just removed from `path`.
+    fn restore_blocked(&mut self, path: &Path, key: &Folded) {
+        let siblings = self.case_siblings(path, key);
+        let mut blocked: Vec<PathBuf> = self
+            .blocked
+            .iter()
+            .filter(|key| {
+                // Files beneath the removed one, directories' worth of files above it that
+                // no longer have anything in them, and files it conflicted with by case.
+                (*key != path && key.starts_with(path))
+                    || (path.starts_with(key) && self.directory_claim(key).is_none())
+                    || siblings.contains(key)
+            })
+            .cloned()
+            .collect();
+        blocked.sort();
+
+        for key in blocked {
+            if self.blocked.remove(&key) {
+                let index = self.input_map[&key].peek().unwrap().index;
+                self.stats.input_unshadowed(index, self.size(index, &key));
+                self.place(&key, &Folded::new(&key));
+            }
+        }
+    }
+
+    /// Gives the inputs in `changes` their new ranks, then puts the new winner of every path
+    /// they provide into the output where it changed, and tries the blocked paths again.
+    fn rerank(&mut self, changes: Vec<(usize, Rank)>) {
+        let changed: HashSet<usize> = changes
+            .iter()
+            .filter(|(index, rank)| self.inputs[*index].rank != *rank)
+            .map(|(index, _)| *index)
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        for (index, rank) in changes {
+            self.inputs[index].rank = rank;
+        }
+
+        // The heaps hold the ranks of the inputs, so they are rebuilt with the new ones.
+        let (inputs, blocked) = (&self.inputs, &self.blocked);
+        let mut affected: BTreeMap<PathBuf, Option<usize>> = BTreeMap::new();
+        for (path, heap) in self.input_map.iter_mut() {
+            if heap.iter().any(|input| changed.contains(&input.index)) {
+                let previous = heap.peek().map(|input| input.index);
+                *heap = engine::reranked(heap, |index| inputs[index].rank);
+                let previous = previous.filter(|_| !blocked.contains(path));
+                affected.insert(path.clone(), previous);
+            }
+        }
+        for path in &self.blocked {
+            affected.entry(path.clone()).or_insert(None);
+        }
+
+        for (path, previous) in affected {
+            if self.graft_of(&path).is_some() {
+                // Nothing else provides anything in a graft.
+                continue;
+            }
+            let winner = match self.input_map.get(&path).and_then(BinaryHeap::peek) {
+                Some(winner) => winner.index,
+                None => continue,
+            };
+
+            if self.blocked.remove(&path) {
+                self.line
+                    .begin(format_args!("Reordering {}:", path.display()));
+                self.stats
+                    .input_unshadowed(winner, self.size(winner, &path));
+                self.place(&path, &Folded::new(&path));
+                self.line.end();
+            } else if self.merging.merged.contains(&path) && previous == Some(winner) {
+                // The same files, merged in another order.
+                self.line
+                    .begin(format_args!("Reordering {}:", path.display()));
+                self.remerge(&path);
+                self.line.end();
+            } else if let Some(previous) = previous.filter(|previous| *previous != winner) {
+                self.line
+                    .begin(format_args!("Reordering {}:", path.display()));
+                self.unlink(&path, previous);
+                self.stats
+                    .input_hidden(previous, self.size(previous, &path));
+                self.stats
+                    .input_shadowed(previous, self.size(previous, &path));
+                self.stats
+                    .input_unshadowed(winner, self.size(winner, &path));
+                self.place(&path, &Folded::new(&path));
+                self.line.end();
+            }
+        }
+    }
+
+    /// The span of syncing input `index`, entered.
+    #[cfg(feature = "tracing")]
+    fn sync_input_span(&self, index: usize) -> tracing::span::EnteredSpan {
+        instrument::sync_input(index, self.inputs[index].label.as_deref()).entered()
+    }
+
+    /// Returns the tracked paths at or beneath `prefix` that input `index` provides, sorted.
+    fn provided_under(&self, index: usize, prefix: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .tree
+            .beneath(prefix)
+            .into_iter()
+            .chain(Some(prefix))
+            .filter(|path| path.starts_with(prefix) && self.provided_by(index, path))
+            .map(Path::to_path_buf)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn apply_event(&mut self, event: EventType) {
+        #[cfg(feature = "tracing")]
+        let _span = instrument::event(&event).entered();
+        // The path is folded once, for everything the decision looks it up in.
+        let key = match &event.event {
+            Event::Create(path) | Event::Remove(path) => Some(Folded::new(path)),
+            _ => None,
+        };
+        // Only new files can be decided about while the workers put other files there.
+        let independent = match (&event.event, &key) {
+            (Event::Create(_), Some(key)) => !self.in_flight(key),
+            _ => false,
+        };
+        if !self.pool.in_flight.is_empty()
+            && (!independent || self.pool.in_flight.len() >= MAX_IN_FLIGHT)
+        {
+            self.finish_links();
+        }
+        let pending = self.begin_trace(&event);
+        self.decide(event, key);
+        if let Some(pending) = pending {
+            self.end_trace(pending);
+        }
+    }
+
+    fn decide(&mut self, event: EventType, key: Option<Folded>) {
+        match self.inputs[event.index].label.as_deref() {
+            Some(label) => self.line.begin(format_args!("[{}] {:?}", label, &event)),
+            None => self.line.begin(format_args!("{:?}", &event)),
+        }
 
         match event.event {
             Event::Create(path) => {
+                let key = key.unwrap_or_else(|| Folded::new(&path));
                 let input = &self.inputs[event.index];
-                if let Some(input_heap) = self.input_map.get_mut(&path) {
-                    if let Some(highest_prio) = input_heap.peek() {
-                        if input.priority <= highest_prio.priority {
-                            println!(" IGNORED!");
-                            input_heap.push(input.clone());
-                            return;
+                if self.fs.is_dir(&input.source(&path)) {
+                    if self.graftable(event.index, &path) && self.graft(event.index, &path) {
+                        self.stats.set_tracked_paths(self.input_map.len());
+                        self.line.end();
+                        return;
+                    }
+
+                    let input = &self.inputs[event.index];
+                    if self.strategy == Strategy::Hybrid {
+                        // Each directory inside gets its own chance at being grafted.
+                        let temp_files = &self.temp_files;
+                        let children: Vec<PathBuf> = input
+                            .children(&path)
+                            .into_iter()
+                            .filter(|child| temp_files.accepts_file(child))
+                            .collect();
+                        say!(self.line, Debug, " {} PATHS", children.len());
+                        self.line.end();
+                        for child in children {
+                            self.apply_event(EventType::following(
+                                event.index,
+                                Event::Create(child),
+                                event.observation,
+                            ));
                         }
+                        return;
                     }
+
+                    // Whatever was already inside a directory that appears is never
+                    // reported on its own.
+                    let temp_files = &self.temp_files;
+                    let found: Vec<PathBuf> = input
+                        .walk(&path)
+                        .into_iter()
+                        .filter(|file| temp_files.accepts_file(file))
+                        .collect();
+                    say!(self.line, Debug, " {} PATHS", found.len());
+                    self.line.end();
+                    for file in found {
+                        self.apply_event(EventType::following(
+                            event.index,
+                            Event::Create(file),
+                            event.observation,
+                        ));
+                    }
+                    return;
                 }
 
-                let mut input_file = input.path.clone();
-                let mut output_file = self.output.clone();
+                if !input.accepts_file(&path) {
+                    say!(self.line, Debug, " FILTERED!");
+                    self.line.end();
+                    self.skip(event.index, Skip::Filtered);
+                    return;
+                }
+                if self.too_young(event.index, &path) {
+                    say!(self.line, Debug, " TOO YOUNG!");
+                    self.line.end();
+                    return;
+                }
 
-                input_file.push(&path);
-                output_file.push(&path);
+                self.measure(event.index, &path);
+                // Directories only matter for the files in them, which are created next.
+                self.ungraft_around(event.index, &path);
+                let input = &self.inputs[event.index];
 
-                if output_file.exists() {
-                    print!(" DELETED,");
-                    fs::remove_file(&output_file).unwrap();
+                let index = input.index;
+                if self.graft_of(&path).is_some() {
+                    // Only this input has anything here, and the output already shows it.
+                    let input = input.ranked();
+                    let heap = self.track(&path, &key);
+                    if heap.iter().any(|other| other.index == index) {
+                        say!(self.line, Debug, " UNCHANGED!");
+                        self.line.end();
+                        return;
+                    }
+                    heap.push(input);
+                    self.stats.input_visible(index, self.size(index, &path));
+                    self.stats.set_tracked_paths(self.input_map.len());
+                    say!(self.line, Debug, " GRAFTED!");
+                    self.line.end();
+                    return;
                 }
-                if fs::hard_link(input_file, output_file).is_ok() {
-                    print!(" LINKED!");
-                    self.input_map
-                        .entry(path)
-                        .or_insert_with(BinaryHeap::new)
-                        .push(input.clone());
+
+                self.warn_folded(index, &path);
+                let blocked = self.blocked.contains(&path);
+                let plan = engine::plan_appeared(
+                    &path,
+                    self.input_map.get(&path),
+                    blocked,
+                    input.ranked(),
+                );
+                self.execute(plan.actions, Some(&key));
+                match plan.outcome {
+                    Appeared::Recreated if blocked => say!(self.line, Debug, " BLOCKED!"),
+                    Appeared::Shadowed | Appeared::Known => {
+                        if plan.outcome == Appeared::Shadowed {
+                            self.audit(AuditAction::Ignore, Some(&path), index, None);
+                        }
+                        if !self.remerge(&path) {
+                            say!(self.line, Debug, " IGNORED!");
+                            self.skip(index, Skip::Ignored);
+                        }
+                    }
+                    Appeared::Recreated | Appeared::Wins { .. } => {}
                 }
             }
             Event::Remove(path) => {
-                let mut output_file = self.output.clone();
-                output_file.push(&path);
-
-                if let Some(input_heap) = self.input_map.get_mut(&path) {
-                    if let Some(highest_prio) = input_heap.peek() {
-                        if event.index == highest_prio.index {
-                            input_heap.pop();
-                            print!(" DELETED!");
-                            fs::remove_file(&output_file).unwrap();
-                            if let Some(highest_prio) = input_heap.peek() {
-                                let mut input_file = highest_prio.path.clone();
-                                input_file.push(&path);
-
-                                if fs::hard_link(input_file, output_file).is_ok() {
-                                    print!(" LINKED!");
-                                }
-                            }
+                let key = key.unwrap_or_else(|| Folded::new(&path));
+                let index = event.index;
+                self.drop_grafts(index, &path);
+
+                // A removed directory may only be reported once, not once per file. The same
+                // path can also be a file in some other input.
+                let nested: Vec<PathBuf> = self
+                    .provided_under(index, &path)
+                    .into_iter()
+                    .filter(|key| *key != path)
+                    .collect();
+                let tracked = self.input_map.contains_key(&path);
+                if !nested.is_empty() || !tracked {
+                    say!(self.line, Debug, " {} PATHS", nested.len());
+                    self.line.end();
+                    for key in nested {
+                        self.apply_event(EventType::following(
+                            index,
+                            Event::Remove(key),
+                            event.observation,
+                        ));
+                    }
+
+                    self.remove_empty_dirs(&path);
+                    self.prune_under(&path);
+                    if !tracked {
+                        return;
+                    }
+                }
+
+                if self.graft_of(&path).is_some() {
+                    // The file already went from the output along with the input.
+                    if let Some(heap) = self.input_map.get_mut(&path) {
+                        if heap.iter().any(|input| input.index == index) {
+                            heap.retain(|input| input.index != index);
+                            self.stats.input_hidden(index, self.size(index, &path));
+                        }
+                    }
+                    self.prune(&path);
+                    say!(self.line, Debug, " GRAFTED!");
+                    self.line.end();
+                    return;
+                }
+
+                let blocked = self.blocked.contains(&path);
+                let plan = engine::plan_vanished(&path, self.input_map.get(&path), blocked, index);
+                self.execute(plan.actions, Some(&key));
+                // The winner's file stays, but what it was merged with doesn't.
+                if plan.outcome == Vanished::Shadowed && !self.remerge(&path) {
+                    self.skip(index, Skip::Ignored);
+                }
+                self.prune(&path);
+                if self.strategy == Strategy::Hybrid {
+                    let ancestors = path.ancestors().skip(1);
+                    self.grafts.collapsible.extend(
+                        ancestors
+                            .filter(|dir| !dir.as_os_str().is_empty())
+                            .map(Path::to_path_buf),
+                    );
+                }
+            }
+            Event::Rename(from, to) => {
+                let index = event.index;
+                let grafts = self.drop_grafts(index, &from);
+
+                // A renamed directory arrives as a single event, so everything this input
+                // provides beneath it has to be moved individually.
+                let plan = engine::plan_renamed(&from, &to, self.provided_under(index, &from));
+                let renames = plan.outcome;
+                say!(self.line, Debug, " {} PATHS", renames.len());
+                self.line.end();
+                self.execute(plan.actions, None);
+
+                let target = self.inputs[index].source(&to);
+                if renames.is_empty() {
+                    if self.fs.exists(&target) {
+                        self.apply_event(EventType::following(
+                            index,
+                            Event::Create(to),
+                            event.observation,
+                        ));
+                    }
+                } else {
+                    // Grafted directories that moved along are put back where they went.
+                    for graft in grafts {
+                        let rest = graft.strip_prefix(&from).unwrap();
+                        self.apply_event(EventType::following(
+                            index,
+                            Event::Create(to.join(rest)),
+                            event.observation,
+                        ));
+                    }
+                }
+                // Where they went is decided like for any file that appears there, against
+                // whatever other inputs have at that path already.
+                for (old, new) in renames {
+                    self.apply_event(EventType::following(
+                        index,
+                        Event::Remove(old.clone()),
+                        event.observation,
+                    ));
+                    self.apply_event(EventType::following(
+                        index,
+                        Event::Create(new),
+                        event.observation,
+                    ));
+                    if let Some(parent) = old.parent() {
+                        self.remove_empty_dirs(parent);
+                    }
+                }
+                // Grafts that moved along left their former paths behind.
+                self.prune_under(&from);
+
+                return;
+            }
+            Event::PermissionsChanged(path) => {
+                let index = event.index;
+                let source = self.inputs[index].source(&path);
+                let output_file = self.output.join(&path);
+
+                if self.materialized(&path).map(|input| input.index) != Some(index) {
+                    say!(self.line, Debug, " IGNORED!");
+                    self.skip(index, Skip::Ignored);
+                } else if self.graft_of(&path).is_some()
+                    || self.fs.same_file(&source, &output_file).unwrap_or(false)
+                {
+                    // Hard links and grafted files have the input's permissions already,
+                    // copies have to be given them.
+                    say!(self.line, Debug, " UNCHANGED!");
+                } else {
+                    let result = self.copy_permissions(&source, &output_file);
+                    match &result {
+                        Ok(()) => say!(self.line, Info, " PERMISSIONS COPIED!"),
+                        Err(e) => {
+                            say!(self.line, Error, " {}", e);
+                            self.failed(Some(&path), Some(index), error_kind(e), e.to_string());
                         }
                     }
+                    if let Err(e) = &result {
+                        let error = e.to_string();
+                        self.audit(AuditAction::Error, Some(&path), index, Some(&error));
+                    }
                 }
             }
-            Event::Rename(_from, _to) => {
-                //TODO: Implement this >..>
+            Event::Error(e, path) => {
+                say!(self.line, Error, " {} ({:?})", e, path);
+                let kind = match e.downcast_ref::<io::Error>() {
+                    Some(e) => error_kind(e),
+                    None => "watch".to_string(),
+                };
+                self.failed(path.as_deref(), Some(event.index), kind, e.to_string());
+                let error = e.to_string();
+                self.audit(
+                    AuditAction::Error,
+                    path.as_deref(),
+                    event.index,
+                    Some(&error),
+                );
             }
-            _ => {}
+            // `process_loop` restarts the watcher instead, this only reports it.
+            Event::WatcherFailed(e) => say!(self.line, Error, " {}", e),
+            // `process_event` only counts it.
+            Event::Polled(_) => say!(self.line, Debug, " POLLED!"),
         }
 
-        println!();
+        self.stats.set_tracked_paths(self.input_map.len());
+        self.line.end();
     }
 
-    pub fn process_loop(&mut self) -> Result<(), Error> {
-        // TODO: Clean output directory.
-        // TODO: Walk input directories and build output directory.
+    /// Carries out the actions `engine` planned, in order.
+    /// Carries out `actions`, which are those of a plan about the path that folds to `key`,
+    /// if it is about one.
+    fn execute(&mut self, actions: Vec<Action>, key: Option<&Folded>) {
+        let key = |path: &Path| key.map_or_else(|| Cow::Owned(Folded::new(path)), Cow::Borrowed);
+        for action in actions {
+            match action {
+                Action::CreateDir { path } => {
+                    let _ = self.create_dir_all(&self.output.join(path));
+                }
+                Action::Link { to, .. } => self.place(&to, &key(&to)),
+                Action::Relink { from, to } => {
+                    let input_file = self.inputs[from].source(&to);
+                    let output_file = self.output.join(&to);
+                    if self.provides(from, &input_file, &output_file) {
+                        // Already linked, e.g. by walking a directory this file was
+                        // created in.
+                        say!(self.line, Debug, " UNCHANGED!");
+                    } else {
+                        self.link(&to, &key(&to), from);
+                    }
+                }
+                Action::Remove { path, index } => self.unlink(&path, index),
+                Action::RemoveEmptyDirs { path } => self.remove_empty_dirs(&path),
+                Action::UpdateMap { path, update } => match update {
+                    MapUpdate::Track(_) => {
+                        engine::update_heap(self.track(&path, &key(&path)), update)
+                    }
+                    MapUpdate::Untrack(_) => {
+                        if let Some(heap) = self.input_map.get_mut(&path) {
+                            engine::update_heap(heap, update);
+                        }
+                    }
+                    MapUpdate::Unblock => {
+                        self.blocked.remove(&path);
+                    }
+                    MapUpdate::Release => self.restore_blocked(&path, &key(&path)),
+                },
+                Action::Count { index, path, count } => {
+                    let size = self.size(index, &path);
+                    match count {
+                        Count::Hidden => self.stats.input_hidden(index, size),
+                        Count::Shadowed => self.stats.input_shadowed(index, size),
+                        Count::Unshadowed => self.stats.input_unshadowed(index, size),
+                    }
+                }
+            }
+        }
+    }
 
-        let rx: Receiver<EventType> = self.build_watchers()?;
+    /// Syncs the output once, with the same care `process_loop` takes before it starts
+    /// watching, and returns what the sync did. Nothing is watched afterwards, which is
+    /// all there is without the `watch` feature.
+    pub fn sync_once(&mut self) -> Result<SyncReport, Error> {
+        let _lock = self.lock()?;
+        self.prepare()?;
+        self.apply_load_order()?;
+        self.check_free_space()?;
+        let report = self.sync();
+        self.report_sync(&report);
+        self.collapse_grafts();
+        self.drain_retries();
+        self.save_ledger();
+        self.enforce_trash_retention();
+        if let Some(audit) = self.audit.as_mut() {
+            audit.flush();
+        }
+        Ok(report)
+    }
 
-        loop {
-            self.process_event(rx.recv()?);
+    /// Locks the output, unless other overlays may run on it too or this is a dry run. It
+    /// stays locked until the lock is dropped.
+    fn lock(&self) -> Result<Option<InstanceLock>, Error> {
+        if self.single_instance && !self.dry_run {
+            Ok(Some(InstanceLock::acquire(&self.output)?))
+        } else {
+            Ok(None)
         }
     }
+
+    /// Reads what earlier runs put into the output, and finds out how the files of each
+    /// input can get there.
+    fn prepare(&mut self) -> Result<(), Error> {
+        self.ledger = Ledger::load(&self.output)?;
+        self.open_trash()?;
+        self.check_existing_output()?;
+        self.probe_inputs()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// An empty directory of its own for the test called `name`.
+    pub(crate) fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("overlay-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A synced overlay whose inputs, of the priorities given, and output are in a
+    /// `MemoryFs`, fed the events of the changes made to them.
+    pub(crate) struct Harness {
+        pub(crate) overlay: Overlay,
+        pub(crate) fs: Arc<MemoryFs>,
+        pub(crate) inputs: Vec<PathBuf>,
+        pub(crate) output: PathBuf,
+    }
+
+    impl Harness {
+        pub(crate) fn new(name: &str, priorities: &[u32]) -> Self {
+            Harness::with(name, priorities, |builder| builder)
+        }
+
+        /// Like `new`, with the builder set up further by `configure`.
+        pub(crate) fn with<F>(name: &str, priorities: &[u32], configure: F) -> Self
+        where
+            F: FnOnce(OverlayBuilder) -> OverlayBuilder,
+        {
+            let root = scratch(name);
+            let fs = Arc::new(MemoryFs::new());
+            let output = root.join("output");
+            let mut builder = OverlayBuilder::new(&output)
+                .file_ops(fs.clone())
+                .single_instance(false)
+                .workers(1);
+            let mut inputs = vec![];
+            for (index, priority) in priorities.iter().enumerate() {
+                // Inputs have to be directories when they are added, their files are only
+                // in memory.
+                let input = root.join(format!("input{}", index));
+                fs::create_dir_all(&input).unwrap();
+                fs.create_dir(&input);
+                builder = builder.input(&input, *priority);
+                inputs.push(input);
+            }
+            let mut overlay = configure(builder).build().unwrap();
+            overlay.sync_once().unwrap();
+            Harness {
+                overlay,
+                fs,
+                inputs,
+                output,
+            }
+        }
+
+        /// Creates the file `path` in input `index`, and tells the overlay.
+        pub(crate) fn create(&mut self, index: usize, path: &str) {
+            self.fs.create_file(self.inputs[index].join(path));
+            self.event(index, Event::Create(PathBuf::from(path)));
+        }
+
+        pub(crate) fn remove(&mut self, index: usize, path: &str) {
+            let file = self.inputs[index].join(path);
+            if self.fs.is_dir(&file) {
+                self.fs.remove_dir_all(&file).unwrap();
+            } else {
+                self.fs.remove_file(&file).unwrap();
+            }
+            self.event(index, Event::Remove(PathBuf::from(path)));
+        }
+
+        pub(crate) fn rename(&mut self, index: usize, from: &str, to: &str) {
+            let (from_file, to_file) = (self.inputs[index].join(from), self.inputs[index].join(to));
+            self.fs.create_dir(to_file.parent().unwrap());
+            self.fs.rename(&from_file, &to_file).unwrap();
+            self.event(index, Event::Rename(PathBuf::from(from), PathBuf::from(to)));
+        }
+
+        pub(crate) fn event(&mut self, index: usize, event: Event) {
+            self.overlay.apply_event(EventType::new(index, event));
+            self.overlay.finish_links();
+        }
+
+        /// The input whose file is at `path` in the output, if it has one of theirs.
+        pub(crate) fn winner(&self, path: &str) -> Option<usize> {
+            let output = self.output.join(path);
+            (0..self.inputs.len()).find(|&index| {
+                self.fs
+                    .same_file(&self.inputs[index].join(path), &output)
+                    .unwrap_or(false)
+            })
+        }
+
+        pub(crate) fn in_output(&self, path: &str) -> bool {
+            self.fs.exists(&self.output.join(path))
+        }
+    }
+
+    #[test]
+    fn create_links_the_file() {
+        let mut harness = Harness::new("create-links", &[0]);
+        harness.create(0, "a/x");
+        assert_eq!(harness.winner("a/x"), Some(0));
+        assert_eq!(
+            harness.overlay.resolve("a/x").unwrap().input,
+            InputId::of(0)
+        );
+    }
+
+    #[test]
+    fn create_of_higher_priority_replaces_the_winner() {
+        let mut harness = Harness::new("create-replaces", &[0, 1]);
+        harness.create(0, "x");
+        harness.create(1, "x");
+        assert_eq!(harness.winner("x"), Some(1));
+        assert_eq!(harness.overlay.providers("x").len(), 2);
+    }
+
+    #[test]
+    fn create_of_lower_priority_is_shadowed() {
+        let mut harness = Harness::new("create-shadowed", &[1, 0]);
+        harness.create(0, "x");
+        harness.create(1, "x");
+        assert_eq!(harness.winner("x"), Some(0));
+        assert_eq!(harness.overlay.stats().inputs[1].shadowed, 1);
+    }
+
+    #[test]
+    fn remove_of_the_winner_falls_back_to_the_next() {
+        let mut harness = Harness::new("remove-falls-back", &[0, 1]);
+        harness.create(0, "x");
+        harness.create(1, "x");
+        harness.remove(1, "x");
+        assert_eq!(harness.winner("x"), Some(0));
+        harness.remove(0, "x");
+        assert!(!harness.in_output("x"));
+        assert!(harness.overlay.resolve("x").is_none());
+    }
+
+    #[test]
+    fn remove_of_a_shadowed_file_keeps_the_winner() {
+        let mut harness = Harness::new("remove-shadowed", &[0, 1]);
+        harness.create(0, "x");
+        harness.create(1, "x");
+        harness.remove(0, "x");
+        assert_eq!(harness.winner("x"), Some(1));
+        assert_eq!(harness.overlay.providers("x").len(), 1);
+    }
+
+    #[test]
+    fn rename_moves_the_file_in_the_output() {
+        let mut harness = Harness::new("rename-moves", &[0]);
+        harness.create(0, "a/x");
+        harness.rename(0, "a/x", "b/y");
+        assert!(!harness.in_output("a/x"));
+        assert!(!harness.in_output("a"));
+        assert_eq!(harness.winner("b/y"), Some(0));
+    }
+
+    #[test]
+    fn renaming_a_directory_moves_each_file_beneath_it() {
+        let mut harness = Harness::new("rename-directory", &[0, 1]);
+        for path in ["textures/a.dds", "textures/sub/b.dds", "textures/sub/c.dds"] {
+            harness.create(0, path);
+        }
+        harness.create(1, "textures/a.dds");
+        harness.create(1, "textures_old/c.dds");
+        harness.rename(0, "textures", "textures_old");
+
+        assert!(harness.overlay.failures.is_empty());
+        // What the input was shadowed at, it still is, and what it won it no longer has.
+        assert_eq!(harness.winner("textures/a.dds"), Some(1));
+        assert!(!harness.in_output("textures/sub"));
+        assert_eq!(harness.winner("textures_old/a.dds"), Some(0));
+        assert_eq!(harness.winner("textures_old/sub/b.dds"), Some(0));
+        assert_eq!(harness.winner("textures_old/sub/c.dds"), Some(0));
+        assert_eq!(harness.winner("textures_old/c.dds"), Some(1));
+        let providers = harness.overlay.providers("textures/a.dds");
+        assert_eq!(providers.len(), 1);
+    }
+
+    #[test]
+    fn a_directory_that_appears_links_what_was_already_in_it() {
+        let mut harness = Harness::new("directory-appears", &[0]);
+        // Walked on disk, and linked from memory.
+        for path in ["pack/a.esp", "pack/sub/b.esp", "pack/sub/c.esp.part"] {
+            let file = harness.inputs[0].join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, path).unwrap();
+            harness.fs.create_file(&file);
+        }
+        harness.event(0, Event::Create(PathBuf::from("pack")));
+        assert_eq!(harness.winner("pack/a.esp"), Some(0));
+        assert_eq!(harness.winner("pack/sub/b.esp"), Some(0));
+        assert!(!harness.in_output("pack/sub/c.esp.part"));
+        assert_eq!(harness.overlay.stats.linked, 2);
+
+        // The watcher telling of a file in it too changes nothing.
+        harness.event(0, Event::Create(PathBuf::from("pack/sub/b.esp")));
+        assert!(harness.overlay.failures.is_empty());
+        assert_eq!(harness.overlay.stats.linked, 2);
+        assert_eq!(harness.winner("pack/sub/b.esp"), Some(0));
+    }
+
+    #[test]
+    fn rename_over_a_shadowed_path_is_decided_by_priority() {
+        let mut harness = Harness::new("rename-priority", &[1, 0]);
+        harness.create(0, "y");
+        harness.create(1, "x");
+        harness.rename(1, "x", "y");
+        assert_eq!(harness.winner("y"), Some(0));
+        assert!(!harness.in_output("x"));
+    }
+
+    #[test]
+    fn failing_remove_keeps_the_file_and_is_reported() {
+        let mut harness = Harness::new("remove-denied", &[0]);
+        harness.create(0, "x");
+        let output = harness.output.join("x");
+        harness
+            .fs
+            .fail(FileOp::RemoveFile, &output, io::ErrorKind::PermissionDenied);
+        harness.remove(0, "x");
+        assert!(harness.in_output("x"));
+        assert!(harness.overlay.resolve("x").is_none());
+        let failure = harness.overlay.failures().last().unwrap();
+        assert_eq!(failure.path.as_deref(), Some(Path::new("x")));
+        assert_eq!(failure.kind, "permission_denied");
+        // It won't be any different later.
+        assert!(!harness
+            .overlay
+            .retries
+            .holds(Path::new("x"), RetryAction::Unlink, 0));
+    }
+
+    #[test]
+    fn busy_remove_is_tried_again_later() {
+        let mut harness = Harness::with("remove-busy", &[0], |builder| {
+            builder.retry_policy(RetryPolicy {
+                attempts: 1,
+                delay: Duration::ZERO,
+            })
+        });
+        harness.create(0, "x");
+        let output = harness.output.join("x");
+        harness
+            .fs
+            .fail(FileOp::RemoveFile, &output, io::ErrorKind::ResourceBusy);
+        harness.remove(0, "x");
+        assert!(harness.in_output("x"));
+        assert!(harness
+            .overlay
+            .retries
+            .holds(Path::new("x"), RetryAction::Unlink, 0));
+    }
+
+    #[test]
+    fn failing_link_leaves_the_path_out_of_the_output() {
+        let mut harness = Harness::new("link-denied", &[0]);
+        let output = harness.output.join("x");
+        harness
+            .fs
+            .fail(FileOp::HardLink, &output, io::ErrorKind::PermissionDenied);
+        harness.create(0, "x");
+        assert!(!harness.in_output("x"));
+        assert!(harness.overlay.resolve("x").is_none());
+        assert_eq!(
+            harness.overlay.failures().last().unwrap().kind,
+            "permission_denied"
+        );
+    }
+
+    #[test]
+    fn failing_replacement_keeps_the_previous_file() {
+        let mut harness = Harness::new("replace-denied", &[0, 1]);
+        harness.create(0, "x");
+        let staged = beside(&harness.output.join("x"), ".overlay-new");
+        harness
+            .fs
+            .fail(FileOp::HardLink, &staged, io::ErrorKind::PermissionDenied);
+        harness.create(1, "x");
+        assert_eq!(harness.winner("x"), Some(0));
+        assert!(!harness.fs.exists(&staged));
+    }
+
+    #[test]
+    fn directory_index_follows_what_is_tracked() {
+        let mut harness = Harness::new("directory-index", &[0, 1]);
+        harness.create(0, "a/b/x");
+        harness.create(1, "a/y");
+        assert_eq!(
+            harness.overlay.provided_under(0, Path::new("a")),
+            [Path::new("a/b/x")]
+        );
+        assert_eq!(
+            harness.overlay.directory_claim(Path::new("a")),
+            Some(harness.overlay.inputs[1].rank)
+        );
+
+        harness.remove(0, "a/b");
+        assert!(harness.overlay.tree.beneath(Path::new("a/b")).is_empty());
+        harness.remove(1, "a");
+        assert!(harness.overlay.tree.beneath(Path::new("")).is_empty());
+        assert!(harness.overlay.case.folded.is_empty());
+    }
+
+    #[test]
+    fn create_and_remove_cycles_leave_nothing_behind() {
+        let mut harness = Harness::new("cycles", &[0, 1]);
+        harness.create(0, "keep");
+        let (paths, sizes) = (harness.overlay.input_map.len(), harness.overlay.sizes.len());
+        for _ in 0..3 {
+            harness.create(0, "a/b/x");
+            harness.create(1, "a/b/x");
+            harness.create(1, "a/y");
+            harness.remove(0, "a/b/x");
+            // The whole directory at once, as a single event.
+            harness.remove(1, "a");
+            assert_eq!(harness.overlay.input_map.len(), paths);
+        }
+        assert_eq!(harness.overlay.sizes.len(), sizes);
+        assert_eq!(
+            harness.overlay.tree.beneath(Path::new("")),
+            [Path::new("keep")]
+        );
+        assert_eq!(harness.overlay.stats().tracked_paths, paths);
+    }
+
+    #[test]
+    fn removing_a_directory_takes_out_what_only_it_provides() {
+        let mut harness = Harness::new("remove-directory", &[1, 0]);
+        harness.create(0, "only/a/x");
+        harness.create(0, "only/y");
+        harness.create(0, "shared/a/x");
+        harness.create(1, "shared/a/x");
+        harness.create(1, "shared/z");
+        harness.create(0, "shared/w");
+
+        harness.remove(0, "only");
+        assert!(!harness.in_output("only"));
+        assert!(harness.overlay.resolve("only/a/x").is_none());
+
+        assert_eq!(harness.winner("shared/a/x"), Some(0));
+        harness.remove(0, "shared");
+        assert_eq!(harness.winner("shared/a/x"), Some(1));
+        assert_eq!(harness.winner("shared/z"), Some(1));
+        assert!(!harness.in_output("shared/w"));
+        assert_eq!(harness.overlay.input_map.len(), 2);
+    }
+
+    #[test]
+    fn rename_onto_the_file_of_a_higher_input_is_shadowed() {
+        let mut harness = Harness::new("rename-shadowed", &[0, 1]);
+        harness.create(1, "core.dat");
+        harness.create(0, "patch.dat");
+        harness.rename(0, "patch.dat", "core.dat");
+        assert_eq!(harness.winner("core.dat"), Some(1));
+        assert!(!harness.in_output("patch.dat"));
+        assert_eq!(harness.overlay.providers("core.dat").len(), 2);
+        assert_eq!(harness.overlay.stats().inputs[0].shadowed, 1);
+    }
+
+    #[test]
+    fn rename_onto_the_file_of_a_lower_input_replaces_it() {
+        let mut harness = Harness::new("rename-replaces", &[0, 1]);
+        harness.create(0, "core.dat");
+        harness.create(1, "patch.dat");
+        harness.rename(1, "patch.dat", "core.dat");
+        assert_eq!(harness.winner("core.dat"), Some(1));
+        assert!(!harness.in_output("patch.dat"));
+        assert_eq!(harness.overlay.stats().inputs[0].shadowed, 1);
+    }
+
+    #[test]
+    fn rename_onto_a_file_of_the_same_input_follows_the_new_file() {
+        let mut harness = Harness::new("rename-own", &[0, 1]);
+        harness.create(0, "core.dat");
+        harness.create(0, "patch.dat");
+        harness.rename(0, "patch.dat", "core.dat");
+        assert_eq!(harness.winner("core.dat"), Some(0));
+        assert!(!harness.in_output("patch.dat"));
+        assert_eq!(harness.overlay.providers("core.dat").len(), 1);
+    }
+
+    #[test]
+    fn list_has_each_winner_by_path() {
+        let mut harness = Harness::new("list", &[0, 1]);
+        harness.create(0, "b");
+        harness.create(0, "a");
+        harness.create(1, "a");
+        harness.create(1, "c/d");
+        harness.create(1, "e");
+        harness.remove(1, "e");
+
+        let entries: Vec<(PathBuf, InputId, usize)> = harness
+            .overlay
+            .list()
+            .map(|entry| (entry.path, entry.input, entry.providers))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (PathBuf::from("a"), InputId::of(1), 2),
+                (PathBuf::from("b"), InputId::of(0), 1),
+                (PathBuf::from("c/d"), InputId::of(1), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn providers_are_the_winner_first_and_flag_what_is_gone() {
+        let mut harness = Harness::new("providers", &[5, 20, 10]);
+        for index in 0..3 {
+            harness.create(index, "x");
+        }
+        // Gone without the overlay hearing of it.
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("x"))
+            .unwrap();
+
+        let providers: Vec<(InputId, u32, PathBuf, bool)> = harness
+            .overlay
+            .providers("x")
+            .into_iter()
+            .map(|provider| {
+                (
+                    provider.input,
+                    provider.priority,
+                    provider.source,
+                    provider.stale,
+                )
+            })
+            .collect();
+        let source = |index: usize| harness.inputs[index].join("x");
+        assert_eq!(
+            providers,
+            [
+                (InputId::of(1), 20, source(1), false),
+                (InputId::of(2), 10, source(2), false),
+                (InputId::of(0), 5, source(0), true),
+            ]
+        );
+        assert!(harness.overlay.providers("y").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_changed_in_the_winner_are_given_to_its_copy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = scratch("permissions");
+        let (output, inputs) = (root.join("output"), [root.join("base"), root.join("mods")]);
+        for input in &inputs {
+            fs::create_dir_all(input).unwrap();
+            fs::write(input.join("run.sh"), "exit 0").unwrap();
+            fs::set_permissions(input.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        for (priority, input) in inputs.iter().enumerate() {
+            let id = overlay.add_input(input, priority as u32).unwrap();
+            overlay.set_link_strategy(id, Arc::new(Copies)).unwrap();
+        }
+        overlay.sync_once().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let copy = output.join("run.sh");
+        assert_eq!(mode(&copy), 0o644);
+
+        // Only the shadowed file's, which changes nothing.
+        let chmod = |overlay: &mut Overlay, index: usize, mode: u32| {
+            let file = inputs[index].join("run.sh");
+            fs::set_permissions(&file, fs::Permissions::from_mode(mode)).unwrap();
+            let event = Event::PermissionsChanged(PathBuf::from("run.sh"));
+            overlay.apply_event(EventType::new(index, event));
+        };
+        chmod(&mut overlay, 0, 0o600);
+        assert_eq!(mode(&copy), 0o644);
+        chmod(&mut overlay, 1, 0o755);
+        assert_eq!(mode(&copy), 0o755);
+        assert!(!RealFs.same_file(&inputs[1].join("run.sh"), &copy).unwrap());
+        assert!(overlay.failures.is_empty());
+    }
+
+    #[test]
+    fn input_stats_follow_what_is_visible_and_shadowed_of_each_input() {
+        let mut harness = Harness::new("input-stats", &[0, 1]);
+        let write = |harness: &mut Harness, index: usize, path: &str, size: usize| {
+            let file = harness.inputs[index].join(path);
+            harness.fs.write_file(file, &vec![0; size]);
+            harness.event(index, Event::Create(PathBuf::from(path)));
+        };
+        let counts = |harness: &Harness, index: usize| {
+            let stats = harness.overlay.input_stats(InputId::of(index)).unwrap();
+            (
+                stats.visible,
+                stats.visible_bytes,
+                stats.shadowed,
+                stats.shadowed_bytes,
+            )
+        };
+        write(&mut harness, 0, "a", 10);
+        write(&mut harness, 0, "b", 20);
+        write(&mut harness, 1, "a", 5);
+        assert_eq!(counts(&harness, 0), (1, 20, 1, 10));
+        assert_eq!(counts(&harness, 1), (1, 5, 0, 0));
+
+        write(&mut harness, 0, "b", 30);
+        assert_eq!(counts(&harness, 0), (1, 30, 1, 10));
+        harness.overlay.set_priority(InputId::of(0), 2).unwrap();
+        assert_eq!(counts(&harness, 0), (2, 40, 0, 0));
+        assert_eq!(counts(&harness, 1), (0, 0, 1, 5));
+        harness.remove(0, "a");
+        assert_eq!(counts(&harness, 0), (1, 30, 0, 0));
+        assert_eq!(counts(&harness, 1), (1, 5, 0, 0));
+
+        let json = serde_json::to_value(harness.overlay.stats()).unwrap();
+        assert_eq!(json["inputs"][0]["visible"], 1);
+        assert_eq!(json["inputs"][0]["visible_bytes"], 30);
+        assert_eq!(json["inputs"][1]["shadowed_bytes"], 0);
+    }
+
+    #[test]
+    fn a_walk_finds_the_same_files_whatever_order_it_reads_them_in() {
+        let root = scratch("parallel-walk");
+        let (input, output) = (root.join("input"), root.join("output"));
+        let mut expected = vec![];
+        for n in 0..300 {
+            let path = PathBuf::from(format!("dir{}/sub{}/file{:03}", n % 7, n % 3, n));
+            fs::create_dir_all(input.join(&path).parent().unwrap()).unwrap();
+            fs::write(input.join(&path), "").unwrap();
+            expected.push(path);
+        }
+        for path in ["cache/a", "cache/deep/b", "dir0/too/deep/c"] {
+            fs::create_dir_all(input.join(path).parent().unwrap()).unwrap();
+            fs::write(input.join(path), "").unwrap();
+        }
+        fs::write(input.join("top"), "").unwrap();
+        expected.push(PathBuf::from("top"));
+        expected.sort();
+
+        let options = InputOptions::new().exclude("cache").max_depth(3);
+        let mut overlay = Overlay::new(&output);
+        overlay.add_input_with_options(&input, 0, &options).unwrap();
+        for _ in 0..3 {
+            assert_eq!(overlay.inputs[0].walk(Path::new("")), expected);
+        }
+        let below: Vec<PathBuf> = expected
+            .iter()
+            .filter(|path| path.starts_with("dir3"))
+            .cloned()
+            .collect();
+        assert_eq!(overlay.inputs[0].walk(Path::new("dir3")), below);
+        assert_eq!(
+            overlay.inputs[0].walk(Path::new("cache")),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn an_inputs_shadowed_files_are_those_resolve_has_from_another() {
+        let mut harness = Harness::new("shadowed", &[0, 5, 9]);
+        for path in ["a", "b", "c"] {
+            harness.create(0, path);
+        }
+        harness.create(1, "a");
+        harness.create(2, "b");
+        harness.create(2, "d");
+
+        let shadowed = harness.overlay.shadowed(InputId::of(0));
+        let listed: Vec<(&str, usize, u32)> = shadowed
+            .iter()
+            .map(|entry| {
+                let winner = &entry.winner;
+                (
+                    entry.path.to_str().unwrap(),
+                    winner.input.index(),
+                    winner.priority,
+                )
+            })
+            .collect();
+        assert_eq!(listed, [("a", 1, 5), ("b", 2, 9)]);
+        for entry in &shadowed {
+            assert_eq!(entry.source, harness.inputs[0].join(&entry.path));
+            assert_eq!(
+                harness.overlay.resolve(&entry.path),
+                Some(entry.winner.clone())
+            );
+        }
+        assert!(harness.overlay.shadowed(InputId::of(2)).is_empty());
+
+        // As things are now.
+        harness.remove(1, "a");
+        let paths: Vec<PathBuf> = harness
+            .overlay
+            .shadowed(InputId::of(0))
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, [PathBuf::from("b")]);
+        harness.overlay.remove_input(InputId::of(2)).unwrap();
+        harness.overlay.finish_links();
+        assert!(harness.overlay.shadowed(InputId::of(0)).is_empty());
+        assert!(harness.overlay.shadowed(InputId::of(2)).is_empty());
+    }
 }
@@ -0,0 +1,154 @@
+use crate::fs_ops::{is_too_many_links, FileOps};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What a file in the output is of the input file it was made from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    /// A hard link of it.
+    #[default]
+    HardLink,
+    /// A copy of it, as `FileOps::copy` makes them.
+    Copy,
+    /// Something else made of it, which only the strategy that made it knows.
+    Custom,
+}
+
+/// How the overlay puts the file of an input into the output, and takes it out again.
+///
+/// Every input hard links its files with `HardLinks` by default, or copies them with
+/// `Copies` where they can't be linked, unless `Overlay::set_link_strategy` gave it another
+/// strategy. Files are put there and removed through `ops`, the `FileOps` of the overlay. A
+/// file being replaced is put beside it first, then renamed over it by the overlay.
+///
+/// The overlay remembers which strategy made each file of the output, and what it made,
+/// so the same one removes it again even if the input has another strategy by then.
+pub trait LinkStrategy: Debug + Send + Sync {
+    /// Makes `dst`, where there is nothing yet, out of the input file `src`.
+    fn materialize(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<LinkKind>;
+
+    /// Removes the file `materialize` made at `dst`, which it said was of `kind`.
+    fn remove(&self, dst: &Path, kind: LinkKind, ops: &dyn FileOps) -> io::Result<()> {
+        let _ = kind;
+        ops.remove_file(dst)
+    }
+
+    /// Whether `dst` is what `materialize` makes of `src`, so there is nothing to do. It
+    /// isn't by default, and a strategy that can't tell has its files made again whenever
+    /// the overlay syncs.
+    fn provides(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<bool> {
+        let _ = (src, dst, ops);
+        Ok(false)
+    }
+}
+
+/// Hard links the input's files into the output, or copies those that already have as
+/// many hard links as their filesystem allows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HardLinks;
+
+impl LinkStrategy for HardLinks {
+    fn materialize(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<LinkKind> {
+        match ops.hard_link(src, dst) {
+            Ok(()) => Ok(LinkKind::HardLink),
+            Err(ref e) if is_too_many_links(e) => ops.copy(src, dst).map(|()| LinkKind::Copy),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn provides(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<bool> {
+        ops.same_file(src, dst)
+    }
+}
+
+/// Copies the input's files into the output, like is done for inputs that can't be hard
+/// linked from.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Copies;
+
+impl LinkStrategy for Copies {
+    fn materialize(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<LinkKind> {
+        ops.copy(src, dst).map(|()| LinkKind::Copy)
+    }
+
+    fn provides(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<bool> {
+        ops.same_copy(src, dst)
+    }
+}
+
+/// A file of the output, as the strategy that made it made it.
+#[derive(Debug, Clone)]
+pub(crate) struct Link {
+    pub(crate) strategy: Arc<dyn LinkStrategy>,
+    pub(crate) kind: LinkKind,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use crate::InputId;
+    use std::sync::Mutex;
+
+    /// Copies, and tells what it was asked to do.
+    #[derive(Debug, Default)]
+    struct Recording(Mutex<Vec<String>>);
+
+    impl LinkStrategy for Recording {
+        fn materialize(&self, src: &Path, dst: &Path, ops: &dyn FileOps) -> io::Result<LinkKind> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("make {}", dst.display()));
+            ops.copy(src, dst).map(|()| LinkKind::Custom)
+        }
+
+        fn remove(&self, dst: &Path, kind: LinkKind, ops: &dyn FileOps) -> io::Result<()> {
+            let removed = format!("remove {} {:?}", dst.display(), kind);
+            self.0.lock().unwrap().push(removed);
+            ops.remove_file(dst)
+        }
+    }
+
+    #[test]
+    fn a_file_is_removed_by_the_strategy_that_made_it() {
+        let mut harness = Harness::new("custom-strategy", &[0, 1]);
+        let recording = Arc::new(Recording::default());
+        let base = InputId::of(0);
+        harness
+            .overlay
+            .set_link_strategy(base, recording.clone())
+            .unwrap();
+        harness.create(0, "x");
+        harness.create(0, "y");
+        assert!(harness.in_output("x"));
+        assert_eq!(harness.winner("x"), None);
+        let ledger = harness.overlay.links.get(Path::new("x")).unwrap().kind;
+        assert_eq!(ledger, LinkKind::Custom);
+
+        // Files already there stay as they were made.
+        harness
+            .overlay
+            .set_link_strategy(base, Arc::new(HardLinks))
+            .unwrap();
+        harness.remove(0, "x");
+        // A file that is replaced is renamed over instead.
+        harness.create(1, "y");
+        assert_eq!(harness.winner("y"), Some(1));
+        harness.create(0, "z");
+        assert_eq!(harness.winner("z"), Some(0));
+        let (x, y) = (harness.output.join("x"), harness.output.join("y"));
+        assert_eq!(
+            *recording.0.lock().unwrap(),
+            [
+                format!("make {}", x.display()),
+                format!("make {}", y.display()),
+                format!("remove {} Custom", x.display()),
+            ]
+        );
+    }
+}
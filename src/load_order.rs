@@ -0,0 +1,253 @@
+use crate::input::Rank;
+use crate::Overlay;
+#[cfg(feature = "watch")]
+use crossbeam_channel::{bounded, Receiver, TrySendError};
+use failure::{format_err, Error};
+use log::info;
+#[cfg(feature = "watch")]
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+#[cfg(feature = "watch")]
+use std::path::PathBuf;
+#[cfg(feature = "watch")]
+use std::sync::mpsc;
+#[cfg(feature = "watch")]
+use std::thread;
+#[cfg(feature = "watch")]
+use std::time::Duration;
+
+/// How long the file has to stay unchanged before it is read again.
+#[cfg(feature = "watch")]
+const DELAY: Duration = Duration::from_secs(1);
+
+/// Reads a load order: one input per line, by label or by path, from the lowest priority
+/// to the highest. Blank lines and lines starting with `#` are skipped.
+///
+/// Returns the indices of the inputs in that order. `names` gives the label, if any, and
+/// the path of every input, all of which have to be listed exactly once, so that a file
+/// that was only partly written never applies.
+pub(crate) fn read(path: &Path, names: &[(Option<&str>, &Path)]) -> Result<Vec<usize>, Error> {
+    let text = fs::read_to_string(path)?;
+    let mut order = vec![];
+    let mut seen = HashSet::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let index = names
+            .iter()
+            .position(|(label, path)| *label == Some(line) || *path == Path::new(line))
+            .ok_or_else(|| format_err!("line {}: there is no input {:?}", number + 1, line))?;
+        if !seen.insert(index) {
+            return Err(format_err!(
+                "line {}: {:?} is listed twice",
+                number + 1,
+                line
+            ));
+        }
+        order.push(index);
+    }
+
+    if order.len() != names.len() {
+        return Err(format_err!(
+            "{} of {} inputs are missing",
+            names.len() - order.len(),
+            names.len()
+        ));
+    }
+
+    Ok(order)
+}
+
+/// Watches the load order at `path`, sending on the returned channel once it changed and
+/// then stayed the same for a while.
+#[cfg(feature = "watch")]
+pub(crate) fn watch(path: &Path) -> Result<Receiver<()>, Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file = path.to_path_buf();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = watcher(tx, DELAY)?;
+    // Editors often save by replacing the file, which a watch on it alone would miss.
+    watcher.watch(&parent, RecursiveMode::NonRecursive)?;
+
+    let (changed, receiver) = bounded(1);
+    thread::spawn(move || {
+        // Dropping the watcher would stop it.
+        let _watcher = watcher;
+
+        while let Ok(event) = rx.recv() {
+            let touched = match &event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Chmod(path) => same_name(path, &file),
+                DebouncedEvent::Rename(_, to) => same_name(to, &file),
+                _ => false,
+            };
+
+            if !touched {
+                continue;
+            }
+            // A change that is already waiting covers this one too.
+            if let Err(TrySendError::Disconnected(_)) = changed.try_send(()) {
+                break;
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Whether `path`, as reported by the watcher, is the load order at `file`.
+#[cfg(feature = "watch")]
+fn same_name(path: &Path, file: &Path) -> bool {
+    path.file_name() == file.file_name()
+}
+
+impl Overlay {
+    /// Takes the priorities of the inputs from the load order at `path`, and keeps taking
+    /// them from it whenever it changes while `process_loop` runs.
+    ///
+    /// The file lists every input by label or path, one per line, from the lowest priority
+    /// to the highest, and each gets its line as its priority within its group. One that
+    /// doesn't list every input exactly once is ignored, as when it is only partly saved.
+    pub fn set_load_order<P: AsRef<Path>>(&mut self, path: P) {
+        self.load_order = Some(path.as_ref().to_path_buf());
+    }
+
+    /// Reorders the inputs as the load order says, relinking the paths whose winner changes.
+    pub fn apply_load_order(&mut self) -> Result<(), Error> {
+        let path = match &self.load_order {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let names: Vec<(Option<&str>, &Path)> = self
+            .inputs
+            .iter()
+            .map(|input| (input.label.as_deref(), input.path.as_path()))
+            .collect();
+        let order =
+            read(path, &names).map_err(|e| format_err!("load order {}: {}", path.display(), e))?;
+
+        let changes: Vec<(usize, Rank)> = order
+            .into_iter()
+            .enumerate()
+            .map(|(priority, index)| {
+                let rank = Rank {
+                    priority: priority as u32,
+                    ..self.inputs[index].rank
+                };
+                (index, rank)
+            })
+            .filter(|(index, rank)| self.inputs[*index].rank != *rank)
+            .collect();
+        if !changes.is_empty() {
+            info!(
+                "Reordering {} inputs after the load order changed",
+                changes.len()
+            );
+            self.rerank(changes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+
+    #[test]
+    fn only_an_order_of_every_input_once_is_read() {
+        let file = scratch("load-order-read").join("loadorder.txt");
+        let names = [
+            (Some("base"), Path::new("/games/base")),
+            (None, Path::new("/games/patch")),
+            (Some("mods"), Path::new("/games/mods")),
+        ];
+        let read = |text: &str| {
+            fs::write(&file, text).unwrap();
+            read(&file, &names).map_err(|e| e.to_string())
+        };
+
+        let order = read("# lowest first\nmods\n\n/games/base\n  /games/patch  \n");
+        assert_eq!(order, Ok(vec![2, 0, 1]));
+        // As an editor leaves it halfway through saving.
+        assert_eq!(
+            read("mods\nbase\n"),
+            Err("1 of 3 inputs are missing".into())
+        );
+        assert_eq!(
+            read("mods\nbase\nmo"),
+            Err("line 3: there is no input \"mo\"".into())
+        );
+        assert_eq!(
+            read("mods\nbase\n/games/mods\n"),
+            Err("line 3: \"/games/mods\" is listed twice".into())
+        );
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn a_burst_of_saves_is_one_change_once_they_stop() {
+        let file = scratch("load-order-watch").join("loadorder.txt");
+        fs::write(&file, "base\n").unwrap();
+        let changed = watch(&file).unwrap();
+        fs::write(&file, "mods\n").unwrap();
+        thread::sleep(DELAY / 4);
+        fs::write(&file, "mods\nbase\n").unwrap();
+        fs::write(file.with_file_name("other.txt"), "").unwrap();
+
+        changed.recv_timeout(DELAY * 10).unwrap();
+        assert!(changed.recv_timeout(DELAY * 2).is_err());
+    }
+
+    #[test]
+    fn a_load_order_relinks_what_it_reorders_unless_it_is_partial() {
+        let mut harness = Harness::new("load-order", &[0, 1, 2]);
+        for index in 0..3 {
+            harness.create(index, "all");
+        }
+        harness.create(0, "base");
+        harness.create(1, "patch");
+        harness.create(1, "shared");
+        harness.create(2, "shared");
+        let file = harness.output.with_file_name("loadorder.txt");
+        let names: Vec<String> = harness
+            .inputs
+            .iter()
+            .map(|input| input.display().to_string())
+            .collect();
+        harness.overlay.set_load_order(&file);
+
+        // Patch goes to the top.
+        let linked = harness.overlay.stats.linked;
+        let order = [&names[0], &names[2], &names[1]];
+        fs::write(&file, format!("{}\n{}\n{}\n", order[0], order[1], order[2])).unwrap();
+        harness.overlay.apply_load_order().unwrap();
+        assert_eq!(harness.overlay.stats.linked, linked + 2);
+        assert_eq!(harness.winner("all"), Some(1));
+        assert_eq!(harness.winner("shared"), Some(1));
+        assert_eq!(harness.winner("base"), Some(0));
+
+        // As an editor leaves it halfway through saving.
+        fs::write(&file, format!("{}\n{}\n", order[1], order[0])).unwrap();
+        let error = harness.overlay.apply_load_order().unwrap_err();
+        assert!(
+            error.to_string().contains("1 of 3 inputs are missing"),
+            "{}",
+            error
+        );
+        assert_eq!(harness.overlay.stats.linked, linked + 2);
+        assert_eq!(harness.winner("all"), Some(1));
+    }
+}
@@ -0,0 +1,162 @@
+use failure::{format_err, Error};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// An advisory lock on an output directory, held for as long as this is alive.
+///
+/// The lock file lives beside the output and names the process holding it. Only the lock
+/// itself counts, so a file left behind by a process that crashed is simply taken over.
+#[derive(Debug)]
+pub(crate) struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    pub(crate) fn acquire(output: &Path) -> Result<Self, Error> {
+        let path = lock_path(output);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                // Some platforms don't allow reading a file another process has locked.
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => "another process",
+                    holder => holder,
+                };
+
+                return Err(format_err!(
+                    "{} is already being overlaid by {} (lock file {})",
+                    output.display(),
+                    holder,
+                    path.display()
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        write!(file, "pid {} on {}", process::id(), hostname())?;
+        file.flush()?;
+
+        Ok(InstanceLock { file })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Who has the lock on an output, as `inspect` finds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LockState {
+    /// Nobody, and nobody left it behind either.
+    Free,
+    /// The process it names, which is still running.
+    Held(String),
+    /// The process it names, which stopped without letting go of it.
+    Left(String),
+}
+
+/// Who has the lock on `output`, without taking it over.
+pub(crate) fn inspect(output: &Path) -> io::Result<LockState> {
+    let path = lock_path(output);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(LockState::Free),
+        Err(e) => return Err(e),
+    };
+    let mut holder = String::new();
+    let _ = file.read_to_string(&mut holder);
+    let holder = holder.trim().to_string();
+
+    match file.try_lock_shared() {
+        Ok(()) => {
+            let _ = file.unlock();
+            if holder.is_empty() {
+                Ok(LockState::Free)
+            } else {
+                Ok(LockState::Left(holder))
+            }
+        }
+        Err(TryLockError::WouldBlock) if holder.is_empty() => {
+            Ok(LockState::Held("another process".to_string()))
+        }
+        Err(TryLockError::WouldBlock) => Ok(LockState::Held(holder)),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// `<output>.lock`, beside the output so it never shows up in it.
+pub(crate) fn lock_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.components().as_path());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+fn hostname() -> String {
+    env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "an unknown host".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    #[test]
+    fn a_second_instance_is_told_who_has_the_output() {
+        let output = scratch("lock-held").join("output");
+        let held = InstanceLock::acquire(&output).unwrap();
+        let holder = format!("pid {} on {}", process::id(), hostname());
+        assert_eq!(inspect(&output).unwrap(), LockState::Held(holder.clone()));
+
+        let error = InstanceLock::acquire(&output).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("is already being overlaid by {}", holder)),
+            "{}",
+            error
+        );
+
+        drop(held);
+        assert_eq!(inspect(&output).unwrap(), LockState::Free);
+        InstanceLock::acquire(&output).unwrap();
+    }
+
+    #[test]
+    fn a_lock_left_behind_is_taken_over() {
+        let output = scratch("lock-left").join("output");
+        // A process that crashed, whose lock went with it but whose file didn't.
+        let mut crashed = File::create(lock_path(&output)).unwrap();
+        crashed.lock().unwrap();
+        write!(crashed, "pid 4242 on elsewhere").unwrap();
+        drop(crashed);
+        assert_eq!(
+            inspect(&output).unwrap(),
+            LockState::Left("pid 4242 on elsewhere".to_string())
+        );
+
+        let _lock = InstanceLock::acquire(&output).unwrap();
+        let holder = fs::read_to_string(lock_path(&output)).unwrap();
+        assert_eq!(holder, format!("pid {} on {}", process::id(), hostname()));
+    }
+}
@@ -0,0 +1,36 @@
+use log::{log, log_enabled, Level};
+use std::fmt::{self, Write};
+
+/// The line logged for an event or command, put together piece by piece as it is handled.
+///
+/// The line is logged at the level of its most important piece, and pieces whose level
+/// isn't logged are never formatted at all. A line with no such pieces isn't logged.
+#[derive(Debug, Default)]
+pub(crate) struct LogLine {
+    text: String,
+    level: Option<Level>,
+}
+
+impl LogLine {
+    /// Starts a new line saying what is being done, logging the one before if need be.
+    pub(crate) fn begin(&mut self, header: fmt::Arguments) {
+        self.end();
+        if log_enabled!(Level::Error) {
+            let _ = self.text.write_fmt(header);
+        }
+    }
+
+    pub(crate) fn say(&mut self, level: Level, piece: fmt::Arguments) {
+        if log_enabled!(level) {
+            let _ = self.text.write_fmt(piece);
+            self.level = Some(self.level.map_or(level, |current| current.min(level)));
+        }
+    }
+
+    pub(crate) fn end(&mut self) {
+        if let Some(level) = self.level.take() {
+            log!(level, "{}", self.text);
+        }
+        self.text.clear();
+    }
+}
@@ -1,10 +1,883 @@
-use failure::Error;
-use overlay::Overlay;
-
-fn main() -> Result<(), Error> {
-    let mut overlay = Overlay::new("D:\\Temp\\overlay\\output");
-    overlay.add_input("D:\\Temp\\overlay\\input1", 0);
-    overlay.add_input("D:\\Temp\\overlay\\input2", 10);
-    overlay.process_loop()?;
-    Ok(())
+mod daemon;
+mod system_log;
+
+use crate::daemon::{Detached, PidFile};
+use crate::system_log::{Sink, SystemLog};
+use failure::{err_msg, format_err, Error};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use overlay::{
+    read_recording, CheckStatus, Config, ConfigError, ControlRequest, ExistingOutputPolicy,
+    HooksConfig, MemoryFs, Overlay, ReplayPace, SUMMARY,
+};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+const USAGE: &str = "usage: overlay [-q | -v...] [--log-file PATH] [--daemon] [--pidfile PATH] \
+                     [--dry-run] [--adopt | --force] [--output-format text|json] \
+                     [--log-syslog [--syslog-facility NAME] | --log-eventlog] \
+                     [--log-label LABEL] [--system-log-level LEVEL] [<config.toml>]
+       overlay ctl [--socket PATH | --config <config.toml>] [--preview] <command>
+       overlay manifest [--config <config.toml>] <manifest.json>
+       overlay verify [--config <config.toml>] --manifest <manifest.json> [--json]
+       overlay doctor [--config <config.toml>] [--json]
+       overlay shadowed [--config <config.toml>] [--json] <input>
+       overlay undo [--config <config.toml>] [<count>]
+       overlay replay [--config <config.toml>] [--output DIR] [--memory] [--paced] [--trace] \
+                      <recording>...
+
+commands: status, resync, conflicts, shadowed <input>, set-priority <input> <priority>,
+          move-above <input> <reference>, move-below <input> <reference>, pause <input>,
+          resume <input>, undo [<count>]
+
+--adopt deals with the files already in an output the overlay never ran on as the foreign
+file policy says, --force replaces and removes them like its own
+
+with --preview, set-priority, move-above, move-below, pause and resume only list the
+paths whose file would come from another input
+
+undo puts the last file, or the last <count> files, moved into the trash back into the
+output; while the overlay runs, it has to be asked with ctl";
+
+struct Args {
+    /// Without a file, everything comes from the environment.
+    config: Option<OsString>,
+    verbosity: LevelFilter,
+    log_file: Option<PathBuf>,
+    daemon: bool,
+    pidfile: Option<PathBuf>,
+    dry_run: bool,
+    /// What `--adopt` or `--force` say to do with an output the overlay never ran on.
+    existing_output: Option<ExistingOutputPolicy>,
+    /// Whether stdout is an event stream, see `OverlayBuilder::event_stream`, rather than
+    /// the log.
+    json: bool,
+    /// Where the log of the system is, if the records are written there too.
+    system_log: Option<Sink>,
+    /// What the overlay is told apart by there, from other overlays on the same machine.
+    log_label: Option<String>,
+    /// Warnings and errors by default, whatever the verbosity.
+    system_log_level: LevelFilter,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Error> {
+        let mut config = None;
+        let mut verbosity = LevelFilter::Info;
+        let mut log_file = None;
+        let mut daemon = false;
+        let mut pidfile = None;
+        let mut dry_run = false;
+        let mut existing_output = None;
+        let mut json = false;
+        let mut system_log = None;
+        let mut facility = None;
+        let mut log_label = None;
+        let mut system_log_level = LevelFilter::Warn;
+
+        let mut args = env::args_os().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-q") | Some("--quiet") => verbosity = LevelFilter::Error,
+                Some("-v") | Some("--verbose") => verbosity = more(verbosity),
+                Some("-vv") => verbosity = more(more(verbosity)),
+                Some("--log-file") => {
+                    let path = args.next().ok_or_else(|| err_msg(USAGE))?;
+                    log_file = Some(PathBuf::from(path));
+                }
+                Some("--log-syslog") => system_log = Some(Sink::Syslog(String::new())),
+                Some("--log-eventlog") => system_log = Some(Sink::EventLog),
+                Some("--syslog-facility") => {
+                    let name = args.next().ok_or_else(|| err_msg(USAGE))?;
+                    let name = name.to_str().ok_or_else(|| err_msg(USAGE))?.to_string();
+                    #[cfg(unix)]
+                    if system_log::facility(&name).is_none() {
+                        return Err(format_err!("there is no syslog facility {}", name));
+                    }
+                    facility = Some(name);
+                }
+                Some("--log-label") => {
+                    let label = args.next().ok_or_else(|| err_msg(USAGE))?;
+                    log_label = Some(label.to_str().ok_or_else(|| err_msg(USAGE))?.to_string());
+                }
+                Some("--system-log-level") => {
+                    system_log_level = args
+                        .next()
+                        .as_ref()
+                        .and_then(|level| level.to_str())
+                        .and_then(|level| level.parse().ok())
+                        .ok_or_else(|| err_msg(USAGE))?;
+                }
+                Some("--daemon") => daemon = true,
+                Some("--dry-run") => dry_run = true,
+                Some("--adopt") | Some("--force") if existing_output.is_some() => {
+                    return Err(err_msg("--adopt and --force can't both be given"));
+                }
+                Some("--adopt") => existing_output = Some(ExistingOutputPolicy::Adopt),
+                Some("--force") => existing_output = Some(ExistingOutputPolicy::Force),
+                Some("--output-format") => {
+                    json = match args.next().as_ref().and_then(|format| format.to_str()) {
+                        Some("text") => false,
+                        Some("json") => true,
+                        _ => return Err(err_msg(USAGE)),
+                    };
+                }
+                Some("--pidfile") => {
+                    let path = args.next().ok_or_else(|| err_msg(USAGE))?;
+                    pidfile = Some(PathBuf::from(path));
+                }
+                Some(flag) if flag.starts_with('-') => {
+                    return Err(format_err!("unknown option {}\n{}", flag, USAGE));
+                }
+                _ if config.is_none() => config = Some(arg),
+                _ => return Err(err_msg(USAGE)),
+            }
+        }
+
+        if let Some(Sink::Syslog(name)) = &mut system_log {
+            *name = facility.unwrap_or_else(|| "daemon".to_string());
+        }
+
+        Ok(Args {
+            config,
+            verbosity,
+            log_file,
+            daemon,
+            pidfile,
+            dry_run,
+            existing_output,
+            json,
+            system_log,
+            log_label,
+            system_log_level,
+        })
+    }
+}
+
+fn more(verbosity: LevelFilter) -> LevelFilter {
+    match verbosity {
+        LevelFilter::Error => LevelFilter::Info,
+        LevelFilter::Info => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Logs to the console at the chosen verbosity, and with timestamps to the log file at
+/// least at the default one. The summaries always show. The log of the system only gets
+/// the records of its own level, the summaries only if that is low enough.
+struct Logger {
+    console: LevelFilter,
+    /// Whether everything goes to stderr, as stdout has the event stream.
+    stderr: bool,
+    file: Option<LogFile>,
+    system: Option<SystemLog>,
+}
+
+struct LogFile {
+    level: LevelFilter,
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl LogFile {
+    fn open(path: &Path, level: LevelFilter) -> Result<Self, Error> {
+        Ok(LogFile {
+            level,
+            path: path.to_path_buf(),
+            file: Mutex::new(LogFile::append(path)?),
+        })
+    }
+
+    fn append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Starts writing to a new file at the same path, after the old one was rotated away.
+    fn reopen(&self) {
+        match LogFile::append(&self.path) {
+            Ok(file) => *self.file.lock().unwrap() = file,
+            Err(e) => log::error!("Could not reopen {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl Logger {
+    fn to_console(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.console || metadata.target() == SUMMARY
+    }
+
+    fn to_file(&self, metadata: &Metadata) -> bool {
+        match &self.file {
+            Some(file) => metadata.level() <= file.level || metadata.target() == SUMMARY,
+            None => false,
+        }
+    }
+
+    fn to_system(&self, metadata: &Metadata) -> bool {
+        self.system
+            .as_ref()
+            .is_some_and(|system| system.enabled(metadata))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.to_console(metadata) || self.to_file(metadata) || self.to_system(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.to_console(record.metadata()) {
+            if self.stderr || record.level() <= Level::Warn {
+                eprintln!("{}", record.args());
+            } else {
+                println!("{}", record.args());
+            }
+        }
+
+        if let (true, Some(file)) = (self.to_file(record.metadata()), &self.file) {
+            let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+            let mut file = file.file.lock().unwrap();
+            let _ = writeln!(
+                file,
+                "{} {:<5} {}",
+                timestamp,
+                record.level(),
+                record.args()
+            );
+        }
+
+        if let (true, Some(system)) = (self.to_system(record.metadata()), &self.system) {
+            system.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// The run completed, but some files couldn't be dealt with. For `overlay ctl`, the
+/// overlay couldn't do what it was asked to.
+const EXIT_FILE_ERRORS: i32 = 1;
+/// The arguments or the configuration are wrong.
+const EXIT_CONFIG: i32 = 2;
+/// The overlay couldn't start or had to stop, e.g. when its watchers died.
+const EXIT_FATAL: i32 = 3;
+
+/// How many failed files are listed when the run ends.
+const LISTED_FAILURES: usize = 10;
+
+/// Runs the overlay, returning the exit code, or the error that stopped it and its code.
+fn run() -> Result<i32, (i32, Error)> {
+    let config_error = |e: Error| (EXIT_CONFIG, e);
+    let fatal = |e: Error| (EXIT_FATAL, e);
+
+    if env::args_os().nth(1).as_deref() == Some("ctl".as_ref()) {
+        return ctl();
+    }
+    if env::args_os().nth(1).as_deref() == Some("manifest".as_ref()) {
+        return manifest();
+    }
+    if env::args_os().nth(1).as_deref() == Some("verify".as_ref()) {
+        return verify();
+    }
+    if env::args_os().nth(1).as_deref() == Some("doctor".as_ref()) {
+        return doctor();
+    }
+    if env::args_os().nth(1).as_deref() == Some("shadowed".as_ref()) {
+        return shadowed();
+    }
+    if env::args_os().nth(1).as_deref() == Some("replay".as_ref()) {
+        return replay();
+    }
+    if env::args_os().nth(1).as_deref() == Some("undo".as_ref()) {
+        return undo();
+    }
+
+    let args = Args::parse().map_err(config_error)?;
+    // Read before detaching, so that mistakes in it are still seen.
+    let mut config = match &args.config {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    }
+    .map_err(config_error)?;
+    // Flags override the file and the environment.
+    if args.dry_run {
+        config.dry_run = true;
+    }
+    if let Some(policy) = args.existing_output {
+        config.existing_output = policy;
+    }
+
+    let file = match &args.log_file {
+        Some(path) => {
+            let level = args.verbosity.max(LevelFilter::Info);
+            Some(LogFile::open(path, level).map_err(config_error)?)
+        }
+        None => None,
+    };
+    let detached = if args.daemon {
+        Some(daemon::detach().map_err(fatal)?)
+    } else {
+        None
+    };
+    let detached = Arc::new(Mutex::new(detached));
+    // Once detached, as the connection to syslog wouldn't survive it.
+    let (system, unavailable) = match &args.system_log {
+        Some(sink) => {
+            match SystemLog::open(sink, args.log_label.as_deref(), args.system_log_level) {
+                Ok(system) => (Some(system), None),
+                Err(e) => (None, Some(e)),
+            }
+        }
+        None => (None, None),
+    };
+
+    let logger: &'static Logger = Box::leak(Box::new(Logger {
+        // Nobody would see it.
+        console: if args.daemon {
+            LevelFilter::Off
+        } else {
+            args.verbosity
+        },
+        stderr: args.json,
+        file,
+        system,
+    }));
+    // The summaries are logged at the default level, which has to get through.
+    let max = args
+        .verbosity
+        .max(
+            logger
+                .file
+                .as_ref()
+                .map_or(LevelFilter::Off, |file| file.level),
+        )
+        .max(
+            logger
+                .system
+                .as_ref()
+                .map_or(LevelFilter::Off, SystemLog::level),
+        )
+        .max(LevelFilter::Info);
+    log::set_logger(logger).map_err(|e| fatal(e.into()))?;
+    log::set_max_level(max);
+    if let Some(e) = unavailable {
+        log::warn!("Not logging to the log of the system: {}", e);
+    }
+
+    let result = serve(&args, config, logger, detached.clone());
+    if let Some(detached) = detached.lock().unwrap().as_mut() {
+        // Only if it didn't get ready, otherwise there is nobody waiting anymore.
+        detached.exit(*result.as_ref().unwrap_or_else(|(code, _)| code));
+    }
+    if let (Err((_, e)), true) = (&result, args.daemon) {
+        // There is no console to report it on.
+        log::error!("error: {}", e);
+    }
+    result
+}
+
+fn serve(
+    args: &Args,
+    config: Config,
+    logger: &'static Logger,
+    detached: Arc<Mutex<Option<Detached>>>,
+) -> Result<i32, (i32, Error)> {
+    let config_error = |e: Error| (EXIT_CONFIG, e);
+    let fatal = |e: Error| (EXIT_FATAL, e);
+
+    let _pidfile = match &args.pidfile {
+        Some(path) => Some(PidFile::create(path).map_err(fatal)?),
+        None => None,
+    };
+
+    let mut builder = config.builder();
+    if args.json {
+        builder = builder.event_stream(io::stdout());
+    }
+    let mut overlay = builder.build().map_err(config_error)?;
+    let controller = overlay.controller();
+    daemon::handle_signals(controller.clone(), move || {
+        if let Some(file) = &logger.file {
+            file.reopen();
+        }
+    })
+    .map_err(fatal)?;
+
+    let ready = thread::spawn(move || {
+        if controller.wait_ready() {
+            daemon::notify("READY=1");
+            if let Some(detached) = detached.lock().unwrap().as_mut() {
+                detached.exit(0);
+            }
+        }
+    });
+
+    let result = overlay.process_loop();
+    let _ = ready.join();
+    result.map_err(|e| match e.downcast_ref::<ConfigError>() {
+        Some(_) => config_error(e),
+        None => fatal(e),
+    })?;
+
+    let errors = overlay.stats().errors;
+    if errors == 0 {
+        return Ok(0);
+    }
+
+    eprintln!("{} errors:", errors);
+    for failure in overlay.failures().iter().take(LISTED_FAILURES) {
+        let path = failure
+            .path
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |path| path.display().to_string());
+        eprintln!("  {}: {}: {}", path, failure.kind, failure.message);
+    }
+    if errors > LISTED_FAILURES as u64 {
+        eprintln!("  and {} more", errors - LISTED_FAILURES as u64);
+    }
+    Ok(EXIT_FILE_ERRORS)
+}
+
+/// `overlay ctl`: sends a request to the control socket of a running overlay, and prints
+/// what it returned as JSON.
+fn ctl() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut socket = None;
+    let mut config = None;
+    let mut preview = false;
+    let mut words = vec![];
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--socket") => socket = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some("--preview") => preview = true,
+            Some(word) if !word.starts_with('-') => words.push(word.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+
+    let request = match words.as_slice() {
+        [command] if command == "status" => ControlRequest::Status,
+        [command] if command == "resync" => ControlRequest::Resync,
+        [command] if command == "conflicts" => ControlRequest::Conflicts,
+        [command, input, priority] if command == "set-priority" => ControlRequest::SetPriority {
+            input: input.clone(),
+            priority: priority.parse().map_err(|_| usage())?,
+        },
+        [command, input, reference] if command == "move-above" => ControlRequest::MoveAbove {
+            input: input.clone(),
+            reference: reference.clone(),
+        },
+        [command, input, reference] if command == "move-below" => ControlRequest::MoveBelow {
+            input: input.clone(),
+            reference: reference.clone(),
+        },
+        [command, input] if command == "shadowed" => ControlRequest::Shadowed {
+            input: input.clone(),
+        },
+        [command, input] if command == "pause" => ControlRequest::Pause {
+            input: input.clone(),
+        },
+        [command, input] if command == "resume" => ControlRequest::Resume {
+            input: input.clone(),
+        },
+        [command] if command == "undo" => ControlRequest::Undo { count: 1 },
+        [command, count] if command == "undo" => ControlRequest::Undo {
+            count: count.parse().map_err(|_| usage())?,
+        },
+        _ => return Err(usage()),
+    };
+    let request = if preview {
+        ControlRequest::Preview {
+            changes: vec![request],
+        }
+    } else {
+        request
+    };
+
+    let socket = match socket {
+        Some(socket) => socket,
+        None => match &config {
+            Some(path) => Config::load(path),
+            None => Config::from_env(),
+        }
+        .map_err(|e| (EXIT_CONFIG, e))?
+        .control_socket
+        .ok_or_else(|| {
+            (
+                EXIT_CONFIG,
+                err_msg("there is no control_socket configured"),
+            )
+        })?,
+    };
+
+    let response = request.send(socket).map_err(|e| (EXIT_FATAL, e))?;
+    if !response.result.is_null() {
+        let result =
+            serde_json::to_string_pretty(&response.result).map_err(|e| (EXIT_FATAL, e.into()))?;
+        println!("{}", result);
+    }
+    match response.error {
+        Some(error) => {
+            eprintln!("error: {}", error);
+            Ok(EXIT_FILE_ERRORS)
+        }
+        None => Ok(0),
+    }
+}
+
+/// `overlay manifest`: writes a manifest of the output of the configured overlay, as a
+/// dry run finds it, without changing anything.
+fn manifest() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut manifest = None;
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some(flag) if flag.starts_with('-') => return Err(usage()),
+            _ if manifest.is_none() => manifest = Some(PathBuf::from(arg)),
+            _ => return Err(usage()),
+        }
+    }
+    let manifest = manifest.ok_or_else(usage)?;
+
+    dry_run(config.as_ref())?
+        .write_manifest(&manifest)
+        .map_err(|e| (EXIT_FILE_ERRORS, e))?;
+    Ok(0)
+}
+
+/// `overlay verify`: compares the output of the configured overlay with a manifest, and
+/// lists how it differs, failing if it does at all.
+fn verify() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut manifest = None;
+    let mut json = false;
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some("--manifest") => manifest = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+            Some("--json") => json = true,
+            _ => return Err(usage()),
+        }
+    }
+    let manifest = manifest.ok_or_else(usage)?;
+
+    let report = dry_run(config.as_ref())?
+        .verify_manifest(&manifest)
+        .map_err(|e| (EXIT_FATAL, e))?;
+    if json {
+        let report = serde_json::to_string_pretty(&report).map_err(|e| (EXIT_FATAL, e.into()))?;
+        println!("{}", report);
+    } else {
+        let listed = [
+            ("added", &report.added),
+            ("missing", &report.missing),
+            ("modified", &report.modified),
+            ("remapped", &report.remapped),
+        ];
+        for (kind, paths) in listed.iter() {
+            for path in paths.iter() {
+                println!("{}: {}", kind, path.display());
+            }
+        }
+    }
+    Ok(if report.is_empty() {
+        0
+    } else {
+        EXIT_FILE_ERRORS
+    })
+}
+
+/// `overlay doctor`: checks whether the configured overlay can run as it should, without
+/// changing anything, failing if any check does.
+fn doctor() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut json = false;
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some("--json") => json = true,
+            _ => return Err(usage()),
+        }
+    }
+    let config = match &config {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    }
+    .map_err(|e| (EXIT_CONFIG, e))?;
+
+    let checks = config.doctor();
+    if json {
+        let checks = serde_json::to_string_pretty(&checks).map_err(|e| (EXIT_FATAL, e.into()))?;
+        println!("{}", checks);
+    } else {
+        for check in &checks {
+            println!("{}", check);
+        }
+    }
+    Ok(
+        if checks.iter().any(|check| check.status == CheckStatus::Fail) {
+            EXIT_FILE_ERRORS
+        } else {
+            0
+        },
+    )
+}
+
+/// `overlay shadowed`: lists the files of an input that those of other inputs win over in
+/// the output of the configured overlay, as a dry run finds it.
+fn shadowed() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut input = None;
+    let mut json = false;
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some("--json") => json = true,
+            Some(flag) if flag.starts_with('-') => return Err(usage()),
+            Some(name) if input.is_none() => input = Some(name.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+    let input = input.ok_or_else(usage)?;
+
+    let overlay = dry_run(config.as_ref())?;
+    let id = overlay.find_input(&input).map_err(|e| (EXIT_CONFIG, e))?;
+    let shadowed = overlay.shadowed(id);
+    if json {
+        let shadowed =
+            serde_json::to_string_pretty(&shadowed).map_err(|e| (EXIT_FATAL, e.into()))?;
+        println!("{}", shadowed);
+    } else {
+        for entry in &shadowed {
+            let winner = &entry.winner;
+            match &winner.label {
+                Some(label) => println!(
+                    "{}: input {} [{}], priority {} of group {}",
+                    entry.path.display(),
+                    winner.input,
+                    label,
+                    winner.priority,
+                    winner.group
+                ),
+                None => println!(
+                    "{}: input {}, priority {} of group {}",
+                    entry.path.display(),
+                    winner.input,
+                    winner.priority,
+                    winner.group
+                ),
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// `overlay undo`: puts the files the configured overlay moved into its trash last back
+/// into the output, and lists them.
+fn undo() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut count = None;
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some(flag) if flag.starts_with('-') => return Err(usage()),
+            Some(number) if count.is_none() => {
+                count = Some(number.parse().map_err(|_| usage())?);
+            }
+            _ => return Err(usage()),
+        }
+    }
+
+    let config = match &config {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    }
+    .map_err(|e| (EXIT_CONFIG, e))?;
+    if config.trash.is_none() {
+        return Err((EXIT_CONFIG, err_msg("there is no trash configured")));
+    }
+    let mut overlay = config.builder().build().map_err(|e| (EXIT_CONFIG, e))?;
+    let report = overlay
+        .undo_last(count.unwrap_or(1))
+        .map_err(|e| (EXIT_FATAL, e))?;
+    for path in &report.restored {
+        println!("restored {}", path.display());
+    }
+    for (path, error) in &report.failed {
+        println!("couldn't restore {}: {}", path.display(), error);
+    }
+    Ok(if report.failed.is_empty() {
+        0
+    } else {
+        EXIT_FILE_ERRORS
+    })
+}
+
+/// `overlay replay`: hands the events recorded with `record_events` to the configured
+/// overlay again, but with a scratch output, printing what it decided about each of them
+/// with `--trace`. The recordings are replayed in the order they are given, the rotated
+/// one first.
+///
+/// With `--memory` the inputs and the output are in memory instead, with the recorded
+/// changes made to them, so neither has to exist.
+fn replay() -> Result<i32, (i32, Error)> {
+    let usage = || (EXIT_CONFIG, err_msg(USAGE));
+
+    let mut config = None;
+    let mut output = None;
+    let mut memory = false;
+    let mut pace = ReplayPace::FullSpeed;
+    let mut trace = false;
+    let mut recordings = vec![];
+    let mut args = env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => config = Some(args.next().ok_or_else(usage)?),
+            Some("--output") => output = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+            Some("--memory") => memory = true,
+            Some("--paced") => pace = ReplayPace::Original,
+            Some("--trace") => trace = true,
+            Some(flag) if flag.starts_with('-') => return Err(usage()),
+            _ => recordings.push(PathBuf::from(arg)),
+        }
+    }
+    if recordings.is_empty() {
+        return Err(usage());
+    }
+
+    let mut config = match &config {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    }
+    .map_err(|e| (EXIT_CONFIG, e))?;
+    let mut events = vec![];
+    for path in &recordings {
+        events.extend(read_recording(path).map_err(|e| (EXIT_CONFIG, e))?);
+    }
+
+    // Nothing but the scratch output is touched.
+    config.record_events = None;
+    config.audit_log = None;
+    config.control_socket = None;
+    config.http_status = None;
+    config.load_order = None;
+    config.hooks = HooksConfig::default();
+    let scratch =
+        output.unwrap_or_else(|| env::temp_dir().join(format!("overlay-replay-{}", process::id())));
+    let fs = Arc::new(MemoryFs::new());
+    let mut builder = if memory {
+        for (index, input) in config.inputs.iter_mut().enumerate() {
+            input.path = scratch.join("inputs").join(index.to_string());
+            let root = input.source.as_ref().map(|source| input.path.join(source));
+            fs::create_dir_all(root.as_ref().unwrap_or(&input.path))
+                .map_err(|e| (EXIT_FATAL, e.into()))?;
+        }
+        config.output = scratch.join("output");
+        config.builder().file_ops(fs.clone())
+    } else {
+        if scratch == config.output {
+            return Err((
+                EXIT_CONFIG,
+                err_msg("the output of a replay has to be another than the overlay's"),
+            ));
+        }
+        config.output = scratch.clone();
+        config.builder()
+    };
+    builder = builder.single_instance(false);
+    let mut overlay = builder.build().map_err(|e| (EXIT_CONFIG, e))?;
+
+    let printer = if trace {
+        let traces = overlay.trace_decisions();
+        Some(thread::spawn(move || {
+            for trace in traces {
+                if let Ok(json) = serde_json::to_string(&trace) {
+                    println!("{}", json);
+                }
+            }
+        }))
+    } else {
+        None
+    };
+    let simulated = if memory { Some(&*fs) } else { None };
+    let result = overlay.replay(events, pace, simulated);
+    let errors = overlay.stats().errors;
+    // Which ends the traces.
+    drop(overlay);
+    if let Some(printer) = printer {
+        let _ = printer.join();
+    }
+    if memory {
+        let _ = fs::remove_dir_all(&scratch);
+    }
+    let report = result.map_err(|e| (EXIT_FATAL, e))?;
+
+    if memory {
+        eprintln!(
+            "Replayed {} events in memory, {} left out, with {} errors",
+            report.replayed, report.skipped, errors
+        );
+    } else {
+        eprintln!(
+            "Replayed {} events into {}, {} left out, with {} errors",
+            report.replayed,
+            scratch.display(),
+            report.skipped,
+            errors
+        );
+    }
+    Ok(if errors == 0 { 0 } else { EXIT_FILE_ERRORS })
+}
+
+/// The overlay of the configuration at `config`, or of the environment, synced as a dry run
+/// so that it knows what is where without changing anything.
+fn dry_run(config: Option<&OsString>) -> Result<Overlay, (i32, Error)> {
+    let mut config = match config {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    }
+    .map_err(|e| (EXIT_CONFIG, e))?;
+    config.dry_run = true;
+    let mut overlay = config.builder().build().map_err(|e| (EXIT_CONFIG, e))?;
+    overlay.sync_once().map_err(|e| (EXIT_FATAL, e))?;
+    Ok(overlay)
+}
+
+fn main() {
+    match run() {
+        Ok(code) => process::exit(code),
+        Err((code, e)) => {
+            eprintln!("error: {}", e);
+            process::exit(code);
+        }
+    }
 }
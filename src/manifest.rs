@@ -0,0 +1,428 @@
+use crate::{InputId, Overlay};
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use walkdir::WalkDir;
+use xxhash_rust::xxh3::Xxh3;
+
+/// The version of `Manifest` written by this overlay. Newer ones are refused.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// How the files of a manifest are hashed: XXH3 with 128 bits, in lowercase hex.
+pub const MANIFEST_ALGORITHM: &str = "xxh3-128";
+
+/// How much of a file is hashed at a time.
+const CHUNK: usize = 1 << 20;
+
+/// A checksum of every file of the output, as `Overlay::write_manifest` writes it.
+///
+/// It is written as JSON, like:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "algorithm": "xxh3-128",
+///   "output": "D:\\Games\\Merged",
+///   "entries": [
+///     {
+///       "path": "Data/textures.pak",
+///       "size": 1048576,
+///       "hash": "9c3e5e1d35b7e2d01b0c7a1f6f1e4a0b",
+///       "input": 1,
+///       "label": "BaseGame"
+///     }
+///   ]
+/// }
+/// ```
+///
+/// The entries are sorted by `path`, which is relative to the output, and `input` and
+/// `label` are of the input whose file is in the output. Fields may be added in the same
+/// version; anything else changes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub algorithm: String,
+    pub output: PathBuf,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    pub input: InputId,
+    pub label: Option<String>,
+}
+
+impl Manifest {
+    /// Reads a manifest written by this or an earlier version of the overlay.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json)?;
+        let version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| format_err!("the manifest has no version"))?;
+        if version > u64::from(MANIFEST_VERSION) {
+            return Err(format_err!(
+                "the manifest is of version {}, only up to {} can be read",
+                version,
+                MANIFEST_VERSION
+            ));
+        }
+
+        let manifest: Manifest = serde_json::from_value(value)?;
+        if manifest.algorithm != MANIFEST_ALGORITHM {
+            return Err(format_err!(
+                "the manifest is hashed with {}, only {} can be read",
+                manifest.algorithm,
+                MANIFEST_ALGORITHM
+            ));
+        }
+        Ok(manifest)
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .map_err(|e| format_err!("couldn't read {}: {}", path.display(), e))?;
+        Manifest::from_json(&json)
+    }
+}
+
+/// How the output differs from a manifest written earlier, see `Overlay::verify_manifest`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestReport {
+    /// Files in the output that aren't in the manifest.
+    pub added: Vec<PathBuf>,
+    /// Files in the manifest that aren't in the output.
+    pub missing: Vec<PathBuf>,
+    /// Files that changed though the input providing them is still the one that did,
+    /// so the overlay has no reason to think anything is wrong with them. It is likely
+    /// they were written to through a hard link of the input, or in the output itself.
+    pub modified: Vec<PathBuf>,
+    /// Files that changed because another input provides them now, or none does.
+    pub remapped: Vec<PathBuf>,
+}
+
+impl ManifestReport {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.missing.is_empty()
+            && self.modified.is_empty()
+            && self.remapped.is_empty()
+    }
+}
+
+impl Overlay {
+    /// Hashes every file the overlay has in the output and writes a `Manifest` of them to
+    /// `path`, e.g. to find out later whether anything changed them. The files are hashed
+    /// on as many threads as there are cores.
+    ///
+    /// Fails without writing anything if a file can't be read.
+    pub fn write_manifest<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let manifest = self.manifest()?;
+
+        // Written aside and renamed, so a crash never leaves half of it.
+        let temporary = crate::beside(path, ".new");
+        fs::write(&temporary, manifest.to_json()?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Hashes the files in the output again and compares them with the manifest at
+    /// `path`. Its files are compared with what is on disk, whatever the inputs have, so
+    /// what the overlay did wrong shows up as much as what was done to the output behind
+    /// its back. Directory links are followed.
+    pub fn verify_manifest<P: AsRef<Path>>(&self, path: P) -> Result<ManifestReport, Error> {
+        let manifest = Manifest::load(path)?;
+        let mut expected: BTreeMap<PathBuf, ManifestEntry> = manifest
+            .entries
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        let mut report = ManifestReport::default();
+        let mut found = vec![];
+        for entry in WalkDir::new(&self.output)
+            .min_depth(1)
+            .follow_links(true)
+            .sort_by_file_name()
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&self.output).unwrap();
+            match expected.remove(relative) {
+                Some(expected) => found.push((entry.path().to_path_buf(), expected)),
+                None => report.added.push(relative.to_path_buf()),
+            }
+        }
+        report.missing = expected.into_keys().collect();
+
+        let files: Vec<PathBuf> = found.iter().map(|(file, _)| file.clone()).collect();
+        for ((_, expected), hashed) in found.into_iter().zip(hash_files(&files)) {
+            let (size, hash) = match hashed {
+                Ok(hashed) => hashed,
+                // Gone since the output was walked.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    report.missing.push(expected.path);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(format_err!(
+                        "couldn't hash {}: {}",
+                        expected.path.display(),
+                        e
+                    ))
+                }
+            };
+            if size == expected.size && hash == expected.hash {
+                continue;
+            }
+            let provider = self.materialized(&expected.path).map(|input| input.id());
+            if provider == Some(expected.input) {
+                report.modified.push(expected.path);
+            } else {
+                report.remapped.push(expected.path);
+            }
+        }
+        report.missing.sort();
+        Ok(report)
+    }
+
+    /// The manifest of the output as it is now.
+    pub(crate) fn manifest(&self) -> Result<Manifest, Error> {
+        let listed: Vec<_> = self.list().collect();
+        let files: Vec<PathBuf> = listed
+            .iter()
+            .map(|entry| self.output.join(&entry.path))
+            .collect();
+
+        let mut entries = Vec::with_capacity(listed.len());
+        for (entry, hashed) in listed.into_iter().zip(hash_files(&files)) {
+            let (size, hash) =
+                hashed.map_err(|e| format_err!("couldn't hash {}: {}", entry.path.display(), e))?;
+            entries.push(ManifestEntry {
+                path: entry.path,
+                size,
+                hash,
+                input: entry.input,
+                label: entry.label,
+            });
+        }
+
+        Ok(Manifest {
+            version: MANIFEST_VERSION,
+            algorithm: MANIFEST_ALGORITHM.to_string(),
+            output: self.output.clone(),
+            entries,
+        })
+    }
+}
+
+/// The size and hash of each of `files`, in the same order, hashed on a thread per core.
+pub(crate) fn hash_files(files: &[PathBuf]) -> Vec<io::Result<(u64, String)>> {
+    let threads = thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(files.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+
+    let mut hashed: Vec<(usize, io::Result<(u64, String)>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        match files.get(index) {
+                            Some(file) => done.push((index, hash_file(file))),
+                            None => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    hashed.sort_by_key(|(index, _)| *index);
+    hashed.into_iter().map(|(_, result)| result).collect()
+}
+
+/// The size and hash of `path`, read a chunk at a time.
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; CHUNK];
+    let mut size = 0;
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, format!("{:032x}", hasher.digest128())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+    use crate::{Event, EventType, InputOptions, OverlayBuilder};
+
+    /// A synced overlay of `base` and `mods` above it, both labelled so, in `root`.
+    fn synced(root: &Path) -> Overlay {
+        let (base, mods) = (root.join("base"), root.join("mods"));
+        fs::create_dir_all(base.join("Data")).unwrap();
+        fs::create_dir_all(&mods).unwrap();
+        fs::write(base.join("a"), "a").unwrap();
+        fs::write(base.join("Data/b"), "bb").unwrap();
+        fs::write(base.join("shared"), "base").unwrap();
+        fs::write(mods.join("shared"), "mods").unwrap();
+        let mut overlay = OverlayBuilder::new(root.join("output"))
+            .single_instance(false)
+            .input_with_options(&base, 0, InputOptions::new().label("base"))
+            .input_with_options(&mods, 1, InputOptions::new().label("mods"))
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        overlay
+    }
+
+    #[test]
+    fn a_manifest_has_every_file_of_the_output_and_who_provides_it() {
+        let root = scratch("manifest");
+        let overlay = synced(&root);
+        let path = root.join("manifest.json");
+        overlay.write_manifest(&path).unwrap();
+        assert!(!crate::beside(&path, ".new").exists());
+
+        let manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest, overlay.manifest().unwrap());
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.output, root.join("output"));
+        let listed: Vec<(&str, u64, usize, Option<&str>)> = manifest
+            .entries
+            .iter()
+            .map(|entry| {
+                let path = entry.path.to_str().unwrap();
+                (
+                    path,
+                    entry.size,
+                    entry.input.index(),
+                    entry.label.as_deref(),
+                )
+            })
+            .collect();
+        let data_b = Path::new("Data").join("b");
+        assert_eq!(
+            listed,
+            [
+                (data_b.to_str().unwrap(), 2, 0, Some("base")),
+                ("a", 1, 0, Some("base")),
+                ("shared", 4, 1, Some("mods")),
+            ]
+        );
+        let shared = &manifest.entries[2];
+        assert_eq!(
+            hash_file(&root.join("mods/shared")).unwrap(),
+            (shared.size, shared.hash.clone())
+        );
+        assert_ne!(manifest.entries[1].hash, shared.hash);
+    }
+
+    #[test]
+    fn the_output_is_compared_with_a_manifest_whatever_the_inputs_have() {
+        let root = scratch("verify-manifest");
+        let mut overlay = synced(&root);
+        let (output, path) = (root.join("output"), root.join("manifest.json"));
+        overlay.write_manifest(&path).unwrap();
+        assert!(overlay.verify_manifest(&path).unwrap().is_empty());
+
+        // Through the hard link, so the overlay sees nothing wrong with it.
+        fs::write(output.join("a"), "edited").unwrap();
+        fs::remove_file(output.join("Data/b")).unwrap();
+        fs::write(output.join("foreign"), "").unwrap();
+        fs::remove_file(root.join("mods/shared")).unwrap();
+        overlay.apply_event(EventType::new(1, Event::Remove(PathBuf::from("shared"))));
+        assert_eq!(
+            overlay
+                .materialized(Path::new("shared"))
+                .unwrap()
+                .id()
+                .index(),
+            0
+        );
+
+        let report = overlay.verify_manifest(&path).unwrap();
+        assert_eq!(
+            report,
+            ManifestReport {
+                added: vec![PathBuf::from("foreign")],
+                missing: vec![Path::new("Data").join("b")],
+                modified: vec![PathBuf::from("a")],
+                remapped: vec![PathBuf::from("shared")],
+            }
+        );
+        assert!(!report.is_empty());
+    }
+
+    #[test]
+    fn only_manifests_this_overlay_can_compare_with_are_read() {
+        let root = scratch("manifest-versions");
+        let manifest = synced(&root).manifest().unwrap();
+        let json = |edit: &dyn Fn(&mut Value)| {
+            let mut value = serde_json::to_value(&manifest).unwrap();
+            edit(&mut value);
+            value.to_string()
+        };
+
+        // Fields it doesn't know of are of a later overlay of the same version.
+        let added = json(&|value| value["comment"] = "from elsewhere".into());
+        assert_eq!(Manifest::from_json(&added).unwrap(), manifest);
+        let newer = json(&|value| value["version"] = (MANIFEST_VERSION + 1).into());
+        let error = Manifest::from_json(&newer).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "the manifest is of version {}, only up to {} can be read",
+                MANIFEST_VERSION + 1,
+                MANIFEST_VERSION
+            )
+        );
+        let other = json(&|value| value["algorithm"] = "sha1".into());
+        let error = Manifest::from_json(&other).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "the manifest is hashed with sha1, only xxh3-128 can be read"
+        );
+        let unversioned = json(&|value| {
+            value.as_object_mut().unwrap().remove("version");
+        });
+        assert!(Manifest::from_json(&unversioned).is_err());
+    }
+}
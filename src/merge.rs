@@ -0,0 +1,510 @@
+use crate::audit::AuditAction;
+use crate::filter::Glob;
+use crate::input::Input;
+use crate::key::Folded;
+use crate::{beside, error_kind, Overlay, ProcessedAction};
+use failure::{format_err, Error};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Merges the files of a format the overlay doesn't know, for the application that does.
+///
+/// The overlay decides when: whenever more than one input has a file at a path the merger
+/// can merge, and again whenever one of them changes. The merged file belongs to the
+/// overlay like a link does. If merging fails, the winner's file is linked instead.
+pub trait FileMerger: Debug + Send + Sync {
+    /// Whether the files at `path`, relative to the output, are merged by this merger.
+    fn can_merge(&self, path: &Path) -> bool;
+
+    /// Merges `sources`, given from the lowest priority to the highest, into a new file at
+    /// `dest`. The overlay moves it into the output itself.
+    fn merge(&self, sources: &[PathBuf], dest: &Path) -> Result<(), Error>;
+}
+
+/// What merges the files at the paths it applies to.
+#[derive(Debug, Clone)]
+pub(crate) enum Merger {
+    Format(Glob, MergeFormat),
+    Custom(Arc<dyn FileMerger>),
+}
+
+impl Merger {
+    pub(crate) fn can_merge(&self, path: &Path) -> bool {
+        match self {
+            Merger::Format(glob, _) => glob.matches(path),
+            Merger::Custom(merger) => merger.can_merge(path),
+        }
+    }
+}
+
+/// How the files at a path are merged into one when more than one input has a file there,
+/// instead of only the winner's being linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeFormat {
+    /// Objects are merged key by key, all the way down. Anything else, arrays included,
+    /// is taken from the input of highest priority that has it.
+    Json,
+    /// Sections are merged key by key, the input of highest priority deciding each value.
+    /// Keys and section names are compared without regard to case, and comments are left
+    /// out.
+    Ini,
+}
+
+/// Files whose paths match `pattern` are merged as `format`.
+///
+/// The pattern is a glob like those of `InputOptions::include`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeRule {
+    pub pattern: String,
+    pub format: MergeFormat,
+}
+
+/// Merges `sources`, given from the lowest priority to the highest.
+pub(crate) fn merge(format: MergeFormat, sources: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    match format {
+        MergeFormat::Json => merge_json(sources),
+        MergeFormat::Ini => merge_ini(sources),
+    }
+}
+
+fn merge_json(sources: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let mut merged = Value::Null;
+    for (number, source) in sources.iter().enumerate() {
+        let value: Value = serde_json::from_slice(source)
+            .map_err(|e| format_err!("source {} isn't JSON: {}", number + 1, e))?;
+        overlay_json(&mut merged, value);
+    }
+
+    let mut bytes = serde_json::to_vec_pretty(&merged)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+/// Puts `value` over `base`, merging the objects in both.
+fn overlay_json(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (key, value) in value {
+                overlay_json(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// A section of an INI file: its name, `None` for the keys before the first one, and its
+/// lines by key.
+struct Section {
+    name: Option<String>,
+    keys: Vec<(String, String)>,
+}
+
+fn merge_ini(sources: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let mut sections = vec![Section {
+        name: None,
+        keys: vec![],
+    }];
+    let mut newline = "\n";
+
+    for (number, source) in sources.iter().enumerate() {
+        let text = std::str::from_utf8(source)
+            .map_err(|e| format_err!("source {} isn't text: {}", number + 1, e))?;
+        newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+        let mut section = 0;
+        for (line_number, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = trimmed
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                let name = name.trim();
+                section = match sections.iter().position(|section| {
+                    section
+                        .name
+                        .as_deref()
+                        .is_some_and(|other| other.eq_ignore_ascii_case(name))
+                }) {
+                    Some(section) => section,
+                    None => {
+                        sections.push(Section {
+                            name: Some(name.to_string()),
+                            keys: vec![],
+                        });
+                        sections.len() - 1
+                    }
+                };
+                continue;
+            }
+
+            let key = match trimmed.find('=') {
+                Some(at) => trimmed[..at].trim(),
+                None => {
+                    return Err(format_err!(
+                        "line {} of source {} is neither a section nor a key: {:?}",
+                        line_number + 1,
+                        number + 1,
+                        line
+                    ))
+                }
+            };
+            let keys = &mut sections[section].keys;
+            match keys
+                .iter_mut()
+                .find(|(other, _)| other.eq_ignore_ascii_case(key))
+            {
+                Some((_, existing)) => *existing = trimmed.to_string(),
+                None => keys.push((key.to_string(), trimmed.to_string())),
+            }
+        }
+    }
+
+    let mut merged = String::new();
+    for section in &sections {
+        if let Some(name) = &section.name {
+            if !merged.is_empty() {
+                merged.push_str(newline);
+            }
+            merged.push('[');
+            merged.push_str(name);
+            merged.push(']');
+            merged.push_str(newline);
+        }
+        for (_, line) in &section.keys {
+            merged.push_str(line);
+            merged.push_str(newline);
+        }
+    }
+    Ok(merged.into_bytes())
+}
+
+/// What merges the files of the overlay, and which of them it merged.
+#[derive(Debug, Default)]
+pub(crate) struct Merging {
+    /// What merges the files at which paths when more than one input has one, the first
+    /// that can merge a path deciding.
+    pub(crate) mergers: Vec<Merger>,
+    /// The paths of the output holding files merged by the overlay.
+    pub(crate) merged: HashSet<PathBuf>,
+}
+
+impl Overlay {
+    /// What merges the files at `path`, if they are merged.
+    pub(crate) fn merger(&self, path: &Path) -> Option<Merger> {
+        self.merging
+            .mergers
+            .iter()
+            .find(|merger| merger.can_merge(path))
+            .cloned()
+    }
+
+    /// The enabled inputs with a file at `path`, from the lowest priority to the highest.
+    pub(crate) fn merge_sources(&self, path: &Path) -> Vec<usize> {
+        let mut sources: Vec<&Input> = self
+            .inputs
+            .iter()
+            .filter(|input| input.enabled && input.accepts_file(path))
+            .filter(|input| {
+                let source = input.source(path);
+                self.fs.exists(&source) && !self.fs.is_dir(&source)
+            })
+            .collect();
+        sources.sort_by_key(|input| input.rank);
+        sources.into_iter().map(|input| input.index).collect()
+    }
+
+    /// Puts the files the inputs in `sources` have at `path`, merged by `merger`, into the
+    /// output in place of the file of input `index`. Returns whether it is there like
+    /// `link` does, or an error if the files couldn't be merged at all.
+    pub(crate) fn write_merged(
+        &mut self,
+        path: &Path,
+        index: usize,
+        merger: &Merger,
+        sources: &[usize],
+    ) -> Result<bool, Error> {
+        let files: Vec<PathBuf> = sources
+            .iter()
+            .map(|source| self.inputs[*source].source(path))
+            .collect();
+        let output_file = self.output.join(path);
+        let merged = match merger {
+            Merger::Format(_, format) => {
+                let contents = files
+                    .iter()
+                    .map(|file| self.fs.read(file))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(merge(*format, &contents)?)
+            }
+            // The merger writes beside the output file, which a dry run leaves alone, so
+            // what it would merge isn't known.
+            Merger::Custom(_) if self.dry_run => None,
+            Merger::Custom(merger) => {
+                // Merged beside the output file, then read back so that it is only
+                // replaced if it changed.
+                let temporary = beside(&output_file, ".overlay-merge");
+                let result = merger.merge(&files, &temporary).and_then(|()| {
+                    self.fs.read(&temporary).map_err(|e| {
+                        format_err!("the merger wrote nothing to {}: {}", temporary.display(), e)
+                    })
+                });
+                let _ = self.fs.remove_file(&temporary);
+                Some(result?)
+            }
+        };
+
+        let mut replaced = self.fs.exists(&output_file);
+        if replaced
+            && merged.is_some()
+            && self.removable(path)
+            && self.fs.read(&output_file).ok() == merged
+        {
+            say!(self.line, Debug, " UNCHANGED!");
+            self.links.remove(path);
+            self.merging.merged.insert(path.to_path_buf());
+            self.record(path);
+            self.note(|report| report.confirmed += 1);
+            return Ok(true);
+        }
+        if replaced && !self.removable(path) {
+            if !self.ledger.contains(path) {
+                say!(self.line, Warn, " NOT PUT THERE BY THE OVERLAY,");
+            }
+            if !self.handle_foreign(path) {
+                return Ok(false);
+            }
+            replaced = false;
+        }
+        if replaced && self.displaces(path) {
+            if !self.make_room(path, index) {
+                return Ok(false);
+            }
+            replaced = false;
+        }
+        let action = if replaced {
+            AuditAction::Replace
+        } else {
+            AuditAction::Link
+        };
+
+        let result = match &merged {
+            Some(merged) if !self.dry_run => self.fs.write(&output_file, merged),
+            _ => Ok(()),
+        };
+        match &result {
+            Ok(()) => {
+                say!(self.line, Info, " MERGED {} FILES!", sources.len());
+                self.links.remove(path);
+                self.merging.merged.insert(path.to_path_buf());
+                self.record(path);
+                self.run_hooks(true, path, &output_file, index);
+                self.stats.linked();
+                if replaced {
+                    self.processed(ProcessedAction::Replaced);
+                    self.note(|report| report.relinked += 1);
+                } else {
+                    self.processed(ProcessedAction::Linked);
+                    self.note(|report| report.linked += 1);
+                }
+            }
+            Err(e) => {
+                say!(self.line, Error, " NOT MERGED: {}!", e);
+                self.failed(Some(path), Some(index), error_kind(e), e.to_string());
+                let error = format!("couldn't merge {}: {}", path.display(), e);
+                self.note(|report| report.errors.push(error));
+            }
+        }
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit(action, Some(path), index, error.as_deref());
+
+        Ok(result.is_ok())
+    }
+
+    /// Merges the files at `path` again after one that isn't the winner's changed, or
+    /// links the winner's alone if it is the only one left. Returns `false` if the files
+    /// at `path` aren't merged at all.
+    pub(crate) fn remerge(&mut self, path: &Path) -> bool {
+        if self.merger(path).is_none() {
+            return false;
+        }
+        if let Some(index) = self.materialized(path).map(|input| input.index) {
+            if self.graft_of(path).is_none() {
+                self.link(path, &Folded::new(path), index);
+            }
+        }
+        true
+    }
+
+    /// Merges the files at paths matching `pattern` as `format` when more than one input
+    /// has one, instead of linking the winner's. The first pattern or merger added that
+    /// applies to a path decides.
+    ///
+    /// The merged file belongs to the overlay, and is merged again whenever one of the
+    /// files changes. Where only one input is left, its file is linked as usual.
+    pub fn add_merge(&mut self, pattern: &str, format: MergeFormat) -> Result<(), Error> {
+        self.merging
+            .mergers
+            .push(Merger::Format(Glob::new(pattern)?, format));
+        Ok(())
+    }
+
+    /// Merges the files `merger` can merge when more than one input has one, like
+    /// `add_merge` does for the formats the overlay knows.
+    pub fn add_merger<M: FileMerger + 'static>(&mut self, merger: M) {
+        self.merging.mergers.push(Merger::Custom(Arc::new(merger)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::Harness;
+    use crate::Event;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn merged(format: MergeFormat, sources: &[&str]) -> Result<String, String> {
+        let sources: Vec<Vec<u8>> = sources
+            .iter()
+            .map(|source| source.as_bytes().to_vec())
+            .collect();
+        merge(format, &sources)
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn json_objects_are_merged_and_the_rest_replaced() {
+        let base = r#"{"video": {"width": 1280, "vsync": true}, "mods": ["a"], "name": "base"}"#;
+        let patch = r#"{"video": {"width": 1920}, "mods": ["b"], "extra": null}"#;
+        let json = merged(MergeFormat::Json, &[base, patch]).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&json).unwrap(),
+            serde_json::json!({
+                "video": {"width": 1920, "vsync": true},
+                "mods": ["b"],
+                "name": "base",
+                "extra": null,
+            })
+        );
+        let error = merged(MergeFormat::Json, &[base, "{"]).unwrap_err();
+        assert!(error.starts_with("source 2 isn't JSON: "), "{}", error);
+    }
+
+    #[test]
+    fn ini_keys_are_merged_by_section_whatever_their_case() {
+        let base = "top=1\n[Display]\nWidth=1280\nVSync=1\n; a comment\n[Sound]\nVolume=5\n";
+        let patch = "[display]\nwidth = 1920\n[Controls]\nInvert=0\n";
+        assert_eq!(
+            merged(MergeFormat::Ini, &[base, patch]).unwrap(),
+            "top=1\n\n[Display]\nwidth = 1920\nVSync=1\n\n[Sound]\nVolume=5\n\n\
+             [Controls]\nInvert=0\n"
+        );
+        assert_eq!(
+            merged(MergeFormat::Ini, &["[A]\r\nx=1\r\n", "[A]\r\ny=2\r\n"]).unwrap(),
+            "[A]\r\nx=1\r\ny=2\r\n"
+        );
+        assert_eq!(
+            merged(MergeFormat::Ini, &[base, "[Display]\nnonsense\n"]).unwrap_err(),
+            "line 2 of source 2 is neither a section nor a key: \"nonsense\""
+        );
+    }
+
+    /// Merges nothing, only counting how often it is asked to.
+    #[derive(Debug)]
+    struct CountingMerger(Arc<AtomicUsize>);
+
+    impl FileMerger for CountingMerger {
+        fn can_merge(&self, path: &Path) -> bool {
+            path.extension() == Some("cfg".as_ref())
+        }
+
+        fn merge(&self, sources: &[PathBuf], dest: &Path) -> Result<(), Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            fs::write(dest, format!("{:?}", sources))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_settings_of_every_provider_are_merged_until_one_is_left() {
+        let mut harness = Harness::with("merge", &[0, 1, 2], |builder| {
+            builder.merge("*.json", MergeFormat::Json)
+        });
+        let write = |harness: &mut Harness, index: usize, contents: &str| {
+            let file = harness.inputs[index].join("settings.json");
+            harness.fs.write_file(file, contents.as_bytes());
+            harness.event(index, Event::Create(PathBuf::from("settings.json")));
+        };
+        let output = harness.output.join("settings.json");
+        let merged = |harness: &Harness| -> serde_json::Value {
+            serde_json::from_slice(&harness.fs.read(&output).unwrap()).unwrap()
+        };
+        write(&mut harness, 0, r#"{"width": 1280, "vsync": true}"#);
+        assert_eq!(harness.winner("settings.json"), Some(0));
+
+        write(&mut harness, 2, r#"{"width": 1920}"#);
+        assert_eq!(harness.winner("settings.json"), None);
+        assert_eq!(
+            merged(&harness),
+            serde_json::json!({"width": 1920, "vsync": true})
+        );
+        write(&mut harness, 1, r#"{"width": 1600, "fov": 90}"#);
+        assert_eq!(
+            merged(&harness),
+            serde_json::json!({"width": 1920, "vsync": true, "fov": 90})
+        );
+        write(&mut harness, 2, r#"{"vsync": false}"#);
+        assert_eq!(
+            merged(&harness),
+            serde_json::json!({"width": 1600, "vsync": false, "fov": 90})
+        );
+        assert!(harness
+            .overlay
+            .merging
+            .merged
+            .contains(Path::new("settings.json")));
+        assert!(harness.overlay.ledger.contains(Path::new("settings.json")));
+
+        harness.remove(2, "settings.json");
+        harness.remove(0, "settings.json");
+        assert_eq!(harness.winner("settings.json"), Some(1));
+        assert!(!harness
+            .overlay
+            .merging
+            .merged
+            .contains(Path::new("settings.json")));
+        assert!(harness.overlay.failures.is_empty());
+    }
+
+    #[test]
+    fn a_dry_run_merges_nothing_beside_the_output() {
+        let merges = Arc::new(AtomicUsize::new(0));
+        let mut harness = Harness::with("dry-run-merge", &[0, 1], |builder| {
+            builder.dry_run(true).merger(CountingMerger(merges.clone()))
+        });
+        harness.create(0, "settings.cfg");
+        harness.create(1, "settings.cfg");
+
+        assert_eq!(merges.load(Ordering::SeqCst), 0);
+        assert!(harness
+            .overlay
+            .merging
+            .merged
+            .contains(Path::new("settings.cfg")));
+        let merged = harness.output.join("settings.cfg.overlay-merge");
+        assert!(!merged.exists() && !harness.fs.exists(&merged));
+        assert!(!harness.fs.exists(&harness.output.join("settings.cfg")));
+    }
+}
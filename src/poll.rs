@@ -0,0 +1,106 @@
+use crate::source::EventSink;
+use notify::DebouncedEvent;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// What a poll saw of an entry of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Seen {
+    dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+type Scan = BTreeMap<PathBuf, Seen>;
+
+/// Looks at every file and directory beneath `root`. Links aren't followed, like the
+/// native watchers don't.
+fn scan(root: &Path) -> io::Result<Scan> {
+    // A share that went away is a failure, not an input that is empty all of a sudden.
+    fs::metadata(root)?;
+
+    let mut scan = BTreeMap::new();
+    for entry in WalkDir::new(root).min_depth(1).into_iter().flatten() {
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() || metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let seen = Seen {
+            dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        };
+        scan.insert(entry.into_path(), seen);
+    }
+    Ok(scan)
+}
+
+/// What changed from `last` to `next`: removed entries first, then new ones, then the
+/// files written to. A directory that came or went is one change, rather than one for
+/// everything in it.
+fn changes(last: &Scan, next: &Scan) -> Vec<DebouncedEvent> {
+    let kind = |scan: &Scan, path: &Path| scan.get(path).map(|seen| seen.dir);
+    let gone = |path: &Path| kind(last, path).is_some() && kind(next, path) != kind(last, path);
+    let new = |path: &Path| kind(next, path).is_some() && kind(last, path) != kind(next, path);
+    let outermost = |path: &Path, changed: &dyn Fn(&Path) -> bool| {
+        path.parent().is_none_or(|parent| !changed(parent))
+    };
+
+    let removed = last
+        .keys()
+        .filter(|path| gone(path) && outermost(path, &gone))
+        .map(|path| DebouncedEvent::Remove(path.clone()));
+    let created = next
+        .keys()
+        .filter(|path| new(path) && outermost(path, &new))
+        .map(|path| DebouncedEvent::Create(path.clone()));
+    let written = next
+        .iter()
+        .filter(|(path, seen)| {
+            !seen.dir && last.get(*path).is_some_and(|old| !old.dir && old != *seen)
+        })
+        .map(|(path, _)| DebouncedEvent::Write(path.clone()));
+
+    removed.chain(created).chain(written).collect()
+}
+
+/// Scans the input at `root` every `interval` and tells `sink` what changed since the last
+/// time, for inputs whose changes can't be watched, or are too costly to, like those on
+/// network shares.
+///
+/// Each completed poll is reported to `sink` too. If the input can't be scanned anymore,
+/// the watch fails, as a native watcher's would.
+pub(crate) fn watch(root: &Path, interval: Duration, sink: EventSink) -> io::Result<()> {
+    let mut last = scan(root)?;
+    let root = root.to_path_buf();
+
+    thread::spawn(move || loop {
+        if !sink.polled() {
+            // The overlay has stopped listening.
+            break;
+        }
+        thread::sleep(interval);
+
+        let next = match scan(&root) {
+            Ok(next) => next,
+            Err(e) => {
+                sink.fail(e.into());
+                break;
+            }
+        };
+        if !changes(&last, &next)
+            .into_iter()
+            .all(|event| sink.send(event))
+        {
+            break;
+        }
+        last = next;
+    });
+
+    Ok(())
+}
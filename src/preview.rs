@@ -0,0 +1,377 @@
+use crate::engine::{self, Action, Ranked};
+use crate::input::{moved, Rank};
+use crate::{InputId, Overlay, Provider};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::path::{Path, PathBuf};
+
+/// A change to the inputs, to see what it would do with `Overlay::preview` before making
+/// it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum PlannedChange {
+    /// See `Overlay::set_priority`.
+    SetPriority {
+        input: InputId,
+        priority: u32,
+    },
+    /// See `Overlay::set_group_priority`.
+    SetGroupPriority {
+        group: u32,
+        to: u32,
+    },
+    /// See `Overlay::move_input_above`.
+    MoveAbove {
+        input: InputId,
+        reference: InputId,
+    },
+    MoveBelow {
+        input: InputId,
+        reference: InputId,
+    },
+    /// See `Overlay::set_enabled`.
+    SetEnabled {
+        input: InputId,
+        enabled: bool,
+    },
+    /// See `Overlay::remove_input`.
+    Remove {
+        input: InputId,
+    },
+}
+
+/// A path whose file would come from another input, or from none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PreviewEntry {
+    /// The path relative to the output.
+    pub path: PathBuf,
+    /// The input whose file is there now, as `Overlay::resolve` has it.
+    pub before: Option<Provider>,
+    /// The input whose file would be there, with the group and priority it would have.
+    pub after: Option<Provider>,
+}
+
+/// What `Overlay::preview` found the changes would do to the output.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PreviewReport {
+    /// Every path whose winner would change, sorted.
+    pub entries: Vec<PreviewEntry>,
+}
+
+/// The inputs as they would be, and the providers of the paths the changes get to.
+struct Planned {
+    ranks: Vec<Rank>,
+    /// Whether each input would provide anything.
+    enabled: Vec<bool>,
+    removed: Vec<bool>,
+    heaps: BTreeMap<PathBuf, BinaryHeap<Ranked>>,
+}
+
+impl Overlay {
+    /// Which paths would change winners, and to which input, if `changes` were made one
+    /// after the other. Like the changes themselves, it is decided by the engine from the
+    /// providers of each path, so it is what they would do. Nothing is changed, and only
+    /// the files of inputs that would be enabled are read.
+    ///
+    /// Changes to inputs that the overlay doesn't have, or that were removed by then, are
+    /// left out. Paths held back for case conflicts stay that way.
+    pub fn preview(&self, changes: &[PlannedChange]) -> PreviewReport {
+        let mut planned = Planned {
+            ranks: self.inputs.iter().map(|input| input.rank).collect(),
+            enabled: self.inputs.iter().map(|input| input.enabled).collect(),
+            removed: self.inputs.iter().map(|input| input.removed).collect(),
+            heaps: BTreeMap::new(),
+        };
+
+        for change in changes {
+            match *change {
+                PlannedChange::SetPriority { input, priority } => {
+                    if let Some(index) = planned.existing(input) {
+                        let rank = Rank {
+                            priority,
+                            ..planned.ranks[index]
+                        };
+                        self.plan_ranks(&mut planned, vec![(index, rank)]);
+                    }
+                }
+                PlannedChange::SetGroupPriority { group, to } => {
+                    let ranks = planned
+                        .ranks
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, rank)| rank.group == group)
+                        .map(|(index, rank)| (index, Rank { group: to, ..*rank }))
+                        .collect();
+                    self.plan_ranks(&mut planned, ranks);
+                }
+                PlannedChange::MoveAbove { input, reference }
+                | PlannedChange::MoveBelow { input, reference } => {
+                    let above = matches!(change, PlannedChange::MoveAbove { .. });
+                    if let (Some(index), Some(reference)) =
+                        (planned.existing(input), planned.existing(reference))
+                    {
+                        let ranks = moved(&planned.ranks, index, reference, above);
+                        self.plan_ranks(&mut planned, ranks);
+                    }
+                }
+                PlannedChange::SetEnabled { input, enabled } => {
+                    if let Some(index) = planned.existing(input) {
+                        self.plan_enabled(&mut planned, index, enabled);
+                    }
+                }
+                PlannedChange::Remove { input } => {
+                    if let Some(index) = planned.existing(input) {
+                        self.plan_enabled(&mut planned, index, false);
+                        planned.removed[index] = true;
+                    }
+                }
+            }
+        }
+
+        let mut report = PreviewReport::default();
+        for (path, heap) in &planned.heaps {
+            let before = self.materialized(path).map(|input| input.index);
+            let after = heap
+                .peek()
+                .map(|input| input.index)
+                .filter(|_| !self.blocked.contains(path));
+            if before == after {
+                continue;
+            }
+            report.entries.push(PreviewEntry {
+                path: path.clone(),
+                before: before.map(|index| self.provider(&self.inputs[index], path)),
+                after: after.map(|index| {
+                    let rank = planned.ranks[index];
+                    Provider {
+                        group: rank.group,
+                        priority: rank.priority,
+                        ..self.provider(&self.inputs[index], path)
+                    }
+                }),
+            });
+        }
+        report
+    }
+
+    /// Gives the inputs of `changes` their new ranks, as `rerank` does.
+    fn plan_ranks(&self, planned: &mut Planned, changes: Vec<(usize, Rank)>) {
+        let changes: Vec<(usize, Rank)> = changes
+            .into_iter()
+            .filter(|(index, rank)| planned.ranks[*index] != *rank)
+            .collect();
+        for (index, rank) in &changes {
+            planned.ranks[*index] = *rank;
+        }
+        // Each heap is rebuilt once, like those of the overlay.
+        let paths: BTreeSet<PathBuf> = changes
+            .iter()
+            .flat_map(|(index, _)| self.planned_paths(planned, *index))
+            .collect();
+        for path in paths {
+            let ranks = &planned.ranks;
+            let heap = planned.heaps.get_mut(&path).unwrap();
+            *heap = engine::reranked(heap, |index| ranks[index]);
+        }
+    }
+
+    /// Enables or disables input `index`, as if all of its files appeared or went away.
+    fn plan_enabled(&self, planned: &mut Planned, index: usize, enabled: bool) {
+        if planned.enabled[index] == enabled {
+            return;
+        }
+        planned.enabled[index] = enabled;
+
+        if !enabled {
+            for path in self.planned_paths(planned, index) {
+                let blocked = self.blocked.contains(&path);
+                let plan = engine::plan_vanished(&path, planned.heaps.get(&path), blocked, index);
+                planned.follow(plan.actions);
+            }
+            return;
+        }
+        let input = &self.inputs[index];
+        let ranked = Ranked {
+            index,
+            rank: planned.ranks[index],
+        };
+        for path in input.walk(Path::new("")) {
+            let blocked = self.blocked.contains(&path);
+            let heap = self.planned_heap(planned, &path);
+            let plan = engine::plan_appeared(&path, Some(heap), blocked, ranked);
+            planned.follow(plan.actions);
+        }
+    }
+
+    /// Every path input `index` would provide, with their heaps in `planned`.
+    fn planned_paths(&self, planned: &mut Planned, index: usize) -> Vec<PathBuf> {
+        let provides = |heap: &BinaryHeap<Ranked>| heap.iter().any(|input| input.index == index);
+        let mut paths: Vec<PathBuf> = planned
+            .heaps
+            .iter()
+            .filter(|(_, heap)| provides(heap))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let live: Vec<PathBuf> = self
+            .input_map
+            .iter()
+            .filter(|(path, heap)| !planned.heaps.contains_key(*path) && provides(heap))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in live {
+            self.planned_heap(planned, &path);
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// The providers `path` would have in `planned`, as it has them now until a change
+    /// gets to it.
+    fn planned_heap<'a>(
+        &self,
+        planned: &'a mut Planned,
+        path: &Path,
+    ) -> &'a mut BinaryHeap<Ranked> {
+        planned
+            .heaps
+            .entry(path.to_path_buf())
+            .or_insert_with(|| self.input_map.get(path).cloned().unwrap_or_default())
+    }
+}
+
+impl Planned {
+    /// Makes the changes `actions` plan to the providers of their paths, and leaves the
+    /// output alone.
+    fn follow(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            if let Action::UpdateMap { path, update } = action {
+                if let Some(heap) = self.heaps.get_mut(&path) {
+                    engine::update_heap(heap, update);
+                }
+            }
+        }
+    }
+
+    /// Where the data of input `id` is, if it is there and wouldn't be removed.
+    fn existing(&self, id: InputId) -> Option<usize> {
+        let index = id.index();
+        Some(index).filter(|&index| index < self.removed.len() && !self.removed[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+
+    const PATHS: [&str; 4] = ["a", "b", "c", "d"];
+
+    /// Inputs 0 to 2 by priority, 0 with `a`, `b` and `c`, 1 with `a` and 2 with `b` and
+    /// `d`.
+    fn layered(name: &str) -> Harness {
+        let mut harness = Harness::new(name, &[0, 1, 2]);
+        for (index, paths) in [(0, &["a", "b", "c"][..]), (1, &["a"]), (2, &["b", "d"])] {
+            for path in paths {
+                harness.create(index, path);
+            }
+        }
+        harness
+    }
+
+    fn winners(harness: &Harness) -> Vec<Option<InputId>> {
+        PATHS
+            .iter()
+            .map(|path| harness.overlay.resolve(path).map(|winner| winner.input))
+            .collect()
+    }
+
+    /// That previewing `changes` tells exactly the winners that making them with `make`
+    /// changes.
+    fn previews_what_it_does(
+        name: &str,
+        changes: &[PlannedChange],
+        make: impl FnOnce(&mut Overlay),
+    ) -> PreviewReport {
+        let mut harness = layered(name);
+        let before = winners(&harness);
+        let report = harness.overlay.preview(changes);
+        assert_eq!(winners(&harness), before, "the preview changed something");
+        make(&mut harness.overlay);
+        harness.overlay.finish_links();
+        let after = winners(&harness);
+
+        let changed: Vec<(PathBuf, Option<InputId>, Option<InputId>)> = PATHS
+            .iter()
+            .zip(before.into_iter().zip(after))
+            .filter(|(_, (before, after))| before != after)
+            .map(|(path, (before, after))| (PathBuf::from(path), before, after))
+            .collect();
+        let previewed: Vec<(PathBuf, Option<InputId>, Option<InputId>)> = report
+            .entries
+            .iter()
+            .map(|entry| {
+                let input = |provider: &Option<Provider>| provider.as_ref().map(|p| p.input);
+                (
+                    entry.path.clone(),
+                    input(&entry.before),
+                    input(&entry.after),
+                )
+            })
+            .collect();
+        assert_eq!(previewed, changed);
+        for entry in &report.entries {
+            assert_eq!(entry.after, harness.overlay.resolve(&entry.path));
+        }
+        report
+    }
+
+    #[test]
+    fn a_preview_tells_the_winners_the_changes_would_bring() {
+        let (first, third) = (InputId::of(0), InputId::of(2));
+        let report = previews_what_it_does(
+            "preview-priority",
+            &[PlannedChange::SetPriority {
+                input: first,
+                priority: 10,
+            }],
+            |overlay| overlay.set_priority(first, 10).unwrap(),
+        );
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.entries[0].after.as_ref().unwrap().priority, 10);
+
+        previews_what_it_does(
+            "preview-move",
+            &[PlannedChange::MoveBelow {
+                input: third,
+                reference: first,
+            }],
+            |overlay| overlay.move_input_below(third, first).unwrap(),
+        );
+        // One after the other, the second seeing what the first did.
+        let report = previews_what_it_does(
+            "preview-toggles",
+            &[
+                PlannedChange::SetEnabled {
+                    input: third,
+                    enabled: false,
+                },
+                PlannedChange::Remove { input: first },
+                PlannedChange::SetPriority {
+                    input: first,
+                    priority: 10,
+                },
+            ],
+            |overlay| {
+                overlay.set_enabled(third, false).unwrap();
+                overlay.remove_input(first).unwrap();
+            },
+        );
+        let nobody: Vec<&Path> = report
+            .entries
+            .iter()
+            .filter(|entry| entry.after.is_none())
+            .map(|entry| entry.path.as_path())
+            .collect();
+        assert_eq!(nobody, [Path::new("b"), Path::new("c"), Path::new("d")]);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::fs_ops::{is_too_many_links, FileOps};
+use crate::{InputId, Overlay};
+use failure::{format_err, Error};
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process;
+use walkdir::WalkDir;
+
+/// Whether files of an input can be hard linked into the output, as found by trying.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", content = "reason", rename_all = "snake_case")]
+pub enum LinkProbe {
+    Works,
+    /// The input and the output are on different volumes.
+    CrossDevice(String),
+    /// The output's filesystem has no hard links, like FAT.
+    Unsupported(String),
+    /// The input is on read-only media, like a mounted disc image, which can't be linked
+    /// from. Its files are always copied.
+    ReadOnly(String),
+    /// Linking failed for some other reason.
+    Failed(String),
+    /// There was no way to try, e.g. because the input has no files yet. Linking is
+    /// assumed to work.
+    Untested(String),
+}
+
+impl LinkProbe {
+    /// Whether it is worth hard linking at all.
+    pub fn can_link(&self) -> bool {
+        matches!(self, LinkProbe::Works | LinkProbe::Untested(_))
+    }
+}
+
+impl fmt::Display for LinkProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkProbe::Works => write!(f, "hard links work"),
+            LinkProbe::CrossDevice(reason) => {
+                write!(f, "they are on different volumes ({})", reason)
+            }
+            LinkProbe::Unsupported(reason) => {
+                write!(f, "the output doesn't support hard links ({})", reason)
+            }
+            LinkProbe::ReadOnly(reason) => write!(f, "the input is read-only ({})", reason),
+            LinkProbe::Failed(reason) => write!(f, "{}", reason),
+            LinkProbe::Untested(reason) => write!(f, "untested, {}", reason),
+        }
+    }
+}
+
+/// Tries to hard link a file of the `input` directory into the `output` one, removing the
+/// link again. Nothing in the input is written to.
+pub(crate) fn probe(fs: &dyn FileOps, input: &Path, output: &Path) -> LinkProbe {
+    let source = match WalkDir::new(input)
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|entry| entry.file_type().is_file())
+    {
+        Some(entry) => entry.into_path(),
+        None => return LinkProbe::Untested(format!("{} has no files", input.display())),
+    };
+    // It matches the default temporary file patterns, should anything catch it anyway.
+    let link = output.join(format!(".overlay-probe-{}.part", process::id()));
+
+    if let Err(e) = fs.create_dir_all(output) {
+        return LinkProbe::Untested(format!("couldn't create {}: {}", output.display(), e));
+    }
+
+    match fs.hard_link(&source, &link) {
+        Ok(()) => {
+            let _ = fs.remove_file(&link);
+            LinkProbe::Works
+        }
+        // Only after the volumes were found to allow it, the file just has no room for
+        // another, and is copied.
+        Err(ref e) if is_too_many_links(e) => LinkProbe::Works,
+        Err(e) => classify(&e),
+    }
+}
+
+fn classify(e: &io::Error) -> LinkProbe {
+    if e.kind() == io::ErrorKind::ReadOnlyFilesystem || e.raw_os_error() == Some(READ_ONLY) {
+        LinkProbe::ReadOnly(e.to_string())
+    } else if crosses_devices(e) {
+        LinkProbe::CrossDevice(e.to_string())
+    } else if e.kind() == io::ErrorKind::Unsupported
+        || e.raw_os_error()
+            .is_some_and(|code| UNSUPPORTED.contains(&code))
+    {
+        LinkProbe::Unsupported(e.to_string())
+    } else {
+        LinkProbe::Failed(e.to_string())
+    }
+}
+
+/// Whether `e` is of a hard link or rename from one volume to another.
+pub(crate) fn crosses_devices(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::CrossesDevices || e.raw_os_error() == Some(CROSS_DEVICE)
+}
+
+/// Whether the volume `path` is on is mounted read-only.
+#[cfg(unix)]
+pub(crate) fn read_only(path: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_flag & libc::ST_RDONLY != 0)
+}
+
+/// Whether the volume `path` is on is read-only, like a CD or a mounted ISO.
+#[cfg(windows)]
+pub(crate) fn read_only(path: &Path) -> io::Result<bool> {
+    use windows_sys::Win32::System::SystemServices::FILE_READ_ONLY_VOLUME;
+
+    let (flags, _) = volume_information(path)?;
+    Ok(flags & FILE_READ_ONLY_VOLUME != 0)
+}
+
+/// The flags and the name of the filesystem of the volume `path` is on.
+#[cfg(windows)]
+fn volume_information(path: &Path) -> io::Result<(u32, String)> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::{GetVolumeInformationW, GetVolumePathNameW};
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = vec![0u16; path.len().max(261)];
+    if unsafe { GetVolumePathNameW(path.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut flags = 0;
+    let mut name = [0u16; 261];
+    let found = unsafe {
+        GetVolumeInformationW(
+            root.as_ptr(),
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut flags,
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if found == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok((flags, String::from_utf16_lossy(&name[..len])))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_only(_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Whether this process may create files in the directory `path`, going by its permissions
+/// and the volume, without trying.
+#[cfg(unix)]
+pub(crate) fn writable(path: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    if read_only(path)? {
+        return Ok(false);
+    }
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    Ok(unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) } == 0)
+}
+
+/// Whether this process may create files in the directory `path`, going by the volume.
+/// Windows ignores the read-only attribute of directories, and what the access control
+/// lists allow is only found out by trying.
+#[cfg(not(unix))]
+pub(crate) fn writable(path: &Path) -> io::Result<bool> {
+    std::fs::metadata(path)?;
+    Ok(!read_only(path)?)
+}
+
+/// The name of the filesystem of the volume `path`, or the closest of its parents that
+/// exists, is on, if it is one that has no hard links at all, like FAT and exFAT.
+pub(crate) fn without_hard_links(path: &Path) -> Option<String> {
+    let existing = path.ancestors().find(|path| path.exists())?;
+    filesystem(existing).ok().flatten()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn filesystem(path: &Path) -> io::Result<Option<String>> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    /// `MSDOS_SUPER_MAGIC` and `EXFAT_SUPER_MAGIC` of `linux/magic.h`.
+    const FAT: u64 = 0x4d44;
+    const EXFAT: u64 = 0x2011_bab0;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(match stat.f_type as u64 {
+        FAT => Some("FAT".to_string()),
+        EXFAT => Some("exFAT".to_string()),
+        _ => None,
+    })
+}
+
+#[cfg(windows)]
+fn filesystem(path: &Path) -> io::Result<Option<String>> {
+    let (_, name) = volume_information(path)?;
+    // FAT, FAT32 and exFAT.
+    Ok(Some(name).filter(|name| name.to_ascii_uppercase().contains("FAT")))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn filesystem(_path: &Path) -> io::Result<Option<String>> {
+    Ok(None)
+}
+
+/// `EXDEV`.
+#[cfg(unix)]
+const CROSS_DEVICE: i32 = libc::EXDEV;
+/// `EPERM`, which Linux gives for FAT, and `EOPNOTSUPP`.
+#[cfg(unix)]
+const UNSUPPORTED: &[i32] = &[libc::EPERM, libc::EOPNOTSUPP];
+/// `EROFS`.
+#[cfg(unix)]
+const READ_ONLY: i32 = libc::EROFS;
+
+/// `ERROR_NOT_SAME_DEVICE`.
+#[cfg(windows)]
+const CROSS_DEVICE: i32 = 17;
+/// `ERROR_INVALID_FUNCTION`, which FAT gives, and `ERROR_NOT_SUPPORTED`.
+#[cfg(windows)]
+const UNSUPPORTED: &[i32] = &[1, 50];
+/// `ERROR_WRITE_PROTECT`.
+#[cfg(windows)]
+const READ_ONLY: i32 = 19;
+
+#[cfg(not(any(unix, windows)))]
+const CROSS_DEVICE: i32 = -1;
+#[cfg(not(any(unix, windows)))]
+const UNSUPPORTED: &[i32] = &[];
+#[cfg(not(any(unix, windows)))]
+const READ_ONLY: i32 = -1;
+
+impl Overlay {
+    /// Whether the files of input `id` can be hard linked into the output, once the
+    /// overlay started and tried.
+    pub fn link_probe(&self, id: InputId) -> Option<&LinkProbe> {
+        self.inputs
+            .get(id.index())
+            .and_then(|input| input.probe.as_ref())
+    }
+
+    /// Tries hard linking from every input into the output. Inputs that can't be linked
+    /// from are copied from if the fallback is on, and fail the start otherwise, except
+    /// those on read-only media, which are always copied from.
+    pub(crate) fn probe_inputs(&mut self) -> Result<(), Error> {
+        let filesystem = self.output_filesystem();
+        for index in 0..self.inputs.len() {
+            let input = &mut self.inputs[index];
+            input.copies = false;
+            if input.is_archive() {
+                let reason = "it is an archive, whose files can only be extracted";
+                input.probe = Some(LinkProbe::Failed(reason.to_string()));
+                input.copies = true;
+                continue;
+            }
+            if input.strategy.is_some() {
+                let reason = "it has a link strategy of its own".to_string();
+                input.probe = Some(LinkProbe::Untested(reason));
+                continue;
+            }
+
+            let result = if read_only(&input.path).unwrap_or(false) {
+                LinkProbe::ReadOnly(format!("{} is mounted read-only", input.path.display()))
+            } else if let Some(filesystem) = &filesystem {
+                LinkProbe::Unsupported(format!(
+                    "{} is on {}, which has none",
+                    self.output.display(),
+                    filesystem
+                ))
+            } else if self.dry_run {
+                LinkProbe::Untested("this is a dry run".to_string())
+            } else {
+                probe(&*self.fs, &self.inputs[index].path, &self.output)
+            };
+
+            let input = &mut self.inputs[index];
+            if let LinkProbe::ReadOnly(_) = result {
+                // There is no linking from it whatever the fallback says, like from archives.
+                info!(
+                    "Copying the files of {} into {}: {}",
+                    input.path.display(),
+                    self.output.display(),
+                    result
+                );
+                input.copies = true;
+            } else if !result.can_link() {
+                if !self.copying.fallback {
+                    return Err(format_err!(
+                        "can't hard link from {} into {}: {}",
+                        input.path.display(),
+                        self.output.display(),
+                        result
+                    ));
+                }
+                let message = format!(
+                    "Copying the files of {} instead of linking them into {}: {}",
+                    input.path.display(),
+                    self.output.display(),
+                    result
+                );
+                // Nothing is wrong when the output's filesystem is known to have no links.
+                if filesystem.is_some() {
+                    info!("{}", message);
+                } else {
+                    warn!("{}", message);
+                }
+                input.copies = true;
+            }
+            input.probe = Some(result);
+        }
+        Ok(())
+    }
+
+    /// The name of the output's filesystem if it has no hard links, found out once for each
+    /// output.
+    pub(crate) fn output_filesystem(&mut self) -> Option<String> {
+        match &self.copying.filesystem {
+            Some((output, filesystem)) if *output == self.output => filesystem.clone(),
+            _ => {
+                let filesystem = without_hard_links(&self.output);
+                self.copying.filesystem = Some((self.output.clone(), filesystem.clone()));
+                filesystem
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+    use crate::{
+        canonical, error_kind, Event, EventType, FileOp, LinkKind, MemoryFs, OverlayBuilder,
+    };
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn an_output_that_hard_links_cannot_reach_is_refused_or_copied_into() {
+        let root = scratch("link-probe");
+        let (input, empty, output) = (root.join("input"), root.join("empty"), root.join("output"));
+        let memory = Arc::new(MemoryFs::new());
+        // The probe looks for a file on disk.
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&empty).unwrap();
+        fs::write(input.join("x"), "").unwrap();
+        memory.create_file(input.join("x"));
+        memory.create_dir(&empty);
+        let probe = output.join(format!(".overlay-probe-{}.part", process::id()));
+        memory.fail(FileOp::HardLink, &probe, io::ErrorKind::CrossesDevices);
+        let build = |fallback: bool| {
+            OverlayBuilder::new(&output)
+                .file_ops(memory.clone())
+                .single_instance(false)
+                .input(&input, 0)
+                .input(&empty, 1)
+                .copy_fallback(fallback)
+                .build()
+                .unwrap()
+        };
+
+        let error = build(false).sync_once().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "can't hard link from {} into {}: they are on different volumes \
+                 (HardLink failed)",
+                canonical(&input).display(),
+                output.display()
+            )
+        );
+        assert!(!memory.exists(&output.join("x")));
+
+        let mut overlay = build(true);
+        overlay.sync_once().unwrap();
+        assert_eq!(
+            overlay.link_probe(InputId::of(0)),
+            Some(&LinkProbe::CrossDevice("HardLink failed".to_string()))
+        );
+        let untested = format!("{} has no files", canonical(&empty).display());
+        assert_eq!(
+            overlay.link_probe(InputId::of(1)),
+            Some(&LinkProbe::Untested(untested))
+        );
+        // Copied rather than linked.
+        assert!(memory.exists(&output.join("x")));
+        assert!(!memory
+            .same_file(&input.join("x"), &output.join("x"))
+            .unwrap());
+        assert!(!memory.exists(&probe));
+    }
+
+    #[test]
+    fn an_output_on_a_filesystem_without_hard_links_is_copied_into_or_refused() {
+        for copy_fallback in [false, true] {
+            let root = scratch("output-without-links");
+            let (input, output) = (root.join("input"), root.join("output"));
+            let memory = Arc::new(MemoryFs::new());
+            // The sync finds the file on disk.
+            fs::create_dir_all(&input).unwrap();
+            fs::write(input.join("x"), "").unwrap();
+            memory.create_file(input.join("x"));
+            let mut overlay = OverlayBuilder::new(&output)
+                .file_ops(memory.clone())
+                .single_instance(false)
+                .input(&input, 0)
+                .copy_fallback(copy_fallback)
+                .build()
+                .unwrap();
+            // As an exFAT stick would be found to be, once for the output.
+            overlay.copying.filesystem = Some((output.clone(), Some("exFAT".to_string())));
+
+            let synced = overlay.sync_once();
+            let unsupported = format!("{} is on exFAT, which has none", output.display());
+            if !copy_fallback {
+                assert_eq!(
+                    synced.unwrap_err().to_string(),
+                    format!(
+                        "can't hard link from {} into {}: the output doesn't support hard links \
+                         ({})",
+                        input.display(),
+                        output.display(),
+                        unsupported
+                    )
+                );
+                continue;
+            }
+            synced.unwrap();
+            assert_eq!(
+                overlay.link_probe(InputId::of(0)),
+                Some(&LinkProbe::Unsupported(unsupported))
+            );
+            assert_eq!(overlay.links[Path::new("x")].kind, LinkKind::Copy);
+            assert!(!memory
+                .same_file(&input.join("x"), &output.join("x"))
+                .unwrap());
+
+            // Found out again for another output.
+            overlay.output = root.join("elsewhere");
+            assert_eq!(overlay.output_filesystem(), None);
+            assert_eq!(
+                overlay.copying.filesystem,
+                Some((root.join("elsewhere"), None))
+            );
+        }
+    }
+
+    #[test]
+    fn a_file_with_as_many_links_as_allowed_is_copied_and_kept_as_a_copy() {
+        let mut harness = Harness::new("too-many-links", &[0]);
+        let (source, copy) = (harness.inputs[0].join("x"), harness.output.join("x"));
+        harness
+            .fs
+            .fail(FileOp::HardLink, &copy, io::ErrorKind::TooManyLinks);
+        harness.create(0, "x");
+        harness.create(0, "y");
+
+        assert!(harness.fs.exists(&copy));
+        assert!(!harness.fs.same_file(&source, &copy).unwrap());
+        assert_eq!(harness.winner("y"), Some(0));
+        assert_eq!(harness.overlay.links[Path::new("x")].kind, LinkKind::Copy);
+        assert_eq!(harness.overlay.stats.over_link_limit, 1);
+        assert!(harness.overlay.failures.is_empty());
+        // Its copy is what the input provides, not a file to link again.
+        assert!(harness.overlay.provides(0, &source, &copy));
+        assert_eq!(
+            error_kind(&io::Error::from(io::ErrorKind::TooManyLinks)),
+            "too_many_links"
+        );
+    }
+
+    #[test]
+    fn an_input_on_read_only_media_is_copied_from_whatever_the_fallback_says() {
+        let root = scratch("read-only-input");
+        let (disc, output) = (root.join("disc"), root.join("output"));
+        let memory = Arc::new(MemoryFs::new());
+        // The probe looks for a file on disk.
+        fs::create_dir_all(&disc).unwrap();
+        fs::write(disc.join("x"), "").unwrap();
+        memory.create_file(disc.join("x"));
+        let probe = output.join(format!(".overlay-probe-{}.part", process::id()));
+        memory.fail(FileOp::HardLink, &probe, io::ErrorKind::ReadOnlyFilesystem);
+        let mut overlay = OverlayBuilder::new(&output)
+            .file_ops(memory.clone())
+            .single_instance(false)
+            .input(&disc, 0)
+            .copy_fallback(false)
+            .build()
+            .unwrap();
+
+        overlay.sync_once().unwrap();
+        assert!(matches!(
+            overlay.link_probe(InputId::of(0)),
+            Some(LinkProbe::ReadOnly(_))
+        ));
+        let copy = output.join("x");
+        assert!(memory.exists(&copy));
+        assert!(!memory.same_file(&disc.join("x"), &copy).unwrap());
+        assert_eq!(overlay.links[Path::new("x")].kind, LinkKind::Copy);
+        // Checked as a copy, not as the same file.
+        assert!(overlay.provides(0, &disc.join("x"), &copy));
+
+        memory.remove_file(&disc.join("x")).unwrap();
+        overlay.apply_event(EventType::new(0, Event::Remove(PathBuf::from("x"))));
+        assert!(!memory.exists(&copy));
+        assert!(overlay.links.is_empty());
+    }
+}
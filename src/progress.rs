@@ -0,0 +1,310 @@
+use crate::audit::{AuditLog, StreamWriter};
+use crate::fs_ops::FileOps;
+use crate::identity::FileIdentity;
+use crate::{space, Overlay};
+use log::info;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the progress of a copy is told at most.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How large a file has to be for how far copying it is told, unless the builder says
+/// otherwise.
+pub(crate) const DEFAULT_COPY_PROGRESS: u64 = 1 << 30;
+
+/// How the overlay copies the files it can't link into the output.
+#[derive(Debug)]
+pub(crate) struct Copying {
+    pub(crate) fallback: bool,
+    /// The output, and the name of its filesystem if it has no hard links, once known.
+    pub(crate) filesystem: Option<(PathBuf, Option<String>)>,
+    /// How large a file has to be for how far copying it is told, if any is.
+    pub(crate) progress: Option<u64>,
+    /// Set once the overlay is asked to stop, which gives up the copies done in between.
+    pub(crate) stopping: Arc<AtomicBool>,
+    /// Whether to sync anyway when the files to copy don't fit into the output.
+    pub(crate) ignore_free_space: bool,
+}
+
+impl Default for Copying {
+    fn default() -> Self {
+        Copying {
+            fallback: false,
+            filesystem: None,
+            progress: Some(DEFAULT_COPY_PROGRESS),
+            stopping: Arc::new(AtomicBool::new(false)),
+            ignore_free_space: false,
+        }
+    }
+}
+
+/// Where the progress of copying a large file of an input into the output is told, see
+/// `Overlay::set_copy_progress`.
+#[derive(Debug, Clone)]
+pub(crate) struct CopyProgress {
+    /// Only the copies of files at least this large are followed.
+    pub(crate) threshold: u64,
+    pub(crate) input: usize,
+    pub(crate) label: Option<String>,
+    pub(crate) stream: Option<StreamWriter>,
+    /// Set once the overlay is asked to stop, which gives up the copies being followed.
+    pub(crate) stopping: Arc<AtomicBool>,
+}
+
+/// How far the copy of a file into the output is, as the event stream has it.
+#[derive(Debug, Serialize)]
+struct ProgressRecord<'a> {
+    path: &'a Path,
+    input: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    copied: u64,
+    total: u64,
+}
+
+impl CopyProgress {
+    fn report(&self, path: &Path, copied: u64, total: u64) {
+        info!(
+            "Copying {}: {} of {}",
+            path.display(),
+            space::format(copied),
+            space::format(total)
+        );
+        if let Some(stream) = &self.stream {
+            let record = ProgressRecord {
+                path,
+                input: self.input,
+                label: self.label.as_deref(),
+                copied,
+                total,
+            };
+            // The overlay finds out about a stream that can't be written to itself.
+            let _ = stream.emit("copy_progress", &record);
+        }
+    }
+}
+
+/// The `FileOps` a `LinkStrategy` puts the file at `path` into the output with, which
+/// tells how far copying it is if it is large.
+#[derive(Debug)]
+pub(crate) struct Reporting<'a> {
+    inner: &'a dyn FileOps,
+    progress: &'a CopyProgress,
+    path: &'a Path,
+}
+
+impl<'a> Reporting<'a> {
+    pub(crate) fn new(inner: &'a dyn FileOps, progress: &'a CopyProgress, path: &'a Path) -> Self {
+        Reporting {
+            inner,
+            progress,
+            path,
+        }
+    }
+}
+
+impl FileOps for Reporting<'_> {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.inner.link_dir(target, link)
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.inner.unlink_dir(link)
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.copy_permissions(from, to)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_empty(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.copy_with_progress(from, to, &mut |_, _| true)
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        let large = self
+            .inner
+            .identity(from)
+            .is_ok_and(|identity| identity.len >= self.progress.threshold);
+        if !large {
+            return self.inner.copy_with_progress(from, to, progress);
+        }
+
+        let mut told = Instant::now();
+        self.inner
+            .copy_with_progress(from, to, &mut |copied, total| {
+                if self.progress.stopping.load(Ordering::Relaxed) {
+                    return false;
+                }
+                if copied == total || told.elapsed() >= INTERVAL {
+                    told = Instant::now();
+                    self.progress.report(self.path, copied, total);
+                }
+                progress(copied, total)
+            })
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        self.inner.identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.inner.write(path, contents)
+    }
+}
+
+impl Overlay {
+    /// Copies the files of inputs that can't be hard linked into the output, instead of
+    /// refusing to start.
+    pub fn set_copy_fallback(&mut self, fallback: bool) {
+        self.copying.fallback = fallback;
+    }
+
+    /// Tells how far copying a file into the output is, at most once a second, for files
+    /// of at least `threshold` bytes, 1 GB by default, or for none. It is logged, and
+    /// written to the event stream as `copy_progress`.
+    ///
+    /// Those copies are given up, and what was copied removed, once the overlay is asked
+    /// to stop.
+    pub fn set_copy_progress(&mut self, threshold: Option<u64>) {
+        self.copying.progress = threshold;
+    }
+
+    /// Warns and syncs anyway when the files that have to be copied into the output take
+    /// more space than its volume has free, instead of refusing to start.
+    pub fn set_ignore_free_space(&mut self, ignore: bool) {
+        self.copying.ignore_free_space = ignore;
+    }
+
+    /// Where copying a large file of input `index` is told about, if anywhere.
+    pub(crate) fn copy_progress(&self, index: usize) -> Option<CopyProgress> {
+        self.copying.progress.map(|threshold| CopyProgress {
+            threshold,
+            input: index,
+            label: self.inputs[index].label.clone(),
+            stream: self.stream.as_ref().and_then(AuditLog::stream_writer),
+            stopping: self.copying.stopping.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+    use crate::{Copies, Event, EventType, InputId, OverlayBuilder};
+    use std::fs;
+
+    #[test]
+    fn large_copies_tell_how_far_they_are_and_leave_nothing_once_cancelled() {
+        let root = scratch("copy-progress");
+        let (input, output) = (root.join("input"), root.join("output"));
+        fs::create_dir_all(&input).unwrap();
+        // More than one chunk, and not sparse.
+        fs::write(input.join("large"), vec![1; 20 << 20]).unwrap();
+        fs::write(input.join("small"), "small").unwrap();
+        let stream = root.join("events.jsonl");
+        let mut overlay = OverlayBuilder::new(&output)
+            .single_instance(false)
+            .input(&input, 0)
+            .copy_progress(Some(1 << 20))
+            .event_stream(fs::File::create(&stream).unwrap())
+            .build()
+            .unwrap();
+        overlay
+            .set_link_strategy(InputId::of(0), Arc::new(Copies))
+            .unwrap();
+        overlay.sync_once().unwrap();
+        assert_eq!(fs::read(output.join("large")).unwrap().len(), 20 << 20);
+        let progress: Vec<serde_json::Value> = fs::read_to_string(&stream)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["type"] == "copy_progress")
+            .collect();
+        // Only the large one, and at least once it is done.
+        assert!(progress.iter().all(|event| event["path"] == "large"));
+        let done = progress.last().unwrap();
+        assert_eq!(done["input"], 0);
+        assert_eq!(done["copied"], 20 << 20);
+        assert_eq!(done["total"], 20 << 20);
+
+        fs::write(input.join("cancelled"), vec![2; 20 << 20]).unwrap();
+        overlay.copying.stopping.store(true, Ordering::Relaxed);
+        overlay.apply_event(EventType::new(0, Event::Create(PathBuf::from("cancelled"))));
+        assert!(!output.join("cancelled").exists());
+        assert!(!overlay.links.contains_key(Path::new("cancelled")));
+        // Small copies are not followed, so aren't given up either.
+        fs::write(input.join("also small"), "small").unwrap();
+        overlay.apply_event(EventType::new(
+            0,
+            Event::Create(PathBuf::from("also small")),
+        ));
+        assert!(output.join("also small").exists());
+    }
+}
@@ -0,0 +1,498 @@
+use crate::{Event, EventType, InputId, MemoryFs, Observation, Overlay, ReplaySource};
+use crossbeam_channel::Receiver;
+use failure::{err_msg, format_err, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+/// How large a recording gets before it is rotated, by default.
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+/// An event as the overlay received it, one JSON object per line of a recording. See
+/// `OverlayBuilder::record_events`.
+///
+/// The paths are where the files go in the output, as the overlay sees them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// The order in which the watchers observed their events, across all inputs.
+    pub seq: u64,
+    pub observed: SystemTime,
+    pub input: InputId,
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub change: RecordedChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedChange {
+    Create {
+        path: PathBuf,
+    },
+    Remove {
+        path: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    PermissionsChanged {
+        path: PathBuf,
+    },
+    Error {
+        error: String,
+        path: Option<PathBuf>,
+    },
+    /// The source of the input stopped.
+    WatcherFailed {
+        error: String,
+    },
+    /// A poll of the input completed.
+    Polled {
+        at: SystemTime,
+    },
+}
+
+impl RecordedEvent {
+    fn of(event: &EventType, label: Option<&str>) -> Self {
+        let change = match &event.event {
+            Event::Create(path) => RecordedChange::Create { path: path.clone() },
+            Event::Remove(path) => RecordedChange::Remove { path: path.clone() },
+            Event::Rename(from, to) => RecordedChange::Rename {
+                from: from.clone(),
+                to: to.clone(),
+            },
+            Event::PermissionsChanged(path) => {
+                RecordedChange::PermissionsChanged { path: path.clone() }
+            }
+            Event::Error(e, path) => RecordedChange::Error {
+                error: e.to_string(),
+                path: path.clone(),
+            },
+            Event::WatcherFailed(e) => RecordedChange::WatcherFailed {
+                error: e.to_string(),
+            },
+            Event::Polled(at) => RecordedChange::Polled { at: *at },
+        };
+        RecordedEvent {
+            seq: event.observation.seq,
+            observed: event.observation.at,
+            input: InputId::of(event.index),
+            label: label.map(str::to_string),
+            change,
+        }
+    }
+
+    /// The event again, as observed when it was recorded.
+    pub(crate) fn event(&self) -> (Event, Observation) {
+        let event = match self.change.clone() {
+            RecordedChange::Create { path } => Event::Create(path),
+            RecordedChange::Remove { path } => Event::Remove(path),
+            RecordedChange::Rename { from, to } => Event::Rename(from, to),
+            RecordedChange::PermissionsChanged { path } => Event::PermissionsChanged(path),
+            RecordedChange::Error { error, path } => Event::Error(err_msg(error), path),
+            RecordedChange::WatcherFailed { error } => Event::WatcherFailed(err_msg(error)),
+            RecordedChange::Polled { at } => Event::Polled(at),
+        };
+        let observation = Observation {
+            seq: self.seq,
+            at: self.observed,
+        };
+        (event, observation)
+    }
+}
+
+/// Reads the events recorded in the file at `path`, in the order they were written.
+pub fn read_recording<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedEvent>, Error> {
+    let path = path.as_ref();
+    let mut events = vec![];
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|e| format_err!("{}, line {}: {}", path.display(), number + 1, e))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Appends every event the overlay receives to a file, which is moved aside to
+/// `<path>.1` once it has `max_size` bytes, replacing the one there.
+///
+/// Like the audit log, a recording that can't be written to is reported once and then
+/// disabled.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    path: PathBuf,
+    max_size: u64,
+    /// How much the file has.
+    size: u64,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub(crate) fn open(path: &Path, max_size: u64) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            max_size,
+            size: file.metadata()?.len(),
+            writer: Some(BufWriter::new(file)),
+        })
+    }
+
+    pub(crate) fn record(&mut self, event: &EventType, label: Option<&str>) {
+        if self.writer.is_none() {
+            return;
+        }
+        let result = serde_json::to_vec(&RecordedEvent::of(event, label))
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.write(&line)
+            });
+        if let Err(e) = result {
+            self.disable(e);
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<(), Error> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(line)?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = Some(BufWriter::new(file));
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Writes what is buffered, once per batch of events rather than for each of them.
+    pub(crate) fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                self.disable(e.into());
+            }
+        }
+    }
+
+    fn disable(&mut self, e: Error) {
+        warn!(
+            "The recording {} is no longer being written: {}",
+            self.path.display(),
+            e
+        );
+        self.writer = None;
+    }
+}
+
+/// How fast recorded events are handed to the overlay by `Overlay::replay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayPace {
+    /// Each as soon as the one before it was handled.
+    FullSpeed,
+    /// As far apart as they were observed.
+    Original,
+}
+
+/// What `Overlay::replay` went through.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    /// The events of inputs the overlay doesn't have, which were left out.
+    pub skipped: usize,
+}
+
+impl Overlay {
+    /// Syncs the output, then hands it `events`, as recorded with
+    /// `OverlayBuilder::record_events`, through a `ReplaySource` rather than watching the
+    /// inputs, and handles them until nothing waits anymore. Meant for an output that is
+    /// only there to see what the overlay does, which can be followed with
+    /// `trace_decisions`.
+    ///
+    /// With `simulated`, the `MemoryFs` the overlay was built with, each change is made to
+    /// it before its event is handled, so the files are there, or gone, as they were in the
+    /// input. Nothing else is in the inputs then, and they can be empty directories
+    /// anywhere.
+    pub fn replay(
+        &mut self,
+        events: Vec<RecordedEvent>,
+        pace: ReplayPace,
+        simulated: Option<&MemoryFs>,
+    ) -> Result<ReplayReport, Error> {
+        let _lock = self.lock()?;
+        let source = Arc::new(ReplaySource::new());
+        self.watch.source = Box::new(source.clone());
+        self.prepare()?;
+        let received = self.build_watchers()?;
+        if let Some(fs) = simulated {
+            for input in &self.inputs {
+                fs.create_dir(&input.path);
+            }
+        }
+        let report = self.sync();
+        self.report_sync(&report);
+
+        let mut replayed = ReplayReport::default();
+        let started = (Instant::now(), events.first().map(|event| event.observed));
+        for event in events {
+            let index = event.input.index();
+            if self.inputs.get(index).is_none_or(|input| input.removed) {
+                replayed.skipped += 1;
+                continue;
+            }
+            if let (ReplayPace::Original, (at, Some(first))) = (pace, started) {
+                let offset = event.observed.duration_since(first).unwrap_or_default();
+                if let Some(wait) = (at + offset).checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            if let Some(fs) = simulated {
+                self.simulate(fs, index, &event.change);
+            }
+            source.push_recorded(event);
+            replayed.replayed += 1;
+            self.replay_received(&received)?;
+        }
+
+        // What is held back for a while is handled when it would have been.
+        while let Some(due) = self.held_until() {
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            self.replay_received(&received)?;
+        }
+        self.collapse_grafts();
+        self.drain_retries();
+        self.save_ledger();
+        info!(
+            "Replayed {} recorded events, left out {} of inputs the overlay doesn't have",
+            replayed.replayed, replayed.skipped
+        );
+        Ok(replayed)
+    }
+
+    /// Handles the events handed over so far and whatever is due by now.
+    fn replay_received(&mut self, received: &Receiver<EventType>) -> Result<(), Error> {
+        let batch: Vec<EventType> = received.try_iter().collect();
+        if !batch.is_empty() {
+            self.process_batch(batch)?;
+        }
+        if self.next_restart().is_some_and(|due| due <= Instant::now()) {
+            self.restart_watchers()?;
+        }
+        self.process_gathered(false);
+        self.process_throttled(false);
+        self.process_settling();
+        self.process_retries();
+        Ok(())
+    }
+
+    /// When the first of the changes that are held back is due.
+    fn held_until(&self) -> Option<Instant> {
+        [
+            self.watch.gathering.next_due(),
+            self.watch
+                .throttle
+                .as_ref()
+                .and_then(|throttle| throttle.next_due()),
+            self.watch.settling.next_due(),
+            self.next_restart(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Makes `change` in input `index` as `fs` has it.
+    fn simulate(&self, fs: &MemoryFs, index: usize, change: &RecordedChange) {
+        use crate::FileOps;
+
+        let input = &self.inputs[index];
+        // Nothing, for paths the input doesn't have.
+        let source = |key: &Path| Some(input.source(key)).filter(|path| path != Path::new(""));
+        let remove = |path: &Path| {
+            if fs.is_dir(path) {
+                let _ = fs.remove_dir_all(path);
+            } else {
+                let _ = fs.remove_file(path);
+            }
+        };
+        match change {
+            RecordedChange::Create { path } => {
+                if let Some(path) = source(path).filter(|path| !fs.exists(path)) {
+                    fs.create_file(path);
+                }
+            }
+            RecordedChange::Remove { path } => {
+                if let Some(path) = source(path) {
+                    remove(&path);
+                }
+            }
+            RecordedChange::Rename { from, to } => {
+                let to = match source(to) {
+                    Some(to) => to,
+                    None => return,
+                };
+                remove(&to);
+                match source(from).filter(|from| fs.exists(from)) {
+                    Some(from) => {
+                        if let Some(parent) = to.parent() {
+                            fs.create_dir(parent);
+                        }
+                        let _ = fs.rename(&from, &to);
+                    }
+                    None => fs.create_file(&to),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+    use crate::{FileOps, OverlayBuilder};
+    use std::time::Duration;
+
+    /// What input `index` had happen to it, observed `after` the first event.
+    fn recorded(seq: u64, index: usize, after: u64, change: RecordedChange) -> RecordedEvent {
+        RecordedEvent {
+            seq,
+            observed: SystemTime::UNIX_EPOCH + Duration::from_millis(after),
+            input: InputId::of(index),
+            label: None,
+            change,
+        }
+    }
+
+    #[test]
+    fn events_are_read_back_as_they_were_received_across_a_rotation() {
+        let path = scratch("recording").join("events.jsonl");
+        let observation = |seq| Observation {
+            seq,
+            at: SystemTime::UNIX_EPOCH + Duration::from_secs(seq),
+        };
+        let events = vec![
+            EventType::following(0, Event::Create(PathBuf::from("a")), observation(0)),
+            EventType::following(
+                1,
+                Event::Rename(PathBuf::from("a"), PathBuf::from("b")),
+                observation(1),
+            ),
+            EventType::following(0, Event::Error(err_msg("gone"), None), observation(2)),
+            EventType::following(1, Event::Polled(SystemTime::UNIX_EPOCH), observation(3)),
+        ];
+        let expected: Vec<RecordedEvent> = events
+            .iter()
+            .map(|event| RecordedEvent::of(event, Some("mods").filter(|_| event.index == 1)))
+            .collect();
+        let line = serde_json::to_vec(&expected[0]).unwrap().len() as u64 + 1;
+        // Room for about two of them.
+        let mut recorder = Recorder::open(&path, line * 2 + line / 2).unwrap();
+        for event in &events {
+            recorder.record(event, Some("mods").filter(|_| event.index == 1));
+        }
+        recorder.flush();
+
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let mut read = read_recording(PathBuf::from(rotated)).unwrap();
+        assert!(!read.is_empty() && read.len() < events.len());
+        read.extend(read_recording(&path).unwrap());
+        assert_eq!(read, expected);
+        let (event, observed) = read[1].event();
+        assert!(
+            matches!(event, Event::Rename(from, to) if from == Path::new("a") && to == Path::new("b"))
+        );
+        assert_eq!(observed.seq, 1);
+        assert_eq!(read[1].label.as_deref(), Some("mods"));
+
+        fs::write(&path, "{\"seq\": 0}\n").unwrap();
+        let error = read_recording(&path).unwrap_err().to_string();
+        assert!(
+            error.starts_with(&format!("{}, line 1: ", path.display())),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn a_replay_in_memory_makes_the_recorded_changes_and_handles_their_events() {
+        let root = scratch("replay");
+        let (base, mods) = (root.join("base"), root.join("mods"));
+        for input in [&base, &mods] {
+            fs::create_dir_all(input).unwrap();
+        }
+        let memory = Arc::new(MemoryFs::new());
+        let output = root.join("output");
+        let mut overlay = OverlayBuilder::new(&output)
+            .file_ops(memory.clone())
+            .single_instance(false)
+            .input(&base, 0)
+            .input(&mods, 1)
+            .build()
+            .unwrap();
+        let path = PathBuf::from;
+        let events = vec![
+            recorded(0, 0, 0, RecordedChange::Create { path: path("a") }),
+            recorded(1, 1, 10, RecordedChange::Create { path: path("a") }),
+            recorded(2, 2, 20, RecordedChange::Create { path: path("c") }),
+            recorded(
+                3,
+                1,
+                30,
+                RecordedChange::Rename {
+                    from: path("a"),
+                    to: path("b"),
+                },
+            ),
+            recorded(4, 0, 40, RecordedChange::Remove { path: path("a") }),
+        ];
+
+        let started = Instant::now();
+        let report = overlay
+            .replay(events, ReplayPace::Original, Some(&memory))
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(
+            report,
+            ReplayReport {
+                replayed: 4,
+                skipped: 1
+            }
+        );
+        assert!(!memory.exists(&base.join("a")));
+        assert!(memory
+            .same_file(&mods.join("b"), &output.join("b"))
+            .unwrap());
+        assert!(!memory.exists(&output.join("a")));
+        assert!(!memory.exists(&output.join("c")));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::fs_ops::is_transient;
+use crate::identity::FileIdentity;
+use crate::stats::Skip;
+use crate::{Overlay, ProcessedAction};
+use log::warn;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before trying a parked action again the first time.
+const INITIAL: Duration = Duration::from_secs(1);
+
+/// The longest wait between two tries, for files that stay in use for a long time.
+const MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RetryAction {
+    /// Putting the input's file into the output.
+    Link,
+    /// Removing the input's file from the output.
+    Unlink,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Parked {
+    pub(crate) action: RetryAction,
+    pub(crate) input: usize,
+    /// Why it failed the last time.
+    pub(crate) error: String,
+    since: SystemTime,
+    failures: u32,
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    due: Instant,
+    /// Whether it is being tried right now, and hasn't failed again yet.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    retrying: bool,
+}
+
+/// The actions on files that something else keeps open for longer than the quick retries
+/// of a `RetryPolicy` wait, tried again twice as late for every failure, up to a minute.
+///
+/// Files too young to be linked wait in it as well, to be linked once they are old enough.
+///
+/// There is at most one for each path and input, the last one that failed.
+#[derive(Debug, Default)]
+pub(crate) struct RetryQueue {
+    parked: BTreeMap<(PathBuf, usize), Parked>,
+}
+
+impl RetryQueue {
+    pub(crate) fn park(
+        &mut self,
+        path: &Path,
+        action: RetryAction,
+        input: usize,
+        error: String,
+        now: Instant,
+    ) {
+        let key = (path.to_path_buf(), input);
+        let (since, failures) = match self.parked.get(&key) {
+            Some(parked) if parked.action == action => (parked.since, parked.failures + 1),
+            _ => (SystemTime::now(), 1),
+        };
+        let delay = INITIAL
+            .checked_mul(1 << (failures - 1).min(16))
+            .unwrap_or(MAX)
+            .min(MAX);
+        self.insert(key, action, error, since, failures, now + delay);
+    }
+
+    /// Parks linking the file of input `input` at `path` until `due`, when it is old
+    /// enough, rather than until it was given a while to stop being in use.
+    pub(crate) fn defer(&mut self, path: &Path, input: usize, error: String, due: Instant) {
+        let key = (path.to_path_buf(), input);
+        let since = match self.parked.get(&key) {
+            Some(parked) if parked.action == RetryAction::Link => parked.since,
+            _ => SystemTime::now(),
+        };
+        self.insert(key, RetryAction::Link, error, since, 0, due);
+    }
+
+    fn insert(
+        &mut self,
+        key: (PathBuf, usize),
+        action: RetryAction,
+        error: String,
+        since: SystemTime,
+        failures: u32,
+        due: Instant,
+    ) {
+        let input = key.1;
+        self.parked.insert(
+            key,
+            Parked {
+                action,
+                input,
+                error,
+                since,
+                failures,
+                due,
+                retrying: false,
+            },
+        );
+    }
+
+    /// Forgets what is parked for `path`, as something newer happened to it.
+    #[cfg(feature = "watch")]
+    pub(crate) fn supersede(&mut self, path: &Path) -> bool {
+        let before = self.parked.len();
+        self.parked.retain(|(parked, _), _| parked != path);
+        self.parked.len() != before
+    }
+
+    /// Whether anything is parked for `path`.
+    #[cfg(feature = "watch")]
+    pub(crate) fn parks(&self, path: &Path) -> bool {
+        self.parked
+            .range((path.to_path_buf(), 0)..=(path.to_path_buf(), usize::MAX))
+            .next()
+            .is_some()
+    }
+
+    /// Whether `action` of input `input` is what is parked for `path`.
+    pub(crate) fn holds(&self, path: &Path, action: RetryAction, input: usize) -> bool {
+        self.parked
+            .get(&(path.to_path_buf(), input))
+            .is_some_and(|parked| parked.action == action)
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.parked.values().map(|parked| parked.due).min()
+    }
+
+    /// The actions due at `now`, which are forgotten by `settle` unless they are parked
+    /// again in between.
+    #[cfg(feature = "watch")]
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<(PathBuf, Parked)> {
+        self.parked
+            .iter_mut()
+            .filter(|(_, parked)| parked.due <= now)
+            .map(|((path, _), parked)| {
+                parked.retrying = true;
+                (path.clone(), parked.clone())
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn settle(&mut self, path: &Path, input: usize) {
+        let key = (path.to_path_buf(), input);
+        if self.parked.get(&key).is_some_and(|parked| parked.retrying) {
+            self.parked.remove(&key);
+        }
+    }
+
+    /// Removes and returns everything that is parked, by path.
+    pub(crate) fn drain(&mut self) -> Vec<(PathBuf, Parked)> {
+        std::mem::take(&mut self.parked)
+            .into_iter()
+            .map(|((path, _), parked)| (path, parked))
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    /// When the action that is parked the longest first failed.
+    pub(crate) fn oldest(&self) -> Option<SystemTime> {
+        self.parked.values().map(|parked| parked.since).min()
+    }
+}
+
+impl Overlay {
+    /// Parks `action` on `path` to be tried again later if it failed with `e` because
+    /// something has the file open.
+    pub(crate) fn park(&mut self, path: &Path, action: RetryAction, index: usize, e: &io::Error) {
+        if self.dry_run || !is_transient(e) {
+            return;
+        }
+
+        say!(self.line, Warn, " IN USE, TRYING AGAIN LATER!");
+        self.processed(ProcessedAction::Deferred);
+        self.skip(index, Skip::Deferred);
+        self.retries
+            .park(path, action, index, e.to_string(), Instant::now());
+        self.stats
+            .set_retry_queue(self.retries.len(), self.retries.oldest());
+    }
+
+    /// Whether the file of input `index` at `path` was modified too recently to be linked,
+    /// in which case it is parked until it is old enough.
+    pub(crate) fn too_young(&mut self, index: usize, path: &Path) -> bool {
+        let min_age = match self.inputs[index].min_age {
+            Some(min_age) => min_age,
+            None => return false,
+        };
+        let modified = match self.fs.identity(&self.inputs[index].source(path)) {
+            Ok(FileIdentity {
+                modified: Some(modified),
+                ..
+            }) => UNIX_EPOCH + Duration::from_nanos(modified as u64),
+            // Whatever can't tell its age is old enough.
+            _ => return false,
+        };
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age >= min_age {
+            return false;
+        }
+
+        let error = format!(
+            "it was modified less than {} ago",
+            humantime::format_duration(min_age)
+        );
+        self.processed(ProcessedAction::Deferred);
+        self.skip(index, Skip::Deferred);
+        let due = Instant::now() + (min_age - age);
+        self.retries.defer(path, index, error, due);
+        self.stats
+            .set_retry_queue(self.retries.len(), self.retries.oldest());
+        true
+    }
+
+    /// Gives up on everything that is parked, with a warning for each, as there won't be
+    /// another try.
+    pub(crate) fn drain_retries(&mut self) {
+        for (path, parked) in self.retries.drain() {
+            let action = match parked.action {
+                RetryAction::Link => "linked",
+                RetryAction::Unlink => "removed",
+            };
+            warn!(
+                "{} from input {} was never {}: {}",
+                path.display(),
+                self.input_name(parked.input),
+                action,
+                parked.error
+            );
+        }
+        self.stats.set_retry_queue(0, None);
+    }
+}
+
+#[cfg(all(test, feature = "watch"))]
+mod tests {
+    use super::*;
+
+    fn due_in(queue: &RetryQueue, path: &str, now: Instant) -> Duration {
+        queue.parked[&(PathBuf::from(path), 0)].due - now
+    }
+
+    #[test]
+    fn each_failure_waits_twice_as_long_up_to_a_minute() {
+        let mut queue = RetryQueue::default();
+        let now = Instant::now();
+        let mut waits = vec![];
+        for _ in 0..8 {
+            queue.park(Path::new("save"), RetryAction::Link, 0, "busy".into(), now);
+            waits.push(due_in(&queue, "save", now).as_secs());
+        }
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+        // Another action starts over, a deferred link keeps when the link first failed.
+        queue.park(
+            Path::new("save"),
+            RetryAction::Unlink,
+            0,
+            "busy".into(),
+            now,
+        );
+        assert_eq!(due_in(&queue, "save", now), INITIAL);
+        queue.park(Path::new("save"), RetryAction::Link, 0, "busy".into(), now);
+        assert_eq!(due_in(&queue, "save", now), INITIAL);
+        let since = queue.oldest();
+        queue.defer(Path::new("save"), 0, "too young".into(), now + MAX);
+        assert_eq!(queue.oldest(), since);
+        assert_eq!(due_in(&queue, "save", now), MAX);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn only_what_is_being_tried_is_settled() {
+        let mut queue = RetryQueue::default();
+        let now = Instant::now();
+        for path in ["a", "b"] {
+            queue.park(Path::new(path), RetryAction::Unlink, 0, "busy".into(), now);
+        }
+        queue.defer(Path::new("c"), 1, "too young".into(), now + MAX);
+        assert_eq!(queue.next_due(), Some(now + INITIAL));
+        assert!(queue.due(now).is_empty());
+
+        let due: Vec<PathBuf> = queue
+            .due(now + INITIAL)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(due, [PathBuf::from("a"), PathBuf::from("b")]);
+        // Failed again while it was tried.
+        queue.park(Path::new("a"), RetryAction::Unlink, 0, "busy".into(), now);
+        queue.settle(Path::new("a"), 0);
+        queue.settle(Path::new("b"), 0);
+        queue.settle(Path::new("c"), 1);
+        assert!(queue.parks(Path::new("a")) && queue.parks(Path::new("c")));
+        assert!(!queue.parks(Path::new("b")));
+
+        assert!(queue.supersede(Path::new("c")));
+        assert!(!queue.supersede(Path::new("c")));
+        let drained: Vec<PathBuf> = queue.drain().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(drained, [PathBuf::from("a")]);
+        assert_eq!((queue.len(), queue.oldest()), (0, None));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::filter::Glob;
+use crate::{ConfigError, InputOptions};
+use failure::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// A rule renaming files of an input on their way into the output, see
+/// `InputOptions::rewrite`.
+///
+/// A `from` ending in `/` is a directory of the input whose files go beneath directory `to`
+/// instead, e.g. `Textures/` to `textures/`. Any other `from` is a pattern, like those of
+/// `InputOptions::include`, and every file it matches is renamed to the path `to`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub from: String,
+    pub to: String,
+}
+
+impl RewriteRule {
+    pub fn new<F: Into<String>, T: Into<String>>(from: F, to: T) -> Self {
+        RewriteRule {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Rule {
+    Prefix(PathBuf, PathBuf),
+    Rename(Glob, PathBuf),
+}
+
+/// Where the files of an input go in the output: renamed by the first of its rules that
+/// applies, put beneath the directory it is mounted at, then folded to lowercase if the
+/// output is, see `OutputCase`.
+///
+/// Renaming by pattern and folding can't be undone from the output's path alone, so what
+/// each such path was rewritten from is remembered, for as far back as it was rewritten.
+#[derive(Debug, Default)]
+pub(crate) struct KeyMap {
+    mount: PathBuf,
+    rules: Vec<Rule>,
+    lower: AtomicBool,
+    renamed: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl KeyMap {
+    pub(crate) fn set_lower(&self, lower: bool) {
+        self.lower.store(lower, Ordering::Relaxed);
+    }
+
+    fn lower(&self) -> bool {
+        self.lower.load(Ordering::Relaxed)
+    }
+
+    /// Where in the output everything of the input goes beneath.
+    pub(crate) fn mount(&self) -> PathBuf {
+        self.fold(self.mount.clone())
+    }
+
+    fn fold(&self, path: PathBuf) -> PathBuf {
+        if self.lower() {
+            crate::fold(&path)
+        } else {
+            path
+        }
+    }
+
+    /// Whether some of the input's files are in the output under another name.
+    pub(crate) fn rewrites(&self) -> bool {
+        !self.rules.is_empty() || self.lower()
+    }
+
+    /// Where the file or directory at `relative` in the input goes in the output.
+    pub(crate) fn key(&self, relative: &Path) -> PathBuf {
+        let (key, remember) = self.map(relative);
+        if remember {
+            self.renamed
+                .lock()
+                .unwrap()
+                .insert(key.clone(), relative.to_path_buf());
+        }
+        key
+    }
+
+    /// Where the output has `relative` before it is folded to lowercase, as it was named
+    /// in the input as far as the rules leave it.
+    pub(crate) fn unfolded(&self, relative: &Path) -> PathBuf {
+        self.mount.join(self.rewrite(relative).0)
+    }
+
+    /// Where `relative` goes, and whether that can only be undone by remembering it.
+    fn map(&self, relative: &Path) -> (PathBuf, bool) {
+        let (rewritten, renamed) = self.rewrite(relative);
+        let unfolded = self.mount.join(rewritten);
+        let key = self.fold(unfolded.clone());
+        let remember = renamed || key != unfolded;
+        (key, remember)
+    }
+
+    /// `relative` as the first rule that applies rewrites it, and whether that renamed it
+    /// by a pattern.
+    fn rewrite(&self, relative: &Path) -> (PathBuf, bool) {
+        for rule in &self.rules {
+            match rule {
+                Rule::Prefix(from, to) => {
+                    if let Ok(rest) = relative.strip_prefix(from) {
+                        return (to.join(rest).components().collect(), false);
+                    }
+                }
+                Rule::Rename(glob, to) if glob.matches(relative) => return (to.clone(), true),
+                Rule::Rename(..) => {}
+            }
+        }
+        (relative.to_path_buf(), false)
+    }
+
+    /// What in the input goes to `key` in the output, if anything could.
+    pub(crate) fn relative(&self, key: &Path) -> Option<PathBuf> {
+        if !self.rewrites() {
+            return key.strip_prefix(&self.mount).ok().map(Path::to_path_buf);
+        }
+        if let Some(relative) = self.renamed.lock().unwrap().get(key) {
+            return Some(relative.clone());
+        }
+
+        // Only what goes to `key` again can have come from there.
+        let rest = key.strip_prefix(self.mount()).ok()?;
+        let prefixed = self.rules.iter().filter_map(|rule| match rule {
+            Rule::Prefix(from, to) => rest.strip_prefix(to).ok().map(|rest| from.join(rest)),
+            Rule::Rename(..) => None,
+        });
+        prefixed
+            .map(|relative| relative.components().collect::<PathBuf>())
+            .chain(Some(rest.to_path_buf()))
+            .find(|relative| self.map(relative).0 == key)
+    }
+}
+
+/// The directory the input at `path` is taken from, and where its files go in the output,
+/// as `options` map them.
+pub(crate) fn mapping(path: &Path, options: &InputOptions) -> Result<(PathBuf, KeyMap), Error> {
+    let source = plain(path, options.source.as_deref())?;
+    let path = if source.as_os_str().is_empty() {
+        path.to_path_buf()
+    } else {
+        path.join(source)
+    };
+
+    let mut rules = vec![];
+    for rule in &options.rewrite {
+        let to = plain(&path, Some(Path::new(&rule.to)))?;
+        let rule = match rule.from.strip_suffix('/') {
+            Some(from) => Rule::Prefix(plain(&path, Some(Path::new(from)))?, to),
+            None if to.as_os_str().is_empty() => {
+                return Err(ConfigError::InvalidMapping {
+                    path,
+                    dir: PathBuf::from(&rule.to),
+                }
+                .into());
+            }
+            None => Rule::Rename(Glob::new(&rule.from)?, to),
+        };
+        rules.push(rule);
+    }
+
+    let keys = KeyMap {
+        mount: plain(&path, options.mount.as_deref())?,
+        rules,
+        lower: AtomicBool::default(),
+        renamed: Mutex::default(),
+    };
+    Ok((path, keys))
+}
+
+/// `dir` without the `.`s in it, as long as it is a path beneath where it is relative to.
+fn plain(path: &Path, dir: Option<&Path>) -> Result<PathBuf, ConfigError> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(PathBuf::new()),
+    };
+    if !dir
+        .components()
+        .all(|part| matches!(part, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(ConfigError::InvalidMapping {
+            path: path.to_path_buf(),
+            dir: dir.to_path_buf(),
+        });
+    }
+    Ok(dir
+        .components()
+        .filter(|part| matches!(part, Component::Normal(_)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_go_where_the_first_rule_that_applies_puts_them() {
+        let none = InputOptions::new();
+        let mounted = InputOptions::new().mount("Data");
+        let prefixed = InputOptions::new().rewrite("Textures/", "textures");
+        let dotted = InputOptions::new().rewrite("./Textures/", "./t");
+        let renamed = InputOptions::new().rewrite("*.txt", "docs/readme.txt");
+        let ordered = InputOptions::new().rewrite("a/", "b").rewrite("*", "c");
+        let combined = InputOptions::new()
+            .mount("Data")
+            .rewrite("Tex/", "Textures");
+        let cases = [
+            (&none, false, "a/b", "a/b"),
+            (&none, true, "Data/Tex.DDS", "data/tex.dds"),
+            (&mounted, false, "a", "Data/a"),
+            (&prefixed, false, "Textures/x.dds", "textures/x.dds"),
+            (&prefixed, false, "Meshes/y.nif", "Meshes/y.nif"),
+            (&dotted, false, "Textures/x.dds", "t/x.dds"),
+            (&renamed, false, "notes/a.txt", "docs/readme.txt"),
+            (&renamed, false, "notes/a.md", "notes/a.md"),
+            (&ordered, false, "a/x", "b/x"),
+            (&ordered, false, "y", "c"),
+            (&combined, true, "Tex/A.dds", "data/textures/a.dds"),
+        ];
+
+        for (options, lower, relative, key) in cases {
+            let (_, keys) = mapping(Path::new("/in"), options).unwrap();
+            keys.set_lower(lower);
+            assert_eq!(
+                keys.key(Path::new(relative)),
+                Path::new(key),
+                "{}",
+                relative
+            );
+            // And back.
+            assert_eq!(
+                keys.relative(Path::new(key)).as_deref(),
+                Some(Path::new(relative)),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn only_plain_relative_directories_map() {
+        let cases = [
+            (InputOptions::new().mount("../up"), "../up"),
+            (InputOptions::new().mount("/abs"), "/abs"),
+            (InputOptions::new().source("a/../.."), "a/../.."),
+            (InputOptions::new().rewrite("a/", "../b"), "../b"),
+            (InputOptions::new().rewrite("../a/", "b"), "../a"),
+            // Every file can't be renamed to nothing.
+            (InputOptions::new().rewrite("*.txt", ""), ""),
+        ];
+        for (options, dir) in cases {
+            let e = mapping(Path::new("/in"), &options).err().unwrap();
+            assert_eq!(
+                e.downcast_ref::<ConfigError>(),
+                Some(&ConfigError::InvalidMapping {
+                    path: PathBuf::from("/in"),
+                    dir: PathBuf::from(dir),
+                }),
+                "{}",
+                dir
+            );
+        }
+    }
+
+    #[test]
+    fn what_nothing_maps_to_comes_from_nowhere() {
+        let options = InputOptions::new().mount("Data");
+        let (_, keys) = mapping(Path::new("/in"), &options).unwrap();
+        assert_eq!(keys.relative(Path::new("Other/x")), None);
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The size and the time it was modified of a file, which stop changing once whatever
+/// writes it is done.
+pub(crate) type FileState = (u64, Option<u128>);
+
+/// Holds back changes to the files of inputs that have to settle first, until they stayed
+/// the same for a while. See `InputOptions::settle`.
+#[derive(Debug, Default)]
+pub(crate) struct Settling {
+    held: HashMap<(usize, PathBuf), (FileState, Instant)>,
+}
+
+impl Settling {
+    /// Whether the file of input `index` at `path`, now in `state`, is the same as when it
+    /// was held and has been for as long as it was held. If not, it is held for `wait` and
+    /// `false` returned, unless there is nothing to wait for.
+    pub(crate) fn settled(
+        &mut self,
+        index: usize,
+        path: &Path,
+        state: FileState,
+        wait: Duration,
+        now: Instant,
+    ) -> bool {
+        let key = (index, path.to_path_buf());
+        let unchanged = matches!(
+            self.held.get(&key),
+            Some((held, due)) if *held == state && *due <= now
+        );
+
+        if unchanged || wait.is_zero() {
+            self.held.remove(&key);
+            return true;
+        }
+        self.held.insert(key, (state, now + wait));
+        false
+    }
+
+    /// Forgets the file of input `index` at `path`, which is gone or went elsewhere.
+    pub(crate) fn forget(&mut self, index: usize, path: &Path) {
+        self.held.remove(&(index, path.to_path_buf()));
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.held.values().map(|(_, due)| *due).min()
+    }
+
+    /// The files that may have settled by `now`. They stay held until `settled` says so.
+    pub(crate) fn due(&self, now: Instant) -> Vec<(usize, PathBuf)> {
+        self.held
+            .iter()
+            .filter(|(_, (_, due))| *due <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,225 @@
+use crate::input::Rank;
+use crate::{InputId, Overlay};
+use failure::{format_err, Error};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What an overlay looks like at one moment: how its inputs are ordered and switched, and
+/// which of them won each path. See `Overlay::snapshot` and `Overlay::restore`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlaySnapshot {
+    pub inputs: Vec<InputSnapshot>,
+    /// Every path in the output and the index, in `inputs`, of the input it came from.
+    pub winners: BTreeMap<PathBuf, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputSnapshot {
+    pub path: PathBuf,
+    pub label: Option<String>,
+    pub enabled: bool,
+    pub group: u32,
+    pub priority: u32,
+}
+
+/// What restoring a snapshot changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RestoreReport {
+    pub enabled: Vec<InputId>,
+    pub disabled: Vec<InputId>,
+    pub reranked: Vec<InputId>,
+    /// The paths that ended up with a different input than before.
+    pub relinked: Vec<PathBuf>,
+    /// The paths whose input still isn't the one in the snapshot, because the files in the
+    /// inputs changed since it was taken.
+    pub differing: Vec<PathBuf>,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Restored snapshot: {} enabled, {} disabled, {} reordered, {} relinked, {} differing",
+            self.enabled.len(),
+            self.disabled.len(),
+            self.reranked.len(),
+            self.relinked.len(),
+            self.differing.len()
+        )
+    }
+}
+
+impl Overlay {
+    /// Captures which inputs are on, how they are ordered and which of them won each path.
+    pub fn snapshot(&self) -> OverlaySnapshot {
+        OverlaySnapshot {
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| InputSnapshot {
+                    path: input.path.clone(),
+                    label: input.label.clone(),
+                    enabled: input.enabled,
+                    group: input.rank.group,
+                    priority: input.rank.priority,
+                })
+                .collect(),
+            winners: self
+                .list()
+                .map(|entry| (entry.path, entry.input.index()))
+                .collect(),
+        }
+    }
+
+    /// The input saved in a snapshot or state with `label` and `path`, matched by label,
+    /// or by path if it had none.
+    pub(crate) fn saved_input(&self, label: Option<&str>, path: &Path) -> Option<usize> {
+        match label {
+            Some(label) => self.input_by_label(label).map(InputId::index),
+            None => self.inputs.iter().position(|input| input.path == path),
+        }
+    }
+
+    /// Switches and orders the inputs as they were in `snapshot`, relinking the paths whose
+    /// winner changes.
+    ///
+    /// Inputs are matched by label, or by path if they have none, so a snapshot saved in
+    /// an earlier session applies as long as the same inputs are there.
+    pub fn restore(&mut self, snapshot: &OverlaySnapshot) -> Result<RestoreReport, Error> {
+        let mut indices = Vec::with_capacity(snapshot.inputs.len());
+        for saved in &snapshot.inputs {
+            let index = self.saved_input(saved.label.as_deref(), &saved.path);
+            let index = index.ok_or_else(|| {
+                format_err!(
+                    "the snapshot has input {}, which isn't there",
+                    saved
+                        .label
+                        .as_deref()
+                        .map_or_else(|| saved.path.display().to_string(), str::to_string)
+                )
+            })?;
+            indices.push(index);
+        }
+
+        let before: BTreeMap<PathBuf, usize> = self
+            .list()
+            .map(|entry| (entry.path, entry.input.index()))
+            .collect();
+        let mut report = RestoreReport::default();
+        let saved = snapshot.inputs.iter().zip(indices.iter().copied());
+
+        // Off first and on last, so nothing is linked from an input only to be replaced.
+        for (saved, index) in saved.clone() {
+            if !saved.enabled && self.inputs[index].enabled {
+                self.set_enabled(InputId::of(index), false)?;
+                report.disabled.push(InputId::of(index));
+            }
+        }
+        let changes: Vec<(usize, Rank)> = saved
+            .clone()
+            .map(|(saved, index)| {
+                let rank = Rank {
+                    group: saved.group,
+                    priority: saved.priority,
+                };
+                (index, rank)
+            })
+            .filter(|(index, rank)| self.inputs[*index].rank != *rank)
+            .collect();
+        report.reranked = changes
+            .iter()
+            .map(|(index, _)| InputId::of(*index))
+            .collect();
+        self.rerank(changes);
+        for (saved, index) in saved {
+            if saved.enabled && !self.inputs[index].enabled {
+                self.set_enabled(InputId::of(index), true)?;
+                report.enabled.push(InputId::of(index));
+            }
+        }
+
+        let after: BTreeMap<PathBuf, usize> = self
+            .list()
+            .map(|entry| (entry.path, entry.input.index()))
+            .collect();
+        let paths: BTreeSet<&PathBuf> = before.keys().chain(after.keys()).collect();
+        report.relinked = paths
+            .into_iter()
+            .filter(|path| before.get(*path) != after.get(*path))
+            .cloned()
+            .collect();
+        let paths: BTreeSet<&PathBuf> = snapshot.winners.keys().chain(after.keys()).collect();
+        report.differing = paths
+            .into_iter()
+            .filter(|path| {
+                snapshot.winners.get(*path).map(|saved| indices[*saved])
+                    != after.get(*path).copied()
+            })
+            .cloned()
+            .collect();
+
+        info!("{}", report);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use std::fs;
+
+    #[test]
+    fn a_restored_snapshot_relinks_only_what_it_changes_back() {
+        let mut harness = Harness::new("snapshot", &[0, 1, 2]);
+        // Enabling an input again walks it on disk.
+        let write = |harness: &mut Harness, index: usize, path: &str| {
+            fs::write(harness.inputs[index].join(path), path).unwrap();
+            harness.create(index, path);
+        };
+        for (index, path) in [(0, "a"), (1, "a"), (2, "a"), (0, "b"), (1, "b"), (0, "c")] {
+            write(&mut harness, index, path);
+        }
+        let json = serde_json::to_string(&harness.overlay.snapshot()).unwrap();
+        let snapshot: OverlaySnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, harness.overlay.snapshot());
+        let winners: Vec<(&str, usize)> = snapshot
+            .winners
+            .iter()
+            .map(|(path, input)| (path.to_str().unwrap(), *input))
+            .collect();
+        assert_eq!(winners, [("a", 2), ("b", 1), ("c", 0)]);
+
+        harness.overlay.set_enabled(InputId::of(2), false).unwrap();
+        harness.overlay.set_priority(InputId::of(0), 5).unwrap();
+        write(&mut harness, 1, "new");
+        assert_eq!(harness.winner("a"), Some(0));
+        assert_eq!(harness.winner("b"), Some(0));
+
+        let report = harness.overlay.restore(&snapshot).unwrap();
+        assert_eq!(
+            report,
+            RestoreReport {
+                enabled: vec![InputId::of(2)],
+                disabled: vec![],
+                reranked: vec![InputId::of(0)],
+                relinked: vec![PathBuf::from("a"), PathBuf::from("b")],
+                differing: vec![PathBuf::from("new")],
+            }
+        );
+        assert_eq!(harness.winner("a"), Some(2));
+        assert_eq!(harness.winner("b"), Some(1));
+        assert_eq!(harness.winner("c"), Some(0));
+
+        let mut elsewhere = snapshot;
+        elsewhere.inputs[1].path = PathBuf::from("/gone");
+        let error = harness.overlay.restore(&elsewhere).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the snapshot has input /gone, which isn't there"
+        );
+    }
+}
@@ -0,0 +1,502 @@
+use crate::poll;
+use crate::rewrite::KeyMap;
+use crate::{Event, EventType, InputId, RecordedEvent};
+use crossbeam_channel::Sender;
+use failure::Error;
+use log::{debug, error};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Where the overlay gets the changes to its inputs from.
+///
+/// `NotifySource` is used unless the builder is given something else, `ReplaySource`
+/// delivers a scripted sequence of events instead.
+pub trait EventSource: Debug + Send + Sync {
+    /// Starts delivering the changes beneath input `id`, rooted at `path`, to `sink`.
+    fn watch(&self, id: InputId, path: &Path, sink: EventSink) -> Result<(), Error>;
+
+    /// How long a file was left alone at least when a change to it is delivered, for an
+    /// input with `poll_interval` of its own, if it has one. Nothing is promised by default.
+    fn quiet_for(&self, _poll_interval: Option<Duration>) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl<T: EventSource + ?Sized> EventSource for Arc<T> {
+    fn watch(&self, id: InputId, path: &Path, sink: EventSink) -> Result<(), Error> {
+        (**self).watch(id, path, sink)
+    }
+
+    fn quiet_for(&self, poll_interval: Option<Duration>) -> Duration {
+        (**self).quiet_for(poll_interval)
+    }
+}
+
+/// The receiving end of an overlay for the events of one input.
+#[derive(Debug, Clone)]
+pub struct EventSink {
+    index: usize,
+    root: PathBuf,
+    /// Where the input's files go in the output.
+    keys: Arc<KeyMap>,
+    poll_interval: Option<Duration>,
+    transmitter: Sender<EventType>,
+}
+
+impl EventSink {
+    pub(crate) fn new(
+        index: usize,
+        root: &Path,
+        keys: Arc<KeyMap>,
+        poll_interval: Option<Duration>,
+        transmitter: Sender<EventType>,
+    ) -> Self {
+        EventSink {
+            index,
+            root: root.to_path_buf(),
+            keys,
+            poll_interval,
+            transmitter,
+        }
+    }
+
+    /// How often the input is to be polled, if it has an interval of its own, see
+    /// `InputOptions::poll_interval`.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    /// Hands `event`, with paths inside the input, to the overlay, which sees them where
+    /// they go in the output.
+    ///
+    /// Returns `false` once the overlay has stopped listening.
+    pub fn send(&self, event: DebouncedEvent) -> bool {
+        debug!("Watcher {} reported {:?}", self.index, event);
+        let relative = |path: &Path| {
+            path.strip_prefix(&self.root)
+                .map(|path| self.keys.key(path))
+        };
+
+        let event = match event {
+            DebouncedEvent::Create(path) => relative(&path).map(Event::Create),
+            DebouncedEvent::Remove(path) => relative(&path).map(Event::Remove),
+            DebouncedEvent::Chmod(path) => relative(&path).map(Event::PermissionsChanged),
+            DebouncedEvent::Rename(from, to) => {
+                relative(&from).and_then(|from| relative(&to).map(|to| Event::Rename(from, to)))
+            }
+            // A file written to is looked at again, as if it was just created; merged and
+            // copied files have to follow it.
+            DebouncedEvent::Write(path) => relative(&path).map(Event::Create),
+            DebouncedEvent::Error(e, path) => Ok(Event::Error(e.into(), path)),
+            _ => return true,
+        };
+
+        let event = event.unwrap_or_else(|e| Event::Error(e.into(), None));
+        self.transmitter
+            .send(EventType::new(self.index, event))
+            .is_ok()
+    }
+
+    /// Hands `event`, with the paths and the observation it was recorded with, to the
+    /// overlay as it is.
+    pub(crate) fn deliver(&self, event: &RecordedEvent) -> bool {
+        let (event, observation) = event.event();
+        self.transmitter
+            .send(EventType::following(self.index, event, observation))
+            .is_ok()
+    }
+
+    /// Tells the overlay a poll of the input completed, and everything that changed before
+    /// it was sent.
+    ///
+    /// Returns `false` once the overlay has stopped listening.
+    pub fn polled(&self) -> bool {
+        self.transmitter
+            .send(EventType::new(self.index, Event::Polled(SystemTime::now())))
+            .is_ok()
+    }
+
+    /// Tells the overlay the source of this input failed and no more events will follow.
+    pub fn fail(&self, error: Error) {
+        let _ = self
+            .transmitter
+            .send(EventType::new(self.index, Event::WatcherFailed(error)));
+    }
+}
+
+/// Watches inputs with `notify`, debouncing changes for `delay`.
+///
+/// Inputs are polled instead, every `poll` or every interval of their own if they have
+/// one, which works wherever the files can be read. Their changes aren't debounced, the
+/// interval does much the same.
+#[derive(Debug, Clone, Copy)]
+pub struct NotifySource {
+    pub delay: Duration,
+    pub poll: Option<Duration>,
+}
+
+impl Default for NotifySource {
+    fn default() -> Self {
+        NotifySource {
+            delay: Duration::from_secs(1),
+            poll: None,
+        }
+    }
+}
+
+impl EventSource for NotifySource {
+    fn watch(&self, id: InputId, path: &Path, sink: EventSink) -> Result<(), Error> {
+        if let Some(interval) = sink.poll_interval().or(self.poll) {
+            return Ok(poll::watch(path, interval, sink)?);
+        }
+
+        let (tx, rx): (mpsc::Sender<DebouncedEvent>, mpsc::Receiver<DebouncedEvent>) =
+            mpsc::channel();
+
+        let mut watcher: RecommendedWatcher = watcher(tx, self.delay)?;
+        watcher.watch(path, RecursiveMode::Recursive)?;
+
+        thread::spawn(move || {
+            // Dropping the watcher would stop it.
+            let _watcher = watcher;
+
+            loop {
+                match rx.recv() {
+                    Ok(event) => {
+                        if !sink.send(event) {
+                            // The overlay has stopped listening.
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        sink.fail(e.into());
+
+                        error!("Unrecoverable error on watcher {}: {:?}", id, e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// The delay, as changes are debounced for it. Polls see changes as they are.
+    fn quiet_for(&self, poll_interval: Option<Duration>) -> Duration {
+        match poll_interval.or(self.poll) {
+            Some(_) => Duration::ZERO,
+            None => self.delay,
+        }
+    }
+}
+
+/// An event of the script of a `ReplaySource`.
+#[derive(Debug)]
+enum Scripted {
+    Notify(DebouncedEvent),
+    Recorded(RecordedEvent),
+}
+
+#[derive(Debug, Default)]
+struct Replay {
+    sinks: HashMap<InputId, EventSink>,
+    failed: HashSet<InputId>,
+    script: VecDeque<(InputId, Scripted)>,
+}
+
+impl Replay {
+    /// Delivers scripted events in order for as long as their input is being watched.
+    fn flush(&mut self) {
+        while let Some((id, _)) = self.script.front() {
+            if !self.sinks.contains_key(id) && !self.failed.contains(id) {
+                break;
+            }
+
+            let (id, event) = self.script.pop_front().unwrap();
+            match (self.sinks.get(&id), event) {
+                (Some(sink), Scripted::Notify(event)) => {
+                    sink.send(event);
+                }
+                (Some(sink), Scripted::Recorded(event)) => {
+                    sink.deliver(&event);
+                }
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+/// Delivers a programmed list of events, exactly in the order they were pushed.
+///
+/// An event waits until its input is watched, and holds back the ones pushed after it.
+/// Events for an input that failed are dropped until it is watched again.
+/// Share it with an overlay through an `Arc` to push more events while it runs.
+#[derive(Debug, Default)]
+pub struct ReplaySource {
+    replay: Mutex<Replay>,
+}
+
+impl ReplaySource {
+    pub fn new() -> Self {
+        ReplaySource::default()
+    }
+
+    /// Queues `event` for input `id`, with absolute paths as `notify` reports them.
+    pub fn push(&self, id: InputId, event: DebouncedEvent) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.script.push_back((id, Scripted::Notify(event)));
+        replay.flush();
+    }
+
+    /// Queues `event` for the input it was recorded for, see `OverlayBuilder::record_events`.
+    /// It is handed over just as it was observed, with the paths in the output.
+    pub fn push_recorded(&self, event: RecordedEvent) {
+        let mut replay = self.replay.lock().unwrap();
+        replay
+            .script
+            .push_back((event.input, Scripted::Recorded(event)));
+        replay.flush();
+    }
+
+    /// Ends the events of input `id` as if its watcher had died with `error`.
+    pub fn fail(&self, id: InputId, error: Error) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.flush();
+        if let Some(sink) = replay.sinks.remove(&id) {
+            sink.fail(error);
+        }
+        replay.failed.insert(id);
+        replay.flush();
+    }
+
+    /// How many pushed events have not been delivered yet.
+    pub fn pending(&self) -> usize {
+        self.replay.lock().unwrap().script.len()
+    }
+}
+
+impl EventSource for ReplaySource {
+    fn watch(&self, id: InputId, _path: &Path, sink: EventSink) -> Result<(), Error> {
+        let mut replay = self.replay.lock().unwrap();
+        replay.sinks.insert(id, sink);
+        replay.failed.remove(&id);
+        replay.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch, Harness};
+    use crate::{FileOps, InputOptions, OutputCase, OverlayBuilder};
+    use crossbeam_channel::Receiver;
+    use std::fs;
+    use std::time::Instant;
+
+    /// Hands what the source delivered so far to the overlay.
+    fn handle(harness: &mut Harness, received: &Receiver<EventType>) {
+        let batch: Vec<EventType> = received.try_iter().collect();
+        harness.overlay.process_batch(batch).unwrap();
+        harness.overlay.finish_links();
+    }
+
+    #[test]
+    fn a_scripted_sequence_is_handled_in_the_order_it_was_pushed() {
+        let source = Arc::new(ReplaySource::new());
+        let mut harness = Harness::with("replayed", &[0, 1], |builder| {
+            builder
+                .event_source(source.clone())
+                .cross_input_window(Duration::ZERO)
+        });
+        let (base, mods) = (InputId::of(0), InputId::of(1));
+        let file = |harness: &Harness, index: usize, path: &str| harness.inputs[index].join(path);
+
+        // What is pushed before the inputs are watched waits for them to be.
+        for (id, index, path) in [(base, 0, "a"), (mods, 1, "a"), (base, 0, "b")] {
+            let file = file(&harness, index, path);
+            harness.fs.create_file(&file);
+            source.push(id, DebouncedEvent::Create(file));
+        }
+        assert_eq!(source.pending(), 3);
+        let received = harness.overlay.build_watchers().unwrap();
+        assert_eq!(source.pending(), 0);
+        handle(&mut harness, &received);
+        assert_eq!(harness.winner("a"), Some(1));
+        assert_eq!(harness.winner("b"), Some(0));
+
+        let (a, b, c) = (
+            file(&harness, 1, "a"),
+            file(&harness, 0, "b"),
+            file(&harness, 0, "c"),
+        );
+        harness.fs.remove_file(&a).unwrap();
+        source.push(mods, DebouncedEvent::Remove(a));
+        harness.fs.rename(&b, &c).unwrap();
+        source.push(base, DebouncedEvent::Rename(b, c));
+        handle(&mut harness, &received);
+        assert!(harness.overlay.failures.is_empty());
+        assert_eq!(harness.winner("a"), Some(0));
+        assert!(!harness.in_output("b"));
+        assert_eq!(harness.winner("c"), Some(0));
+    }
+
+    #[test]
+    fn each_input_is_polled_at_its_own_interval() {
+        let root = scratch("poll-intervals");
+        let (local, share) = (root.join("local"), root.join("share"));
+        for input in [&local, &share] {
+            fs::create_dir_all(input).unwrap();
+        }
+        let source = NotifySource {
+            poll: Some(Duration::from_secs(3600)),
+            ..NotifySource::default()
+        };
+        let mut overlay = OverlayBuilder::new(root.join("output"))
+            .input_with_options(
+                &local,
+                0,
+                InputOptions::new().poll_interval(Duration::from_millis(10)),
+            )
+            .input(&share, 1)
+            .event_source(source)
+            .cross_input_window(Duration::ZERO)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        let received = overlay.build_watchers().unwrap();
+
+        fs::write(local.join("new"), "new").unwrap();
+        fs::write(share.join("unseen"), "unseen").unwrap();
+        let mut events = vec![];
+        let until = Instant::now() + Duration::from_secs(10);
+        while !events
+            .iter()
+            .any(|event: &EventType| matches!(&event.event, Event::Create(path) if path.ends_with("new")))
+        {
+            assert!(Instant::now() < until, "polling took too long");
+            events.extend(received.recv_timeout(Duration::from_secs(1)));
+        }
+        // Another poll of local is said to be done after the one that found the file.
+        while !matches!(events.last().unwrap().event, Event::Polled(_)) {
+            events.extend(received.recv_timeout(Duration::from_secs(10)));
+        }
+        let polls = |index: usize| {
+            events
+                .iter()
+                .filter(|event| event.index == index && matches!(event.event, Event::Polled(_)))
+                .count()
+        };
+        // The share only said it is being polled, once.
+        assert_eq!(polls(1), 1);
+        assert!(polls(0) >= 2);
+        assert!(!events
+            .iter()
+            .any(|event| event.index == 1 && !matches!(event.event, Event::Polled(_))));
+
+        overlay.process_batch(events).unwrap();
+        overlay.finish_links();
+        let stats = overlay.stats();
+        let (local_poll, share_poll) = (stats.inputs[0].last_poll, stats.inputs[1].last_poll);
+        assert!(local_poll.unwrap() > share_poll.unwrap());
+        assert!(overlay.resolve("new").is_some());
+        assert!(overlay.resolve("unseen").is_none());
+    }
+
+    #[test]
+    fn mapped_inputs_are_walked_and_watched_where_they_go() {
+        let root = scratch("mapped");
+        let (game, dlc, plain) = (root.join("game"), root.join("dlc"), root.join("plain"));
+        for (input, path) in [
+            (&game, "Data Files/x.esp"),
+            (&game, "readme.txt"),
+            (&dlc, "y.esp"),
+            (&plain, "x.esp"),
+            (&plain, "dlc/y.esp"),
+        ] {
+            let file = input.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, input.to_str().unwrap()).unwrap();
+        }
+        let source = Arc::new(ReplaySource::new());
+        let mut overlay = OverlayBuilder::new(root.join("output"))
+            .input(&plain, 0)
+            .event_source(source.clone())
+            .cross_input_window(Duration::ZERO)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        let game_id = overlay
+            .add_input_mapped(&game, 1, "Data Files", "")
+            .unwrap();
+        let dlc_id = overlay.add_input_mapped(&dlc, 2, "", "dlc").unwrap();
+        overlay.sync_once().unwrap();
+        assert_eq!(overlay.resolve("x.esp").unwrap().input, game_id);
+        assert_eq!(overlay.resolve("dlc/y.esp").unwrap().input, dlc_id);
+        assert!(overlay.resolve("readme.txt").is_none());
+        assert_eq!(overlay.providers("x.esp").len(), 2);
+
+        let received = overlay.build_watchers().unwrap();
+        let (inside, outside) = (game.join("Data Files/z.esp"), game.join("notes.txt"));
+        for file in [&inside, &outside] {
+            fs::write(file, "new").unwrap();
+            source.push(game_id, DebouncedEvent::Create(file.clone()));
+        }
+        fs::remove_file(dlc.join("y.esp")).unwrap();
+        source.push(dlc_id, DebouncedEvent::Remove(dlc.join("y.esp")));
+        let batch: Vec<EventType> = received.try_iter().collect();
+        overlay.process_batch(batch).unwrap();
+        overlay.finish_links();
+        assert_eq!(overlay.resolve("z.esp").unwrap().input, game_id);
+        assert!(overlay.resolve("notes.txt").is_none());
+        // The file of plain under the same mount is what is left.
+        assert_eq!(overlay.resolve("dlc/y.esp").unwrap().input, InputId::of(0));
+    }
+
+    #[test]
+    fn lowercase_output_names_are_what_every_event_is_about() {
+        let source = Arc::new(ReplaySource::new());
+        let mut harness = Harness::with("output-lower", &[0, 1], |builder| {
+            builder
+                .event_source(source.clone())
+                .output_case(OutputCase::Lower)
+                .cross_input_window(Duration::ZERO)
+        });
+        let (base, mods) = (InputId::of(0), InputId::of(1));
+        let received = harness.overlay.build_watchers().unwrap();
+        let (authored, lower) = (
+            harness.inputs[0].join("Meshes/Armor/Iron.NIF"),
+            harness.inputs[1].join("meshes/armor/iron.nif"),
+        );
+        for (id, file) in [(base, &authored), (mods, &lower)] {
+            harness.fs.create_file(file);
+            source.push(id, DebouncedEvent::Create(file.clone()));
+        }
+        handle(&mut harness, &received);
+        assert_eq!(harness.winner("meshes/armor/iron.nif"), Some(1));
+        assert_eq!(harness.overlay.providers("meshes/armor/iron.nif").len(), 2);
+
+        harness.fs.remove_file(&lower).unwrap();
+        source.push(mods, DebouncedEvent::Remove(lower));
+        handle(&mut harness, &received);
+        // Read from the file as it is called in base.
+        let output = harness.output.join("meshes/armor/iron.nif");
+        assert!(harness.fs.same_file(&authored, &output).unwrap());
+
+        let renamed = harness.inputs[0].join("Meshes/Armor/Steel.NIF");
+        harness.fs.rename(&authored, &renamed).unwrap();
+        source.push(base, DebouncedEvent::Rename(authored, renamed.clone()));
+        handle(&mut harness, &received);
+        assert!(!harness.in_output("meshes/armor/iron.nif"));
+        assert!(harness.overlay.resolve("meshes/armor/iron.nif").is_none());
+        let output = harness.output.join("meshes/armor/steel.nif");
+        assert!(harness.fs.same_file(&renamed, &output).unwrap());
+        assert!(!harness.in_output("Meshes/Armor/Steel.NIF"));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::input::Rank;
+use crate::Overlay;
+use failure::{format_err, Error};
+use log::warn;
+use rustc_hash::FxHashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How many bytes can still be written to the volume `path` is on, or would be on once
+/// created.
+pub(crate) fn available(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(path);
+    fs2::available_space(existing)
+}
+
+/// Whether `e` says the volume is full, `ENOSPC` on Unix and `ERROR_DISK_FULL` or
+/// `ERROR_HANDLE_DISK_FULL` on Windows.
+pub(crate) fn is_full(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::StorageFull
+        || e.raw_os_error().is_some_and(|code| FULL.contains(&code))
+}
+
+#[cfg(unix)]
+const FULL: &[i32] = &[libc::ENOSPC];
+#[cfg(windows)]
+const FULL: &[i32] = &[112, 39];
+#[cfg(not(any(unix, windows)))]
+const FULL: &[i32] = &[];
+
+/// A size in whole bytes or decimal units, e.g. `2.1 GB`.
+pub(crate) fn format(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB", "PB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+impl Overlay {
+    /// Makes sure the files the sync is going to copy fit into the output, so it doesn't
+    /// fill the volume halfway through.
+    pub(crate) fn check_free_space(&mut self) -> Result<(), Error> {
+        if !self
+            .inputs
+            .iter()
+            .any(|input| input.enabled && input.copies)
+        {
+            return Ok(());
+        }
+        for index in 0..self.inputs.len() {
+            self.load_ignore(index);
+        }
+
+        // Only the winner of each path ends up in the output.
+        let mut winners: FxHashMap<PathBuf, (Rank, usize)> = FxHashMap::default();
+        for input in self.inputs.iter().filter(|input| input.enabled) {
+            for file in input.walk(Path::new("")) {
+                if !self.temp_files.accepts_file(&file) {
+                    continue;
+                }
+                let winner = winners.entry(file).or_insert((input.rank, input.index));
+                if input.rank > winner.0 {
+                    *winner = (input.rank, input.index);
+                }
+            }
+        }
+
+        let needed: u64 = winners
+            .iter()
+            .filter(|(_, (_, index))| self.inputs[*index].copies)
+            .filter_map(|(path, (_, index))| {
+                let source = self.inputs[*index].source(path);
+                let copied = self.provides(*index, &source, &self.output.join(path));
+                let len = self.fs.identity(&source).ok()?.len;
+                Some(len).filter(|_| !copied)
+            })
+            .sum();
+        let available = available(&self.output).map_err(|e| {
+            format_err!(
+                "couldn't tell the free space of {}: {}",
+                self.output.display(),
+                e
+            )
+        })?;
+        if needed <= available {
+            return Ok(());
+        }
+
+        let message = format!(
+            "copying into {} takes {} and only {} are free, {} short",
+            self.output.display(),
+            format(needed),
+            format(available),
+            format(needed - available)
+        );
+        if self.copying.ignore_free_space || self.dry_run {
+            warn!("Syncing anyway: {}", message);
+            Ok(())
+        } else {
+            Err(format_err!("{}", message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::scratch;
+    use crate::{Event, EventType, FileOp, MemoryFs, OverlayBuilder};
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    #[test]
+    fn copies_that_wont_fit_stop_the_sync_and_a_full_volume_is_its_own_error() {
+        let root = scratch("free-space");
+        let (input, output) = (root.join("input"), root.join("output"));
+        let memory = Arc::new(MemoryFs::new());
+        // The sync and the probe look for the files on disk.
+        fs::create_dir_all(&input).unwrap();
+        fs::write(input.join("big"), "").unwrap();
+        memory.create_file(input.join("big"));
+        memory.set_len(input.join("big"), 1 << 60);
+        let probe = output.join(format!(".overlay-probe-{}.part", process::id()));
+        memory.fail(FileOp::HardLink, &probe, io::ErrorKind::CrossesDevices);
+        let build = |ignore: bool| {
+            OverlayBuilder::new(&output)
+                .file_ops(memory.clone())
+                .single_instance(false)
+                .input(&input, 0)
+                .copy_fallback(true)
+                .ignore_free_space(ignore)
+                .build()
+                .unwrap()
+        };
+
+        let error = build(false).sync_once().unwrap_err().to_string();
+        let takes = format!(
+            "copying into {} takes 1152.9 PB and only ",
+            output.display()
+        );
+        assert!(error.starts_with(&takes), "{}", error);
+        assert!(error.ends_with(" short"), "{}", error);
+        assert!(!memory.exists(&output.join("big")));
+
+        let mut overlay = build(true);
+        overlay.sync_once().unwrap();
+        assert!(memory.exists(&output.join("big")));
+        memory.create_file(input.join("more"));
+        memory.fail(
+            FileOp::Copy,
+            output.join("more"),
+            io::ErrorKind::StorageFull,
+        );
+        overlay.apply_event(EventType::new(0, Event::Create(PathBuf::from("more"))));
+        overlay.finish_links();
+        assert_eq!(overlay.failures.len(), 1);
+        assert_eq!(overlay.failures[0].kind, "storage_full");
+        assert_eq!(overlay.failures[0].path, Some(PathBuf::from("more")));
+    }
+}
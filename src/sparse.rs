@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io;
+
+/// The `(offset, len)` ranges of `from` that have data, if it has holes, with `to`, a new
+/// and empty file, made as long as it with nothing but holes, so that copying only those
+/// ranges takes no more space than `from` does. `None` if it has no holes, or they can't
+/// be told apart from data here.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn data(from: &File, to: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = from.metadata()?;
+    let len = metadata.len();
+    // Blocks are 512 bytes, whatever the block size of the filesystem.
+    if metadata.blocks() * 512 >= len {
+        return Ok(None);
+    }
+
+    let mut ranges = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let data = match seek(from, offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // There is nothing but a hole up to the end.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // The filesystem doesn't tell, which is only found out by asking.
+            Err(_) if offset == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let hole = seek(from, data, libc::SEEK_HOLE)?;
+        ranges.push((data, hole - data));
+        offset = hole;
+    }
+
+    to.set_len(len)?;
+    Ok(Some(ranges))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let found = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if found < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(found as u64)
+}
+
+/// The ranges of `from` that are allocated, if it is a sparse file, with `to`, a new and
+/// empty file, made a sparse file as long as it. `None` if it isn't one, or the volume of
+/// either has no sparse files.
+#[cfg(windows)]
+pub(crate) fn data(from: &File, to: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::mem;
+    use std::os::windows::fs::MetadataExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_SPARSE_FILE;
+    use windows_sys::Win32::System::Ioctl::{
+        FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_SPARSE,
+    };
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    const SIZE: usize = mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>();
+
+    let metadata = from.metadata()?;
+    if metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0 {
+        return Ok(None);
+    }
+    let len = metadata.len() as i64;
+
+    let mut ranges = vec![];
+    let mut query = FILE_ALLOCATED_RANGE_BUFFER {
+        FileOffset: 0,
+        Length: len,
+    };
+    let mut found = vec![FILE_ALLOCATED_RANGE_BUFFER::default(); 256];
+    loop {
+        let mut returned = 0;
+        let done = unsafe {
+            DeviceIoControl(
+                from.as_raw_handle(),
+                FSCTL_QUERY_ALLOCATED_RANGES,
+                &query as *const _ as *const _,
+                SIZE as u32,
+                found.as_mut_ptr() as *mut _,
+                (found.len() * SIZE) as u32,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        // Only as many as fit are returned at a time.
+        let more =
+            done == 0 && io::Error::last_os_error().raw_os_error() == Some(ERROR_MORE_DATA as i32);
+        if done == 0 && !more {
+            return Ok(None);
+        }
+
+        let count = returned as usize / SIZE;
+        let found = &found[..count];
+        ranges.extend(
+            found
+                .iter()
+                .map(|range| (range.FileOffset as u64, range.Length as u64)),
+        );
+        match found.last() {
+            Some(last) if more => {
+                let next = last.FileOffset + last.Length;
+                query = FILE_ALLOCATED_RANGE_BUFFER {
+                    FileOffset: next,
+                    Length: len - next,
+                };
+            }
+            _ => break,
+        }
+    }
+
+    let mut returned = 0;
+    let sparse = unsafe {
+        DeviceIoControl(
+            to.as_raw_handle(),
+            FSCTL_SET_SPARSE,
+            ptr::null(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if sparse == 0 {
+        return Ok(None);
+    }
+
+    to.set_len(len as u64)?;
+    Ok(Some(ranges))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub(crate) fn data(_from: &File, _to: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use crate::fs_ops::{FileOps, RealFs};
+    use crate::tests::scratch;
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    const HOLE: u64 = 64 << 20;
+
+    /// A file of a block of data, a hole of `HOLE` bytes and another block.
+    fn sparse_file(path: &Path) -> File {
+        let mut file = File::create(path).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        file.seek(SeekFrom::Start(4096 + HOLE)).unwrap();
+        file.write_all(&[2; 4096]).unwrap();
+        file.sync_all().unwrap();
+        file
+    }
+
+    fn allocated(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[test]
+    fn copies_of_sparse_files_keep_their_holes() {
+        let dir = scratch("sparse-copy");
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        sparse_file(&from);
+        if allocated(&from) >= HOLE {
+            // The filesystem of the temporary directory has no holes.
+            return;
+        }
+
+        RealFs.copy(&from, &to).unwrap();
+        let len = fs::metadata(&to).unwrap().len();
+        assert_eq!(len, 8192 + HOLE);
+        assert!(allocated(&to) < HOLE / 64, "{} allocated", allocated(&to));
+        let copied = fs::read(&to).unwrap();
+        let (data, rest) = copied.split_at(4096);
+        let (hole, end) = rest.split_at(HOLE as usize);
+        assert!(data.iter().all(|&byte| byte == 1));
+        assert!(hole.iter().all(|&byte| byte == 0));
+        assert!(end.iter().all(|&byte| byte == 2));
+    }
+
+    #[test]
+    fn only_the_ranges_with_data_are_found() {
+        let dir = scratch("sparse-data");
+        let from = sparse_file(&dir.join("from"));
+        let to = File::create(dir.join("to")).unwrap();
+        let ranges = match data(&from, &to).unwrap() {
+            Some(ranges) => ranges,
+            None => return,
+        };
+        assert_eq!(ranges, [(0, 4096), (4096 + HOLE, 4096)]);
+        assert_eq!(to.metadata().unwrap().len(), 8192 + HOLE);
+
+        let mut dense = File::create(dir.join("dense")).unwrap();
+        dense.write_all(&[1; 8192]).unwrap();
+        dense.sync_all().unwrap();
+        assert_eq!(
+            data(&File::open(dir.join("dense")).unwrap(), &to).unwrap(),
+            None
+        );
+    }
+}
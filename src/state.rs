@@ -0,0 +1,310 @@
+use crate::input::Input;
+use crate::snapshot::{InputSnapshot, OverlaySnapshot, RestoreReport};
+use crate::{canonical, InputId, LinkKind, Overlay, Strategy};
+use failure::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The version of `OverlayState` written by this overlay. Older ones are migrated when
+/// they are read, newer ones are refused.
+pub const STATE_VERSION: u32 = 2;
+
+/// Everything about an overlay that isn't on disk already: its inputs, how they are switched
+/// and ordered, and which of them provide each path. See `Overlay::state`.
+///
+/// It is meant to be saved and read back, by another version of the overlay too, which is
+/// what `version` is for. `from_json` reads every version there was, including the
+/// `OverlaySnapshot`s written before there was a version, as version 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayState {
+    pub version: u32,
+    /// Empty in state migrated from a snapshot, which didn't have it.
+    pub output: PathBuf,
+    pub strategy: Strategy,
+    pub inputs: Vec<InputState>,
+    /// Every path an input provides, and the inputs providing it from the one whose file
+    /// is in the output down. Only the winners are known of state migrated from a
+    /// snapshot.
+    pub providers: BTreeMap<PathBuf, Vec<InputId>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputState {
+    pub id: InputId,
+    pub path: PathBuf,
+    pub label: Option<String>,
+    pub enabled: bool,
+    pub group: u32,
+    pub priority: u32,
+    /// What its files are in the output. Hard links in state of version 1 and before,
+    /// which didn't have it.
+    #[serde(default)]
+    pub strategy: LinkKind,
+}
+
+impl OverlayState {
+    /// Reads state written by this or an earlier version of the overlay.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json)?;
+        let version = match value.get("version") {
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| format_err!("the version of the state isn't a number"))?,
+            None => 0,
+        };
+        match version {
+            0 => Ok(serde_json::from_value::<OverlaySnapshot>(value)?.into()),
+            1 | 2 => Ok(OverlayState {
+                version: STATE_VERSION,
+                ..serde_json::from_value(value)?
+            }),
+            _ => Err(format_err!(
+                "the state is of version {}, only up to {} can be read",
+                version,
+                STATE_VERSION
+            )),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// The inputs and winners, as `Overlay::restore` takes them.
+    pub(crate) fn snapshot(&self) -> OverlaySnapshot {
+        let positions: BTreeMap<InputId, usize> = self
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(position, input)| (input.id, position))
+            .collect();
+        OverlaySnapshot {
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| InputSnapshot {
+                    path: input.path.clone(),
+                    label: input.label.clone(),
+                    enabled: input.enabled,
+                    group: input.group,
+                    priority: input.priority,
+                })
+                .collect(),
+            winners: self
+                .providers
+                .iter()
+                .filter_map(|(path, providers)| {
+                    let winner = positions.get(providers.first()?)?;
+                    Some((path.clone(), *winner))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Version 0, where the inputs were only listed, and only the winners known.
+impl From<OverlaySnapshot> for OverlayState {
+    fn from(snapshot: OverlaySnapshot) -> Self {
+        let ids: Vec<InputId> = (0..snapshot.inputs.len()).map(InputId::of).collect();
+        OverlayState {
+            version: STATE_VERSION,
+            output: PathBuf::new(),
+            strategy: Strategy::default(),
+            inputs: snapshot
+                .inputs
+                .into_iter()
+                .zip(ids.iter().copied())
+                .map(|(input, id)| InputState {
+                    id,
+                    path: input.path,
+                    label: input.label,
+                    enabled: input.enabled,
+                    group: input.group,
+                    priority: input.priority,
+                    strategy: LinkKind::default(),
+                })
+                .collect(),
+            providers: snapshot
+                .winners
+                .into_iter()
+                .filter_map(|(path, winner)| Some((path, vec![*ids.get(winner)?])))
+                .collect(),
+        }
+    }
+}
+
+impl Overlay {
+    /// Captures the inputs, how they are switched and ordered, and which of them provide
+    /// each path, to save and `apply_state` later. Removed inputs are left out.
+    pub fn state(&self) -> OverlayState {
+        OverlayState {
+            version: STATE_VERSION,
+            output: self.output.clone(),
+            strategy: self.strategy,
+            inputs: self
+                .inputs
+                .iter()
+                .filter(|input| !input.removed)
+                .map(|input| InputState {
+                    id: input.id(),
+                    path: input.path.clone(),
+                    label: input.label.clone(),
+                    enabled: input.enabled,
+                    group: input.rank.group,
+                    priority: input.rank.priority,
+                    strategy: self.planned_kind(input.index),
+                })
+                .collect(),
+            providers: self
+                .input_map
+                .iter()
+                .map(|(path, heap)| {
+                    let providers = self.by_precedence(heap).into_iter().map(Input::id);
+                    (path.clone(), providers.collect())
+                })
+                .collect(),
+        }
+    }
+
+    /// Switches and orders the inputs as they were in `state`, like `restore` does with a
+    /// snapshot. Errs if the state is of another output, unless it doesn't know its output
+    /// as it was migrated from a snapshot.
+    ///
+    /// The strategy isn't changed, nor that of any input, only warned about if it differs,
+    /// as the output would have to be synced anew.
+    pub fn apply_state(&mut self, state: &OverlayState) -> Result<RestoreReport, Error> {
+        if !state.output.as_os_str().is_empty()
+            && canonical(&state.output) != canonical(&self.output)
+        {
+            return Err(format_err!(
+                "the state is of output {}, not of {}",
+                state.output.display(),
+                self.output.display()
+            ));
+        }
+        if state.strategy != self.strategy {
+            warn!(
+                "The state was saved with strategy {:?}, keeping {:?}",
+                state.strategy, self.strategy
+            );
+        }
+        let report = self.restore(&state.snapshot())?;
+        for saved in &state.inputs {
+            let index = match self.saved_input(saved.label.as_deref(), &saved.path) {
+                Some(index) => index,
+                None => continue,
+            };
+            let strategy = self.planned_kind(index);
+            if saved.strategy != strategy {
+                warn!(
+                    "Input {} was saved making {:?}s of its files, keeping {:?}s",
+                    self.input_name(index),
+                    saved.strategy,
+                    strategy
+                );
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+    use std::path::Path;
+
+    fn input(id: u64, path: &str, label: Option<&str>, enabled: bool, group: u32) -> InputState {
+        InputState {
+            id: InputId::of(id as usize),
+            path: PathBuf::from(path),
+            label: label.map(str::to_string),
+            enabled,
+            group,
+            priority: if enabled { 0 } else { 5 },
+            strategy: LinkKind::default(),
+        }
+    }
+
+    fn providers(entries: &[(&str, &[u64])]) -> BTreeMap<PathBuf, Vec<InputId>> {
+        entries
+            .iter()
+            .map(|(path, ids)| {
+                let ids = ids.iter().map(|&id| InputId::of(id as usize)).collect();
+                (PathBuf::from(path), ids)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut patch = input(2, "/mods/patch", None, false, 1);
+        patch.strategy = LinkKind::Copy;
+        let state = OverlayState {
+            version: STATE_VERSION,
+            output: PathBuf::from("/game/data"),
+            strategy: Strategy::Hybrid,
+            inputs: vec![input(0, "/mods/base", Some("base"), true, 0), patch],
+            providers: providers(&[("data/a.txt", &[0]), ("data/b.txt", &[2, 0])]),
+        };
+        let json = state.to_json().unwrap();
+        assert_eq!(OverlayState::from_json(&json).unwrap(), state);
+    }
+
+    #[test]
+    fn round_trips_the_state_of_an_overlay() {
+        let mut harness = Harness::new("state-round-trip", &[0, 1]);
+        harness.create(0, "a");
+        harness.create(0, "b");
+        harness.create(1, "b");
+
+        let state = harness.overlay.state();
+        assert_eq!(state.providers[Path::new("b")].len(), 2);
+        let read = OverlayState::from_json(&state.to_json().unwrap()).unwrap();
+        assert_eq!(read, state);
+        harness.overlay.apply_state(&read).unwrap();
+        assert_eq!(harness.overlay.state(), state);
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let state = OverlayState::from_json(include_str!("../tests/fixtures/state-v0.json"));
+        let expected = OverlayState {
+            version: STATE_VERSION,
+            output: PathBuf::new(),
+            strategy: Strategy::default(),
+            inputs: vec![
+                input(0, "/mods/base", Some("base"), true, 0),
+                input(1, "/mods/patch", None, false, 1),
+            ],
+            providers: providers(&[("data/a.txt", &[0]), ("data/b.txt", &[1])]),
+        };
+        assert_eq!(state.unwrap(), expected);
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let state = OverlayState::from_json(include_str!("../tests/fixtures/state-v1.json"));
+        let expected = OverlayState {
+            version: STATE_VERSION,
+            output: PathBuf::from("/game/data"),
+            strategy: Strategy::Hybrid,
+            inputs: vec![
+                input(0, "/mods/base", Some("base"), true, 0),
+                input(2, "/mods/patch", None, false, 1),
+            ],
+            providers: providers(&[("data/a.txt", &[0]), ("data/b.txt", &[2, 0])]),
+        };
+        assert_eq!(state.unwrap(), expected);
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let json = format!(r#"{{"version": {}}}"#, STATE_VERSION + 1);
+        assert!(OverlayState::from_json(&json).is_err());
+        assert!(OverlayState::from_json(r#"{"version": "1"}"#).is_err());
+    }
+}
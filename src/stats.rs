@@ -0,0 +1,585 @@
+use crate::Overlay;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Counters describing what an `Overlay` has done since it started.
+///
+/// With the `metrics` feature enabled every update is mirrored to the `metrics` facade,
+/// so any installed exporter picks them up.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// How many changes the watchers reported.
+    pub events: u64,
+    pub linked: u64,
+    /// How many of them were copied instead, as their file had as many hard links as its
+    /// filesystem allows.
+    pub over_link_limit: u64,
+    pub unlinked: u64,
+    pub errors: u64,
+    /// How many of the last events failed to be handled, one after the other.
+    pub consecutive_failures: u32,
+    pub queue_depth: usize,
+    pub tracked_paths: usize,
+    /// How many actions on files that were in use wait to be tried again.
+    pub retry_queue: usize,
+    /// When the one of them that waits the longest first failed.
+    pub oldest_retry: Option<SystemTime>,
+    /// The events of all inputs that weren't acted on.
+    pub skipped: Skipped,
+    pub inputs: Vec<InputStats>,
+    /// What the last housekeeping did, if there was any yet.
+    pub housekeeping: Option<Housekeeping>,
+}
+
+/// How many paths the overlay tracked before and after it last tidied them up, and how
+/// many it had room for, on a tick of `Overlay::set_tick_interval`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Housekeeping {
+    pub entries_before: usize,
+    pub entries_after: usize,
+    pub capacity_before: usize,
+    pub capacity_after: usize,
+}
+
+/// How many of an input's files are currently in the output, and how many are hidden
+/// behind a higher priority input, with their sizes as of when they were last seen.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InputStats {
+    pub label: Option<String>,
+    pub visible: usize,
+    pub shadowed: usize,
+    pub visible_bytes: u64,
+    pub shadowed_bytes: u64,
+    pub watcher: WatcherHealth,
+    /// When the last poll of the input completed, if it is polled.
+    pub last_poll: Option<SystemTime>,
+    pub skipped: Skipped,
+}
+
+impl InputStats {
+    /// How many files the input provides, in the output or not.
+    pub fn files(&self) -> usize {
+        self.visible + self.shadowed
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.visible_bytes + self.shadowed_bytes
+    }
+}
+
+/// How many events the overlay didn't act on, by why. Those of syncing and resyncing
+/// aren't counted, only the changes that were watched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Skipped {
+    /// About files the patterns or ignore files of the input exclude, or temporary ones.
+    pub filtered: u64,
+    /// About files shadowed by those of an input of higher priority.
+    pub ignored: u64,
+    /// Taken together with later ones about the same file, when throttled or replayed
+    /// after a resync that saw them already.
+    pub coalesced: u64,
+    /// Parked in the retry queue, or held until the file settles.
+    pub deferred: u64,
+    /// Never looked at, as their input was disabled.
+    pub dropped: u64,
+}
+
+/// Why an event wasn't acted on, see `Skipped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Skip {
+    Filtered,
+    Ignored,
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    Coalesced,
+    Deferred,
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    Dropped,
+}
+
+impl Skip {
+    #[cfg(feature = "metrics")]
+    fn name(self) -> &'static str {
+        match self {
+            Skip::Filtered => "filtered",
+            Skip::Ignored => "ignored",
+            Skip::Coalesced => "coalesced",
+            Skip::Deferred => "deferred",
+            Skip::Dropped => "dropped",
+        }
+    }
+}
+
+impl Skipped {
+    pub fn total(&self) -> u64 {
+        self.filtered + self.ignored + self.coalesced + self.deferred + self.dropped
+    }
+
+    fn count(&mut self, why: Skip) {
+        let count = match why {
+            Skip::Filtered => &mut self.filtered,
+            Skip::Ignored => &mut self.ignored,
+            Skip::Coalesced => &mut self.coalesced,
+            Skip::Deferred => &mut self.deferred,
+            Skip::Dropped => &mut self.dropped,
+        };
+        *count += 1;
+    }
+
+    /// What was counted since `since`.
+    fn since(&self, since: &Skipped) -> Skipped {
+        Skipped {
+            filtered: self.filtered - since.filtered,
+            ignored: self.ignored - since.ignored,
+            coalesced: self.coalesced - since.coalesced,
+            deferred: self.deferred - since.deferred,
+            dropped: self.dropped - since.dropped,
+        }
+    }
+}
+
+/// Whether the changes to an input are being watched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WatcherHealth {
+    #[default]
+    Healthy,
+    /// The watcher failed `failures` times in a row and is restarted at `retry_at`.
+    BackingOff {
+        failures: u32,
+        #[serde(skip)]
+        retry_at: Instant,
+    },
+    /// The watcher failed too often and is no longer restarted.
+    GivenUp,
+}
+
+impl Stats {
+    /// How long the action that waits to be tried again the longest has been waiting.
+    pub fn oldest_retry_age(&self) -> Option<Duration> {
+        let oldest = self.oldest_retry?;
+        Some(SystemTime::now().duration_since(oldest).unwrap_or_default())
+    }
+
+    pub(crate) fn add_input(&mut self, label: Option<String>) {
+        self.inputs.push(InputStats {
+            label,
+            ..InputStats::default()
+        });
+        self.publish_input(self.inputs.len() - 1);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn event(&mut self) {
+        self.events += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("overlay_events_total").increment(1);
+    }
+
+    pub(crate) fn linked(&mut self) {
+        self.linked += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("overlay_files_linked_total").increment(1);
+    }
+
+    pub(crate) fn over_link_limit(&mut self) {
+        self.over_link_limit += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("overlay_files_over_link_limit_total").increment(1);
+    }
+
+    pub(crate) fn unlinked(&mut self) {
+        self.unlinked += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("overlay_files_unlinked_total").increment(1);
+    }
+
+    /// Records that an event of input `index` wasn't acted on, for `why`.
+    pub(crate) fn skipped(&mut self, index: usize, why: Skip) {
+        self.skipped.count(why);
+        self.inputs[index].skipped.count(why);
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = [
+                ("input", index.to_string()),
+                ("reason", why.name().to_string()),
+            ];
+            metrics::counter!("overlay_events_skipped_total", &labels).increment(1);
+        }
+    }
+
+    pub(crate) fn error(&mut self) {
+        self.errors += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("overlay_errors_total").increment(1);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn set_consecutive_failures(&mut self, failures: u32) {
+        self.consecutive_failures = failures;
+
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("overlay_consecutive_failures").set(failures as f64);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("overlay_queue_depth").set(depth as f64);
+    }
+
+    pub(crate) fn set_retry_queue(&mut self, parked: usize, oldest: Option<SystemTime>) {
+        self.retry_queue = parked;
+        self.oldest_retry = oldest;
+
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("overlay_retry_queue").set(parked as f64);
+    }
+
+    pub(crate) fn set_tracked_paths(&mut self, tracked: usize) {
+        self.tracked_paths = tracked;
+
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("overlay_tracked_paths").set(tracked as f64);
+    }
+
+    /// Records that a file of `bytes` from `index` is now the one in the output.
+    pub(crate) fn input_visible(&mut self, index: usize, bytes: u64) {
+        let input = &mut self.inputs[index];
+        input.visible += 1;
+        input.visible_bytes += bytes;
+        self.publish_input(index);
+    }
+
+    /// Records that a file of `bytes` from `index` is no longer the one in the output.
+    pub(crate) fn input_hidden(&mut self, index: usize, bytes: u64) {
+        let input = &mut self.inputs[index];
+        input.visible -= 1;
+        input.visible_bytes = input.visible_bytes.saturating_sub(bytes);
+        self.publish_input(index);
+    }
+
+    pub(crate) fn input_shadowed(&mut self, index: usize, bytes: u64) {
+        let input = &mut self.inputs[index];
+        input.shadowed += 1;
+        input.shadowed_bytes += bytes;
+        self.publish_input(index);
+    }
+
+    pub(crate) fn input_unshadowed(&mut self, index: usize, bytes: u64) {
+        let input = &mut self.inputs[index];
+        input.shadowed -= 1;
+        input.shadowed_bytes = input.shadowed_bytes.saturating_sub(bytes);
+        self.publish_input(index);
+    }
+
+    /// Records that a file from `index` changed size from `from` to `to` bytes.
+    pub(crate) fn input_resized(&mut self, index: usize, visible: bool, from: u64, to: u64) {
+        let input = &mut self.inputs[index];
+        let bytes = if visible {
+            &mut input.visible_bytes
+        } else {
+            &mut input.shadowed_bytes
+        };
+        *bytes = bytes.saturating_sub(from) + to;
+        self.publish_input(index);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn set_watcher(&mut self, index: usize, health: WatcherHealth) {
+        self.inputs[index].watcher = health;
+        self.publish_input(index);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn polled(&mut self, index: usize, at: SystemTime) {
+        self.inputs[index].last_poll = Some(at);
+    }
+
+    /// What happened since `since`, which was counted `period` ago.
+    pub(crate) fn summary(&self, since: &Counters, period: Duration) -> Summary {
+        Summary {
+            period,
+            events: self.events - since.events,
+            linked: self.linked - since.linked,
+            unlinked: self.unlinked - since.unlinked,
+            errors: self.errors - since.errors,
+            skipped: self.skipped.since(&since.skipped),
+            queue_depth: self.queue_depth,
+            tracked_paths: self.tracked_paths,
+            inputs: self.inputs.clone(),
+            housekeeping: self.housekeeping,
+        }
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn counters(&self) -> Counters {
+        Counters {
+            events: self.events,
+            linked: self.linked,
+            unlinked: self.unlinked,
+            errors: self.errors,
+            skipped: self.skipped,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn publish_input(&self, index: usize) {
+        let input = &self.inputs[index];
+        let labels = [
+            ("input", index.to_string()),
+            ("label", input.label.clone().unwrap_or_default()),
+        ];
+        metrics::gauge!("overlay_input_visible_files", &labels).set(input.visible as f64);
+        metrics::gauge!("overlay_input_shadowed_files", &labels).set(input.shadowed as f64);
+        metrics::gauge!("overlay_input_visible_bytes", &labels).set(input.visible_bytes as f64);
+        metrics::gauge!("overlay_input_shadowed_bytes", &labels).set(input.shadowed_bytes as f64);
+        let watcher = match input.watcher {
+            WatcherHealth::Healthy => 0.0,
+            WatcherHealth::BackingOff { .. } => 1.0,
+            WatcherHealth::GivenUp => 2.0,
+        };
+        metrics::gauge!("overlay_input_watcher_state", &labels).set(watcher);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[inline(always)]
+    fn publish_input(&self, _index: usize) {}
+}
+
+/// The counters of the stats at some moment, which summaries count from.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Counters {
+    events: u64,
+    linked: u64,
+    unlinked: u64,
+    errors: u64,
+    skipped: Skipped,
+}
+
+/// What an overlay did over a while, and how it stands now. See
+/// `Overlay::set_summary_interval`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// The while, since the last periodic summary or since the overlay started.
+    #[serde(rename = "period_ms", serialize_with = "millis")]
+    pub period: Duration,
+    pub events: u64,
+    pub linked: u64,
+    pub unlinked: u64,
+    pub errors: u64,
+    /// The events that weren't acted on, of every input. Those of each input are counted
+    /// since it was added, like the rest of their stats.
+    pub skipped: Skipped,
+    pub queue_depth: usize,
+    pub tracked_paths: usize,
+    pub inputs: Vec<InputStats>,
+    pub housekeeping: Option<Housekeeping>,
+}
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_millis())
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let period = Duration::from_secs(self.period.as_secs());
+        write!(
+            f,
+            "In the last {}: {} events ({} skipped), {} links, {} unlinks, {} errors; {} queued, \
+             {} tracked",
+            humantime::format_duration(period),
+            self.events,
+            self.skipped.total(),
+            self.linked,
+            self.unlinked,
+            self.errors,
+            self.queue_depth,
+            self.tracked_paths
+        )?;
+        if let Some(housekeeping) = &self.housekeeping {
+            write!(
+                f,
+                " (last tidied from {} to {}, with room for {} then {})",
+                housekeeping.entries_before,
+                housekeeping.entries_after,
+                housekeeping.capacity_before,
+                housekeeping.capacity_after
+            )?;
+        }
+        for (index, input) in self.inputs.iter().enumerate() {
+            match &input.label {
+                Some(label) => write!(f, "; input {} [{}] ", index, label)?,
+                None => write!(f, "; input {} ", index)?,
+            }
+            match input.watcher {
+                WatcherHealth::Healthy => write!(f, "healthy")?,
+                WatcherHealth::BackingOff { failures, .. } => {
+                    write!(f, "backing off after {} failures", failures)?
+                }
+                WatcherHealth::GivenUp => write!(f, "given up")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When the overlay logs its periodic summaries, and what the last one counted from.
+#[derive(Debug)]
+pub(crate) struct Summaries {
+    /// How often a summary is logged while watching, if at all.
+    pub(crate) interval: Option<Duration>,
+    /// When the next summary is logged.
+    pub(crate) next: Option<Instant>,
+    /// When the last summary was logged, and what the stats were then.
+    pub(crate) last: (Instant, Counters),
+}
+
+impl Default for Summaries {
+    fn default() -> Self {
+        Summaries {
+            interval: None,
+            next: None,
+            last: (Instant::now(), Counters::default()),
+        }
+    }
+}
+
+impl Overlay {
+    /// Logs a `Summary` to `SUMMARY` every `interval` while watching, none by default.
+    /// Each counts what happened since the one before.
+    pub fn set_summary_interval(&mut self, interval: Option<Duration>) {
+        self.summaries.interval = interval;
+        self.summaries.next = interval.map(|interval| Instant::now() + interval);
+    }
+
+    /// What happened since the last periodic summary, or since the overlay started if
+    /// there was none yet. Unlike those, it doesn't start a new period.
+    pub fn summary(&self) -> Summary {
+        let (since, counters) = &self.summaries.last;
+        self.stats.summary(counters, since.elapsed())
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use crate::tests::Harness;
+    use metrics::{
+        Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// What each metric was last, by its name and labels, as `name{label=value,...}`.
+    #[derive(Debug, Default)]
+    struct Recorded(Arc<Mutex<BTreeMap<String, f64>>>);
+
+    impl Recorded {
+        fn get(&self, metric: &str) -> Option<f64> {
+            self.0.lock().unwrap().get(metric).copied()
+        }
+
+        fn value(&self, key: &Key) -> Arc<Value> {
+            let labels: Vec<String> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            let name = match labels.is_empty() {
+                true => key.name().to_string(),
+                false => format!("{}{{{}}}", key.name(), labels.join(",")),
+            };
+            Arc::new(Value(name, self.0.clone()))
+        }
+    }
+
+    struct Value(String, Arc<Mutex<BTreeMap<String, f64>>>);
+
+    impl Value {
+        fn update(&self, change: impl FnOnce(f64) -> f64) {
+            let mut values = self.1.lock().unwrap();
+            let value = values.entry(self.0.clone()).or_default();
+            *value = change(*value);
+        }
+    }
+
+    impl CounterFn for Value {
+        fn increment(&self, by: u64) {
+            self.update(|value| value + by as f64);
+        }
+
+        fn absolute(&self, to: u64) {
+            self.update(|value| value.max(to as f64));
+        }
+    }
+
+    impl GaugeFn for Value {
+        fn increment(&self, by: f64) {
+            self.update(|value| value + by);
+        }
+
+        fn decrement(&self, by: f64) {
+            self.update(|value| value - by);
+        }
+
+        fn set(&self, to: f64) {
+            self.update(|_| to);
+        }
+    }
+
+    impl Recorder for Recorded {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.value(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.value(key))
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn the_metrics_follow_the_stats() {
+        let recorded = Recorded::default();
+        let stats = metrics::with_local_recorder(&recorded, || {
+            let mut harness = Harness::new("metrics", &[0, 1]);
+            harness.create(0, "a");
+            harness.create(1, "a");
+            harness.remove(1, "a");
+            harness.overlay.stats().clone()
+        });
+
+        assert_eq!((stats.linked, stats.unlinked), (3, 1));
+        let counted = |metric: &str| recorded.get(metric).unwrap_or_default() as u64;
+        assert_eq!(counted("overlay_files_linked_total"), stats.linked);
+        assert_eq!(counted("overlay_files_unlinked_total"), stats.unlinked);
+        assert_eq!(counted("overlay_errors_total"), 0);
+        assert_eq!(
+            recorded.get("overlay_input_visible_files{input=0,label=}"),
+            Some(1.0)
+        );
+        assert_eq!(
+            recorded.get("overlay_input_visible_files{input=1,label=}"),
+            Some(0.0)
+        );
+        assert_eq!(
+            recorded.get("overlay_input_shadowed_files{input=0,label=}"),
+            Some(0.0)
+        );
+    }
+}
@@ -0,0 +1,646 @@
+use crate::adoption::AdoptionReport;
+use crate::input::{check_readable, Rank};
+#[cfg(feature = "tracing")]
+use crate::instrument;
+use crate::key::Folded;
+use crate::{Event, EventType, Overlay, SUMMARY};
+use failure::Error;
+use log::info;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Every how many scheduled resyncs is a full one, unless the builder says otherwise.
+pub(crate) const DEFAULT_FULL_RESYNC_EVERY: u32 = 10;
+
+/// What an incremental resync saw of a directory of an input, so that it only reads the
+/// directory again once it changed.
+#[derive(Debug, Clone)]
+struct DirState {
+    modified: SystemTime,
+    /// The directories in it, whether the filter accepts them or not.
+    dirs: Vec<PathBuf>,
+}
+
+/// How the output directory on disk differs from what the overlay intends it to be.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffReport {
+    /// Files in the output that the overlay doesn't know about.
+    pub foreign: Vec<PathBuf>,
+    /// Files the overlay provides that aren't in the output.
+    pub missing: Vec<PathBuf>,
+    /// Files in the output that aren't the file of the input providing them.
+    pub mismatched: Vec<PathBuf>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.foreign.is_empty() && self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// How far the sync at startup is, once it synced another input.
+#[derive(Debug, Clone, Serialize)]
+struct SyncProgress {
+    input: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// How many of the enabled inputs are synced.
+    done: usize,
+    total: usize,
+}
+
+/// What a sync did to the output to bring it in line with the inputs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// Entries that were already linked to the input providing them.
+    pub confirmed: usize,
+    /// Entries that weren't in the output yet.
+    pub linked: usize,
+    /// Entries in the output that were replaced with the file of the input providing them.
+    pub relinked: usize,
+    /// Directory links into the inputs that were left behind by an earlier run.
+    pub stale_removed: usize,
+    /// Entries whose input no longer had their file, which only a resync finds.
+    pub vanished: usize,
+    pub foreign_kept: Vec<PathBuf>,
+    pub foreign_adopted: Vec<PathBuf>,
+    pub foreign_deleted: Vec<PathBuf>,
+    pub errors: Vec<String>,
+    /// What was adopted of the output before, if this is the first sync of an output the
+    /// overlay was told to adopt.
+    #[serde(default)]
+    pub adoption: Option<AdoptionReport>,
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Synced output: {} confirmed, {} linked, {} relinked, {} stale removed, \
+             {} vanished, {} foreign kept, {} adopted, {} deleted, {} errors",
+            self.confirmed,
+            self.linked,
+            self.relinked,
+            self.stale_removed,
+            self.vanished,
+            self.foreign_kept.len(),
+            self.foreign_adopted.len(),
+            self.foreign_deleted.len(),
+            self.errors.len()
+        )?;
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the overlay keeps of its syncs, and how often it resyncs while watching.
+#[derive(Debug)]
+pub(crate) struct Syncs {
+    /// Filled in while syncing.
+    pub(crate) current: Option<SyncReport>,
+    pub(crate) last: Option<SyncReport>,
+    /// Whether the changes waiting now came in during a resync, and are replayed like
+    /// those during the initial sync.
+    pub(crate) replay: bool,
+    /// The directories of the inputs as the last incremental resync saw them.
+    dirs: FxHashMap<(usize, PathBuf), DirState>,
+    /// How often to resync while watching, if at all.
+    pub(crate) auto: Option<Duration>,
+    /// Every how many of those resyncs is a full one, the others incremental.
+    pub(crate) full_every: u32,
+    #[cfg(feature = "watch")]
+    pub(crate) resyncs: u32,
+}
+
+impl Default for Syncs {
+    fn default() -> Self {
+        Syncs {
+            current: None,
+            last: None,
+            replay: false,
+            dirs: FxHashMap::default(),
+            auto: None,
+            full_every: DEFAULT_FULL_RESYNC_EVERY,
+            #[cfg(feature = "watch")]
+            resyncs: 0,
+        }
+    }
+}
+
+impl Overlay {
+    /// Compares the output directory on disk against what the overlay has linked into it.
+    pub fn diff(&self) -> DiffReport {
+        let mut report = DiffReport::default();
+
+        for entry in WalkDir::new(&self.output).min_depth(1).into_iter() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if entry.file_type().is_dir() {
+                continue;
+            }
+
+            // Directory links aren't followed, so grafts show up as single entries.
+            let relative = entry.path().strip_prefix(&self.output).unwrap();
+            if self.materialized(relative).is_none() && !self.grafts.dirs.contains_key(relative) {
+                report.foreign.push(relative.to_path_buf());
+            }
+        }
+
+        self.diff_tracked(&mut report);
+        report.foreign.sort();
+        report
+    }
+
+    /// Adds the tracked entries that are missing from the output, or aren't what they
+    /// should be, to `report`.
+    fn diff_tracked(&self, report: &mut DiffReport) {
+        for entry in self.list() {
+            let output_file = self.output.join(&entry.path);
+            let source = self.inputs[entry.input.index()].source(&entry.path);
+            if let Some((graft, _)) = self.graft_of(&entry.path) {
+                // Whatever is in the input is in the output, as long as the link is right.
+                let link = self.output.join(&graft);
+                if fs::symlink_metadata(&link).is_err() {
+                    report.missing.push(entry.path);
+                } else if !self.graft_intact(&graft) {
+                    report.mismatched.push(entry.path);
+                }
+            } else if fs::symlink_metadata(&output_file).is_err() {
+                report.missing.push(entry.path);
+            } else if self.merging.merged.contains(&entry.path) {
+                // Merged files are the overlay's own, as long as nothing changed them.
+                if !self.owned(&entry.path) {
+                    report.mismatched.push(entry.path);
+                }
+            } else if !self.provides(entry.input.index(), &source, &output_file) {
+                report.mismatched.push(entry.path);
+            }
+        }
+    }
+
+    /// Relinks the missing and mismatched entries of `report`, returning how many were
+    /// fixed. Foreign files are dealt with as the foreign file policy says, but don't count.
+    pub fn repair(&mut self, report: &DiffReport) -> usize {
+        let mut repaired = 0;
+        let mut grafts = BTreeSet::new();
+
+        for path in &report.foreign {
+            if self.materialized(path).is_none() && self.fs.exists(&self.output.join(path)) {
+                self.handle_untracked_foreign(path);
+            }
+        }
+
+        for path in report.missing.iter().chain(&report.mismatched) {
+            let index = match self.materialized(path) {
+                Some(input) => input.index,
+                None => continue,
+            };
+            if let Some((graft, _)) = self.graft_of(path) {
+                grafts.insert(graft);
+                continue;
+            }
+
+            self.line
+                .begin(format_args!("Repairing {}:", path.display()));
+            if self.link(path, &Folded::new(path), index) {
+                repaired += 1;
+            }
+            self.line.end();
+        }
+
+        for graft in grafts {
+            let paths: Vec<&PathBuf> = report
+                .missing
+                .iter()
+                .chain(&report.mismatched)
+                .filter(|path| path.starts_with(&graft))
+                .collect();
+
+            self.line
+                .begin(format_args!("Repairing {}:", graft.display()));
+            if self.regraft(&graft) {
+                repaired += paths.len();
+            } else {
+                // Something else is in the way, so link the files one by one instead.
+                self.ungraft(&graft);
+                repaired += paths
+                    .into_iter()
+                    .filter(|path| self.materialized(path).is_some())
+                    .count();
+            }
+            self.line.end();
+        }
+
+        repaired
+    }
+
+    /// The enabled inputs, the highest ranked first. Going from the top means lower
+    /// priority files are never linked only to be replaced.
+    fn sync_order(&self) -> Vec<usize> {
+        let mut inputs: Vec<(Rank, usize)> = self
+            .inputs
+            .iter()
+            .filter(|input| input.enabled)
+            .map(|input| (input.rank, input.index))
+            .collect();
+        inputs.sort_by(|a, b| b.cmp(a));
+        inputs.into_iter().map(|(_, index)| index).collect()
+    }
+
+    /// Brings the output in line with everything that is in the inputs, then deals with
+    /// the files no input provides as the foreign file policy says.
+    ///
+    /// `process_loop` does this before it starts watching, and prints the report.
+    pub fn sync(&mut self) -> SyncReport {
+        self.syncs.current = Some(SyncReport::default());
+        let inputs = self.sync_order();
+        #[cfg(feature = "tracing")]
+        let _span = instrument::sync("sync").entered();
+
+        let total = inputs.len();
+        self.pool.deferring = true;
+        for (done, index) in inputs.into_iter().enumerate() {
+            #[cfg(feature = "tracing")]
+            let _span = self.sync_input_span(index);
+            self.load_ignore(index);
+            self.apply_event(EventType::new(index, Event::Create(PathBuf::new())));
+            let progress = SyncProgress {
+                input: index,
+                label: self.inputs[index].label.clone(),
+                done: done + 1,
+                total,
+            };
+            self.emit("sync_progress", &progress);
+        }
+        self.stop_deferring();
+
+        let report = self.diff();
+        for path in &report.foreign {
+            self.handle_untracked_foreign(path);
+        }
+
+        let mut report = self.syncs.current.take().unwrap();
+        report.adoption = self.adoption.report.take();
+        self.adoption.adopted.clear();
+        self.syncs.last = Some(report.clone());
+        report
+    }
+
+    /// Walks every input again, and brings what the overlay tracks and the output in line
+    /// with what is there now. For when watchers missed changes, like when a mount went
+    /// away for a while or the events overflowed.
+    ///
+    /// `process_loop` replays the changes that came in meanwhile once it is done, leaving
+    /// out what the resync already saw.
+    ///
+    /// Errs without changing anything if an input can't be read, as it would lose all of
+    /// its files otherwise.
+    pub fn resync(&mut self) -> Result<SyncReport, Error> {
+        let inputs = self.sync_order();
+        for &index in &inputs {
+            let input = &self.inputs[index];
+            check_readable(&input.path, input.is_archive())?;
+        }
+        self.syncs.current = Some(SyncReport::default());
+        #[cfg(feature = "tracing")]
+        let _span = instrument::sync("resync").entered();
+
+        self.pool.deferring = true;
+        for index in inputs {
+            #[cfg(feature = "tracing")]
+            let _span = self.sync_input_span(index);
+            #[cfg(feature = "archives")]
+            if self.inputs[index].is_archive() {
+                self.reload_archive(index, true);
+            }
+            self.reload_ignore(index);
+
+            let temp_files = &self.temp_files;
+            let found: HashSet<PathBuf> = self.inputs[index]
+                .walk(Path::new(""))
+                .into_iter()
+                .filter(|path| temp_files.accepts_file(path))
+                .collect();
+            let vanished: Vec<PathBuf> = self
+                .provided_under(index, Path::new(""))
+                .into_iter()
+                .filter(|path| !found.contains(path) && self.graft_of(path).is_none())
+                .collect();
+            for path in vanished {
+                self.note(|report| report.vanished += 1);
+                self.apply_event(EventType::new(index, Event::Remove(path)));
+            }
+            self.apply_event(EventType::new(index, Event::Create(PathBuf::new())));
+        }
+        self.stop_deferring();
+
+        // The directories are all read again by the next incremental resync.
+        self.syncs.dirs.clear();
+        // What is tracked is right now, but the output may not be.
+        let report = self.diff();
+        self.repair(&report);
+
+        self.syncs.replay = true;
+        Ok(self.syncs.current.take().unwrap())
+    }
+
+    /// Like `resync`, but trusts what the overlay tracks instead of walking every input:
+    /// it checks that the file of each tracked entry is still in its input and that the
+    /// output has it, and reads only the directories that were modified since the last
+    /// time for new files. Files that changed in place are only noticed if the output
+    /// doesn't have them anymore, and the foreign files in the output aren't looked for.
+    pub fn resync_incremental(&mut self) -> SyncReport {
+        self.syncs.current = Some(SyncReport::default());
+        let inputs = self.sync_order();
+        #[cfg(feature = "tracing")]
+        let _span = instrument::sync("resync_incremental").entered();
+
+        self.pool.deferring = true;
+        for index in inputs {
+            #[cfg(feature = "tracing")]
+            let _span = self.sync_input_span(index);
+            #[cfg(feature = "archives")]
+            if self.inputs[index].is_archive() {
+                if self.inputs[index].archive_changed() {
+                    self.reload_archive(index, true);
+                }
+                continue;
+            }
+            self.reload_ignore(index);
+
+            let input = &self.inputs[index];
+            let vanished: Vec<PathBuf> = self
+                .provided_under(index, Path::new(""))
+                .into_iter()
+                .filter(|path| {
+                    self.graft_of(path).is_none() && !self.fs.exists(&input.source(path))
+                })
+                .collect();
+            for path in vanished {
+                self.note(|report| report.vanished += 1);
+                self.apply_event(EventType::new(index, Event::Remove(path)));
+            }
+            for path in self.changed_files(index) {
+                self.apply_event(EventType::new(index, Event::Create(path)));
+            }
+        }
+        self.stop_deferring();
+
+        let mut report = DiffReport::default();
+        self.diff_tracked(&mut report);
+        self.repair(&report);
+
+        self.syncs.replay = true;
+        self.syncs.current.take().unwrap()
+    }
+
+    /// The files the input at `index` has that it doesn't provide yet, from the
+    /// directories that were modified since the last incremental resync. Those that
+    /// weren't only have their time looked at.
+    fn changed_files(&mut self, index: usize) -> Vec<PathBuf> {
+        let input = &self.inputs[index];
+        let depth = input.filter.max_depth();
+        let mut files = vec![];
+        let mut pending = vec![PathBuf::new()];
+
+        while let Some(dir) = pending.pop() {
+            if dir != Path::new("") && !input.filter.accepts_dir(&dir) {
+                continue;
+            }
+            let key = (index, dir);
+            let full = input.path.join(&key.1);
+            let modified = match fs::metadata(&full).and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                Err(_) => {
+                    self.syncs.dirs.remove(&key);
+                    continue;
+                }
+            };
+            let below = depth.is_none_or(|depth| key.1.components().count() < depth);
+            if let Some(state) = self.syncs.dirs.get(&key) {
+                if state.modified == modified {
+                    if below {
+                        pending.extend(state.dirs.iter().cloned());
+                    }
+                    continue;
+                }
+            }
+
+            let mut dirs = vec![];
+            for entry in fs::read_dir(&full).into_iter().flatten().flatten() {
+                let path = key.1.join(entry.file_name());
+                match entry.file_type() {
+                    Ok(kind) if kind.is_dir() => dirs.push(path),
+                    Ok(kind) if kind.is_file() => {
+                        let output_key = input.key(&path);
+                        let provided = self
+                            .input_map
+                            .get(&output_key)
+                            .is_some_and(|heap| heap.iter().any(|input| input.index == index));
+                        if !provided
+                            && input.filter.accepts_file(&path)
+                            && self.temp_files.accepts_file(&output_key)
+                        {
+                            files.push(output_key);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if below {
+                pending.extend(dirs.iter().cloned());
+            }
+            self.syncs.dirs.insert(key, DirState { modified, dirs });
+        }
+
+        files.sort();
+        files
+    }
+
+    /// Logs what a sync or resync did, and writes it to the event stream.
+    pub(crate) fn report_sync(&mut self, report: &SyncReport) {
+        info!(target: SUMMARY, "{}", report);
+        self.emit("sync", report);
+    }
+
+    /// Adds to the report of the sync in progress, if there is one.
+    pub(crate) fn note<F: FnOnce(&mut SyncReport)>(&mut self, note: F) {
+        if let Some(report) = self.syncs.current.as_mut() {
+            note(report);
+        }
+    }
+
+    /// Resyncs every `interval` while watching, see `set_full_resync_every`.
+    pub fn set_auto_resync(&mut self, interval: Option<Duration>) {
+        self.syncs.auto = interval;
+    }
+
+    /// Makes every `n`th of the scheduled resyncs a full `resync`, the others being
+    /// `resync_incremental`. With 0, they never are.
+    pub fn set_full_resync_every(&mut self, n: u32) {
+        self.syncs.full_every = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::FileOps;
+    use crate::tests::{scratch, Harness};
+    use crate::{OverlayBuilder, RealFs};
+
+    #[test]
+    fn resync_errs_without_changes_if_an_input_is_gone() {
+        let mut harness = Harness::new("resync-gone", &[0]);
+        harness.create(0, "x");
+        fs::remove_dir_all(&harness.inputs[0]).unwrap();
+
+        assert!(harness.overlay.resync().is_err());
+        assert_eq!(harness.winner("x"), Some(0));
+        assert!(harness.overlay.syncs.current.is_none());
+    }
+
+    #[test]
+    fn resync_brings_back_what_the_watchers_missed_and_tells_how_much() {
+        // On disk, as a resync walks the inputs and looks at the output.
+        let root = scratch("resync-drift");
+        let (base, mods, output) = (root.join("base"), root.join("mods"), root.join("output"));
+        for input in [&base, &mods] {
+            fs::create_dir_all(input).unwrap();
+        }
+        for path in ["kept", "gone", "lost", "shadowed"] {
+            fs::write(base.join(path), path).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .input(&base, 0)
+            .input(&mods, 1)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        // None of which the overlay hears of.
+        fs::write(base.join("missed"), "missed").unwrap();
+        fs::write(mods.join("shadowed"), "mods").unwrap();
+        fs::remove_file(base.join("gone")).unwrap();
+        fs::remove_file(output.join("lost")).unwrap();
+
+        let report = overlay.resync().unwrap();
+        assert_eq!(report.vanished, 1);
+        // Missed and lost are linked, shadowed is relinked to the file of mods.
+        assert_eq!((report.linked, report.relinked), (2, 1));
+        assert!(report.errors.is_empty());
+        assert!(overlay.syncs.replay);
+        assert!(overlay.syncs.current.is_none());
+        assert_eq!(fs::read_to_string(output.join("shadowed")).unwrap(), "mods");
+        assert_eq!(fs::read_to_string(output.join("missed")).unwrap(), "missed");
+        assert!(output.join("lost").exists());
+        assert!(!output.join("gone").exists());
+        assert!(overlay.resolve("gone").is_none());
+        assert!(overlay.diff().is_empty());
+
+        // Nothing has drifted since.
+        assert_eq!(overlay.resync().unwrap(), SyncReport::default());
+    }
+
+    #[test]
+    fn diff_finds_the_drift_of_the_output_and_repair_undoes_it() {
+        let root = scratch("diff");
+        let (input, output) = (root.join("input"), root.join("output"));
+        fs::create_dir_all(input.join("c")).unwrap();
+        for path in ["a", "b", "c/d"] {
+            fs::write(input.join(path), path).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .input(&input, 0)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        assert!(overlay.diff().is_empty());
+
+        fs::remove_file(output.join("a")).unwrap();
+        fs::remove_file(output.join("b")).unwrap();
+        fs::write(output.join("b"), "b, but another file").unwrap();
+        fs::write(output.join("c/foreign"), "foreign").unwrap();
+        let report = overlay.diff();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "foreign": ["c/foreign"],
+                "missing": ["a"],
+                "mismatched": ["b"],
+            })
+        );
+
+        // A file changed by something else is no longer the overlay's to replace.
+        assert_eq!(overlay.repair(&report), 1);
+        let report = overlay.diff();
+        assert_eq!(report.missing, Vec::<PathBuf>::new());
+        assert_eq!(report.mismatched, [PathBuf::from("b")]);
+        assert!(RealFs
+            .same_file(&input.join("a"), &output.join("a"))
+            .unwrap());
+        assert_eq!(
+            fs::read_to_string(output.join("b")).unwrap(),
+            "b, but another file"
+        );
+    }
+
+    #[test]
+    fn a_sync_reports_what_it_did_to_an_output_with_content() {
+        let root = scratch("sync-report");
+        let (base, mods, output) = (root.join("base"), root.join("mods"), root.join("output"));
+        for input in [&base, &mods] {
+            fs::create_dir_all(input).unwrap();
+        }
+        for path in ["kept", "replaced", "gone"] {
+            fs::write(base.join(path), path).unwrap();
+        }
+        let sync = || {
+            OverlayBuilder::new(&output)
+                .input(&base, 0)
+                .input(&mods, 1)
+                .single_instance(false)
+                .build()
+                .unwrap()
+                .sync_once()
+                .unwrap()
+        };
+        assert_eq!(sync().linked, 3);
+
+        fs::write(mods.join("replaced"), "mods").unwrap();
+        fs::remove_file(base.join("gone")).unwrap();
+        fs::write(mods.join("new"), "new").unwrap();
+        fs::write(output.join("foreign"), "foreign").unwrap();
+        let report = sync();
+        assert_eq!(
+            (
+                report.confirmed,
+                report.linked,
+                report.relinked,
+                report.stale_removed
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(report.foreign_kept, [PathBuf::from("foreign")]);
+        assert!(report.errors.is_empty());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["relinked"], 1);
+        assert_eq!(json["foreign_kept"], serde_json::json!(["foreign"]));
+        assert_eq!(
+            report.to_string(),
+            "Synced output: 1 confirmed, 1 linked, 1 relinked, 1 stale removed, 0 vanished, \
+             1 foreign kept, 0 adopted, 0 deleted, 0 errors"
+        );
+    }
+}
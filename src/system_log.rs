@@ -0,0 +1,193 @@
+use failure::{err_msg, Error};
+use log::{LevelFilter, Metadata, Record};
+
+/// Where the log of the system goes, for whoever looks after the machine rather than
+/// the overlay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Sink {
+    /// The local syslog, with the facility of that name.
+    Syslog(String),
+    /// The Windows Event Log.
+    EventLog,
+}
+
+/// The overlay's records at `level` and above in the log of the system, as `tag`, or
+/// `overlay-<label>` if it has a label.
+pub(crate) struct SystemLog {
+    level: LevelFilter,
+    #[cfg(unix)]
+    _tag: std::ffi::CString,
+    /// The handle of the event source, as an integer so it can be shared.
+    #[cfg(windows)]
+    source: isize,
+}
+
+/// The name the overlay logs as.
+fn tag(label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("overlay-{}", label),
+        None => "overlay".to_string(),
+    }
+}
+
+impl SystemLog {
+    /// Opens `sink`, erring if there is no such thing here or it isn't running.
+    pub(crate) fn open(
+        sink: &Sink,
+        label: Option<&str>,
+        level: LevelFilter,
+    ) -> Result<Self, Error> {
+        match sink {
+            Sink::Syslog(facility) => SystemLog::syslog(facility, label, level),
+            Sink::EventLog => SystemLog::event_log(label, level),
+        }
+    }
+
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    pub(crate) fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    #[cfg(unix)]
+    fn syslog(facility: &str, label: Option<&str>, level: LevelFilter) -> Result<Self, Error> {
+        use std::ffi::CString;
+        use std::fs;
+        use std::os::unix::fs::FileTypeExt;
+
+        /// Where syslog listens on Linux, macOS and the BSDs.
+        const SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+        let facility = self::facility(facility)
+            .ok_or_else(|| failure::format_err!("there is no syslog facility {}", facility))?;
+        let listening = |socket: &&str| {
+            fs::metadata(socket).is_ok_and(|metadata| metadata.file_type().is_socket())
+        };
+        if !SOCKETS.iter().any(listening) {
+            return Err(err_msg("syslog isn't running"));
+        }
+        let tag = CString::new(tag(label))?;
+        // The tag has to stay where it is for as long as syslog is used.
+        unsafe { libc::openlog(tag.as_ptr(), libc::LOG_PID | libc::LOG_NDELAY, facility) };
+        Ok(SystemLog { level, _tag: tag })
+    }
+
+    #[cfg(not(unix))]
+    fn syslog(_facility: &str, _label: Option<&str>, _level: LevelFilter) -> Result<Self, Error> {
+        Err(err_msg(
+            "syslog is only on Unix, the Event Log is used with --log-eventlog",
+        ))
+    }
+
+    #[cfg(windows)]
+    fn event_log(label: Option<&str>, level: LevelFilter) -> Result<Self, Error> {
+        use std::ptr;
+        use windows_sys::Win32::System::EventLog::RegisterEventSourceW;
+
+        let name: Vec<u16> = tag(label).encode_utf16().chain(Some(0)).collect();
+        let source = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if source.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(SystemLog {
+            level,
+            source: source as isize,
+        })
+    }
+
+    #[cfg(not(windows))]
+    fn event_log(_label: Option<&str>, _level: LevelFilter) -> Result<Self, Error> {
+        Err(err_msg(
+            "the Event Log is only on Windows, syslog is used with --log-syslog",
+        ))
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn log(&self, record: &Record) {
+        use log::Level;
+        use std::ffi::CString;
+
+        let priority = match record.level() {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG,
+        };
+        // Nothing in the message can be taken for a format.
+        let format = CString::new("%s").unwrap();
+        if let Ok(message) = CString::new(record.args().to_string()) {
+            unsafe { libc::syslog(priority, format.as_ptr(), message.as_ptr()) };
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn log(&self, record: &Record) {
+        use log::Level;
+        use std::ptr;
+        use windows_sys::Win32::System::EventLog::{
+            ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        };
+
+        let kind = match record.level() {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message: Vec<u16> = record
+            .args()
+            .to_string()
+            .encode_utf16()
+            .chain(Some(0))
+            .collect();
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.source as _,
+                kind,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            )
+        };
+    }
+}
+
+impl Drop for SystemLog {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe { libc::closelog() };
+    }
+
+    #[cfg(windows)]
+    fn drop(&mut self) {
+        use windows_sys::Win32::System::EventLog::DeregisterEventSource;
+
+        unsafe { DeregisterEventSource(self.source as _) };
+    }
+}
+
+/// The syslog facility named `name`, like `daemon` or `local0`.
+#[cfg(unix)]
+pub(crate) fn facility(name: &str) -> Option<libc::c_int> {
+    Some(match name {
+        "user" => libc::LOG_USER,
+        "daemon" => libc::LOG_DAEMON,
+        "auth" => libc::LOG_AUTH,
+        "syslog" => libc::LOG_SYSLOG,
+        "local0" => libc::LOG_LOCAL0,
+        "local1" => libc::LOG_LOCAL1,
+        "local2" => libc::LOG_LOCAL2,
+        "local3" => libc::LOG_LOCAL3,
+        "local4" => libc::LOG_LOCAL4,
+        "local5" => libc::LOG_LOCAL5,
+        "local6" => libc::LOG_LOCAL6,
+        "local7" => libc::LOG_LOCAL7,
+        _ => return None,
+    })
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Holds back events for an (input, path) that was acted on within the last `window`.
+///
+/// Held back paths are only reported again once they have been quiet for a whole window,
+/// at which point the caller processes whatever state the file ended up in.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    window: Duration,
+    last: HashMap<(usize, PathBuf), Instant>,
+    deferred: HashMap<(usize, PathBuf), Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(window: Duration) -> Self {
+        Throttle {
+            window,
+            last: HashMap::new(),
+            deferred: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if an event for `path` should be processed right away, and `false`
+    /// if it has been deferred.
+    pub(crate) fn admit(&mut self, index: usize, path: &Path, now: Instant) -> bool {
+        let key = (index, path.to_path_buf());
+
+        if let Some(due) = self.deferred.get_mut(&key) {
+            *due = now + self.window;
+            return false;
+        }
+
+        match self.last.get(&key) {
+            Some(last) if now.duration_since(*last) < self.window => {
+                self.deferred.insert(key, now + self.window);
+                false
+            }
+            _ => {
+                self.last.insert(key, now);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.deferred.values().min().cloned()
+    }
+
+    /// Removes and returns the deferred paths whose quiet period has elapsed.
+    pub(crate) fn take_due(&mut self, now: Instant) -> Vec<(usize, PathBuf)> {
+        let due: Vec<(usize, PathBuf)> = self
+            .deferred
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &due {
+            self.deferred.remove(key);
+            self.last.insert(key.clone(), now);
+        }
+
+        let window = self.window;
+        self.last
+            .retain(|_, last| now.duration_since(*last) < window);
+
+        due
+    }
+
+    /// Removes and returns every deferred path, regardless of how recently it changed.
+    pub(crate) fn take_all(&mut self) -> Vec<(usize, PathBuf)> {
+        self.last.clear();
+        self.deferred.drain().map(|(key, _)| key).collect()
+    }
+}
@@ -0,0 +1,329 @@
+use crate::fs_ops::{FileOp, FileOps, RealFs};
+use crate::identity::FileIdentity;
+use crate::input::Input;
+use crate::{Event, EventType, InputId, Overlay, ProcessedAction, Provider};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{log_enabled, trace, Level};
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The log target of decision traces, which are logged at trace level as JSON.
+pub const DECISIONS: &str = "overlay::decisions";
+
+/// What the overlay decided about one event, and what it did to the output because of
+/// it. See `Overlay::trace_decisions`.
+///
+/// An event about a directory leads to one for everything in it, each traced on its own.
+/// The trace of the directory's event only has what was done for the directory itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DecisionTrace {
+    pub input: InputId,
+    pub label: Option<String>,
+    /// The order in which the watchers observed their events, across all inputs. The events
+    /// an event leads to, like those of the files in a directory, share its number.
+    pub seq: u64,
+    /// When the event was observed, which may be well before it was handled.
+    pub observed: SystemTime,
+    pub kind: EventKind,
+    /// The path the event is about, the new one of a rename.
+    pub path: PathBuf,
+    /// The old path of a rename.
+    pub from: Option<PathBuf>,
+    /// Every input providing the path once the event was handled, as `Overlay::providers`
+    /// returns them.
+    pub providers: Vec<Provider>,
+    /// The input whose file is the one at the path, if any.
+    pub winner: Option<InputId>,
+    /// What the event did to the output. A file that appeared behind the winner's is
+    /// `Ignored`, for example.
+    pub outcome: ProcessedAction,
+    pub actions: Vec<TracedAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Create,
+    Remove,
+    Rename,
+    PermissionsChanged,
+    Error,
+    WatcherFailed,
+}
+
+/// A file operation performed while handling an event, and how it went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TracedAction {
+    pub op: FileOp,
+    /// The path it changed, the destination of links, copies and renames.
+    pub path: PathBuf,
+    pub error: Option<String>,
+}
+
+/// The actions of the event being handled, if one is being traced.
+pub(crate) type Actions = Arc<Mutex<Option<Vec<TracedAction>>>>;
+
+/// A trace that is filled in once its event is handled, and the actions and outcome of the
+/// event it is part of, which are recorded again after.
+#[derive(Debug)]
+pub(crate) struct PendingTrace {
+    pub(crate) trace: DecisionTrace,
+    pub(crate) outer: Option<Vec<TracedAction>>,
+    /// What the event it is part of did until then, which this one's outcome adds to.
+    pub(crate) outer_outcome: ProcessedAction,
+}
+
+/// Records what the file operations it wraps change while an event is traced. Only
+/// put in place once something wants the traces.
+#[derive(Debug)]
+pub(crate) struct TracingFs {
+    inner: Box<dyn FileOps>,
+    actions: Actions,
+}
+
+impl TracingFs {
+    pub(crate) fn new(inner: Box<dyn FileOps>, actions: Actions) -> Self {
+        TracingFs { inner, actions }
+    }
+
+    fn record<T>(&self, op: FileOp, path: &Path, result: io::Result<T>) -> io::Result<T> {
+        if let Some(actions) = self.actions.lock().unwrap().as_mut() {
+            actions.push(TracedAction {
+                op,
+                path: path.to_path_buf(),
+                error: result.as_ref().err().map(ToString::to_string),
+            });
+        }
+        result
+    }
+}
+
+impl FileOps for TracingFs {
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::HardLink, to, self.inner.hard_link(from, to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveFile, path, self.inner.remove_file(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::CreateDirAll, path, self.inner.create_dir_all(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, path, self.inner.remove_dir(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, path, self.inner.remove_dir_all(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::Rename, to, self.inner.rename(from, to))
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_file(a, b)
+    }
+
+    fn link_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.record(FileOp::LinkDir, link, self.inner.link_dir(target, link))
+    }
+
+    fn unlink_dir(&self, link: &Path) -> io::Result<()> {
+        self.record(FileOp::RemoveDir, link, self.inner.unlink_dir(link))
+    }
+
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(link)
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let result = self.inner.copy_permissions(from, to);
+        self.record(FileOp::CopyPermissions, to, result)
+    }
+
+    fn create_empty(&self, path: &Path) -> io::Result<()> {
+        self.record(FileOp::CreateEmpty, path, self.inner.create_empty(path))
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(FileOp::Copy, to, self.inner.copy(from, to))
+    }
+
+    fn copy_with_progress(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> io::Result<()> {
+        let result = self.inner.copy_with_progress(from, to, progress);
+        self.record(FileOp::Copy, to, result)
+    }
+
+    fn same_copy(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_copy(a, b)
+    }
+
+    fn same_contents(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        self.inner.same_contents(a, b)
+    }
+
+    fn identity(&self, path: &Path) -> io::Result<FileIdentity> {
+        self.inner.identity(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.record(FileOp::Write, path, self.inner.write(path, contents))
+    }
+}
+
+/// What wants the traces of decisions, and what the file operations did for the event
+/// being traced.
+#[derive(Debug, Default)]
+pub(crate) struct Traces {
+    /// Where the traces of decisions go, see `trace_decisions`.
+    pub(crate) senders: Vec<Sender<DecisionTrace>>,
+    /// What the file operations did for the event being traced, once anything wants the
+    /// traces.
+    actions: Option<Actions>,
+}
+
+impl Overlay {
+    /// Sends a `DecisionTrace` for every event handled from now on to the receiver it
+    /// returns, for as long as it is kept. They are logged at trace level to `DECISIONS`
+    /// too, if that is enabled; until either wants them, none are put together.
+    pub fn trace_decisions(&mut self) -> Receiver<DecisionTrace> {
+        let (tx, rx) = unbounded();
+        self.traces.senders.push(tx);
+        rx
+    }
+
+    /// Starts tracing the decision about `event`, if anything wants the traces.
+    pub(crate) fn begin_trace(&mut self, event: &EventType) -> Option<PendingTrace> {
+        if self.traces.senders.is_empty() && !log_enabled!(target: DECISIONS, Level::Trace) {
+            return None;
+        }
+        let (kind, path, from) = match &event.event {
+            Event::Create(path) => (EventKind::Create, path.clone(), None),
+            Event::Remove(path) => (EventKind::Remove, path.clone(), None),
+            Event::Rename(from, to) => (EventKind::Rename, to.clone(), Some(from.clone())),
+            Event::PermissionsChanged(path) => (EventKind::PermissionsChanged, path.clone(), None),
+            Event::Error(_, path) => (EventKind::Error, path.clone().unwrap_or_default(), None),
+            Event::WatcherFailed(_) => (EventKind::WatcherFailed, PathBuf::new(), None),
+            // Nothing is decided.
+            Event::Polled(_) => return None,
+        };
+        let actions = match &self.traces.actions {
+            Some(actions) => actions.clone(),
+            None => {
+                let actions = Actions::default();
+                let fs = std::mem::replace(&mut self.fs, Arc::new(RealFs));
+                self.fs = Arc::new(TracingFs::new(Box::new(fs), actions.clone()));
+                self.traces.actions = Some(actions.clone());
+                actions
+            }
+        };
+        let outer = actions.lock().unwrap().replace(vec![]);
+        let outer_outcome = std::mem::take(&mut self.processed);
+
+        let trace = DecisionTrace {
+            input: InputId::of(event.index),
+            label: self.inputs[event.index].label.clone(),
+            seq: event.observation.seq,
+            observed: event.observation.at,
+            kind,
+            path,
+            from,
+            providers: vec![],
+            winner: None,
+            outcome: ProcessedAction::Ignored,
+            actions: vec![],
+        };
+        Some(PendingTrace {
+            trace,
+            outer,
+            outer_outcome,
+        })
+    }
+
+    /// Fills in what was decided and done, and hands the trace to whatever wants it.
+    pub(crate) fn end_trace(&mut self, pending: PendingTrace) {
+        let PendingTrace {
+            mut trace,
+            outer,
+            outer_outcome,
+        } = pending;
+        trace.outcome = self.processed;
+        self.processed(outer_outcome);
+        if let Some(actions) = &self.traces.actions {
+            let mut actions = actions.lock().unwrap();
+            trace.actions = std::mem::replace(&mut *actions, outer).unwrap_or_default();
+        }
+        trace.providers = self.providers(&trace.path);
+        trace.winner = self.materialized(&trace.path).map(Input::id);
+
+        if log_enabled!(target: DECISIONS, Level::Trace) {
+            if let Ok(json) = serde_json::to_string(&trace) {
+                trace!(target: DECISIONS, "{}", json);
+            }
+        }
+        self.traces
+            .senders
+            .retain(|traces| traces.send(trace.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Harness;
+
+    #[test]
+    fn a_file_behind_the_winners_is_traced_as_ignored_by_whom() {
+        let mut harness = Harness::new("trace-shadowed", &[10, 20]);
+        let traces = harness.overlay.trace_decisions();
+        harness.create(1, "x");
+        harness.create(0, "x");
+
+        let traces: Vec<DecisionTrace> = traces.try_iter().collect();
+        assert_eq!(traces.len(), 2);
+        let (linked, ignored) = (&traces[0], &traces[1]);
+        assert_eq!(linked.outcome, ProcessedAction::Linked);
+        assert_eq!(linked.winner, Some(InputId::of(1)));
+        let output_file = harness.output.join("x");
+        assert!(linked
+            .actions
+            .iter()
+            .any(|action| action.op == FileOp::HardLink && action.path == output_file));
+
+        assert_eq!(ignored.kind, EventKind::Create);
+        assert_eq!(ignored.input, InputId::of(0));
+        assert_eq!(ignored.path, Path::new("x"));
+        assert_eq!(ignored.outcome, ProcessedAction::Ignored);
+        assert_eq!(ignored.winner, Some(InputId::of(1)));
+        assert!(ignored.actions.is_empty(), "{:?}", ignored.actions);
+        let providers: Vec<(InputId, u32)> = ignored
+            .providers
+            .iter()
+            .map(|provider| (provider.input, provider.priority))
+            .collect();
+        assert_eq!(providers, [(InputId::of(1), 20), (InputId::of(0), 10)]);
+    }
+}
@@ -0,0 +1,649 @@
+use crate::audit::AuditAction;
+use crate::fs_ops::FileOps;
+use crate::ledger::Ledger;
+use crate::probe::crosses_devices;
+use crate::{canonical, error_kind, ConfigError, Overlay};
+use failure::{err_msg, format_err, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The list of what is in the trash, in the trash directory.
+const JOURNAL: &str = ".overlay-trash.jsonl";
+
+/// How long the files in the trash are kept, and how much of them. They are kept forever
+/// by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrashRetention {
+    /// Files trashed longer ago are removed for good.
+    pub max_age: Option<Duration>,
+    /// The oldest files are removed for good until the others have no more bytes.
+    pub max_size: Option<u64>,
+}
+
+/// A file the overlay moved from the output into the trash, rather than deleting it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashedFile {
+    /// Where it was in the output.
+    pub path: PathBuf,
+    /// Where it is in the trash, relative to it.
+    pub trashed: PathBuf,
+    pub at: SystemTime,
+    pub len: u64,
+}
+
+/// What `Overlay::undo_last` put back into the output.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UndoReport {
+    pub restored: Vec<PathBuf>,
+    /// The files that couldn't be, and why. They stay in the trash.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Where the files the overlay displaces from the output go, see
+/// `OverlayBuilder::trash`. Each is at its path in the output, with the time it was
+/// trashed appended if an earlier one is there already, and a count after that if one
+/// trashed at the same time is too.
+#[derive(Debug)]
+pub(crate) struct Trash {
+    dir: PathBuf,
+    retention: TrashRetention,
+    /// Oldest first.
+    files: Vec<TrashedFile>,
+}
+
+impl Trash {
+    pub(crate) fn open(
+        fs: &dyn FileOps,
+        dir: &Path,
+        retention: TrashRetention,
+    ) -> Result<Self, Error> {
+        let journal = dir.join(JOURNAL);
+        let contents = match fs.read(&journal) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut files: Vec<TrashedFile> = vec![];
+        for (number, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            files.push(
+                serde_json::from_slice(line).map_err(|e| {
+                    format_err!("{}, line {}: {}", journal.display(), number + 1, e)
+                })?,
+            );
+        }
+        // Each is listed before it is moved there, see `put`, the moves that never
+        // happened are dropped.
+        files.retain(|file| fs.exists(&dir.join(&file.trashed)));
+        Ok(Trash {
+            dir: dir.to_path_buf(),
+            retention,
+            files,
+        })
+    }
+
+    /// Moves `file`, which is at `path` in the output, into the trash, returning where it
+    /// is there.
+    pub(crate) fn put(
+        &mut self,
+        fs: &dyn FileOps,
+        file: &Path,
+        path: &Path,
+    ) -> io::Result<PathBuf> {
+        let at = SystemTime::now();
+        let trashed = self.free_name(fs, path, at);
+        let to = self.dir.join(&trashed);
+        if let Some(parent) = to.parent() {
+            fs.create_dir_all(parent)?;
+        }
+        let len = fs.identity(file).map_or(0, |identity| identity.len);
+
+        // Listed first, so a file moved there is never lost track of, even if the overlay
+        // stops right after.
+        self.files.push(TrashedFile {
+            path: path.to_path_buf(),
+            trashed,
+            at,
+            len,
+        });
+        let moved = self.save(fs).and_then(|()| move_file(fs, file, &to));
+        if let Err(e) = moved {
+            self.files.pop();
+            // `open` drops it anyway if this fails too.
+            let _ = self.save(fs);
+            return Err(e);
+        }
+        Ok(to)
+    }
+
+    /// Where in the trash the file at `path` in the output goes, at a name no file in the
+    /// trash has, nor one the journal lists.
+    fn free_name(&self, fs: &dyn FileOps, path: &Path, at: SystemTime) -> PathBuf {
+        let taken = |trashed: &Path| {
+            fs.exists(&self.dir.join(trashed))
+                || self.files.iter().any(|file| file.trashed == trashed)
+        };
+        if !taken(path) {
+            return path.to_path_buf();
+        }
+
+        let millis = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = path.file_name().unwrap_or_default();
+        let mut trashed = path.to_path_buf();
+        for count in 0.. {
+            let mut suffixed = name.to_owned();
+            suffixed.push(format!(".{}", millis));
+            if count > 0 {
+                suffixed.push(format!(".{}", count));
+            }
+            trashed.set_file_name(suffixed);
+            if !taken(&trashed) {
+                break;
+            }
+        }
+        trashed
+    }
+
+    /// The `count` files trashed last, the last first.
+    pub(crate) fn latest(&self, count: usize) -> Vec<TrashedFile> {
+        self.files.iter().rev().take(count).cloned().collect()
+    }
+
+    /// Moves the trashed `file` back to `to`, in the output.
+    pub(crate) fn restore(
+        &mut self,
+        fs: &dyn FileOps,
+        file: &TrashedFile,
+        to: &Path,
+    ) -> io::Result<()> {
+        if let Some(parent) = to.parent() {
+            fs.create_dir_all(parent)?;
+        }
+        let from = self.dir.join(&file.trashed);
+        move_file(fs, &from, to)?;
+        self.forget(fs, std::slice::from_ref(&file.trashed))
+    }
+
+    /// Removes the files that are too old, or too many, for the retention, returning how
+    /// many there were.
+    pub(crate) fn enforce_retention(&mut self, fs: &dyn FileOps) -> Result<usize, Error> {
+        let now = SystemTime::now();
+        let expired = |file: &TrashedFile| {
+            self.retention
+                .max_age
+                .is_some_and(|max| now.duration_since(file.at).unwrap_or_default() > max)
+        };
+        let mut size: u64 = self.files.iter().map(|file| file.len).sum();
+        let mut removed = vec![];
+        for file in &self.files {
+            if expired(file) || self.retention.max_size.is_some_and(|max| size > max) {
+                size -= file.len;
+                removed.push(file.trashed.clone());
+            }
+        }
+        for trashed in &removed {
+            match fs.remove_file(&self.dir.join(trashed)) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.forget(fs, &removed)?;
+        Ok(removed.len())
+    }
+
+    /// Drops the `trashed` files from the list, and the directories they leave empty.
+    fn forget(&mut self, fs: &dyn FileOps, trashed: &[PathBuf]) -> io::Result<()> {
+        if trashed.is_empty() {
+            return Ok(());
+        }
+        self.files.retain(|file| !trashed.contains(&file.trashed));
+        for path in trashed {
+            let mut dir = path.parent();
+            while let Some(parent) = dir.filter(|dir| !dir.as_os_str().is_empty()) {
+                if fs.remove_dir(&self.dir.join(parent)).is_err() {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+        self.save(fs)
+    }
+
+    /// Writes the list to the journal, which is replaced rather than written to, so a
+    /// crash never leaves half of it.
+    fn save(&self, fs: &dyn FileOps) -> io::Result<()> {
+        let mut contents = vec![];
+        for file in &self.files {
+            serde_json::to_writer(&mut contents, file)?;
+            contents.push(b'\n');
+        }
+        fs.write(&self.dir.join(JOURNAL), &contents)
+    }
+}
+
+/// Renames `from` to `to`, or copies it there and removes it if they are on different
+/// volumes. The copy is removed again if `from` can't be.
+fn move_file(fs: &dyn FileOps, from: &Path, to: &Path) -> io::Result<()> {
+    match fs.rename(from, to) {
+        Err(ref e) if crosses_devices(e) => {
+            fs.copy(from, to)?;
+            fs.remove_file(from).inspect_err(|_| {
+                let _ = fs.remove_file(to);
+            })
+        }
+        result => result,
+    }
+}
+
+impl Overlay {
+    /// Whether removing the file at `path` in the output would lose it, as it is neither
+    /// the one the overlay put there nor the file of one of its providers. Those are moved
+    /// into the trash, if there is one.
+    pub(crate) fn displaces(&self, path: &Path) -> bool {
+        self.trash.is_some() && !self.dry_run && !self.owned(path) && !self.provided(path)
+    }
+
+    /// Removes the file at `path` in the output, or moves it into the trash if that would
+    /// lose it. Returns `true` if it was moved.
+    pub(crate) fn discard(&mut self, path: &Path) -> io::Result<bool> {
+        let output_file = self.output.join(path);
+        if !self.displaces(path) {
+            return self.remove_file(&output_file).map(|()| false);
+        }
+
+        let trash = self.trash.as_mut().unwrap();
+        let result = trash.put(&*self.fs, &output_file, path);
+        let trashed = match &result {
+            Ok(trashed) => trashed.clone(),
+            Err(_) => trash.dir.join(path),
+        };
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit_trash(AuditAction::Trash, path, &trashed, error.as_deref());
+        if result.is_ok() {
+            info!(
+                "Moved {} into the trash, to {}",
+                path.display(),
+                trashed.display()
+            );
+        }
+        result.map(|_| true)
+    }
+
+    /// Moves the file at `path` in the output into the trash before input `index` has its
+    /// own put there. Returns `false` if it couldn't be, which leaves it there.
+    pub(crate) fn make_room(&mut self, path: &Path, index: usize) -> bool {
+        match self.discard(path) {
+            Ok(_) => {
+                say!(self.line, Info, " MOVED INTO THE TRASH,");
+                self.ledger.remove(path);
+                self.links.remove(path);
+                true
+            }
+            Err(e) => {
+                say!(self.line, Error, " NOT MOVED INTO THE TRASH: {}!", e);
+                self.failed(Some(path), Some(index), error_kind(&e), e.to_string());
+                let error = format!("couldn't move {} into the trash: {}", path.display(), e);
+                self.note(|report| report.errors.push(error));
+                false
+            }
+        }
+    }
+
+    /// Puts the `count` files moved into the trash last back where they were in the
+    /// output, the last first. The overlay treats them as foreign files from then on, and
+    /// its own files they replace are removed.
+    pub fn undo_last(&mut self, count: usize) -> Result<UndoReport, Error> {
+        let _lock = self.lock()?;
+        self.ledger = Ledger::load(&self.output)?;
+        self.open_trash()?;
+        let report = self.restore_last(count)?;
+        self.save_ledger();
+        if let Some(audit) = self.audit.as_mut() {
+            audit.flush();
+        }
+        Ok(report)
+    }
+
+    /// Does what `undo_last` does, on the output the overlay already has locked.
+    pub(crate) fn restore_last(&mut self, count: usize) -> Result<UndoReport, Error> {
+        let latest = match &self.trash {
+            Some(trash) => trash.latest(count),
+            None => return Err(err_msg("the overlay has no trash to undo from")),
+        };
+
+        let mut report = UndoReport::default();
+        for file in latest {
+            match self.restore_trashed(&file) {
+                Ok(()) => {
+                    info!("Restored {} from the trash", file.path.display());
+                    report.restored.push(file.path);
+                }
+                Err(e) => {
+                    warn!("Could not restore {}: {}", file.path.display(), e);
+                    report.failed.push((file.path, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn restore_trashed(&mut self, file: &TrashedFile) -> Result<(), Error> {
+        let path = &file.path;
+        let output_file = self.output.join(path);
+        if self.fs.exists(&output_file) {
+            if self.fs.is_dir(&output_file) || !self.removable(path) {
+                return Err(format_err!(
+                    "{} is in the output again, and isn't the overlay's to replace",
+                    output_file.display()
+                ));
+            }
+            self.remove_link(path)?;
+        }
+        self.ledger.remove(path);
+        self.links.remove(path);
+        self.merging.merged.remove(path);
+
+        let trash = self.trash.as_mut().unwrap();
+        let trashed = trash.dir.join(&file.trashed);
+        let result = trash.restore(&*self.fs, file, &output_file);
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit_trash(AuditAction::Restore, path, &trashed, error.as_deref());
+        Ok(result?)
+    }
+
+    /// Removes what the trash retention says is no longer kept. Run on every tick.
+    pub(crate) fn enforce_trash_retention(&mut self) {
+        let trash = match self.trash.as_mut() {
+            Some(trash) if !self.dry_run => trash,
+            _ => return,
+        };
+        match trash.enforce_retention(&*self.fs) {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} files from the trash for good", removed),
+            Err(e) => warn!("Could not clean up the trash: {}", e),
+        }
+    }
+
+    /// Writes a move into or out of the trash to the audit log and the event stream, with
+    /// the input whose file is at `path` now, if there is one.
+    fn audit_trash(
+        &mut self,
+        action: AuditAction,
+        path: &Path,
+        trashed: &Path,
+        error: Option<&str>,
+    ) {
+        let index = self
+            .input_map
+            .get(path)
+            .and_then(BinaryHeap::peek)
+            .map(|input| input.index);
+        let inputs = &self.inputs;
+        let input = index.map(|index| (index, inputs[index].label.as_deref()));
+        for log in self.audit.iter_mut().chain(self.stream.iter_mut()) {
+            log.record_trash(action, path, trashed, input, error);
+        }
+    }
+
+    /// Reads what is in the trash, if there is one.
+    pub(crate) fn open_trash(&mut self) -> Result<(), Error> {
+        let (dir, retention) = match &self.trash_dir {
+            Some(trash) => trash,
+            None => return Ok(()),
+        };
+        let (trash, output) = (canonical(dir), canonical(&self.output));
+        if trash.starts_with(&output) || output.starts_with(&trash) {
+            return Err(ConfigError::TrashInOutput {
+                trash: dir.clone(),
+                output: self.output.clone(),
+            }
+            .into());
+        }
+        self.trash = Some(Trash::open(&*self.fs, dir, *retention)?);
+        Ok(())
+    }
+
+    /// Moves the files of the output the overlay would delete or replace, which neither it
+    /// put there nor an input has, into `dir` rather than deleting them, keeping them as
+    /// `retention` says. `Overlay::undo_last` puts them back. It has to be outside of the
+    /// output.
+    pub fn set_trash(&mut self, dir: PathBuf, retention: TrashRetention) {
+        self.trash_dir = Some((dir, retention));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::{FileOp, MemoryFs};
+    use crate::tests::Harness;
+    use crate::ForeignFiles;
+
+    fn open(fs: &MemoryFs, retention: TrashRetention) -> Trash {
+        Trash::open(fs, Path::new("/trash"), retention).unwrap()
+    }
+
+    #[test]
+    fn put_moves_the_file_and_lists_it() {
+        let fs = MemoryFs::new();
+        fs.write_file("/output/a/x", b"contents");
+        let mut trash = open(&fs, TrashRetention::default());
+
+        let to = trash
+            .put(&fs, Path::new("/output/a/x"), Path::new("a/x"))
+            .unwrap();
+        assert_eq!(to, Path::new("/trash/a/x"));
+        assert!(!fs.exists(Path::new("/output/a/x")));
+        assert_eq!(fs.read(&to).unwrap(), b"contents");
+
+        let latest = trash.latest(1);
+        assert_eq!(latest[0].path, Path::new("a/x"));
+        assert_eq!(latest[0].len, 8);
+        let reopened = open(&fs, TrashRetention::default());
+        assert_eq!(reopened.files, trash.files);
+    }
+
+    #[test]
+    fn names_taken_get_the_time_and_then_a_count() {
+        let fs = MemoryFs::new();
+        let mut trash = open(&fs, TrashRetention::default());
+        let at = UNIX_EPOCH + Duration::from_millis(1000);
+        let path = Path::new("a/x");
+        assert_eq!(trash.free_name(&fs, path, at), path);
+
+        fs.create_file("/trash/a/x");
+        assert_eq!(trash.free_name(&fs, path, at), Path::new("a/x.1000"));
+        fs.create_file("/trash/a/x.1000");
+        assert_eq!(trash.free_name(&fs, path, at), Path::new("a/x.1000.1"));
+
+        // Listed, if not there, is taken too.
+        trash.files.push(TrashedFile {
+            path: path.to_path_buf(),
+            trashed: PathBuf::from("a/x.1000.1"),
+            at,
+            len: 0,
+        });
+        assert_eq!(trash.free_name(&fs, path, at), Path::new("a/x.1000.2"));
+    }
+
+    #[test]
+    fn puts_of_the_same_path_keep_every_file() {
+        let fs = MemoryFs::new();
+        let mut trash = open(&fs, TrashRetention::default());
+        let mut trashed = vec![];
+        for contents in [b"1", b"2", b"3"] {
+            fs.write_file("/output/x", contents);
+            trashed.push(
+                trash
+                    .put(&fs, Path::new("/output/x"), Path::new("x"))
+                    .unwrap(),
+            );
+        }
+        let contents: Vec<_> = trashed.iter().map(|to| fs.read(to).unwrap()).collect();
+        assert_eq!(contents, [b"1", b"2", b"3"]);
+    }
+
+    #[test]
+    fn failing_move_leaves_the_file_unlisted() {
+        let fs = MemoryFs::new();
+        fs.create_file("/output/x");
+        fs.fail(FileOp::Rename, "/output/x", io::ErrorKind::PermissionDenied);
+        let mut trash = open(&fs, TrashRetention::default());
+
+        assert!(trash
+            .put(&fs, Path::new("/output/x"), Path::new("x"))
+            .is_err());
+        assert!(fs.exists(Path::new("/output/x")));
+        assert!(trash.files.is_empty());
+        assert!(open(&fs, TrashRetention::default()).files.is_empty());
+    }
+
+    #[test]
+    fn failing_journal_leaves_the_file_where_it_was() {
+        let fs = MemoryFs::new();
+        fs.create_file("/output/x");
+        fs.fail(
+            FileOp::Write,
+            "/trash/.overlay-trash.jsonl",
+            io::ErrorKind::PermissionDenied,
+        );
+        let mut trash = open(&fs, TrashRetention::default());
+
+        assert!(trash
+            .put(&fs, Path::new("/output/x"), Path::new("x"))
+            .is_err());
+        assert!(fs.exists(Path::new("/output/x")));
+        assert!(!fs.exists(Path::new("/trash/x")));
+        assert!(trash.files.is_empty());
+    }
+
+    #[test]
+    fn journal_entries_of_moves_that_never_happened_are_dropped() {
+        let fs = MemoryFs::new();
+        fs.create_dir("/trash");
+        let mut trash = open(&fs, TrashRetention::default());
+        trash.files.push(TrashedFile {
+            path: PathBuf::from("x"),
+            trashed: PathBuf::from("x"),
+            at: SystemTime::now(),
+            len: 0,
+        });
+        trash.save(&fs).unwrap();
+        assert!(open(&fs, TrashRetention::default()).files.is_empty());
+    }
+
+    #[test]
+    fn moves_across_volumes_copy_and_remove() {
+        let fs = MemoryFs::new();
+        fs.write_file("/output/x", b"contents");
+        fs.fail(FileOp::Rename, "/output/x", io::ErrorKind::CrossesDevices);
+        let mut trash = open(&fs, TrashRetention::default());
+
+        let to = trash
+            .put(&fs, Path::new("/output/x"), Path::new("x"))
+            .unwrap();
+        assert!(!fs.exists(Path::new("/output/x")));
+        assert_eq!(fs.read(&to).unwrap(), b"contents");
+        assert_eq!(trash.files.len(), 1);
+    }
+
+    #[test]
+    fn moves_across_volumes_that_cant_remove_leave_no_copy() {
+        let fs = MemoryFs::new();
+        fs.create_file("/output/x");
+        fs.fail(FileOp::Rename, "/output/x", io::ErrorKind::CrossesDevices);
+        fs.fail(
+            FileOp::RemoveFile,
+            "/output/x",
+            io::ErrorKind::PermissionDenied,
+        );
+        let mut trash = open(&fs, TrashRetention::default());
+
+        assert!(trash
+            .put(&fs, Path::new("/output/x"), Path::new("x"))
+            .is_err());
+        assert!(fs.exists(Path::new("/output/x")));
+        assert!(!fs.exists(Path::new("/trash/x")));
+        assert!(trash.files.is_empty());
+    }
+
+    #[test]
+    fn retention_removes_files_too_old() {
+        let fs = MemoryFs::new();
+        let retention = TrashRetention {
+            max_age: Some(Duration::from_secs(3600)),
+            max_size: None,
+        };
+        let mut trash = open(&fs, retention);
+        for name in ["old", "new"] {
+            fs.create_file(Path::new("/output").join(name));
+            trash
+                .put(&fs, &Path::new("/output").join(name), Path::new(name))
+                .unwrap();
+        }
+        trash.files[0].at -= Duration::from_secs(7200);
+
+        assert_eq!(trash.enforce_retention(&fs).unwrap(), 1);
+        assert!(!fs.exists(Path::new("/trash/old")));
+        assert!(fs.exists(Path::new("/trash/new")));
+        let kept = open(&fs, retention).files;
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].path, Path::new("new"));
+    }
+
+    #[test]
+    fn retention_removes_the_oldest_files_beyond_the_size() {
+        let fs = MemoryFs::new();
+        let retention = TrashRetention {
+            max_age: None,
+            max_size: Some(25),
+        };
+        let mut trash = open(&fs, retention);
+        for name in ["a", "b", "c"] {
+            fs.write_file(Path::new("/output").join(name), &[0; 10]);
+            trash
+                .put(&fs, &Path::new("/output").join(name), Path::new(name))
+                .unwrap();
+        }
+
+        assert_eq!(trash.enforce_retention(&fs).unwrap(), 1);
+        assert!(!fs.exists(Path::new("/trash/a")));
+        let kept: Vec<_> = trash.files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(kept, [PathBuf::from("b"), PathBuf::from("c")]);
+        assert_eq!(trash.enforce_retention(&fs).unwrap(), 0);
+    }
+
+    #[test]
+    fn undo_puts_the_file_back_and_forgets_it() {
+        let mut harness = Harness::with("undo", &[0], |builder| {
+            builder.foreign_files(ForeignFiles::Delete).trash("/trash")
+        });
+        let output_file = harness.output.join("x");
+        harness.fs.write_file(&output_file, b"foreign");
+        harness.create(0, "x");
+        assert_eq!(harness.winner("x"), Some(0));
+        assert_eq!(harness.fs.read(Path::new("/trash/x")).unwrap(), b"foreign");
+
+        let report = harness.overlay.undo_last(1).unwrap();
+        assert_eq!(report.restored, [PathBuf::from("x")]);
+        assert!(report.failed.is_empty());
+        assert_eq!(harness.fs.read(&output_file).unwrap(), b"foreign");
+        assert!(!harness.fs.exists(Path::new("/trash/x")));
+        assert!(!harness.overlay.ledger.contains(Path::new("x")));
+        assert!(!harness.overlay.links.contains_key(Path::new("x")));
+        assert!(harness.overlay.trash.as_ref().unwrap().files.is_empty());
+        assert!(open(&harness.fs, TrashRetention::default())
+            .files
+            .is_empty());
+    }
+}
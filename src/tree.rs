@@ -0,0 +1,144 @@
+use crate::key::Folded;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// The tracked paths by the directory they are in, so what is beneath a directory is found
+/// without looking at every path. Each directory holds the tracked paths right in it, and
+/// the directories right in it with tracked paths beneath them.
+///
+/// Directories are kept by their case-folded form, so what is beneath one is that of every
+/// spelling of it. Lookups that care about the spelling check the paths they get.
+#[derive(Debug, Default)]
+pub(crate) struct PathTree {
+    children: FxHashMap<Folded, BTreeSet<PathBuf>>,
+}
+
+impl PathTree {
+    /// Adds `path`, which was just tracked and folds to `key`, and the directories above it.
+    pub(crate) fn insert(&mut self, path: &Path, key: &Folded) {
+        let mut child = path;
+        for (parent, key) in path.ancestors().skip(1).zip(key.parents()) {
+            let siblings = self.children.entry(key).or_default();
+            // Then the directories above are there already.
+            if !siblings.insert(child.to_path_buf()) {
+                break;
+            }
+            child = parent;
+        }
+    }
+
+    /// Removes `path`, which isn't tracked anymore, unless tracked paths are beneath it, and
+    /// the directories above it that nothing `tracked` is beneath anymore.
+    pub(crate) fn remove<F: Fn(&Path) -> bool>(&mut self, path: &Path, tracked: F) {
+        let mut child = path;
+        while let Some(parent) = child.parent() {
+            if (child != path && tracked(child)) || self.has_children(child) {
+                break;
+            }
+            let key = Folded::new(parent);
+            if let Some(siblings) = self.children.get_mut(&key) {
+                siblings.remove(child);
+                if siblings.is_empty() {
+                    self.children.remove(&key);
+                }
+            }
+            child = parent;
+        }
+    }
+
+    /// Whether anything is right in directory `dir`, spelled exactly so.
+    fn has_children(&self, dir: &Path) -> bool {
+        self.children
+            .get(&Folded::new(dir))
+            .is_some_and(|children| children.iter().any(|child| child.parent() == Some(dir)))
+    }
+
+    /// Every path beneath directory `dir`, in any spelling of it, tracked or a directory
+    /// above tracked paths.
+    pub(crate) fn beneath(&self, dir: &Path) -> Vec<&Path> {
+        let mut found = vec![];
+        let mut seen = FxHashSet::default();
+        let mut pending = vec![Folded::new(dir)];
+        while let Some(dir) = pending.pop() {
+            let children = match self.children.get(&dir) {
+                Some(children) if seen.insert(dir) => children,
+                _ => continue,
+            };
+            for child in children {
+                found.push(child.as_path());
+                pending.push(Folded::new(child));
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beneath(tree: &PathTree, dir: &str) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = tree
+            .beneath(Path::new(dir))
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn insert(tree: &mut PathTree, path: &str) {
+        tree.insert(Path::new(path), &Folded::new(Path::new(path)));
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn finds_what_is_beneath_a_directory() {
+        let mut tree = PathTree::default();
+        for path in ["a/b/x", "a/b/y", "a/z", "c/w"] {
+            insert(&mut tree, path);
+        }
+        assert_eq!(
+            beneath(&tree, "a"),
+            paths(&["a/b", "a/b/x", "a/b/y", "a/z"])
+        );
+        assert_eq!(beneath(&tree, "a/b"), paths(&["a/b/x", "a/b/y"]));
+        assert_eq!(beneath(&tree, "").len(), 7);
+        assert!(beneath(&tree, "a/z").is_empty());
+    }
+
+    #[test]
+    fn finds_every_spelling_of_a_directory() {
+        let mut tree = PathTree::default();
+        insert(&mut tree, "Data/x");
+        insert(&mut tree, "data/y");
+        assert_eq!(beneath(&tree, "DATA"), paths(&["Data/x", "data/y"]));
+    }
+
+    #[test]
+    fn removes_the_directories_left_empty() {
+        let mut tree = PathTree::default();
+        insert(&mut tree, "a/b/x");
+        insert(&mut tree, "a/y");
+        tree.remove(Path::new("a/b/x"), |_| false);
+        assert_eq!(beneath(&tree, ""), paths(&["a", "a/y"]));
+        tree.remove(Path::new("a/y"), |_| false);
+        assert!(tree.children.is_empty());
+    }
+
+    #[test]
+    fn keeps_what_is_still_tracked() {
+        let mut tree = PathTree::default();
+        // A file of one input where another has a directory.
+        insert(&mut tree, "a");
+        insert(&mut tree, "a/x");
+        tree.remove(Path::new("a"), |_| false);
+        assert_eq!(beneath(&tree, ""), paths(&["a", "a/x"]));
+        tree.remove(Path::new("a/x"), |path| path == Path::new("a"));
+        assert_eq!(beneath(&tree, ""), paths(&["a"]));
+    }
+}
@@ -0,0 +1,2339 @@
+use crate::audit::AuditAction;
+use crate::control::ControlSocket;
+use crate::gather::Gathering;
+#[cfg(feature = "http-status")]
+use crate::http::HttpStatus;
+use crate::recording::Recorder;
+use crate::retry::RetryAction;
+use crate::settle::Settling;
+use crate::stats::{Housekeeping, Skip};
+use crate::throttle::Throttle;
+use crate::{
+    backoff, error_kind, gather, load_order, CaseConflict, DecisionTrace, DiffReport, Event,
+    EventSink, EventSource, EventType, Failure, InputCommand, InputHandle, InputId, InputStats,
+    LinkProbe, NotifySource, Overlay, OverlayEntry, OverlayError, OverlaySnapshot, OverlayState,
+    PlannedChange, PreviewReport, ProcessedAction, Provider, RestoreReport, ShadowedEntry, Stats,
+    Summary, SyncReport, UndoReport, WatcherHealth, IGNORE_FILE, MAX_RESTARTS, SUMMARY,
+};
+use crossbeam_channel::{at, bounded, never, select, tick, unbounded, Receiver, Sender};
+use failure::{format_err, Error};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::io;
+use std::mem;
+#[cfg(feature = "http-status")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The most events taken off the queue at once, to be handled in the order they were
+/// observed in rather than the one they arrived in.
+const BATCH: usize = 256;
+
+/// A request sent to a running `process_loop` through a `Controller`.
+///
+/// Commands are only ever handled between events, never in the middle of one.
+#[derive(Debug)]
+pub enum Command {
+    Shutdown,
+    Stats(Sender<Stats>),
+    List(Sender<Vec<OverlayEntry>>),
+    Providers(PathBuf, Sender<Vec<Provider>>),
+    Shadowed(InputId, Sender<Vec<ShadowedEntry>>),
+    Preview(Vec<PlannedChange>, Sender<PreviewReport>),
+    Diff(Sender<DiffReport>),
+    Repair(DiffReport, Sender<usize>),
+    CaseConflicts(Sender<Vec<CaseConflict>>),
+    SyncReport(Sender<Option<SyncReport>>),
+    Resync(Sender<Result<SyncReport, Error>>),
+    ResyncIncremental(Sender<SyncReport>),
+    TraceDecisions(Sender<Receiver<DecisionTrace>>),
+    Summary(Sender<Summary>),
+    SetSummaryInterval(Option<Duration>),
+    SetEnabled(InputId, bool, Sender<Result<(), Error>>),
+    SetPriority(InputId, u32, Sender<Result<(), Error>>),
+    SetGroupPriority(u32, u32),
+    /// Moves the first input right above the second one, or below it if not.
+    MoveInput(InputId, InputId, bool, Sender<Result<(), Error>>),
+    /// What an `InputHandle` asks of its input, answered with the input's stats after.
+    Input(InputId, InputCommand, Sender<Result<InputStats, Error>>),
+    Snapshot(Sender<OverlaySnapshot>),
+    Restore(Box<OverlaySnapshot>, Sender<Result<RestoreReport, Error>>),
+    State(Sender<OverlayState>),
+    ApplyState(Box<OverlayState>, Sender<Result<RestoreReport, Error>>),
+    Undo(usize, Sender<Result<UndoReport, Error>>),
+    Failures(Sender<Vec<Failure>>),
+    FindInput(String, Sender<Result<InputId, Error>>),
+    LinkProbes(Sender<Vec<Option<LinkProbe>>>),
+}
+
+/// How far `process_loop` has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// The loop hasn't started yet.
+    Starting,
+    /// The output is being brought in line with the inputs, commands wait until it is.
+    Syncing,
+    /// The output is in line with the inputs and changes are being watched.
+    Ready,
+    /// The loop has ended.
+    Stopped,
+}
+
+/// The phase of an overlay, shared with its controllers.
+#[derive(Debug)]
+pub(crate) struct PhaseCell {
+    phase: Mutex<Phase>,
+    changed: Condvar,
+}
+
+impl PhaseCell {
+    pub(crate) fn new() -> Self {
+        PhaseCell {
+            phase: Mutex::new(Phase::Starting),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn set(&self, phase: Phase) {
+        *self.phase.lock().unwrap() = phase;
+        self.changed.notify_all();
+    }
+}
+
+/// What the overlay needs to watch its inputs.
+#[derive(Debug)]
+pub(crate) struct Watching {
+    pub(crate) events: Option<Sender<EventType>>,
+    /// The inputs whose watchers failed, and when they are restarted.
+    pub(crate) restarting: Vec<(Instant, usize)>,
+    pub(crate) backoff_max: Duration,
+    /// Whether the output was there when the loop last looked, so it is recreated if not.
+    pub(crate) output_present: bool,
+    pub(crate) commands: (Sender<Command>, Receiver<Command>),
+    pub(crate) phase: Arc<PhaseCell>,
+    /// Where every event received is recorded, if anywhere.
+    pub(crate) recorder: Option<Recorder>,
+    /// Where requests are taken while running, if anywhere.
+    pub(crate) control: Option<ControlSocket>,
+    pub(crate) throttle: Option<Throttle>,
+    /// Changed files of inputs that have to settle, held until they did.
+    pub(crate) settling: Settling,
+    /// How long new files are held for the same ones to appear in other inputs.
+    pub(crate) window: Duration,
+    pub(crate) gathering: Gathering,
+    pub(crate) source: Box<dyn EventSource>,
+}
+
+impl Default for Watching {
+    fn default() -> Self {
+        Watching {
+            events: None,
+            restarting: vec![],
+            backoff_max: backoff::DEFAULT_MAX,
+            output_present: false,
+            commands: unbounded(),
+            phase: Arc::new(PhaseCell::new()),
+            recorder: None,
+            control: None,
+            throttle: None,
+            settling: Settling::default(),
+            window: gather::DEFAULT_WINDOW,
+            gathering: Gathering::default(),
+            source: Box::new(NotifySource::default()),
+        }
+    }
+}
+
+/// The paths the overlay tracks are given room for only as many as there are once they
+/// fill less than a part this big of it.
+const SHRINK_BELOW: usize = 4;
+
+/// The output went away while watching, and is recreated.
+#[derive(Debug, Clone, Serialize)]
+struct OutputLost {
+    output: PathBuf,
+}
+
+/// A cloneable handle for sending commands to an `Overlay`, usable from other threads.
+#[derive(Debug, Clone)]
+pub struct Controller {
+    sender: Sender<Command>,
+    phase: Arc<PhaseCell>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Controller {
+    /// How far the overlay has got. Unlike the commands this is answered while syncing.
+    pub fn phase(&self) -> Phase {
+        *self.phase.phase.lock().unwrap()
+    }
+
+    /// Waits until the overlay is done syncing, e.g. to tell a service manager it is up.
+    /// Returns `false` if it stopped first.
+    pub fn wait_ready(&self) -> bool {
+        let mut phase = self.phase.phase.lock().unwrap();
+        loop {
+            match *phase {
+                Phase::Ready => return true,
+                Phase::Stopped => return false,
+                _ => phase = self.phase.changed.wait(phase).unwrap(),
+            }
+        }
+    }
+
+    pub fn send(&self, command: Command) -> Result<(), Error> {
+        self.sender
+            .send(command)
+            .map_err(|_| failure::err_msg("overlay is no longer running"))
+    }
+
+    /// Stops the overlay, giving up the copy of a large file it is in the middle of.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.send(Command::Shutdown)
+    }
+
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Stats(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    pub fn list(&self) -> Result<Vec<OverlayEntry>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::List(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    pub fn providers<P: AsRef<Path>>(&self, relative: P) -> Result<Vec<Provider>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Providers(relative.as_ref().to_path_buf(), tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// The files of input `id` that another input's win over, see `Overlay::shadowed`.
+    pub fn shadowed(&self, id: InputId) -> Result<Vec<ShadowedEntry>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Shadowed(id, tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// Which paths would change winners if `changes` were made, see `Overlay::preview`.
+    pub fn preview(&self, changes: Vec<PlannedChange>) -> Result<PreviewReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Preview(changes, tx))?;
+        Ok(rx.recv()?)
+    }
+
+    pub fn diff(&self) -> Result<DiffReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Diff(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    pub fn case_conflicts(&self) -> Result<Vec<CaseConflict>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::CaseConflicts(tx))?;
+        rx.recv().map_err(Error::from)
+    }
+
+    pub fn repair(&self, report: DiffReport) -> Result<usize, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Repair(report, tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// Turns an input on or off, see `Overlay::set_enabled`.
+    pub fn set_enabled(&self, id: InputId, enabled: bool) -> Result<(), Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::SetEnabled(id, enabled, tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::failures`.
+    pub fn failures(&self) -> Result<Vec<Failure>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Failures(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// See `Overlay::link_probe`, for every input.
+    pub fn link_probes(&self) -> Result<Vec<Option<LinkProbe>>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::LinkProbes(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// A handle on input `id`, see `InputHandle`.
+    pub fn input(&self, id: InputId) -> InputHandle {
+        InputHandle::new(self.clone(), id)
+    }
+
+    /// See `Overlay::find_input`.
+    pub fn find_input(&self, name: &str) -> Result<InputId, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::FindInput(name.to_string(), tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::set_priority`.
+    pub fn set_priority(&self, id: InputId, priority: u32) -> Result<(), Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::SetPriority(id, priority, tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::set_group_priority`.
+    pub fn set_group_priority(&self, group: u32, to: u32) -> Result<(), Error> {
+        self.send(Command::SetGroupPriority(group, to))
+    }
+
+    /// See `Overlay::move_input_above`.
+    pub fn move_input_above(&self, id: InputId, reference: InputId) -> Result<(), Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::MoveInput(id, reference, true, tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::move_input_below`.
+    pub fn move_input_below(&self, id: InputId, reference: InputId) -> Result<(), Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::MoveInput(id, reference, false, tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::snapshot`.
+    pub fn snapshot(&self) -> Result<OverlaySnapshot, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Snapshot(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// See `Overlay::restore`.
+    pub fn restore(&self, snapshot: OverlaySnapshot) -> Result<RestoreReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Restore(Box::new(snapshot), tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::state`.
+    pub fn state(&self) -> Result<OverlayState, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::State(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// See `Overlay::apply_state`.
+    pub fn apply_state(&self, state: OverlayState) -> Result<RestoreReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::ApplyState(Box::new(state), tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::undo_last`.
+    pub fn undo(&self, count: usize) -> Result<UndoReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Undo(count, tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::resync`.
+    pub fn resync(&self) -> Result<SyncReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Resync(tx))?;
+        rx.recv()?
+    }
+
+    /// See `Overlay::resync_incremental`.
+    pub fn resync_incremental(&self) -> Result<SyncReport, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::ResyncIncremental(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// See `Overlay::summary`.
+    pub fn summary(&self) -> Result<Summary, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::Summary(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// See `Overlay::set_summary_interval`.
+    pub fn set_summary_interval(&self, interval: Option<Duration>) -> Result<(), Error> {
+        self.send(Command::SetSummaryInterval(interval))
+    }
+
+    /// See `Overlay::trace_decisions`.
+    pub fn trace_decisions(&self) -> Result<Receiver<DecisionTrace>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::TraceDecisions(tx))?;
+        Ok(rx.recv()?)
+    }
+
+    /// What the sync at startup did, once it is done.
+    pub fn sync_report(&self) -> Result<Option<SyncReport>, Error> {
+        let (tx, rx) = bounded(1);
+        self.send(Command::SyncReport(tx))?;
+        Ok(rx.recv()?)
+    }
+}
+
+impl Overlay {
+    pub fn controller(&self) -> Controller {
+        Controller {
+            sender: self.watch.commands.0.clone(),
+            phase: self.watch.phase.clone(),
+            stopping: self.copying.stopping.clone(),
+        }
+    }
+
+    /// A handle on input `id`, e.g. the one `add_input` returned, to change it while the
+    /// overlay runs. See `InputHandle`.
+    pub fn input_handle(&self, id: InputId) -> InputHandle {
+        self.controller().input(id)
+    }
+
+    /// Takes requests at the control socket `path` from then on, see `ControlRequest`. It
+    /// is removed again when the overlay is dropped.
+    pub fn listen_control<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.watch.control = Some(ControlSocket::listen(path.as_ref(), self.controller())?);
+        Ok(())
+    }
+
+    /// Answers health checks over HTTP at `address` from then on, returning where, which
+    /// tells the port if `address` left it to the system. `GET /healthz` is 200 once the
+    /// output is synced, for as long as every watcher is healthy, and 503 otherwise.
+    /// `GET /status` has the stats as JSON.
+    ///
+    /// It stops when the overlay is dropped.
+    #[cfg(feature = "http-status")]
+    pub fn serve_http_status(&mut self, address: &str) -> Result<SocketAddr, Error> {
+        let status = HttpStatus::serve(address, self.controller())?;
+        let address = status.address();
+        self.http_status = Some(status);
+        Ok(address)
+    }
+
+    pub(crate) fn build_watchers(&mut self) -> Result<Receiver<EventType>, Error> {
+        let (tx, rx): (Sender<EventType>, Receiver<EventType>) = unbounded();
+        // Kept for restarting watchers that fail.
+        self.watch.events = Some(tx);
+
+        // NOTE: There should be a watcher on Output.
+        // NOTE: That moves files not created by Overlay to highest priority Input.
+
+        for index in 0..self.inputs.len() {
+            if !self.inputs[index].removed {
+                self.watch(index)?;
+            }
+        }
+
+        Ok(rx)
+    }
+
+    fn watch(&self, index: usize) -> Result<(), Error> {
+        let input = &self.inputs[index];
+        let tx = self.watch.events.clone().expect("watchers are built first");
+        let sink = EventSink::new(
+            index,
+            &input.path,
+            input.keys.clone(),
+            input.poll_interval,
+            tx,
+        );
+        self.watch
+            .source
+            .watch(InputId::of(index), &input.path, sink)
+    }
+
+    /// Schedules a restart of the watcher of input `index` after it failed with `error`,
+    /// later for every failure in a row.
+    ///
+    /// An input whose watcher failed too often is given up on. That stops the overlay if it
+    /// fails fast, otherwise it goes on without the input's changes.
+    fn watcher_failed(&mut self, index: usize, error: Error) -> Result<(), Error> {
+        if self.inputs[index].removed {
+            // Nothing of it is watched anymore.
+            return Ok(());
+        }
+        let name = self.input_name(index);
+        error!("The watcher of input {} failed: {}", name, error);
+        let kind = match error.downcast_ref::<io::Error>() {
+            Some(e) => error_kind(e),
+            None => "watch".to_string(),
+        };
+        self.failed(None, Some(index), kind, error.to_string());
+        self.audit(AuditAction::Error, None, index, Some(&error.to_string()));
+
+        let now = Instant::now();
+        let backoff = &mut self.inputs[index].backoff;
+        let delay = backoff.fail(now, self.watch.backoff_max);
+        let failures = backoff.failures;
+        if failures > MAX_RESTARTS {
+            self.stats.set_watcher(index, WatcherHealth::GivenUp);
+            if self.fail_fast {
+                return Err(format_err!(
+                    "the watcher of input {} failed {} times in a row, last with: {}",
+                    name,
+                    failures,
+                    error
+                ));
+            }
+            error!("Giving up on watching input {}", name);
+            return Ok(());
+        }
+
+        info!(
+            "Restarting the watcher of input {} in {}",
+            name,
+            humantime::format_duration(Duration::from_millis(delay.as_millis() as u64))
+        );
+        let retry_at = now + delay;
+        self.watch.restarting.push((retry_at, index));
+        self.stats
+            .set_watcher(index, WatcherHealth::BackingOff { failures, retry_at });
+        Ok(())
+    }
+
+    /// When the next watcher is due to be restarted, if any.
+    pub(crate) fn next_restart(&self) -> Option<Instant> {
+        self.watch.restarting.iter().map(|(at, _)| *at).min()
+    }
+
+    /// Restarts the watchers that are due, then catches up with what changed in their
+    /// inputs while they were down.
+    pub(crate) fn restart_watchers(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let (due, waiting) = self
+            .watch
+            .restarting
+            .drain(..)
+            .partition(|(at, _)| *at <= now);
+        self.watch.restarting = waiting;
+
+        for (_, index) in due {
+            if let Err(e) = self.watch(index) {
+                self.watcher_failed(index, e)?;
+                continue;
+            }
+
+            info!("Restarted the watcher of input {}", self.input_name(index));
+            self.inputs[index].backoff.restarted(now);
+            self.stats.set_watcher(index, WatcherHealth::Healthy);
+            #[cfg(feature = "archives")]
+            if self.inputs[index].is_archive() {
+                // It may have changed, or been put back, while it wasn't watched.
+                self.reload_archive(index, false);
+            }
+            if self.inputs[index].enabled {
+                self.reload_ignore(index);
+                self.apply_event(EventType::new(index, Event::Create(PathBuf::new())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the resync that is due, full or incremental, see `set_auto_resync`.
+    fn process_auto_resync(&mut self) {
+        self.syncs.resyncs = self.syncs.resyncs.wrapping_add(1);
+        let full =
+            self.syncs.full_every != 0 && self.syncs.resyncs.is_multiple_of(self.syncs.full_every);
+        let report = if full {
+            self.resync()
+        } else {
+            Ok(self.resync_incremental())
+        };
+        match report {
+            Ok(report) => self.report_sync(&report),
+            Err(e) => warn!("Skipping the resync, {}", e),
+        }
+    }
+
+    /// Recreates the output if it went away since the last look, e.g. because somebody
+    /// deleted it while the overlay ran, and syncs everything into it again. Only fails if
+    /// it can't be recreated or synced into and the overlay fails fast, otherwise it isn't
+    /// tried again until the output was there once more.
+    fn check_output(&mut self) -> Result<(), Error> {
+        let present = self.fs.is_dir(&self.output);
+        let lost = self.watch.output_present && !present && !self.dry_run;
+        self.watch.output_present = present;
+        if !lost {
+            return Ok(());
+        }
+
+        warn!(
+            "The output {} is gone, recreating it and syncing everything into it again",
+            self.output.display()
+        );
+        self.emit(
+            "output_lost",
+            &OutputLost {
+                output: self.output.clone(),
+            },
+        );
+        self.finish_links();
+        if let Err(e) = self.fs.create_dir_all(&self.output) {
+            let error = format!(
+                "the output {} is gone and couldn't be recreated: {}",
+                self.output.display(),
+                e
+            );
+            if self.fail_fast {
+                return Err(failure::err_msg(error));
+            }
+            error!("{}", error);
+            self.failed(None, None, error_kind(&e), error);
+            return Ok(());
+        }
+        self.watch.output_present = true;
+
+        // Nothing the overlay put there is anymore, and it may be on another volume now.
+        self.links.clear();
+        self.merging.merged.clear();
+        self.ledger.clear();
+        self.copying.filesystem = None;
+        self.probe_inputs()?;
+        match self.resync() {
+            Ok(report) => self.report_sync(&report),
+            Err(e) if self.fail_fast => return Err(e),
+            Err(e) => error!("Could not sync everything into the output again, {}", e),
+        }
+        Ok(())
+    }
+
+    /// Processes the events that came in while the overlay synced, but for those only
+    /// telling what the sync already found.
+    fn replay_queued(&mut self, events: &Receiver<EventType>) -> Result<(), Error> {
+        let mut queued: Vec<EventType> = events.try_iter().collect();
+        queued.sort_by_key(|event| event.observation.seq);
+        self.record_events(&queued);
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        let mut redundant = 0;
+        let mut total = queued.len();
+        for event in queued {
+            match event {
+                EventType {
+                    index,
+                    event: Event::WatcherFailed(e),
+                    ..
+                } => self.watcher_failed(index, e)?,
+                EventType {
+                    index,
+                    event: Event::Polled(at),
+                    ..
+                } => {
+                    // Not a change.
+                    self.stats.polled(index, at);
+                    total -= 1;
+                }
+                event if self.redundant(&event) => {
+                    self.skip(event.index, Skip::Coalesced);
+                    redundant += 1;
+                }
+                event => {
+                    let result = self.process_event(event);
+                    self.processed_event(result)?;
+                }
+            }
+        }
+        if total > 0 {
+            info!(
+                "Replayed {} of the {} changes that came in while syncing, the rest were synced \
+                 already",
+                total - redundant,
+                total
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `event` only tells what the overlay knows already: that a file it tracks
+    /// and has in the output is there, or that one it doesn't track isn't.
+    fn redundant(&self, event: &EventType) -> bool {
+        let index = event.index;
+        let source = |path: &Path| self.inputs[index].source(path);
+        match &event.event {
+            Event::Create(path) if !path.as_os_str().is_empty() => {
+                let provided = self
+                    .input_map
+                    .get(path)
+                    .is_some_and(|heap| heap.iter().any(|input| input.index == index));
+                if !provided || self.merging.merged.contains(path) || !self.fs.exists(&source(path))
+                {
+                    return false;
+                }
+                match self.materialized(path) {
+                    Some(winner) if winner.index == index => {
+                        self.provides(index, &source(path), &self.output.join(path))
+                    }
+                    // Shadowed, and staying that way.
+                    _ => true,
+                }
+            }
+            Event::Remove(path) if !path.as_os_str().is_empty() => {
+                self.provided_under(index, path).is_empty() && !self.fs.exists(&source(path))
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles the events that arrived together in the order they were observed in, so of
+    /// two changes to the same path the later one is the last.
+    pub(crate) fn process_batch(&mut self, mut batch: Vec<EventType>) -> Result<(), Error> {
+        batch.sort_by_key(|event| event.observation.seq);
+        self.record_events(&batch);
+        self.pool.deferring = true;
+        let result = self.process_events(batch);
+        self.stop_deferring();
+        result
+    }
+
+    /// Records `events` as they were received, if they are recorded.
+    fn record_events(&mut self, events: &[EventType]) {
+        let recorder = match self.watch.recorder.as_mut() {
+            Some(recorder) if !events.is_empty() => recorder,
+            _ => return,
+        };
+        for event in events {
+            recorder.record(event, self.inputs[event.index].label.as_deref());
+        }
+        recorder.flush();
+    }
+
+    /// Handles the events of a batch, handing the files they link to the workers.
+    fn process_events(&mut self, batch: Vec<EventType>) -> Result<(), Error> {
+        for event in batch {
+            match event {
+                EventType {
+                    index,
+                    event: Event::WatcherFailed(e),
+                    ..
+                } => self.watcher_failed(index, e)?,
+                event => {
+                    let result = self.process_event(event);
+                    self.processed_event(result)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `event`, returning what it did to the output, or what failed if anything
+    /// did.
+    pub(crate) fn process_event(
+        &mut self,
+        event: EventType,
+    ) -> Result<ProcessedAction, OverlayError> {
+        let (errors, kept) = (self.stats.errors, self.failures.len());
+        self.processed = ProcessedAction::Ignored;
+        self.handle_event(event);
+        let processed = mem::take(&mut self.processed);
+
+        if self.stats.errors == errors {
+            return Ok(processed);
+        }
+        Err(OverlayError {
+            errors: self.stats.errors - errors,
+            failures: self.failures.get(kept..).unwrap_or_default().to_vec(),
+        })
+    }
+
+    /// Counts the events that failed to be handled in a row, erring once there are as
+    /// many as `set_max_consecutive_failures` allows.
+    fn processed_event(
+        &mut self,
+        result: Result<ProcessedAction, OverlayError>,
+    ) -> Result<(), Error> {
+        let e = match result {
+            Ok(_) => {
+                self.stats.set_consecutive_failures(0);
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        let failures = self.stats.consecutive_failures + 1;
+        self.stats.set_consecutive_failures(failures);
+        match self.max_consecutive_failures {
+            Some(max) if failures >= max => Err(format_err!(
+                "Stopping after {} events in a row failed, the last with: {}",
+                failures,
+                e
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_event(&mut self, event: EventType) {
+        let (index, observation) = (event.index, event.observation);
+        if let Event::Polled(at) = event.event {
+            self.stats.polled(index, at);
+            return;
+        }
+        self.stats.event();
+        // Anything that happens to the archive of an archive input changes all of it.
+        #[cfg(feature = "archives")]
+        if self.inputs[index].is_archive() {
+            if let Event::Create(_)
+            | Event::Remove(_)
+            | Event::Rename(..)
+            | Event::PermissionsChanged(_) = event.event
+            {
+                self.reload_archive(index, true);
+                return;
+            }
+        }
+        if !self.inputs[index].enabled && !matches!(event.event, Event::Error(..)) {
+            self.skip(index, Skip::Dropped);
+            return;
+        }
+        let ignore_file = |path: &Path| path == Path::new(IGNORE_FILE);
+        let event = match event.event {
+            Event::Create(ref path)
+            | Event::Remove(ref path)
+            | Event::PermissionsChanged(ref path)
+                if ignore_file(path) =>
+            {
+                self.reload_ignore(index);
+                return;
+            }
+            Event::Rename(from, to) if ignore_file(&from) || ignore_file(&to) => {
+                self.reload_ignore(index);
+                match (ignore_file(&from), ignore_file(&to)) {
+                    (true, false) => Event::Create(to),
+                    (false, true) => Event::Remove(from),
+                    _ => return,
+                }
+            }
+            event => event,
+        };
+
+        let temporary = |path: &Path| !self.temp_files.accepts_file(path);
+        let event = match event {
+            Event::Create(ref path)
+            | Event::Remove(ref path)
+            | Event::PermissionsChanged(ref path)
+                if temporary(path) =>
+            {
+                self.skip(index, Skip::Filtered);
+                return;
+            }
+            // A finished download or save is renamed to its final name, which is all the
+            // overlay ever sees of it.
+            Event::Rename(from, to) => match (temporary(&from), temporary(&to)) {
+                (true, true) => {
+                    self.skip(index, Skip::Filtered);
+                    return;
+                }
+                (true, false) => Event::Create(to),
+                (false, true) => Event::Remove(from),
+                (false, false) => Event::Rename(from, to),
+            },
+            event => event,
+        };
+
+        // A file still being written into the input is only looked at once it is done.
+        match &event {
+            Event::Create(path) if !self.settled(index, path, false) => return,
+            Event::Remove(path) | Event::Rename(path, _) => {
+                self.watch.settling.forget(index, path);
+                self.watch.gathering.forget(index, path);
+            }
+            _ => {}
+        }
+        self.dispatch(EventType::following(index, event, observation));
+    }
+
+    /// Whether the file of input `index` at `path` may be looked at, as the input doesn't
+    /// wait for its files to settle or it did. If not, it is held and looked at again once
+    /// it might have, `again` if it already was.
+    fn settled(&mut self, index: usize, path: &Path, again: bool) -> bool {
+        let interval = match self.inputs[index].settle {
+            Some(interval) => interval,
+            None => return true,
+        };
+        let source = self.inputs[index].source(path);
+        let identity = match self.fs.identity(&source) {
+            // What is gone, or a directory, is handled as it is.
+            Ok(identity) if !self.fs.is_dir(&source) => identity,
+            _ => {
+                self.watch.settling.forget(index, path);
+                return true;
+            }
+        };
+
+        // Debounced changes were left alone for a while already, which counts, but only
+        // the first time.
+        let wait = if again {
+            interval
+        } else {
+            let quiet = self
+                .watch
+                .source
+                .quiet_for(self.inputs[index].poll_interval);
+            interval.saturating_sub(quiet)
+        };
+        let state = (identity.len, identity.modified);
+        let settled = self
+            .watch
+            .settling
+            .settled(index, path, state, wait, Instant::now());
+        if !settled {
+            debug!(
+                "Waiting for {} of input {} to settle",
+                path.display(),
+                index
+            );
+            self.processed(ProcessedAction::Deferred);
+            self.skip(index, Skip::Deferred);
+        }
+        settled
+    }
+
+    /// Looks at the held files that may have settled by now, and at those that did as if
+    /// they just changed.
+    pub(crate) fn process_settling(&mut self) {
+        for (index, path) in self.watch.settling.due(Instant::now()) {
+            if !self.inputs[index].enabled {
+                self.watch.settling.forget(index, &path);
+            } else if self.settled(index, &path, true) {
+                self.dispatch(EventType::new(index, Event::Create(path)));
+            }
+        }
+    }
+
+    /// Handles `event`, unless it is throttled.
+    fn dispatch(&mut self, event: EventType) {
+        // Whatever waits to be tried again on the path is settled by what happened since.
+        let superseded = match &event.event {
+            Event::Create(path) | Event::Remove(path) | Event::PermissionsChanged(path) => {
+                self.retries.supersede(path)
+            }
+            Event::Rename(from, to) => self.retries.supersede(from) | self.retries.supersede(to),
+            _ => false,
+        };
+        if superseded {
+            self.stats
+                .set_retry_queue(self.retries.len(), self.retries.oldest());
+        }
+
+        if let Some(throttle) = self.watch.throttle.as_mut() {
+            let path = match &event.event {
+                Event::Create(path) | Event::Remove(path) => Some(path),
+                _ => None,
+            };
+
+            if let Some(path) = path {
+                if !throttle.admit(event.index, path, Instant::now()) {
+                    self.processed(ProcessedAction::Deferred);
+                    self.skip(event.index, Skip::Coalesced);
+                    return;
+                }
+            }
+        }
+
+        // A file that appeared waits for the same one to appear in other inputs too.
+        if let Event::Create(path) = &event.event {
+            if !self.watch.window.is_zero() {
+                let due = Instant::now() + self.watch.window;
+                self.watch.gathering.hold(event.index, path, due);
+                self.processed(ProcessedAction::Deferred);
+                self.skip(event.index, Skip::Deferred);
+                return;
+            }
+        }
+
+        self.apply_event(event);
+    }
+
+    /// Handles the files held for other inputs to have them too that are due, or all of
+    /// them if `all`. The winner's file at each path goes first, so the others only go
+    /// behind it instead of being put into the output and replaced.
+    pub(crate) fn process_gathered(&mut self, all: bool) {
+        for (path, mut inputs) in self.watch.gathering.take_due(Instant::now(), all) {
+            inputs.retain(|&index| self.inputs[index].enabled);
+            inputs.sort_by_key(|&index| Reverse(self.inputs[index].rank));
+            for index in inputs {
+                // It may be gone again by now, which was handled already.
+                if self.fs.exists(&self.inputs[index].source(&path)) {
+                    self.apply_event(EventType::new(index, Event::Create(path.clone())));
+                }
+            }
+        }
+    }
+
+    /// Processes the paths whose throttling window has passed, or all of them if `all`.
+    pub(crate) fn process_throttled(&mut self, all: bool) {
+        let paths = match self.watch.throttle.as_mut() {
+            Some(throttle) if all => throttle.take_all(),
+            Some(throttle) => throttle.take_due(Instant::now()),
+            None => return,
+        };
+
+        for (index, path) in paths {
+            if !self.inputs[index].enabled {
+                continue;
+            }
+
+            // Only the state the file settled in matters, not how it got there.
+            let exists = self.fs.exists(&self.inputs[index].source(&path));
+            let event = if exists {
+                Event::Create(path)
+            } else {
+                Event::Remove(path)
+            };
+            self.apply_event(EventType::new(index, event));
+        }
+    }
+
+    /// Handles a single command, returning `false` once the loop should stop.
+    fn process_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Shutdown => {
+                self.process_gathered(true);
+                self.process_throttled(true);
+                info!(
+                    target: SUMMARY,
+                    "Stopping after {} links, {} unlinks and {} errors",
+                    self.stats.linked,
+                    self.stats.unlinked,
+                    self.stats.errors
+                );
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.flush();
+                }
+                let summary = self.summary();
+                self.emit("summary", &summary);
+                false
+            }
+            Command::Stats(reply) => {
+                let _ = reply.send(self.stats.clone());
+                true
+            }
+            Command::List(reply) => {
+                let _ = reply.send(self.list().collect());
+                true
+            }
+            Command::Providers(path, reply) => {
+                let _ = reply.send(self.providers(path));
+                true
+            }
+            Command::Shadowed(id, reply) => {
+                let _ = reply.send(self.shadowed(id));
+                true
+            }
+            Command::Preview(changes, reply) => {
+                let _ = reply.send(self.preview(&changes));
+                true
+            }
+            Command::Diff(reply) => {
+                let _ = reply.send(self.diff());
+                true
+            }
+            Command::Repair(report, reply) => {
+                let _ = reply.send(self.repair(&report));
+                true
+            }
+            Command::CaseConflicts(reply) => {
+                let _ = reply.send(self.case_conflicts());
+                true
+            }
+            Command::SyncReport(reply) => {
+                let _ = reply.send(self.syncs.last.clone());
+                true
+            }
+            Command::Resync(reply) => {
+                let report = self.resync();
+                if let Ok(report) = &report {
+                    self.report_sync(report);
+                }
+                let _ = reply.send(report);
+                true
+            }
+            Command::Summary(reply) => {
+                let _ = reply.send(self.summary());
+                true
+            }
+            Command::SetSummaryInterval(interval) => {
+                self.set_summary_interval(interval);
+                true
+            }
+            Command::TraceDecisions(reply) => {
+                let _ = reply.send(self.trace_decisions());
+                true
+            }
+            Command::ResyncIncremental(reply) => {
+                let report = self.resync_incremental();
+                self.report_sync(&report);
+                let _ = reply.send(report);
+                true
+            }
+            Command::SetEnabled(id, enabled, reply) => {
+                let _ = reply.send(self.set_enabled(id, enabled));
+                true
+            }
+            Command::SetPriority(id, priority, reply) => {
+                let _ = reply.send(self.set_priority(id, priority));
+                true
+            }
+            Command::SetGroupPriority(group, to) => {
+                self.set_group_priority(group, to);
+                true
+            }
+            Command::MoveInput(id, reference, above, reply) => {
+                let _ = reply.send(self.move_input(id, reference, above));
+                true
+            }
+            Command::Input(id, command, reply) => {
+                let _ = reply.send(self.input_command(id, command));
+                true
+            }
+            Command::Snapshot(reply) => {
+                let _ = reply.send(self.snapshot());
+                true
+            }
+            Command::Restore(snapshot, reply) => {
+                let _ = reply.send(self.restore(&snapshot));
+                true
+            }
+            Command::State(reply) => {
+                let _ = reply.send(self.state());
+                true
+            }
+            Command::ApplyState(state, reply) => {
+                let _ = reply.send(self.apply_state(&state));
+                true
+            }
+            Command::Undo(count, reply) => {
+                let _ = reply.send(self.restore_last(count));
+                true
+            }
+            Command::Failures(reply) => {
+                let _ = reply.send(self.failures.clone());
+                true
+            }
+            Command::FindInput(name, reply) => {
+                let _ = reply.send(self.find_input(&name));
+                true
+            }
+            Command::LinkProbes(reply) => {
+                let probes = self.inputs.iter().map(|input| input.probe.clone());
+                let _ = reply.send(probes.collect());
+                true
+            }
+        }
+    }
+
+    /// Does what an `InputHandle` asked of input `id`, if it is still there.
+    fn input_command(&mut self, id: InputId, command: InputCommand) -> Result<InputStats, Error> {
+        let index = self.existing_input(id)?;
+        match command {
+            InputCommand::SetPriority(priority) => self.set_priority(id, priority)?,
+            InputCommand::SetEnabled(enabled) => self.set_enabled(id, enabled)?,
+            InputCommand::Remove => self.remove_input(id)?,
+            InputCommand::Stats => {}
+        }
+        Ok(self.stats.inputs[index].clone())
+    }
+
+    fn process_tick(&mut self) {
+        self.process_retries();
+        self.housekeeping();
+        self.enforce_trash_retention();
+    }
+
+    /// Forgets the paths that no input provides anymore, and gives back the room of the
+    /// paths that are gone once most of it is unused. The paths of actions that still
+    /// wait to be tried again are kept for them.
+    fn housekeeping(&mut self) {
+        // What is handed to the workers is done with first.
+        self.finish_links();
+        let (entries_before, capacity_before) = (self.input_map.len(), self.input_map.capacity());
+
+        let gone: Vec<PathBuf> = self
+            .input_map
+            .iter()
+            .filter(|(path, heap)| heap.is_empty() && !self.retries.parks(path))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in gone {
+            self.untrack(&path);
+        }
+        if self.input_map.len() * SHRINK_BELOW < self.input_map.capacity() {
+            self.input_map.shrink_to_fit();
+        }
+
+        self.stats.housekeeping = Some(Housekeeping {
+            entries_before,
+            entries_after: self.input_map.len(),
+            capacity_before,
+            capacity_after: self.input_map.capacity(),
+        });
+        self.stats.set_tracked_paths(self.input_map.len());
+    }
+
+    /// Tries the actions parked because their files were in use again, as far as they are
+    /// due and still what the output needs.
+    pub(crate) fn process_retries(&mut self) {
+        for (path, parked) in self.retries.due(Instant::now()) {
+            let index = parked.input;
+            match parked.action {
+                RetryAction::Link => {
+                    // Otherwise the input's own event about the file takes care of it.
+                    let input = &self.inputs[index];
+                    if input.enabled && self.fs.exists(&input.source(&path)) {
+                        self.apply_event(EventType::new(index, Event::Create(path.clone())));
+                    }
+                }
+                RetryAction::Unlink => {
+                    // Something took its place or took it away since.
+                    if self.materialized(&path).is_none()
+                        && self.fs.exists(&self.output.join(&path))
+                    {
+                        self.line
+                            .begin(format_args!("Removing {} again", path.display()));
+                        self.unlink(&path, index);
+                        self.line.end();
+                    }
+                }
+            }
+            self.retries.settle(&path, index);
+        }
+        self.stats
+            .set_retry_queue(self.retries.len(), self.retries.oldest());
+    }
+
+    /// Logs the summary that is due, and schedules the next one. It is due every interval
+    /// from when the last was scheduled, however late the loop gets to it.
+    fn process_summary(&mut self) {
+        let summary = self.summary();
+        info!(target: SUMMARY, "{}", summary);
+        self.emit("summary", &summary);
+        let now = Instant::now();
+        self.summaries.last = (now, self.stats.counters());
+        if let (Some(interval), Some(due)) = (self.summaries.interval, self.summaries.next) {
+            let mut next = due + interval;
+            // Skipping any that were missed altogether, rather than logging them all now.
+            while next <= now {
+                next += interval;
+            }
+            self.summaries.next = Some(next);
+        }
+    }
+
+    /// Syncs the output, then keeps it in line with the inputs until it is told to stop
+    /// through a `Controller`.
+    pub fn process_loop(&mut self) -> Result<(), Error> {
+        let result = self.run_loop();
+        self.drain_retries();
+        self.save_ledger();
+        self.watch.phase.set(Phase::Stopped);
+        result
+    }
+
+    fn run_loop(&mut self) -> Result<(), Error> {
+        // Held until the loop ends, however it does.
+        let _lock = self.lock()?;
+        self.copying.stopping.store(false, Ordering::Relaxed);
+        self.prepare()?;
+        // Anything that changes while syncing waits in the channel.
+        let events: Receiver<EventType> = self.build_watchers()?;
+        let load_order = match &self.load_order {
+            Some(path) => load_order::watch(path)?,
+            None => never(),
+        };
+        self.watch.phase.set(Phase::Syncing);
+        self.apply_load_order()?;
+        self.check_free_space()?;
+        let report = self.sync();
+        self.report_sync(&report);
+        self.replay_queued(&events)?;
+        self.save_ledger();
+        self.enforce_trash_retention();
+        self.check_output()?;
+        self.watch.phase.set(Phase::Ready);
+        self.summaries.last = (Instant::now(), self.stats.counters());
+        self.set_summary_interval(self.summaries.interval);
+
+        let commands = self.watch.commands.1.clone();
+        let ticks = match self.tick_interval {
+            Some(interval) => tick(interval),
+            None => never(),
+        };
+        let resyncs = match self.syncs.auto {
+            Some(interval) => tick(interval),
+            None => never(),
+        };
+
+        loop {
+            let throttled = match self.watch.throttle.as_ref().and_then(Throttle::next_due) {
+                Some(due) => at(due),
+                None => never(),
+            };
+            let restarts = match self.next_restart() {
+                Some(due) => at(due),
+                None => never(),
+            };
+            let settling = match self.watch.settling.next_due() {
+                Some(due) => at(due),
+                None => never(),
+            };
+            let gathered = match self.watch.gathering.next_due() {
+                Some(due) => at(due),
+                None => never(),
+            };
+            let retries = match self.retries.next_due() {
+                Some(due) => at(due),
+                None => never(),
+            };
+            let summaries = match self.summaries.next {
+                Some(due) => at(due),
+                None => never(),
+            };
+            // Once a burst of changes is through, rather than after each of them.
+            if events.is_empty() {
+                self.check_output()?;
+                self.collapse_grafts();
+                self.save_ledger();
+            }
+
+            // Commands that are already waiting go first, so they never race an event.
+            while let Ok(command) = commands.try_recv() {
+                if !self.process_command(command) {
+                    return Ok(());
+                }
+            }
+            if self.syncs.replay {
+                self.syncs.replay = false;
+                self.replay_queued(&events)?;
+            }
+
+            select! {
+                recv(events) -> event => {
+                    // Before linking anything recreates it without the rest.
+                    self.check_output()?;
+                    let mut batch = vec![event?];
+                    batch.extend(events.try_iter().take(BATCH - 1));
+                    self.stats.set_queue_depth(events.len());
+                    self.process_batch(batch)?;
+                }
+                recv(commands) -> command => {
+                    if !self.process_command(command?) {
+                        return Ok(());
+                    }
+                }
+                recv(load_order) -> _ => {
+                    if let Err(e) = self.apply_load_order() {
+                        warn!("Keeping the current order, {}", e);
+                    }
+                }
+                recv(ticks) -> _ => self.process_tick(),
+                recv(retries) -> _ => self.process_retries(),
+                recv(settling) -> _ => self.process_settling(),
+                recv(gathered) -> _ => self.process_gathered(false),
+                recv(resyncs) -> _ => self.process_auto_resync(),
+                recv(summaries) -> _ => self.process_summary(),
+                recv(throttled) -> _ => self.process_throttled(false),
+                recv(restarts) -> _ => self.restart_watchers()?,
+            }
+        }
+    }
+
+    /// The longest the restart of a failed watcher is put off, however often it failed.
+    pub fn set_restart_backoff_max(&mut self, max: Duration) {
+        self.watch.backoff_max = max;
+    }
+
+    /// Holds the files that appear in an input while watching for `window`, 50 ms by
+    /// default, so that when the same file appears in other inputs meanwhile, like when a
+    /// patch is applied to several of them at once, only the winner's is put into the
+    /// output instead of each in turn. Nothing is held with a zero `window`.
+    pub fn set_cross_input_window(&mut self, window: Duration) {
+        self.watch.window = window;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::{FileOp, FileOps};
+    use crate::key::Folded;
+    use crate::tests::Harness;
+    use crate::{
+        EventKind, InputId, InputOptions, NotifySource, OverlayBuilder, ReplaySource, RetryPolicy,
+        Skipped, WatcherHealth,
+    };
+    use notify::DebouncedEvent;
+    use std::thread;
+    use std::time::SystemTime;
+
+    /// Waits for `done`, for a while.
+    fn wait_for<F: FnMut() -> bool>(what: &str, mut done: F) {
+        let until = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < until, "{} took too long", what);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn housekeeping_forgets_the_paths_nothing_provides() {
+        let mut harness = Harness::new("housekeeping", &[0]);
+        harness.create(0, "keep");
+        let paths = harness.overlay.input_map.len();
+        for _ in 0..3 {
+            // Left behind like the path of a retry that gave up.
+            let gone = Path::new("a/gone");
+            harness.overlay.track(gone, &Folded::new(gone));
+            harness.overlay.housekeeping();
+            assert_eq!(harness.overlay.input_map.len(), paths);
+        }
+        let housekeeping = harness.overlay.stats.housekeeping.unwrap();
+        assert_eq!(
+            (housekeeping.entries_before, housekeeping.entries_after),
+            (paths + 1, paths)
+        );
+        assert_eq!(
+            harness.overlay.tree.beneath(Path::new("")),
+            [Path::new("keep")]
+        );
+    }
+
+    #[test]
+    fn housekeeping_shrinks_the_tracked_paths_but_keeps_those_retries_wait_on() {
+        let mut harness = Harness::new("housekeeping-shrinks", &[0]);
+        harness.create(0, "busy");
+        let busy = harness.output.join("busy");
+        harness
+            .fs
+            .fail(FileOp::RemoveFile, &busy, io::ErrorKind::ResourceBusy);
+        harness.remove(0, "busy");
+        assert!(harness.overlay.retries.parks(Path::new("busy")));
+        // Still tracked while the removal waits, like a path left in between.
+        harness
+            .overlay
+            .track(Path::new("busy"), &Folded::new(Path::new("busy")));
+        for n in 0..1000 {
+            let churned = PathBuf::from(format!("churn/{}", n));
+            harness.overlay.track(&churned, &Folded::new(&churned));
+        }
+
+        harness.overlay.process_tick();
+        let housekeeping = harness.overlay.stats.housekeeping.unwrap();
+        assert_eq!(
+            (housekeeping.entries_before, housekeeping.entries_after),
+            (1001, 1)
+        );
+        assert!(housekeeping.capacity_after < housekeeping.capacity_before);
+        assert!(harness.overlay.input_map.contains_key(Path::new("busy")));
+        let summary = harness.overlay.summary().to_string();
+        assert!(
+            summary.contains(&format!(
+                " (last tidied from 1001 to 1, with room for {} then {})",
+                housekeeping.capacity_before, housekeeping.capacity_after
+            )),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn a_failed_watcher_is_restarted_after_a_while() {
+        let source = Arc::new(ReplaySource::new());
+        let Harness {
+            mut overlay,
+            fs,
+            inputs,
+            output,
+        } = Harness::with("watcher-restarts", &[0], |builder| {
+            builder
+                .event_source(source.clone())
+                .restart_backoff_max(Duration::from_millis(300))
+        });
+        let controller = overlay.controller();
+        let running = thread::spawn(move || overlay.process_loop());
+        assert!(controller.wait_ready());
+
+        let id = InputId::of(0);
+        let watcher = || controller.stats().unwrap().inputs[0].watcher;
+        source.fail(id, format_err!("gone"));
+        wait_for("the failure", || watcher() != WatcherHealth::Healthy);
+        assert!(matches!(
+            watcher(),
+            WatcherHealth::BackingOff { failures: 1, .. }
+        ));
+        wait_for("the restart", || watcher() == WatcherHealth::Healthy);
+        assert_eq!(controller.failures().unwrap().len(), 1);
+
+        // What changes is seen again.
+        let file = inputs[0].join("x");
+        fs.create_file(&file);
+        source.push(id, DebouncedEvent::Create(file));
+        wait_for("the event", || fs.exists(&output.join("x")));
+
+        controller.shutdown().unwrap();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn commands_are_answered_between_events_until_shutdown() {
+        let source = Arc::new(ReplaySource::new());
+        let Harness {
+            mut overlay,
+            fs,
+            inputs,
+            ..
+        } = Harness::with("commands-and-events", &[0], |builder| {
+            builder
+                .event_source(source.clone())
+                .cross_input_window(Duration::ZERO)
+        });
+        let controller = overlay.controller();
+        let running = thread::spawn(move || overlay.process_loop());
+        assert!(controller.wait_ready());
+        let paths = || -> Vec<PathBuf> {
+            let list = controller.list().unwrap();
+            list.into_iter().map(|entry| entry.path).collect()
+        };
+        // Nothing happening doesn't keep a command waiting.
+        assert!(paths().is_empty());
+
+        let id = InputId::of(0);
+        let file = inputs[0].join("x");
+        fs.create_file(&file);
+        source.push(id, DebouncedEvent::Create(file));
+        wait_for("the event", || paths() == [PathBuf::from("x")]);
+
+        // A command is done with before the next event.
+        controller.set_enabled(id, false).unwrap();
+        assert!(paths().is_empty());
+        let file = inputs[0].join("y");
+        fs.create_file(&file);
+        source.push(id, DebouncedEvent::Create(file));
+        wait_for("the dropped event", || {
+            controller.stats().unwrap().skipped.dropped == 1
+        });
+        assert!(paths().is_empty());
+
+        controller.shutdown().unwrap();
+        running.join().unwrap().unwrap();
+        let error = controller.stats().unwrap_err();
+        assert_eq!(error.to_string(), "overlay is no longer running");
+    }
+
+    #[test]
+    fn a_watcher_failing_too_often_stops_an_overlay_that_fails_fast() {
+        let source = Arc::new(ReplaySource::new());
+        let mut harness = Harness::with("watcher-gives-up", &[0], |builder| {
+            builder
+                .event_source(source.clone())
+                .restart_backoff_max(Duration::from_millis(1))
+                .fail_fast(true)
+        });
+        let controller = harness.overlay.controller();
+        let running = thread::spawn(move || harness.overlay.process_loop());
+        assert!(controller.wait_ready());
+
+        // Each time it was restarted, until it isn't anymore.
+        wait_for("giving up", || {
+            source.fail(InputId::of(0), format_err!("gone"));
+            running.is_finished()
+        });
+        let error = running.join().unwrap().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("failed {} times in a row", MAX_RESTARTS + 1)),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn changes_that_came_in_while_syncing_are_replayed_unless_synced_already() {
+        // What appears isn't held back for other inputs to have it too.
+        let mut harness = Harness::with("replay-queued", &[0], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        harness.create(0, "synced");
+        harness.create(0, "removed");
+        // Anything done to the file already in the output would fail.
+        let synced = harness.output.join("synced");
+        for op in [FileOp::HardLink, FileOp::RemoveFile, FileOp::Rename] {
+            harness.fs.fail(op, &synced, io::ErrorKind::Other);
+        }
+        harness.fs.create_file(harness.inputs[0].join("new"));
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("removed"))
+            .unwrap();
+
+        let (tx, rx) = unbounded();
+        for event in [
+            Event::Create(PathBuf::from("synced")),
+            Event::Remove(PathBuf::from("never-there")),
+            Event::Create(PathBuf::from("new")),
+            Event::Remove(PathBuf::from("removed")),
+        ] {
+            tx.send(EventType::new(0, event)).unwrap();
+        }
+        harness.overlay.replay_queued(&rx).unwrap();
+        harness.overlay.finish_links();
+
+        assert_eq!(harness.overlay.stats.inputs[0].skipped.coalesced, 2);
+        assert!(harness.overlay.failures.is_empty());
+        assert_eq!(harness.winner("synced"), Some(0));
+        assert_eq!(harness.winner("new"), Some(0));
+        assert!(!harness.in_output("removed"));
+    }
+
+    #[test]
+    fn scheduled_resyncs_only_read_modified_directories_but_every_nth() {
+        let root = crate::tests::scratch("incremental");
+        let (input, output) = (root.join("input"), root.join("output"));
+        for dir in ["a", "b"] {
+            std::fs::create_dir_all(input.join(dir)).unwrap();
+            std::fs::write(input.join(dir).join("x"), dir).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .input(&input, 0)
+            .single_instance(false)
+            .full_resync_every(3)
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        // The first one reads every directory, to know their times.
+        assert_eq!(overlay.resync_incremental(), SyncReport::default());
+
+        std::fs::write(input.join("a/new"), "new").unwrap();
+        // Written into b behind the back of its time.
+        let b = input.join("b");
+        let modified = std::fs::metadata(&b).unwrap().modified().unwrap();
+        std::fs::write(b.join("hidden"), "hidden").unwrap();
+        std::fs::File::open(&b)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        std::fs::remove_file(input.join("a/x")).unwrap();
+        std::fs::remove_file(output.join("b/x")).unwrap();
+
+        let report = overlay.resync_incremental();
+        assert_eq!((report.linked, report.vanished), (2, 1));
+        assert!(overlay.resolve("a/new").is_some());
+        assert!(overlay.resolve("a/x").is_none());
+        assert!(output.join("b/x").exists());
+        assert!(overlay.resolve("b/hidden").is_none());
+
+        // Scheduled, the first two are incremental.
+        overlay.process_auto_resync();
+        overlay.process_auto_resync();
+        assert!(overlay.resolve("b/hidden").is_none());
+        // The third is a full one.
+        overlay.process_auto_resync();
+        assert!(overlay.resolve("b/hidden").is_some());
+        assert!(output.join("b/hidden").exists());
+    }
+
+    #[test]
+    fn a_summary_is_of_what_happened_since_the_last_and_keeps_its_schedule() {
+        let mut harness = Harness::with("summary", &[0], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        let process = |harness: &mut Harness, event: Event| {
+            harness
+                .overlay
+                .process_event(EventType::new(0, event))
+                .unwrap();
+            harness.overlay.finish_links();
+        };
+        for path in ["a", "b"] {
+            harness.fs.create_file(harness.inputs[0].join(path));
+            process(&mut harness, Event::Create(PathBuf::from(path)));
+        }
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("a"))
+            .unwrap();
+        process(&mut harness, Event::Remove(PathBuf::from("a")));
+
+        let summary = harness.overlay.summary();
+        assert_eq!(
+            (
+                summary.events,
+                summary.linked,
+                summary.unlinked,
+                summary.errors
+            ),
+            (3, 2, 1, 0)
+        );
+        assert_eq!(summary.tracked_paths, 1);
+        // Asking doesn't start a new period.
+        assert_eq!(harness.overlay.summary().events, 3);
+        let line = summary.to_string();
+        assert!(line.contains(": 3 events (0 skipped), 2 links, 1 unlinks, 0 errors;"));
+        assert!(line.ends_with("; input 0 healthy"));
+
+        let interval = Duration::from_secs(60);
+        harness.overlay.set_summary_interval(Some(interval));
+        // Three were missed while the loop was busy.
+        let due = Instant::now() - interval * 3 - Duration::from_secs(1);
+        harness.overlay.summaries.next = Some(due);
+        harness.overlay.process_summary();
+        assert_eq!(harness.overlay.summaries.next, Some(due + interval * 4));
+        assert_eq!(harness.overlay.summary().events, 0);
+        process(&mut harness, Event::Remove(PathBuf::from("b")));
+        assert_eq!(harness.overlay.summary().unlinked, 1);
+
+        harness.overlay.set_summary_interval(None);
+        assert_eq!(harness.overlay.summaries.next, None);
+    }
+
+    #[test]
+    fn a_file_in_use_waits_in_the_queue_until_it_is_let_go_of_or_changed() {
+        let mut harness = Harness::with("retry-queue", &[0], |builder| {
+            builder
+                .cross_input_window(Duration::ZERO)
+                .retry_policy(RetryPolicy {
+                    attempts: 1,
+                    delay: Duration::ZERO,
+                })
+        });
+        for path in ["save", "other"] {
+            let output = harness.output.join(path);
+            harness
+                .fs
+                .fail(FileOp::HardLink, &output, io::ErrorKind::ResourceBusy);
+            harness.create(0, path);
+        }
+        let stats = harness.overlay.stats();
+        assert_eq!(stats.retry_queue, 2);
+        assert!(stats.oldest_retry.is_some());
+        assert_eq!(stats.inputs[0].skipped.deferred, 2);
+        assert!(!harness.in_output("save"));
+
+        // Tried again once it is due, not before.
+        harness.fs.clear_failures();
+        harness.overlay.process_retries();
+        assert!(!harness.in_output("save"));
+        // What happened to other since is all that counts.
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("other"))
+            .unwrap();
+        let remove = EventType::new(0, Event::Remove(PathBuf::from("other")));
+        harness.overlay.process_event(remove).unwrap();
+        assert_eq!(harness.overlay.stats().retry_queue, 1);
+        thread::sleep(Duration::from_secs(1));
+        harness.overlay.process_retries();
+        assert_eq!(harness.winner("save"), Some(0));
+        assert_eq!(harness.overlay.stats().retry_queue, 0);
+        assert_eq!(harness.overlay.stats().oldest_retry, None);
+
+        // What never got through is given up on when stopping.
+        let output = harness.output.join("later");
+        harness
+            .fs
+            .fail(FileOp::HardLink, &output, io::ErrorKind::ResourceBusy);
+        harness.create(0, "later");
+        harness.overlay.drain_retries();
+        assert_eq!(harness.overlay.stats().retry_queue, 0);
+        assert!(!harness.in_output("later"));
+    }
+
+    #[test]
+    fn a_file_is_only_linked_once_it_stayed_the_same_for_the_interval() {
+        const SETTLE: Duration = Duration::from_millis(20);
+        let mut harness = Harness::with("settle", &[], |builder| {
+            builder
+                .cross_input_window(Duration::ZERO)
+                .event_source(ReplaySource::new())
+        });
+        let input = harness.output.with_file_name("input0");
+        std::fs::create_dir_all(&input).unwrap();
+        harness.fs.create_dir(&input);
+        let options = InputOptions::new().settle(SETTLE);
+        harness
+            .overlay
+            .add_input_with_options(&input, 0, &options)
+            .unwrap();
+        harness.inputs.push(input.clone());
+        let process = |harness: &mut Harness, event: Event| {
+            harness
+                .overlay
+                .process_event(EventType::new(0, event))
+                .unwrap();
+            harness.overlay.finish_links();
+        };
+        let settle = |harness: &mut Harness| {
+            thread::sleep(SETTLE);
+            harness.overlay.process_settling();
+            harness.overlay.finish_links();
+        };
+
+        harness.fs.create_file(input.join("big"));
+        process(&mut harness, Event::Create(PathBuf::from("big")));
+        assert!(!harness.in_output("big"));
+        assert_eq!(harness.overlay.stats().inputs[0].skipped.deferred, 1);
+        harness.overlay.process_settling();
+        assert!(!harness.in_output("big"));
+        // Still being copied the next time it is looked at.
+        harness.fs.set_len(input.join("big"), 1 << 20);
+        settle(&mut harness);
+        assert!(!harness.in_output("big"));
+        settle(&mut harness);
+        assert_eq!(harness.winner("big"), Some(0));
+        assert_eq!(harness.overlay.watch.settling.next_due(), None);
+
+        // What is gone before it settled is forgotten.
+        harness.fs.create_file(input.join("partial"));
+        process(&mut harness, Event::Create(PathBuf::from("partial")));
+        harness.fs.remove_file(&input.join("partial")).unwrap();
+        process(&mut harness, Event::Remove(PathBuf::from("partial")));
+        assert_eq!(harness.overlay.watch.settling.next_due(), None);
+    }
+
+    #[test]
+    fn a_debounce_as_long_as_the_interval_is_all_the_wait() {
+        let mut harness = Harness::with("settle-debounced", &[], |builder| {
+            builder
+                .cross_input_window(Duration::ZERO)
+                .event_source(NotifySource {
+                    delay: Duration::from_secs(1),
+                    poll: None,
+                })
+        });
+        let input = harness.output.with_file_name("input0");
+        std::fs::create_dir_all(&input).unwrap();
+        harness.fs.create_dir(&input);
+        let options = InputOptions::new().settle(Duration::from_secs(1));
+        harness
+            .overlay
+            .add_input_with_options(&input, 0, &options)
+            .unwrap();
+        harness.inputs.push(input.clone());
+
+        harness.fs.create_file(input.join("big"));
+        let create = EventType::new(0, Event::Create(PathBuf::from("big")));
+        harness.overlay.process_event(create).unwrap();
+        harness.overlay.finish_links();
+        assert_eq!(harness.winner("big"), Some(0));
+    }
+
+    #[test]
+    fn young_files_are_linked_once_old_enough_unless_gone_by_then() {
+        const MIN_AGE: Duration = Duration::from_millis(300);
+        let root = crate::tests::scratch("min-age");
+        let (input, output) = (root.join("input"), root.join("output"));
+        std::fs::create_dir_all(&input).unwrap();
+        for path in ["young", "fleeting"] {
+            std::fs::write(input.join(path), path).unwrap();
+        }
+        let mut overlay = OverlayBuilder::new(&output)
+            .input_with_options(&input, 0, InputOptions::new().min_age(MIN_AGE))
+            .cross_input_window(Duration::ZERO)
+            .single_instance(false)
+            .build()
+            .unwrap();
+        // The sync of the loop leaves them for later too, `sync_once` gives up on them.
+        overlay.sync();
+        assert!(!output.join("young").exists());
+        assert_eq!(overlay.stats().retry_queue, 2);
+
+        std::fs::remove_file(input.join("fleeting")).unwrap();
+        let remove = EventType::new(0, Event::Remove(PathBuf::from("fleeting")));
+        overlay.process_event(remove).unwrap();
+        assert_eq!(overlay.stats().retry_queue, 1);
+
+        overlay.process_retries();
+        assert!(!output.join("young").exists());
+        thread::sleep(MIN_AGE);
+        overlay.process_retries();
+        overlay.finish_links();
+        assert_eq!(
+            std::fs::read_to_string(output.join("young")).unwrap(),
+            "young"
+        );
+        assert_eq!(overlay.stats().retry_queue, 0);
+        assert!(!output.join("fleeting").exists());
+    }
+
+    #[test]
+    fn an_event_tells_what_it_did_and_failures_in_a_row_stop_the_loop() {
+        let mut harness = Harness::with("processed", &[0, 1], |builder| {
+            builder
+                .cross_input_window(Duration::ZERO)
+                .max_consecutive_failures(2)
+        });
+        let process = |harness: &mut Harness, index: usize, event: Event| {
+            let result = harness.overlay.process_event(EventType::new(index, event));
+            harness.overlay.finish_links();
+            result
+        };
+        let create = |harness: &mut Harness, index: usize, path: &str| {
+            harness.fs.create_file(harness.inputs[index].join(path));
+            process(harness, index, Event::Create(PathBuf::from(path)))
+        };
+        assert_eq!(create(&mut harness, 0, "x"), Ok(ProcessedAction::Linked));
+        assert_eq!(create(&mut harness, 1, "x"), Ok(ProcessedAction::Replaced));
+        assert_eq!(create(&mut harness, 0, "x"), Ok(ProcessedAction::Ignored));
+        harness
+            .fs
+            .remove_file(&harness.inputs[1].join("x"))
+            .unwrap();
+        // Falling back to the file of base links it.
+        let remove = Event::Remove(PathBuf::from("x"));
+        assert_eq!(
+            process(&mut harness, 1, remove),
+            Ok(ProcessedAction::Linked)
+        );
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("x"))
+            .unwrap();
+        let remove = Event::Remove(PathBuf::from("x"));
+        assert_eq!(
+            process(&mut harness, 0, remove),
+            Ok(ProcessedAction::Removed)
+        );
+
+        for path in ["a", "b", "c"] {
+            let output = harness.output.join(path);
+            harness
+                .fs
+                .fail(FileOp::HardLink, &output, io::ErrorKind::PermissionDenied);
+        }
+        let failed = create(&mut harness, 0, "a").unwrap_err();
+        assert_eq!(failed.errors, 1);
+        assert_eq!(failed.failures[0].path.as_deref(), Some(Path::new("a")));
+        assert_eq!(failed.failures[0].kind, "permission_denied");
+        assert!(harness.overlay.processed_event(Err(failed)).is_ok());
+        assert_eq!(harness.overlay.stats().consecutive_failures, 1);
+        // One that went fine in between starts the count over.
+        let fine = create(&mut harness, 0, "fine");
+        assert!(harness.overlay.processed_event(fine).is_ok());
+        assert_eq!(harness.overlay.stats().consecutive_failures, 0);
+        for path in ["b", "c"] {
+            let failed = create(&mut harness, 0, path);
+            let stopped = harness.overlay.processed_event(failed);
+            assert_eq!(stopped.is_err(), path == "c");
+        }
+        assert_eq!(harness.overlay.stats().consecutive_failures, 2);
+    }
+
+    #[test]
+    fn a_batch_is_handled_in_the_order_it_was_observed_in() {
+        let mut harness = Harness::with("observed-order", &[0, 1], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        let traces = harness.overlay.trace_decisions();
+        let before = SystemTime::now();
+        harness.fs.create_file(harness.inputs[0].join("x"));
+        let base = EventType::new(0, Event::Create(PathBuf::from("x")));
+        let created = EventType::new(1, Event::Create(PathBuf::from("x")));
+        let removed = EventType::new(1, Event::Remove(PathBuf::from("x")));
+        assert!(created.observation.seq < removed.observation.seq);
+
+        // The watcher of mods got there before the one of base.
+        harness
+            .overlay
+            .process_batch(vec![removed, created, base])
+            .unwrap();
+        harness.overlay.finish_links();
+        assert_eq!(harness.winner("x"), Some(0));
+        assert_eq!(harness.overlay.providers("x").len(), 1);
+
+        let traces: Vec<DecisionTrace> = traces.try_iter().collect();
+        let order: Vec<(usize, EventKind)> = traces
+            .iter()
+            .map(|trace| (trace.input.index(), trace.kind))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (0, EventKind::Create),
+                (1, EventKind::Create),
+                (1, EventKind::Remove)
+            ]
+        );
+        assert!(traces
+            .windows(2)
+            .all(|pair| pair[0].seq < pair[1].seq && pair[0].observed <= pair[1].observed));
+        assert!(traces[0].observed >= before);
+    }
+
+    #[test]
+    fn an_output_deleted_while_watching_is_recreated_and_synced_into_again() {
+        let root = crate::tests::scratch("output-lost");
+        let (input, output) = (root.join("input"), root.join("output"));
+        std::fs::create_dir_all(input.join("dir")).unwrap();
+        std::fs::write(input.join("dir/x"), "x").unwrap();
+        let stream = root.join("events.jsonl");
+        let mut overlay = OverlayBuilder::new(&output)
+            .single_instance(false)
+            .input(&input, 0)
+            .event_stream(std::fs::File::create(&stream).unwrap())
+            .build()
+            .unwrap();
+        overlay.sync_once().unwrap();
+        overlay.check_output().unwrap();
+
+        std::fs::remove_dir_all(&output).unwrap();
+        overlay.check_output().unwrap();
+        assert!(crate::RealFs
+            .same_file(&input.join("dir/x"), &output.join("dir/x"))
+            .unwrap());
+        assert!(overlay.ledger.contains(&Path::new("dir").join("x")));
+        assert!(overlay.failures.is_empty());
+        let lost: Vec<serde_json::Value> = std::fs::read_to_string(&stream)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["type"] == "output_lost")
+            .collect();
+        assert_eq!(lost.len(), 1);
+        assert_eq!(lost[0]["output"], output.to_str().unwrap());
+        // Only once.
+        overlay.check_output().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&stream)
+                .unwrap()
+                .matches("output_lost")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn an_output_that_cant_be_recreated_fails_fast_or_waits_until_it_is_back() {
+        for fail_fast in [true, false] {
+            let mut harness = Harness::with("output-not-recreated", &[0], |builder| {
+                builder.fail_fast(fail_fast)
+            });
+            let output = harness.output.clone();
+            harness.fs.create_dir(&output);
+            harness.overlay.check_output().unwrap();
+            harness.fs.remove_dir_all(&output).unwrap();
+            harness.fs.fail(
+                FileOp::CreateDirAll,
+                &output,
+                io::ErrorKind::PermissionDenied,
+            );
+
+            let error = format!(
+                "the output {} is gone and couldn't be recreated: CreateDirAll failed",
+                output.display()
+            );
+            let checked = harness.overlay.check_output();
+            if fail_fast {
+                assert_eq!(checked.unwrap_err().to_string(), error);
+                continue;
+            }
+            checked.unwrap();
+            assert_eq!(harness.overlay.failures.len(), 1);
+            assert_eq!(harness.overlay.failures[0].message, error);
+            // Not tried again while it stays gone.
+            harness.overlay.check_output().unwrap();
+            assert_eq!(harness.overlay.failures.len(), 1);
+        }
+    }
+
+    #[test]
+    fn the_same_new_file_in_several_inputs_is_only_linked_from_the_winner() {
+        let mut harness = Harness::new("gathered", &[0, 1]);
+        harness
+            .overlay
+            .set_cross_input_window(Duration::from_secs(60));
+        let appear = |harness: &mut Harness, index: usize, path: &str| {
+            harness.fs.create_file(harness.inputs[index].join(path));
+            let event = EventType::new(index, Event::Create(PathBuf::from(path)));
+            harness.overlay.process_event(event).unwrap();
+        };
+        appear(&mut harness, 0, "patched");
+        appear(&mut harness, 1, "patched");
+        appear(&mut harness, 0, "gone");
+        harness
+            .fs
+            .remove_file(&harness.inputs[0].join("gone"))
+            .unwrap();
+        let event = EventType::new(0, Event::Remove(PathBuf::from("gone")));
+        harness.overlay.process_event(event).unwrap();
+        harness.overlay.process_gathered(false);
+        assert!(!harness.in_output("patched"));
+        assert!(harness.overlay.watch.gathering.next_due().unwrap() > Instant::now());
+
+        harness.overlay.process_gathered(true);
+        assert_eq!(harness.winner("patched"), Some(1));
+        assert!(!harness.in_output("gone"));
+        assert_eq!(harness.overlay.stats.linked, 1);
+        assert_eq!(harness.overlay.watch.gathering.next_due(), None);
+
+        // Each in turn without a window, the winner's replacing the first.
+        harness.overlay.set_cross_input_window(Duration::ZERO);
+        appear(&mut harness, 0, "flicker");
+        assert_eq!(harness.winner("flicker"), Some(0));
+        appear(&mut harness, 1, "flicker");
+        assert_eq!(harness.winner("flicker"), Some(1));
+        assert_eq!(harness.overlay.stats.linked, 3);
+    }
+
+    #[test]
+    fn throttled_paths_are_handled_as_they_ended_up_once_stopped() {
+        let mut harness = Harness::with("throttled", &[0], |builder| {
+            builder
+                .throttle(Duration::from_secs(60))
+                .cross_input_window(Duration::ZERO)
+        });
+        let process = |harness: &mut Harness, event: Event| {
+            harness
+                .overlay
+                .process_event(EventType::new(0, event))
+                .unwrap();
+            harness.overlay.finish_links();
+        };
+        let create = |path: &str| Event::Create(PathBuf::from(path));
+        let remove = |path: &str| Event::Remove(PathBuf::from(path));
+        let (a, c) = (harness.inputs[0].join("a"), harness.inputs[0].join("c"));
+        harness.fs.create_file(&a);
+        harness.fs.create_file(&c);
+        harness.fs.create_file(harness.inputs[0].join("b"));
+        process(&mut harness, create("a"));
+        harness.fs.remove_file(&a).unwrap();
+        process(&mut harness, remove("a"));
+        // Another path isn't held back by it.
+        process(&mut harness, create("b"));
+        process(&mut harness, create("c"));
+        harness.fs.remove_file(&c).unwrap();
+        process(&mut harness, remove("c"));
+        harness.fs.create_file(&c);
+        process(&mut harness, create("c"));
+        assert!(harness.in_output("a"));
+        assert!(harness.in_output("b"));
+        assert_eq!(harness.overlay.stats.skipped.coalesced, 3);
+
+        assert!(!harness.overlay.process_command(Command::Shutdown));
+        harness.overlay.finish_links();
+        assert!(!harness.in_output("a"));
+        assert_eq!(harness.winner("b"), Some(0));
+        assert_eq!(harness.winner("c"), Some(0));
+        assert!(harness
+            .overlay
+            .watch
+            .throttle
+            .as_ref()
+            .unwrap()
+            .next_due()
+            .is_none());
+    }
+
+    #[test]
+    fn temporary_files_are_left_out_until_renamed_to_what_they_become() {
+        let process = |harness: &mut Harness, event: Event| {
+            harness
+                .overlay
+                .process_event(EventType::new(0, event))
+                .unwrap();
+            harness.overlay.finish_links();
+        };
+        let create = |harness: &mut Harness, path: &str| {
+            harness.fs.create_file(harness.inputs[0].join(path));
+            process(harness, Event::Create(PathBuf::from(path)));
+        };
+        let temporary = [
+            "foo.txt~",
+            ".foo.txt.swp",
+            "foo.txt.part",
+            "foo.txt.crdownload",
+            "~$foo.docx",
+        ];
+
+        let mut harness = Harness::with("temporary", &[0], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        for path in temporary {
+            create(&mut harness, path);
+            assert!(!harness.in_output(path), "{}", path);
+        }
+        let (part, done) = (
+            harness.inputs[0].join("foo.txt.part"),
+            harness.inputs[0].join("foo.txt"),
+        );
+        harness.fs.rename(&part, &done).unwrap();
+        let rename = Event::Rename(PathBuf::from("foo.txt.part"), PathBuf::from("foo.txt"));
+        process(&mut harness, rename);
+        assert_eq!(harness.winner("foo.txt"), Some(0));
+        assert_eq!(harness.overlay.stats.skipped.filtered, 5);
+
+        // Patterns of its own replace the usual ones.
+        let mut harness = Harness::with("temporary-patterns", &[0], |builder| {
+            builder
+                .cross_input_window(Duration::ZERO)
+                .temp_patterns(vec!["*.tmp"])
+        });
+        create(&mut harness, "foo.txt.part");
+        create(&mut harness, "foo.tmp");
+        assert_eq!(harness.winner("foo.txt.part"), Some(0));
+        assert!(!harness.in_output("foo.tmp"));
+    }
+
+    #[test]
+    fn each_event_not_acted_on_is_counted_once_by_why() {
+        let mut harness = Harness::with("skipped", &[2, 1, 0], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        harness.overlay.set_enabled(InputId::of(2), false).unwrap();
+        let mut process = |index: usize, path: &str| {
+            harness.fs.create_file(harness.inputs[index].join(path));
+            let event = EventType::new(index, Event::Create(PathBuf::from(path)));
+            harness.overlay.process_event(event).unwrap();
+            harness.overlay.finish_links();
+        };
+        process(0, "a");
+        // Shadowed.
+        process(1, "a");
+        // Temporary.
+        process(0, "b.part");
+        process(1, "c.part");
+        // Disabled.
+        process(2, "d");
+        // Synced already.
+        let (tx, rx) = unbounded();
+        tx.send(EventType::new(0, Event::Create(PathBuf::from("a"))))
+            .unwrap();
+        harness.overlay.replay_queued(&rx).unwrap();
+        // Waiting for the other inputs to have it too.
+        harness
+            .overlay
+            .set_cross_input_window(Duration::from_secs(60));
+        harness.fs.create_file(harness.inputs[0].join("e"));
+        let event = EventType::new(0, Event::Create(PathBuf::from("e")));
+        harness.overlay.process_event(event).unwrap();
+
+        let stats = &harness.overlay.stats;
+        assert_eq!(
+            stats.skipped,
+            Skipped {
+                filtered: 2,
+                ignored: 1,
+                coalesced: 1,
+                deferred: 1,
+                dropped: 1,
+            }
+        );
+        let by_input: Vec<Skipped> = stats.inputs.iter().map(|input| input.skipped).collect();
+        assert_eq!(
+            by_input,
+            [
+                Skipped {
+                    filtered: 1,
+                    coalesced: 1,
+                    deferred: 1,
+                    ..Skipped::default()
+                },
+                Skipped {
+                    filtered: 1,
+                    ignored: 1,
+                    ..Skipped::default()
+                },
+                Skipped {
+                    dropped: 1,
+                    ..Skipped::default()
+                },
+            ]
+        );
+    }
+
+    /// Writes the `IGNORE_FILE` of input `index` of `harness` as `rules`, and tells the
+    /// overlay.
+    fn write_ignore_file(harness: &mut Harness, index: usize, rules: &str) {
+        std::fs::write(harness.inputs[index].join(IGNORE_FILE), rules).unwrap();
+        let event = EventType::new(index, Event::Create(PathBuf::from(IGNORE_FILE)));
+        harness.overlay.process_event(event).unwrap();
+        harness.overlay.finish_links();
+    }
+
+    #[test]
+    fn what_an_edited_ignore_file_ignores_now_is_unlinked() {
+        let mut harness = Harness::with("ignore-more", &[0, 1], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        harness.create(0, "shared.esp");
+        harness.create(1, "shared.esp");
+        harness.create(1, "own.esp");
+        harness.create(1, "kept.txt");
+        assert_eq!(harness.winner("shared.esp"), Some(1));
+        assert_eq!(harness.winner("own.esp"), Some(1));
+
+        write_ignore_file(&mut harness, 1, "*.esp\n");
+        assert!(harness.overlay.failures.is_empty());
+        assert_eq!(harness.winner("shared.esp"), Some(0));
+        assert!(!harness.in_output("own.esp"));
+        assert_eq!(harness.winner("kept.txt"), Some(1));
+    }
+
+    #[test]
+    fn what_an_edited_ignore_file_no_longer_ignores_is_linked() {
+        let mut harness = Harness::with("ignore-less", &[0, 1], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        write_ignore_file(&mut harness, 1, "*.esp\n");
+        // The walk after the edit finds them on disk.
+        for path in ["shared.esp", "own.esp"] {
+            std::fs::write(harness.inputs[1].join(path), path).unwrap();
+            harness.create(1, path);
+        }
+        harness.create(0, "shared.esp");
+        assert_eq!(harness.winner("shared.esp"), Some(0));
+        assert!(!harness.in_output("own.esp"));
+
+        write_ignore_file(&mut harness, 1, "");
+        assert!(harness.overlay.failures.is_empty());
+        assert_eq!(harness.winner("shared.esp"), Some(1));
+        assert_eq!(harness.winner("own.esp"), Some(1));
+    }
+
+    #[test]
+    fn a_disabled_input_gives_way_and_comes_back_as_it_is_now() {
+        let mut harness = Harness::with("disabled", &[0, 1], |builder| {
+            builder.cross_input_window(Duration::ZERO)
+        });
+        // Enabling it again walks it on disk.
+        let write = |harness: &mut Harness, index: usize, path: &str| {
+            std::fs::write(harness.inputs[index].join(path), path).unwrap();
+            harness.fs.create_file(harness.inputs[index].join(path));
+            let event = EventType::new(index, Event::Create(PathBuf::from(path)));
+            harness.overlay.process_event(event).unwrap();
+            harness.overlay.finish_links();
+        };
+        write(&mut harness, 0, "shared.esp");
+        write(&mut harness, 1, "shared.esp");
+        write(&mut harness, 1, "own.esp");
+        assert_eq!(harness.winner("shared.esp"), Some(1));
+
+        let id = InputId::of(1);
+        harness.overlay.set_enabled(id, false).unwrap();
+        assert!(!harness.overlay.is_enabled(id).unwrap());
+        assert_eq!(harness.winner("shared.esp"), Some(0));
+        assert!(!harness.in_output("own.esp"));
+        write(&mut harness, 1, "new.esp");
+        assert!(!harness.in_output("new.esp"));
+        assert_eq!(harness.overlay.stats.inputs[1].skipped.dropped, 1);
+
+        harness.overlay.set_enabled(id, true).unwrap();
+        assert!(harness.overlay.failures.is_empty());
+        for path in ["shared.esp", "own.esp", "new.esp"] {
+            assert_eq!(harness.winner(path), Some(1), "{}", path);
+        }
+        // Turning it on again does nothing.
+        let linked = harness.overlay.stats.linked;
+        harness.overlay.set_enabled(id, true).unwrap();
+        assert_eq!(harness.overlay.stats.linked, linked);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::audit::AuditAction;
+use crate::fs_ops::FileOps;
+use crate::key::Folded;
+use crate::link::{Link, LinkKind, LinkStrategy};
+use crate::progress::{CopyProgress, Reporting};
+use crate::retry::RetryAction;
+use crate::trace::DECISIONS;
+use crate::{Overlay, ProcessedAction};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{log_enabled, warn, Level};
+use rustc_hash::{FxBuildHasher, FxHashSet};
+use std::hash::BuildHasher;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+/// Putting the file of input `index` at `path` into the output.
+#[derive(Debug)]
+pub(crate) struct Put {
+    pub(crate) path: PathBuf,
+    pub(crate) index: usize,
+    pub(crate) from: PathBuf,
+    pub(crate) to: PathBuf,
+    pub(crate) strategy: Arc<dyn LinkStrategy>,
+    /// Whether there is a file at `to` already, which is only swapped for the new one
+    /// once that is complete, so the path is never missing from the output in between.
+    pub(crate) replaces: bool,
+    /// Where copying a large file is told about, if anywhere.
+    pub(crate) progress: Option<CopyProgress>,
+    /// The span of the event it is put there for, entered by the worker too.
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
+}
+
+impl Put {
+    /// Where the new file is put until it replaces the one there.
+    fn staged(&self) -> PathBuf {
+        if self.replaces {
+            crate::beside(&self.to, ".overlay-new")
+        } else {
+            self.to.clone()
+        }
+    }
+
+    pub(crate) fn run(&self, fs: &dyn FileOps) -> io::Result<LinkKind> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.enter();
+        let staged = self.staged();
+        if self.replaces && fs.exists(&staged) {
+            // Left behind by a run that stopped halfway.
+            let _ = fs.remove_file(&staged);
+        }
+        let mut result = match &self.progress {
+            Some(progress) => {
+                let reporting = Reporting::new(fs, progress, &self.path);
+                self.strategy.materialize(&self.from, &staged, &reporting)
+            }
+            None => self.strategy.materialize(&self.from, &staged, fs),
+        };
+        if self.replaces {
+            result = result.and_then(|kind| fs.rename(&staged, &self.to).map(|()| kind));
+            if result.is_err() {
+                let _ = fs.remove_file(&staged);
+            }
+        }
+        result
+    }
+}
+
+/// The files handed to a worker at once, and what to put them there with.
+type Job = (Vec<Put>, Arc<dyn FileOps>);
+
+/// How many files are handed to a worker at once, unless waited for before.
+const BATCH: usize = 64;
+
+/// How many files are handed to the workers before waiting for them.
+pub(crate) const MAX_IN_FLIGHT: usize = 4096;
+
+/// Threads that put files into the output while the overlay goes on deciding, see
+/// `Overlay::set_workers`.
+///
+/// Each path always goes to the same thread, so what is done to it is done in order.
+#[derive(Debug)]
+pub(crate) struct Workers {
+    queues: Vec<Sender<Job>>,
+    /// The files of each thread not handed to it yet.
+    batches: Vec<Vec<Put>>,
+    /// What the batches are put there with, the same for all until they are handed over.
+    fs: Option<Arc<dyn FileOps>>,
+    done: Receiver<Vec<(Put, io::Result<LinkKind>)>>,
+    /// How many batches were handed over and not returned yet.
+    pending: usize,
+    finished: Vec<(Put, io::Result<LinkKind>)>,
+}
+
+impl Workers {
+    pub(crate) fn new(count: usize) -> Self {
+        let (done_tx, done) = unbounded();
+        let queues = (0..count)
+            .map(|_| {
+                let (tx, rx) = unbounded::<Job>();
+                let done_tx = done_tx.clone();
+                thread::spawn(move || {
+                    for (batch, fs) in rx {
+                        let results = batch
+                            .into_iter()
+                            .map(|put| {
+                                let result = put.run(&*fs);
+                                (put, result)
+                            })
+                            .collect();
+                        if done_tx.send(results).is_err() {
+                            break;
+                        }
+                    }
+                });
+                tx
+            })
+            .collect();
+
+        Workers {
+            queues,
+            batches: (0..count).map(|_| vec![]).collect(),
+            fs: None,
+            done,
+            pending: 0,
+            finished: vec![],
+        }
+    }
+
+    pub(crate) fn submit(&mut self, put: Put, fs: &Arc<dyn FileOps>) {
+        if !self
+            .fs
+            .as_ref()
+            .is_some_and(|batched| Arc::ptr_eq(batched, fs))
+        {
+            self.hand_over();
+            self.fs = Some(fs.clone());
+        }
+        let queue = FxBuildHasher.hash_one(&put.path) as usize % self.queues.len();
+        self.batches[queue].push(put);
+        if self.batches[queue].len() >= BATCH {
+            self.hand_over_batch(queue);
+        }
+    }
+
+    /// Hands every batch there is to its thread.
+    fn hand_over(&mut self) {
+        for queue in 0..self.queues.len() {
+            self.hand_over_batch(queue);
+        }
+    }
+
+    fn hand_over_batch(&mut self, queue: usize) {
+        let (batch, fs) = match &self.fs {
+            Some(fs) if !self.batches[queue].is_empty() => {
+                (std::mem::take(&mut self.batches[queue]), fs.clone())
+            }
+            _ => return,
+        };
+        match self.queues[queue].send((batch, fs)) {
+            Ok(()) => self.pending += 1,
+            // Only if the thread panicked, in which case the files are put there right away.
+            Err(e) => {
+                let (batch, fs) = e.into_inner();
+                for put in batch {
+                    let result = put.run(&*fs);
+                    self.finished.push((put, result));
+                }
+            }
+        }
+    }
+
+    /// Waits for everything submitted so far, and returns how each went, in the order
+    /// they completed.
+    pub(crate) fn wait(&mut self) -> Vec<(Put, io::Result<LinkKind>)> {
+        self.hand_over();
+        let mut done = std::mem::take(&mut self.finished);
+        while self.pending > 0 {
+            match self.done.recv() {
+                Ok(results) => done.extend(results),
+                Err(_) => break,
+            }
+            self.pending -= 1;
+        }
+        self.pending = 0;
+        done
+    }
+}
+
+/// The workers of an overlay, if it has any, and what it handed them.
+#[derive(Debug, Default)]
+pub(crate) struct Pool {
+    /// What puts files into the output while the overlay goes on deciding, if anything.
+    pub(crate) workers: Option<Workers>,
+    pub(crate) in_flight: InFlight,
+    /// Whether files are handed to the workers, while syncing and handling a batch.
+    pub(crate) deferring: bool,
+}
+
+/// The paths the workers put there that no completion was handled for yet, folded, and
+/// the directories they are in, so what is in flight is found without looking at them all.
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    paths: FxHashSet<Folded>,
+    dirs: FxHashSet<Folded>,
+}
+
+impl InFlight {
+    pub(crate) fn insert(&mut self, key: Folded) {
+        for dir in key.parents() {
+            // Then the directories above are there already.
+            if !self.dirs.insert(dir) {
+                break;
+            }
+        }
+        self.paths.insert(key);
+    }
+
+    /// Whether `key` is in flight, or a path inside of it or that it is in.
+    pub(crate) fn contains(&self, key: &Folded) -> bool {
+        !self.paths.is_empty()
+            && (self.dirs.contains(key)
+                || self.paths.contains(key)
+                || key.parents().any(|dir| self.paths.contains(&dir)))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.paths.clear();
+        self.dirs.clear();
+    }
+}
+
+impl Overlay {
+    /// Whether the file being linked is handed to the workers rather than put there now.
+    /// Not while the decisions are traced, which have to be told what was done.
+    pub(crate) fn defers(&self) -> bool {
+        self.pool.deferring
+            && self.pool.workers.is_some()
+            && !self.dry_run
+            && self.traces.senders.is_empty()
+            && !log_enabled!(target: DECISIONS, Level::Trace)
+    }
+
+    /// Handles how putting a file into the output went.
+    pub(crate) fn linked(&mut self, put: Put, result: io::Result<LinkKind>) -> bool {
+        let Put {
+            path,
+            index,
+            from,
+            to,
+            strategy,
+            replaces,
+            ..
+        } = put;
+        match &result {
+            Ok(kind) => {
+                let done = match kind {
+                    LinkKind::HardLink => " LINKED!",
+                    LinkKind::Copy => " COPIED!",
+                    LinkKind::Custom => " MATERIALIZED!",
+                };
+                say!(self.line, Info, "{}", done);
+                if *kind == LinkKind::Copy && self.planned_kind(index) == LinkKind::HardLink {
+                    warn!(
+                        "Copied {} into the output, its file in input {} has as many hard links \
+                         as its filesystem allows",
+                        path.display(),
+                        self.input_name(index)
+                    );
+                    self.stats.over_link_limit();
+                }
+                self.links.insert(
+                    path.clone(),
+                    Link {
+                        strategy,
+                        kind: *kind,
+                    },
+                );
+                self.merging.merged.remove(&path);
+                self.record(&path);
+                self.run_hooks(true, &path, &to, index);
+                self.stats.linked();
+                if replaces {
+                    self.processed(ProcessedAction::Replaced);
+                    self.note(|report| report.relinked += 1);
+                } else {
+                    self.processed(ProcessedAction::Linked);
+                    self.note(|report| report.linked += 1);
+                }
+            }
+            Err(e) => {
+                say!(self.line, Error, " NOT LINKED: {}!", e);
+                self.link_failed(&path, index, &from, &to, e);
+                self.park(&path, RetryAction::Link, index, e);
+            }
+        }
+        let action = if replaces {
+            AuditAction::Replace
+        } else {
+            AuditAction::Link
+        };
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit(action, Some(&path), index, error.as_deref());
+
+        result.is_ok()
+    }
+
+    /// Waits for the workers to put every file handed to them into the output, and
+    /// handles how each went.
+    pub(crate) fn finish_links(&mut self) {
+        loop {
+            let done = match &mut self.pool.workers {
+                Some(workers) => workers.wait(),
+                None => return,
+            };
+            if done.is_empty() {
+                return;
+            }
+            self.pool.in_flight.clear();
+
+            for (put, result) in done {
+                let (path, index) = (put.path.clone(), put.index);
+                self.line.begin(format_args!(
+                    "Linked {} of input {}:",
+                    path.display(),
+                    self.input_name(index)
+                ));
+                if !self.linked(put, result) && !self.retries.holds(&path, RetryAction::Link, index)
+                {
+                    // It was counted as visible when it was handed over.
+                    self.stats.input_hidden(index, self.size(index, &path));
+                    self.fall_back(&path, index);
+                }
+                self.line.end();
+            }
+        }
+    }
+
+    /// Stops handing files to the workers, once they put every file there.
+    pub(crate) fn stop_deferring(&mut self) {
+        self.pool.deferring = false;
+        self.finish_links();
+    }
+
+    /// Whether a worker may still be busy with the path that folds to `key`, or a path
+    /// inside of it or that it is in, or one it only differs from by case.
+    pub(crate) fn in_flight(&self, key: &Folded) -> bool {
+        self.pool.in_flight.contains(key)
+    }
+
+    /// Puts the files into the output on `count` threads while syncing and handling
+    /// changes, so that deciding about the next file doesn't wait for the last to be
+    /// copied. What is done to the same path is still done in the order it was decided,
+    /// and the hooks, the audit log and the ledger hear about each file once it is there.
+    /// With 1, the default, every file is put there before the next is looked at.
+    ///
+    /// Hard links are quick enough that the pool doesn't make putting them there faster
+    /// yet, see `benches/creates.rs`; it is for copies and slow filesystems.
+    ///
+    /// The files that can't be put there count as errors of the change that was handled
+    /// when the workers were waited for, not of the one that linked them.
+    pub fn set_workers(&mut self, count: usize) {
+        self.finish_links();
+        self.pool.workers = if count > 1 {
+            Some(Workers::new(count))
+        } else {
+            None
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::MemoryFs;
+    use crate::link::HardLinks;
+    use std::path::Path;
+
+    #[test]
+    fn finds_what_is_in_flight_above_and_beneath() {
+        let mut in_flight = InFlight::default();
+        let key = |path| Folded::new(Path::new(path));
+        in_flight.insert(key("a/b/x"));
+        for path in ["a/b/x", "A/b/X", "a/b/x/y", "a/b", "a", ""] {
+            assert!(in_flight.contains(&key(path)), "{}", path);
+        }
+        for path in ["a/b/y", "a/c", "b"] {
+            assert!(!in_flight.contains(&key(path)), "{}", path);
+        }
+        in_flight.clear();
+        assert!(!in_flight.contains(&key("a")));
+    }
+
+    #[test]
+    fn every_file_handed_over_is_put_there_by_the_wait() {
+        let memory = Arc::new(MemoryFs::new());
+        let fs: Arc<dyn FileOps> = memory.clone();
+        memory.create_dir("/output");
+        let mut workers = Workers::new(3);
+        // More than a batch, and batches of some threads that aren't full.
+        let count = BATCH * 3 + 5;
+        for n in 0..count {
+            let from = PathBuf::from(format!("/input/{}", n));
+            memory.create_file(&from);
+            let put = Put {
+                path: PathBuf::from(n.to_string()),
+                index: 0,
+                from,
+                to: PathBuf::from(format!("/output/{}", n)),
+                strategy: Arc::new(HardLinks),
+                replaces: false,
+                progress: None,
+                #[cfg(feature = "tracing")]
+                span: tracing::Span::none(),
+            };
+            workers.submit(put, &fs);
+        }
+        let done = workers.wait();
+        assert_eq!(done.len(), count);
+        assert!(done.iter().all(|(_, result)| result.is_ok()));
+        for n in 0..count {
+            let (from, to) = (format!("/input/{}", n), format!("/output/{}", n));
+            assert!(memory.same_file(Path::new(&from), Path::new(&to)).unwrap());
+        }
+        assert!(workers.wait().is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+//! Counts what handling events allocates, with a global allocator that counts.
+
+use overlay::{InputOptions, MemoryFs, OverlayBuilder, SyntheticEvent};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const FILES: usize = 1000;
+
+/// What handling the creates of `FILES` files in base, all behind those of mods, allocates,
+/// with base set up by `options`.
+fn shadowed_creates(name: &str, options: &InputOptions) -> usize {
+    let root = env::temp_dir().join(format!("overlay-allocations-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&root);
+    let memory = Arc::new(MemoryFs::new());
+    let mut overlay = OverlayBuilder::new(root.join("output"))
+        .file_ops(memory.clone())
+        .single_instance(false)
+        .cross_input_window(Duration::ZERO)
+        .build()
+        .unwrap();
+    let mut inputs = vec![];
+    for (input, priority, options) in [("base", 0, options), ("mods", 1, &InputOptions::new())] {
+        let input = root.join(input);
+        fs::create_dir_all(&input).unwrap();
+        memory.create_dir(&input);
+        let id = overlay
+            .add_input_with_options(&input, priority, options)
+            .unwrap();
+        inputs.push((id, input));
+    }
+    overlay.sync_once().unwrap();
+
+    let paths: Vec<PathBuf> = (0..FILES)
+        .map(|n| PathBuf::from(format!("Data/dir{:02}/file{:03}.dat", n / 100, n)))
+        .collect();
+    let mut create = |(id, input): &(_, PathBuf)| {
+        for path in &paths {
+            memory.create_file(input.join(path));
+            overlay
+                .inject_event(*id, SyntheticEvent::Create(path.clone()))
+                .unwrap();
+        }
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        overlay.drain().unwrap();
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    };
+    create(&inputs[1]);
+    let allocations = create(&inputs[0]);
+    let stats = overlay.stats();
+    assert_eq!(stats.linked, FILES as u64);
+    assert_eq!(stats.inputs[0].shadowed, FILES);
+    let _ = fs::remove_dir_all(&root);
+    allocations
+}
+
+/// The only test here, as the count is of every thread.
+#[test]
+#[cfg_attr(
+    feature = "metrics",
+    ignore = "publishing the stats of an input copies its label into each gauge"
+)]
+fn shadowed_creates_allocate_nothing_for_what_their_input_holds() {
+    let plain = shadowed_creates("plain", &InputOptions::new());
+    // All of which the heaps of the paths used to have a copy of for every file.
+    let options = InputOptions::new()
+        .label("unofficial-patch-with-a-long-label")
+        .exclude("*.tmp")
+        .exclude("*.bak")
+        .exclude("Cache/**");
+    let full = shadowed_creates("full", &options);
+    assert_eq!(
+        full, plain,
+        "allocations for the shadowed creates of an input with a label and patterns, and of \
+         a plain one"
+    );
+}
@@ -0,0 +1,571 @@
+//! Runs the `overlay` binary the way a user would.
+
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+/// A fresh directory of its own for the test `name`.
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("overlay-cli-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Inputs `base`, labelled so, and `mods` above it, labelled so too, that both have the
+/// file `x`, and the configuration of them in `root`, with `extra` at its top.
+fn configure(root: &Path, extra: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let base = root.join("base");
+    let mods = root.join("mods");
+    for input in [&base, &mods] {
+        fs::create_dir_all(input).unwrap();
+        fs::write(input.join("x"), input.to_str().unwrap()).unwrap();
+    }
+    let config = root.join("overlay.toml");
+    let text = format!(
+        "output = '{}'\n{}\n\
+         [[inputs]]\npath = '{}'\nlabel = 'base'\npriority = 0\n\
+         [[inputs]]\npath = '{}'\nlabel = 'mods'\npriority = 1\n",
+        root.join("output").display(),
+        extra,
+        base.display(),
+        mods.display()
+    );
+    fs::write(&config, text).unwrap();
+    (config, base, mods)
+}
+
+fn overlay<P: AsRef<Path>>(args: &[&str], config: P) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(args)
+        .arg("--config")
+        .arg(config.as_ref())
+        .output()
+        .unwrap()
+}
+
+/// Waits until a running overlay wrote that it synced to the log file at `log`.
+#[cfg(unix)]
+fn wait_until_synced(log: &Path) {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let until = Instant::now() + Duration::from_secs(10);
+    while !fs::read_to_string(log).is_ok_and(|lines| lines.contains("Synced output")) {
+        assert!(Instant::now() < until, "the overlay took too long to sync");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Stops the overlay `running` once it wrote that it synced to the log file at `log`, and
+/// returns how it ended.
+#[cfg(unix)]
+fn stop_once_synced(running: process::Child, log: &Path) -> Output {
+    wait_until_synced(log);
+    let stopped = Command::new("kill")
+        .arg(running.id().to_string())
+        .status()
+        .unwrap();
+    assert!(stopped.success());
+    running.wait_with_output().unwrap()
+}
+
+#[test]
+fn shadowed_finds_the_input_by_label_or_path() {
+    let root = scratch("shadowed");
+    let (config, base, _) = configure(&root, "");
+
+    for name in ["base", base.to_str().unwrap()] {
+        let output = overlay(&["shadowed", "--json", name], &config);
+        assert!(output.status.success(), "{:?}", output);
+        let shadowed: Value = serde_json::from_slice(&output.stdout).unwrap();
+        let shadowed = shadowed.as_array().unwrap();
+        assert_eq!(shadowed.len(), 1, "{}", name);
+        assert_eq!(shadowed[0]["path"], "x");
+        assert_eq!(shadowed[0]["winner"]["label"], "mods");
+    }
+
+    let output = overlay(&["shadowed", "base-game"], &config);
+    assert_eq!(output.status.code(), Some(2));
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.contains("unknown input 'base-game', the inputs are 'base', 'mods'"),
+        "{}",
+        error
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn ctl_finds_the_input_by_label_or_path() {
+    use std::process::Stdio;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let root = scratch("ctl");
+    let socket = root.join("control.sock");
+    let extra = format!("control_socket = '{}'", socket.display());
+    let (config, _, mods) = configure(&root, &extra);
+    let mut running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .arg("-q")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let until = Instant::now() + Duration::from_secs(10);
+    while !overlay(&["ctl", "status"], &config).status.success() {
+        assert!(
+            Instant::now() < until,
+            "the overlay took too long to listen"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    for name in ["base", mods.to_str().unwrap()] {
+        let output = overlay(&["ctl", "pause", name], &config);
+        assert!(output.status.success(), "{}: {:?}", name, output);
+    }
+    let output = overlay(&["ctl", "pause", "base-game"], &config);
+    assert_eq!(output.status.code(), Some(1));
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(error.contains("unknown input 'base-game'"), "{}", error);
+
+    running.kill().unwrap();
+    running.wait().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn verbosity_picks_what_the_console_shows_and_the_log_file_keeps_the_rest() {
+    use std::process::Stdio;
+
+    for (name, flags) in [
+        ("quiet", &["-q"][..]),
+        ("default", &[]),
+        ("verbose", &["-v"]),
+    ] {
+        let root = scratch(&format!("verbosity-{}", name));
+        let (config, _, _) = configure(&root, "");
+        let log = root.join("overlay.log");
+        fs::write(&log, "from before\n").unwrap();
+        let running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+            .args(flags)
+            .arg("--log-file")
+            .arg(&log)
+            .arg(&config)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let output = stop_once_synced(running, &log);
+        assert!(output.status.success(), "{}: {:?}", name, output);
+
+        let console = String::from_utf8(output.stdout).unwrap();
+        assert!(console.contains("Synced output: "), "{}: {}", name, console);
+        assert!(
+            console.contains("Stopping after 1 links"),
+            "{}: {}",
+            name,
+            console
+        );
+        assert_eq!(
+            console.contains("LINKED!"),
+            name != "quiet",
+            "{}: {}",
+            name,
+            console
+        );
+        assert_eq!(
+            console.contains("IGNORED!"),
+            name == "verbose",
+            "{}: {}",
+            name,
+            console
+        );
+
+        // Appended to, at least at the default level, with when each line was written.
+        let lines = fs::read_to_string(&log).unwrap();
+        let mut lines = lines.lines();
+        assert_eq!(lines.next(), Some("from before"));
+        let linked = lines.find(|line| line.contains("LINKED!")).unwrap();
+        let (timestamp, rest) = linked.split_once(' ').unwrap();
+        assert!(
+            timestamp.ends_with('Z') && timestamp.contains('T'),
+            "{}",
+            linked
+        );
+        assert!(rest.starts_with("INFO "), "{}", linked);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn a_json_stream_on_stdout_has_every_action_and_the_logs_go_to_stderr() {
+    use std::process::Stdio;
+
+    let root = scratch("json-stream");
+    let (config, _, _) = configure(&root, "");
+    let log = root.join("overlay.log");
+    let running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(["--output-format", "json", "--log-file"])
+        .arg(&log)
+        .arg(&config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let output = stop_once_synced(running, &log);
+    assert!(output.status.success(), "{:?}", output);
+    let records: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(records.iter().all(|record| record["schema"] == 1
+        && record["timestamp"].is_string()
+        && record["type"].is_string()));
+    let of = |kind: &'static str| records.iter().filter(move |record| record["type"] == kind);
+    let linked: Vec<&Value> = of("linked").collect();
+    assert_eq!(linked.len(), 1);
+    assert_eq!(
+        (&linked[0]["path"], &linked[0]["label"], &linked[0]["ok"]),
+        (&Value::from("x"), &Value::from("mods"), &Value::from(true))
+    );
+    assert_eq!(of("shadowed").count(), 1);
+    let sync: Vec<&Value> = of("sync").collect();
+    assert_eq!(sync.len(), 1);
+    assert_eq!(sync[0]["linked"], 1);
+    assert_eq!(of("sync_progress").next_back().unwrap()["done"], 2);
+    assert_eq!(of("summary").count(), 1);
+    // What people read is all on stderr.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Synced output: "), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_daemon_is_up_once_synced_and_reopens_its_log_when_asked() {
+    use std::os::unix::net::UnixDatagram;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let root = scratch("daemon");
+    let (config, _, mods) = configure(&root, "");
+    let (pidfile, log) = (root.join("overlay.pid"), root.join("overlay.log"));
+    let notify = UnixDatagram::bind(root.join("notify.sock")).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let notified = || {
+        let mut state = [0; 64];
+        let length = notify.recv(&mut state).unwrap();
+        String::from_utf8(state[..length].to_vec()).unwrap()
+    };
+
+    // Returns once the detached overlay synced.
+    let status = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(["--daemon", "--pidfile"])
+        .arg(&pidfile)
+        .arg("--log-file")
+        .arg(&log)
+        .arg(&config)
+        .env("NOTIFY_SOCKET", root.join("notify.sock"))
+        .status()
+        .unwrap();
+    assert!(status.success(), "{:?}", status);
+    assert_eq!(notified(), "READY=1");
+    assert_eq!(
+        fs::read(root.join("output/x")).unwrap(),
+        fs::read(mods.join("x")).unwrap()
+    );
+    let pid = fs::read_to_string(&pidfile).unwrap().trim().to_string();
+    let signal = |name: &str| {
+        let status = Command::new("kill").args(["-s", name, &pid]).status();
+        assert!(status.unwrap().success(), "{}", name);
+    };
+
+    // As logrotate does.
+    fs::rename(&log, root.join("overlay.log.1")).unwrap();
+    signal("HUP");
+    signal("TERM");
+    assert_eq!(notified(), "STOPPING=1");
+    let until = Instant::now() + Duration::from_secs(10);
+    while pidfile.exists() {
+        assert!(Instant::now() < until, "the overlay took too long to stop");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let rotated = fs::read_to_string(root.join("overlay.log.1")).unwrap();
+    assert!(rotated.contains("Synced output"), "{}", rotated);
+    let reopened = fs::read_to_string(&log).unwrap();
+    assert!(reopened.contains("Stopping after"), "{}", reopened);
+    assert!(!reopened.contains("Synced output"), "{}", reopened);
+}
+
+#[cfg(unix)]
+#[test]
+fn the_exit_code_tells_a_clean_run_from_file_errors_from_bad_config_from_not_starting() {
+    use std::process::Stdio;
+
+    let root = scratch("exit-codes");
+    let (config, base, _) = configure(&root, "case_conflicts = 'error'");
+    let log = root.join("overlay.log");
+    let start = || {
+        Command::new(env!("CARGO_BIN_EXE_overlay"))
+            .arg("--log-file")
+            .arg(&log)
+            .arg(&config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+
+    fs::write(base.join("X"), "").unwrap();
+    let output = stop_once_synced(start(), &log);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.contains("1 errors:\n  X: case_conflict: differs only by case from x\n"),
+        "{}",
+        error
+    );
+
+    fs::remove_file(base.join("X")).unwrap();
+    fs::remove_file(&log).unwrap();
+    let running = start();
+    wait_until_synced(&log);
+    // Another one can't start on the same output.
+    let pid = running.id();
+    let output = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .arg(&config)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.contains(&format!("already being overlaid by pid {}", pid)),
+        "{}",
+        error
+    );
+    let output = stop_once_synced(running, &log);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+    fs::remove_dir_all(&base).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .arg(&config)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(error.contains("doesn't exist"), "{}", error);
+}
+
+#[test]
+fn the_environment_overrides_the_file_and_names_what_it_got_wrong() {
+    let root = scratch("environment");
+    let (config, base, mods) = configure(&root, "");
+    let shadowed = |vars: &[(&str, &str)]| {
+        Command::new(env!("CARGO_BIN_EXE_overlay"))
+            .args(["shadowed", "--json", mods.to_str().unwrap(), "--config"])
+            .arg(&config)
+            .envs(vars.iter().copied())
+            .output()
+            .unwrap()
+    };
+
+    // The other way around from the file, and without the labels.
+    let inputs = env::join_paths([
+        format!("{}=1", base.display()),
+        format!("{}=0", mods.display()),
+    ])
+    .unwrap();
+    let output = shadowed(&[("OVERLAY_INPUTS", inputs.to_str().unwrap())]);
+    assert!(output.status.success(), "{:?}", output);
+    let shadowed_by: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(shadowed_by[0]["path"], "x");
+    assert_eq!(shadowed_by[0]["winner"]["label"], Value::Null);
+    assert_eq!(shadowed_by[0]["winner"]["priority"], 1);
+
+    for (name, value, expected) in [
+        (
+            "OVERLAY_DEBOUNCE_MS",
+            "soon",
+            "OVERLAY_DEBOUNCE_MS=\"soon\": ",
+        ),
+        (
+            "OVERLAY_DRY_RUN",
+            "maybe",
+            "OVERLAY_DRY_RUN=\"maybe\": expected true or false",
+        ),
+        (
+            "OVERLAY_STRATEGY",
+            "teleport",
+            "OVERLAY_STRATEGY=\"teleport\": ",
+        ),
+        (
+            "OVERLAY_INPUTS",
+            "base=high",
+            "OVERLAY_INPUTS: \"high\" is not a priority",
+        ),
+    ] {
+        let output = shadowed(&[(name, value)]);
+        assert_eq!(output.status.code(), Some(2), "{}: {:?}", name, output);
+        let error = String::from_utf8(output.stderr).unwrap();
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn flags_override_the_environment() {
+    use std::process::Stdio;
+
+    let root = scratch("flags-over-environment");
+    let (config, _, _) = configure(&root, "dry_run = false");
+    let log = root.join("overlay.log");
+    let running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(["--dry-run", "--log-file"])
+        .arg(&log)
+        .arg(&config)
+        .env("OVERLAY_DRY_RUN", "no")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let output = stop_once_synced(running, &log);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!root.join("output").join("x").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn verify_lists_how_the_output_differs_from_its_manifest_and_fails_if_it_does() {
+    use std::process::Stdio;
+
+    let root = scratch("verify");
+    let (config, _, mods) = configure(&root, "");
+    let log = root.join("overlay.log");
+    let running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .arg("--log-file")
+        .arg(&log)
+        .arg(&config)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert!(stop_once_synced(running, &log).status.success());
+    let manifest = root.join("manifest.json");
+    let output = overlay(&["manifest", manifest.to_str().unwrap()], &config);
+    assert!(output.status.success(), "{:?}", output);
+    let written: Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    assert_eq!(written["entries"][0]["path"], "x");
+    assert_eq!(written["entries"][0]["label"], "mods");
+
+    let verify = |json: bool| {
+        let mut args = vec!["verify", "--manifest", manifest.to_str().unwrap()];
+        if json {
+            args.push("--json");
+        }
+        overlay(&args, &config)
+    };
+    let output = verify(false);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(output.stdout.is_empty(), "{:?}", output);
+
+    // Through the hard link into the output.
+    fs::write(mods.join("x"), "edited").unwrap();
+    fs::write(root.join("output/foreign"), "").unwrap();
+    let output = verify(false);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "added: foreign\nmodified: x\n"
+    );
+    let output = verify(true);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["added"], serde_json::json!(["foreign"]));
+    assert_eq!(report["modified"], serde_json::json!(["x"]));
+    assert_eq!(report["missing"], serde_json::json!([]));
+}
+
+#[test]
+fn doctor_tells_each_check_and_fails_if_any_does() {
+    let root = scratch("doctor");
+    let (config, base, _) = configure(&root, "");
+    let output = overlay(&["doctor"], &config);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.lines().all(|line| !line.starts_with("FAIL")),
+        "{}",
+        stdout
+    );
+
+    fs::remove_dir_all(&base).unwrap();
+    let output = overlay(&["doctor"], &config);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let missing = format!("FAIL inputs: {} doesn't exist\n", base.display());
+    assert!(stdout.contains(&missing), "{}", stdout);
+    let output = overlay(&["doctor", "--json"], &config);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let checks: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let failed: Vec<&Value> = checks
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|check| check["status"] == "fail")
+        .collect();
+    assert_eq!(failed.len(), 1, "{:#?}", checks);
+    assert_eq!(failed[0]["name"], "inputs");
+    assert!(failed[0]["hint"].is_string());
+}
+
+#[cfg(unix)]
+#[test]
+fn a_system_log_that_isnt_there_is_warned_about_and_done_without() {
+    use std::process::Stdio;
+
+    let root = scratch("system-log");
+    let (config, _, mods) = configure(&root, "");
+    let output = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(["--log-syslog", "--syslog-facility", "nope"])
+        .arg(&config)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.contains("there is no syslog facility nope"),
+        "{}",
+        error
+    );
+
+    let log = root.join("overlay.log");
+    let running = Command::new(env!("CARGO_BIN_EXE_overlay"))
+        .args(["--log-eventlog", "--log-file"])
+        .arg(&log)
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let output = stop_once_synced(running, &log);
+    assert!(output.status.success(), "{:?}", output);
+    let logged = fs::read_to_string(&log).unwrap();
+    assert!(
+        logged.contains(
+            "Not logging to the log of the system: the Event Log is only on Windows, syslog \
+             is used with --log-syslog"
+        ),
+        "{}",
+        logged
+    );
+    assert_eq!(
+        fs::read(root.join("output/x")).unwrap(),
+        fs::read(mods.join("x")).unwrap()
+    );
+}